@@ -39,6 +39,57 @@ fn configure_file(path: &str, transforms: Vec<(&str, &str)>) -> Result<()> {
     Ok(())
 }
 
+/// Size (in bytes) of LibOSDP's packet buffers, which bounds the largest
+/// packet it can send or receive, overridden with
+/// `LIBOSDP_OSDP_PACKET_BUF_SIZE`. It is also exported to Rust (as
+/// `libosdp_sys::OSDP_PACKET_BUF_SIZE`) so that the `libosdp` crate can size
+/// what it sends to what the other end can take.
+const PACKET_BUF_SIZE: &str = "OSDP_PACKET_BUF_SIZE";
+
+/// Interval (in milliseconds) at which the CP polls a PD that is online;
+/// exported so that the `libosdp` crate knows when LibOSDP next has work to
+/// do.
+const PD_POLL_TIMEOUT: &str = "OSDP_PD_POLL_TIMEOUT_MS";
+
+/// Smallest receive buffer that OSDP allows a PD to have.
+const MIN_PACKET_BUF_SIZE: u32 = 128;
+
+fn override_defines(path: &str, defines: Vec<(&str, String)>) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut lines: Vec<String> = contents.lines().map(|l| l.to_owned()).collect();
+
+    for (name, value) in defines {
+        let prefix = format!("#define {name} ");
+        let line = lines
+            .iter_mut()
+            .find(|l| l.starts_with(&prefix))
+            .context(format!("{name} is not defined in {path}"))?;
+        *line = format!("#define {name} ({value})");
+    }
+    std::fs::write(path, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Find the sysroot of the libc used for a bare-metal (newlib, usually) or
+/// WASI (wasi-libc) target, so that bindgen sees the same headers as the
+/// compiler. `LIBOSDP_SYSROOT` takes precedence over `WASI_SYSROOT` (for
+/// WASI) and what the compiler reports with `-print-sysroot`.
+fn target_sysroot(build: &cc::Build, target_os: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed=LIBOSDP_SYSROOT");
+    if let Ok(sysroot) = std::env::var("LIBOSDP_SYSROOT") {
+        return Some(sysroot);
+    }
+    if target_os == "wasi" {
+        println!("cargo:rerun-if-env-changed=WASI_SYSROOT");
+        return std::env::var("WASI_SYSROOT").ok();
+    }
+    let compiler = build.get_compiler();
+    let compiler = compiler.path().to_str()?;
+    exec_cmd(vec![compiler, "-print-sysroot"])
+        .ok()
+        .filter(|s| !s.is_empty())
+}
+
 fn exec_cmd(cmd: Vec<&str>) -> Result<String> {
     let mut c = Command::new(cmd[0]);
     let mut c = c.borrow_mut();
@@ -98,7 +149,45 @@ fn generate_osdp_build_headers(out_dir: &str) -> Result<()> {
             ("GIT_DIFF", git.diff.as_ref()),
             ("REPO_ROOT", git.root.as_ref()),
         ],
-    )
+    )?;
+
+    let mut defines = Vec::new();
+    let var = format!("LIBOSDP_{PACKET_BUF_SIZE}");
+    println!("cargo:rerun-if-env-changed={var}");
+    if let Ok(value) = std::env::var(&var) {
+        let value: u32 = value
+            .parse()
+            .ok()
+            .filter(|v| (MIN_PACKET_BUF_SIZE..=u16::MAX as u32).contains(v))
+            .context(format!(
+                "{var} must be a number (bytes) from {MIN_PACKET_BUF_SIZE} to 65535"
+            ))?;
+        defines.push((PACKET_BUF_SIZE, value.to_string()));
+    }
+    override_defines(&dest, defines)?;
+    export_defines(&dest, out_dir, &[PACKET_BUF_SIZE, PD_POLL_TIMEOUT])
+}
+
+/// Write the (numeric) value of `names`, as defined in the header at `path`,
+/// to `config.rs` in `out_dir` as Rust constants.
+fn export_defines(path: &str, out_dir: &str, names: &[&str]) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut consts = String::new();
+    for name in names {
+        let prefix = format!("#define {name} ");
+        let value = contents
+            .lines()
+            .find_map(|l| l.strip_prefix(&prefix))
+            .context(format!("{name} is not defined in {path}"))?;
+        let value: usize = value
+            .trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .parse()
+            .context(format!("{name} is not a number in {path}"))?;
+        consts += &format!("pub const {name}: usize = {value};\n");
+    }
+    std::fs::write(path_join(out_dir, "config.rs"), consts).context("Failed to create config.rs")
 }
 
 fn main() -> Result<()> {
@@ -124,10 +213,29 @@ fn main() -> Result<()> {
     }
 
     let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
-    if target_os.is_empty() || target_os == "none" {
+    // wasm32-unknown-unknown has no libc either; it is built like a
+    // bare-metal target against one given with LIBOSDP_SYSROOT.
+    let bare_metal = target_os.is_empty() || target_os == "none" || target_os == "unknown";
+    if bare_metal {
         println!("cargo:warning=Building for bare metal target");
+        // Packet traces are written to pcap files, which needs a file system.
+        if cfg!(feature = "packet_trace") || cfg!(feature = "data_trace") {
+            anyhow::bail!("packet_trace and data_trace are not supported on bare metal targets");
+        }
+        // LibOSDP reads the time (osdp_millis_now) and random numbers
+        // (osdp_fill_random) from functions that the application provides
+        // (libosdp::set_time_source and libosdp::set_rng_source); everything
+        // else it needs comes from newlib.
         build = build.define("__BARE_METAL__", "1")
     }
+    let sysroot = if bare_metal || target_os == "wasi" {
+        target_sysroot(build, &target_os)
+    } else {
+        None
+    };
+    if let Some(sysroot) = &sysroot {
+        build = build.flag(format!("--sysroot={sysroot}"));
+    }
 
     let source_files = vec![
         "vendor/utils/src/list.c",
@@ -142,9 +250,19 @@ fn main() -> Result<()> {
         "vendor/src/osdp_file.c",
         "vendor/src/osdp_pd.c",
         "vendor/src/osdp_cp.c",
+    ];
+
+    // With custom_crypto, the application supplies osdp_encrypt() and
+    // osdp_decrypt() (see libosdp::set_aes_backend); tinyAES is then built
+    // on its own, under other names, as the fallback for when it hasn't.
+    let aes_files = [
         "vendor/src/crypto/tinyaes_src.c",
         "vendor/src/crypto/tinyaes.c",
     ];
+    let mut aes = cfg!(feature = "custom_crypto").then(|| build.clone());
+    if aes.is_none() {
+        build = build.files(aes_files);
+    }
 
     for file in source_files {
         build = build.file(file);
@@ -172,11 +290,27 @@ fn main() -> Result<()> {
     if short_enums {
         build.flag("-fshort-enums");
     }
+    if let Some(aes) = aes.as_mut() {
+        if short_enums {
+            aes.flag("-fshort-enums");
+        }
+        aes.define("osdp_encrypt", "osdp_tinyaes_encrypt")
+            .define("osdp_decrypt", "osdp_tinyaes_decrypt")
+            .files(aes_files)
+            .compile("osdp_tinyaes");
+    }
     build.compile("libosdp.a");
 
     /* generate bindings */
 
     let mut args = vec![format!("-I{}", &out_dir)];
+    if bare_metal {
+        args.push("-D__BARE_METAL__=1".to_owned());
+    }
+    if let Some(sysroot) = &sysroot {
+        args.push(format!("--sysroot={sysroot}"));
+        args.push(format!("-I{}", path_join(sysroot, "include")));
+    }
     if short_enums {
         args.push("-fshort-enums".to_owned());
     } else {
@@ -186,6 +320,10 @@ fn main() -> Result<()> {
         .use_core()
         .header("vendor/include/osdp.h")
         .clang_args(args)
+        // Only the LibOSDP API; types it depends on are pulled in as needed.
+        .allowlist_function("osdp_.*")
+        .allowlist_type("osdp_.*")
+        .allowlist_var("(osdp|OSDP)_.*")
         .generate()
         .context("Unable to generate bindings")?;
 
@@ -10,3 +10,4 @@
 #![allow(unused)]
 
 core::include!(core::concat!(core::env!("OUT_DIR"), "/bindings.rs"));
+core::include!(core::concat!(core::env!("OUT_DIR"), "/config.rs"));
@@ -4,7 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use libosdp::{Channel, ChannelError, OsdpError, OsdpFlag, PdInfoBuilder};
-use std::{env, thread, time::Duration};
+use std::env;
 
 struct OsdpChannel;
 
@@ -58,11 +58,12 @@ fn main() -> Result<(), OsdpError> {
         .baud_rate(115200)?
         .flag(OsdpFlag::EnforceSecure)
         .secure_channel_key(pd_0_key);
-    let mut cp = libosdp::ControlPanelBuilder::new()
+    let cp = libosdp::ControlPanelBuilder::new()
         .add_channel(Box::new(channel), vec![pd_0])
-        .build()?;
-    loop {
-        cp.refresh();
-        thread::sleep(Duration::from_millis(50));
+        .build()?
+        .spawn()?;
+    for (pd, event) in cp.events() {
+        log::info!("PD-{pd}: {:?}", event);
     }
+    Ok(())
 }
@@ -62,7 +62,7 @@ fn main() -> Result<(), OsdpError> {
         .capability(PdCapability::CommunicationSecurity(PdCapEntity::new(1, 1)))
         .secure_channel_key(key);
     let mut pd = libosdp::PeripheralDevice::new(pd_info, Box::new(channel))?;
-    pd.set_command_callback(|_| {
+    let _command_callback = pd.set_command_callback(|_| {
         println!("Received command!");
         0
     });
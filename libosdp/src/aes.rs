@@ -0,0 +1,228 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The secure channel encrypts with AES-128: single blocks in ECB mode to
+//! derive session keys and cryptograms, and messages in CBC mode. LibOSDP
+//! does this in software (tinyAES). With the `aes-backend` feature, it calls
+//! `osdp_encrypt()`/`osdp_decrypt()` from this module instead, which use the
+//! [`AesBackend`] that the application registers with [`set_aes_backend`]
+//! (such as the crypto accelerator of the MCU, which is faster and less prone
+//! to timing side channels). Until one is registered, tinyAES is used; it is
+//! also used for any operation the backend panics in.
+
+use crate::callback::catch_panic;
+use alloc::boxed::Box;
+use core::{
+    ffi::c_int,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// AES-128 primitives for the secure channel
+///
+/// Only the single block (ECB) operations are required; the CBC ones are
+/// built on them by default and can be overridden for hardware that does CBC
+/// itself.
+pub trait AesBackend: Send + Sync {
+    /// Encrypt `block` in place with `key`
+    fn ecb_encrypt(&self, key: &[u8; 16], block: &mut [u8; 16]);
+
+    /// Decrypt `block` in place with `key`
+    fn ecb_decrypt(&self, key: &[u8; 16], block: &mut [u8; 16]);
+
+    /// Encrypt `data` (a multiple of 16 bytes long) in place with `key` in
+    /// CBC mode, starting from `iv`
+    fn cbc_encrypt(&self, key: &[u8; 16], iv: &[u8; 16], data: &mut [u8]) {
+        let mut prev = *iv;
+        for block in data.chunks_exact_mut(16) {
+            let block: &mut [u8; 16] = block.try_into().unwrap();
+            block.iter_mut().zip(prev).for_each(|(b, p)| *b ^= p);
+            self.ecb_encrypt(key, block);
+            prev = *block;
+        }
+    }
+
+    /// Decrypt `data` (a multiple of 16 bytes long) in place with `key` in
+    /// CBC mode, starting from `iv`
+    fn cbc_decrypt(&self, key: &[u8; 16], iv: &[u8; 16], data: &mut [u8]) {
+        let mut prev = *iv;
+        for block in data.chunks_exact_mut(16) {
+            let block: &mut [u8; 16] = block.try_into().unwrap();
+            let cipher = *block;
+            self.ecb_decrypt(key, block);
+            block.iter_mut().zip(prev).for_each(|(b, p)| *b ^= p);
+            prev = cipher;
+        }
+    }
+}
+
+static AES_BACKEND: AtomicPtr<Box<dyn AesBackend>> = AtomicPtr::new(ptr::null_mut());
+
+/// Register the AES implementation that the secure channel uses from then on.
+/// This should be done before creating a CP or PD so that a session doesn't
+/// switch implementations midway (which is harmless, but pointless).
+///
+/// The backend is never dropped, as LibOSDP may be using it while it is being
+/// replaced.
+pub fn set_aes_backend(backend: impl AesBackend + 'static) {
+    let backend: Box<Box<dyn AesBackend>> = Box::new(Box::new(backend));
+    AES_BACKEND.store(Box::into_raw(backend), Ordering::Release);
+}
+
+extern "C" {
+    // tinyAES, renamed by libosdp-sys to make way for the functions below
+    fn osdp_tinyaes_encrypt(key: *mut u8, iv: *mut u8, data: *mut u8, len: c_int);
+    fn osdp_tinyaes_decrypt(key: *mut u8, iv: *mut u8, data: *mut u8, len: c_int);
+}
+
+/// Run `op` with the registered backend and the arguments LibOSDP passed: a
+/// single block without `iv` (ECB) and `len` bytes with it (CBC). Returns
+/// false, with `data` as it was, if there is no backend or it panicked (the
+/// panic is logged rather than unwound into LibOSDP); tinyAES is to be used
+/// then.
+///
+/// # Safety
+///
+/// `key` (and `iv`, if not NULL) must point to 16 bytes and `data` to `len`
+/// bytes, and at least 16 when `iv` is NULL.
+unsafe fn with_backend(
+    key: *mut u8,
+    iv: *mut u8,
+    data: *mut u8,
+    len: c_int,
+    op: impl FnOnce(&dyn AesBackend, &[u8; 16], Option<&[u8; 16]>, &mut [u8]),
+) -> bool {
+    let backend = AES_BACKEND.load(Ordering::Acquire);
+    // SAFETY: only ever set from a leaked box in set_aes_backend.
+    let Some(backend) = (unsafe { backend.as_ref() }) else {
+        return false;
+    };
+    let key = unsafe { &*(key as *const [u8; 16]) };
+    let iv = unsafe { (iv as *const [u8; 16]).as_ref() };
+    let len = match iv {
+        Some(_) => len.max(0) as usize,
+        None => 16,
+    };
+    let data = unsafe { core::slice::from_raw_parts_mut(data, len) };
+    #[cfg(feature = "std")]
+    let saved = data.to_vec();
+    let done = catch_panic("AesBackend", false, || {
+        op(&**backend, key, iv, data);
+        true
+    });
+    #[cfg(feature = "std")]
+    if !done {
+        data.copy_from_slice(&saved);
+    }
+    done
+}
+
+/// Overrides LibOSDP's tinyAES; see libosdp-sys's `custom_crypto` feature
+#[no_mangle]
+extern "C" fn osdp_encrypt(key: *mut u8, iv: *mut u8, data: *mut u8, len: c_int) {
+    let done = unsafe {
+        with_backend(key, iv, data, len, |backend, key, iv, data| match iv {
+            Some(iv) => backend.cbc_encrypt(key, iv, data),
+            None => backend.ecb_encrypt(key, data.try_into().unwrap()),
+        })
+    };
+    if !done {
+        unsafe { osdp_tinyaes_encrypt(key, iv, data, len) }
+    }
+}
+
+/// Overrides LibOSDP's tinyAES; see libosdp-sys's `custom_crypto` feature
+#[no_mangle]
+extern "C" fn osdp_decrypt(key: *mut u8, iv: *mut u8, data: *mut u8, len: c_int) {
+    let done = unsafe {
+        with_backend(key, iv, data, len, |backend, key, iv, data| match iv {
+            Some(iv) => backend.cbc_decrypt(key, iv, data),
+            None => backend.ecb_decrypt(key, data.try_into().unwrap()),
+        })
+    };
+    if !done {
+        unsafe { osdp_tinyaes_decrypt(key, iv, data, len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_backend, AesBackend};
+    use alloc::boxed::Box;
+    use core::sync::atomic::Ordering;
+
+    /// Not AES, but a block cipher all the same, to test the CBC chaining
+    struct Rotate;
+
+    impl AesBackend for Rotate {
+        fn ecb_encrypt(&self, key: &[u8; 16], block: &mut [u8; 16]) {
+            block.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+            block.rotate_left(1);
+        }
+
+        fn ecb_decrypt(&self, key: &[u8; 16], block: &mut [u8; 16]) {
+            block.rotate_right(1);
+            block.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+        }
+    }
+
+    struct Panics;
+
+    impl AesBackend for Panics {
+        fn ecb_encrypt(&self, _key: &[u8; 16], block: &mut [u8; 16]) {
+            block.fill(0);
+            panic!("no crypto engine");
+        }
+
+        fn ecb_decrypt(&self, _key: &[u8; 16], _block: &mut [u8; 16]) {
+            panic!("no crypto engine");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_backend_panic() {
+        let backend: Box<dyn AesBackend> = Box::new(Panics);
+        let backend = Box::into_raw(Box::new(backend));
+        super::AES_BACKEND.store(backend, Ordering::Release);
+        let mut key = [0x5a; 16];
+        let mut data = [0x42u8; 32];
+        let mut iv = [0u8; 16];
+        let done = unsafe {
+            with_backend(
+                key.as_mut_ptr(),
+                iv.as_mut_ptr(),
+                data.as_mut_ptr(),
+                data.len() as _,
+                |backend, key, iv, data| backend.cbc_encrypt(key, iv.unwrap(), data),
+            )
+        };
+        super::AES_BACKEND.store(core::ptr::null_mut(), Ordering::Release);
+        drop(unsafe { Box::from_raw(backend) });
+        // Not unwound into the caller and the data is left for tinyAES
+        assert!(!done);
+        assert_eq!(data, [0x42; 32]);
+    }
+
+    #[test]
+    fn test_cbc() {
+        let key = [0x5a; 16];
+        let iv: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let plain = [0x42u8; 48];
+
+        let mut data = plain;
+        Rotate.cbc_encrypt(&key, &iv, &mut data);
+        // Identical plaintext blocks encrypt differently
+        assert_ne!(data[..16], data[16..32]);
+        assert_ne!(data[16..32], data[32..]);
+        let mut first = <[u8; 16]>::try_from(&plain[..16]).unwrap();
+        first.iter_mut().zip(iv).for_each(|(b, i)| *b ^= i);
+        Rotate.ecb_encrypt(&key, &mut first);
+        assert_eq!(data[..16], first);
+
+        Rotate.cbc_decrypt(&key, &iv, &mut data);
+        assert_eq!(data, plain);
+    }
+}
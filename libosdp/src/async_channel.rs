@@ -0,0 +1,442 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A [`Channel`] over any tokio [`AsyncRead`] + [`AsyncWrite`] transport. This
+//! lets applications that are already written in async Rust (IP-to-RS485
+//! gateways, for instance) run many OSDP buses on a runtime without a
+//! dedicated blocking thread per port.
+//!
+//! For serial buses, [`AsyncChannel::serial`] (with the `tokio-serial`
+//! feature) takes a `tokio_serial::SerialStream`. The channel is then a
+//! [`crate::ReconfigurableChannel`], so that the baud rate is switched after a
+//! COMSET; the pump does that once everything written before is out.
+//!
+//! [`AsyncChannel::new`] splits the transport into two parts: the channel,
+//! which is handed over to a CP or PD as usual, and a [`ChannelPump`] future
+//! that moves bytes between the transport and the channel; it is meant to be
+//! spawned on the runtime. LibOSDP itself never blocks on the transport; its
+//! reads and writes go to buffers that the pump fills and drains.
+//!
+//! ```ignore
+//! let port = tokio_serial::new("/dev/ttyUSB0", 115200).open_native_async()?;
+//! let (channel, pump) = AsyncChannel::serial(0, port);
+//! let readable = channel.readable();
+//! tokio::spawn(pump.run());
+//! let mut cp = ControlPanelBuilder::new()
+//!     .add_channel(Box::new(channel), pd_info)
+//!     .build()?;
+//! loop {
+//!     cp.refresh();
+//!     readable.wait(Duration::from_millis(50)).await;
+//! }
+//! ```
+
+use crate::{Channel, ChannelError, ReconfigurableChannel};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    task::Poll,
+    time::Duration,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Notify,
+};
+
+/// Switches the transport of a [`ChannelPump`] to a new baud rate
+type SetBaudRate<T> = fn(Pin<&mut T>, u32) -> std::io::Result<()>;
+
+#[derive(Debug, Default)]
+struct Shared {
+    rx: Mutex<VecDeque<u8>>,
+    tx: Mutex<VecDeque<u8>>,
+    /// Notified when there is something in `rx` (or the pump stopped)
+    rx_ready: Notify,
+    /// Notified when there is something in `tx` (or a baud rate change)
+    tx_ready: Notify,
+    /// Baud rate to switch to once the first so many bytes of `tx` are out
+    baud_rate: Mutex<Option<(usize, u32)>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.rx_ready.notify_one();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// A [`Channel`] whose I/O is done by a [`ChannelPump`]. See the module
+/// documentation.
+#[derive(Debug)]
+pub struct AsyncChannel {
+    id: i32,
+    shared: Arc<Shared>,
+    /// Whether the pump can change the baud rate of the transport
+    reconfigurable: bool,
+}
+
+impl AsyncChannel {
+    /// Create a channel with the ID `id` over the transport `io`; the
+    /// returned pump needs to be run (see [`ChannelPump::run`]) for the
+    /// channel to send or receive anything.
+    pub fn new<T>(id: i32, io: T) -> (Self, ChannelPump<T>)
+    where
+        T: AsyncRead + AsyncWrite + Send,
+    {
+        Self::with_baud_rate(id, io, None)
+    }
+
+    /// Create a channel with the ID `id` over the serial port `port`, which
+    /// is switched to a new baud rate when LibOSDP asks for it (see
+    /// [`ReconfigurableChannel`]). The returned pump needs to be run as with
+    /// [`AsyncChannel::new`]; if the port can't be switched, the pump stops
+    /// with that error.
+    #[cfg(feature = "tokio-serial")]
+    pub fn serial(
+        id: i32,
+        port: tokio_serial::SerialStream,
+    ) -> (Self, ChannelPump<tokio_serial::SerialStream>) {
+        Self::with_baud_rate(
+            id,
+            port,
+            Some(|port, baud_rate| {
+                use tokio_serial::SerialPort;
+                Ok(port.get_mut().set_baud_rate(baud_rate)?)
+            }),
+        )
+    }
+
+    fn with_baud_rate<T>(
+        id: i32,
+        io: T,
+        set_baud_rate: Option<SetBaudRate<T>>,
+    ) -> (Self, ChannelPump<T>)
+    where
+        T: AsyncRead + AsyncWrite + Send,
+    {
+        let shared = Arc::new(Shared::default());
+        let channel = Self {
+            id,
+            shared: shared.clone(),
+            reconfigurable: set_baud_rate.is_some(),
+        };
+        let pump = ChannelPump {
+            io,
+            shared,
+            set_baud_rate,
+        };
+        (channel, pump)
+    }
+
+    /// A handle to wait on for this channel to become readable, from async
+    /// code. Use it instead of [`crate::ControlPanel::wait_readable`] (or
+    /// its PD counterpart), which would block the runtime.
+    pub fn readable(&self) -> Readable {
+        Readable {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Channel for AsyncChannel {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        let mut rx = self.shared.rx.lock().unwrap_or_else(|e| e.into_inner());
+        if rx.is_empty() && self.shared.is_closed() {
+            return Err(ChannelError::TransportError);
+        }
+        if rx.is_empty() {
+            return Err(ChannelError::WouldBlock);
+        }
+        let len = buf.len().min(rx.len());
+        for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        if self.shared.is_closed() {
+            return Err(ChannelError::TransportError);
+        }
+        let mut tx = self.shared.tx.lock().unwrap_or_else(|e| e.into_inner());
+        tx.extend(buf);
+        self.shared.tx_ready.notify_one();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        Ok(())
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        if self.reconfigurable {
+            Some(self)
+        } else {
+            None
+        }
+    }
+}
+
+impl ReconfigurableChannel for AsyncChannel {
+    /// Have the pump switch the transport to `baud_rate` once everything
+    /// written so far is out. This does not wait for it to happen.
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), ChannelError> {
+        if !self.reconfigurable || self.shared.is_closed() {
+            return Err(ChannelError::TransportError);
+        }
+        let tx = self.shared.tx.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pending = self
+            .shared
+            .baud_rate
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Of several switches in a row, only the last one is made
+        let at = pending.map_or(tx.len(), |(at, _)| at);
+        *pending = Some((at, baud_rate));
+        self.shared.tx_ready.notify_one();
+        Ok(())
+    }
+}
+
+/// Waits for an [`AsyncChannel`] to become readable; see
+/// [`AsyncChannel::readable`].
+#[derive(Debug, Clone)]
+pub struct Readable {
+    shared: Arc<Shared>,
+}
+
+impl Readable {
+    /// Wait until there is something to read on the channel or `timeout`
+    /// elapses; returns false on timeout.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let has_data = || {
+            let rx = self.shared.rx.lock().unwrap_or_else(|e| e.into_inner());
+            !rx.is_empty() || self.shared.is_closed()
+        };
+        if has_data() {
+            return true;
+        }
+        tokio::time::timeout(timeout, self.shared.rx_ready.notified())
+            .await
+            .is_ok()
+    }
+}
+
+/// Moves bytes between an [`AsyncChannel`] and its transport; see
+/// [`AsyncChannel::new`].
+#[derive(Debug)]
+pub struct ChannelPump<T> {
+    io: T,
+    shared: Arc<Shared>,
+    set_baud_rate: Option<SetBaudRate<T>>,
+}
+
+/// Marks the channel closed when the pump stops, including when its future
+/// is dropped.
+struct CloseOnDrop<'a>(&'a Shared);
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send> ChannelPump<T> {
+    /// Run until the transport fails or reaches EOF; after that, the channel
+    /// returns [`ChannelError::TransportError`].
+    pub async fn run(self) -> std::io::Result<()> {
+        let ChannelPump {
+            io,
+            shared,
+            set_baud_rate,
+        } = self;
+        let _close = CloseOnDrop(&shared);
+        let mut io = pin!(io);
+        let mut buf = [0u8; 256];
+        loop {
+            Self::send(io.as_mut(), &shared, set_baud_rate).await?;
+            let mut written = pin!(shared.tx_ready.notified());
+            let len = core::future::poll_fn(|cx| {
+                let mut read_buf = ReadBuf::new(&mut buf);
+                if let Poll::Ready(res) = io.as_mut().poll_read(cx, &mut read_buf) {
+                    return Poll::Ready(res.map(|()| Some(read_buf.filled().len())));
+                }
+                written.as_mut().poll(cx).map(|()| Ok(None))
+            })
+            .await?;
+            match len {
+                Some(0) => return Ok(()),
+                Some(len) => {
+                    let mut buffer = shared.rx.lock().unwrap_or_else(|e| e.into_inner());
+                    buffer.extend(&buf[..len]);
+                    drop(buffer);
+                    shared.rx_ready.notify_one();
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Write out what the channel has written so far, switching baud rates
+    /// on the way as asked.
+    async fn send(
+        mut io: Pin<&mut T>,
+        shared: &Shared,
+        set_baud_rate: Option<SetBaudRate<T>>,
+    ) -> std::io::Result<()> {
+        loop {
+            let (data, baud_rate) = {
+                let mut tx = shared.tx.lock().unwrap_or_else(|e| e.into_inner());
+                let mut pending = shared.baud_rate.lock().unwrap_or_else(|e| e.into_inner());
+                let (at, baud_rate) = match pending.take() {
+                    Some((at, baud_rate)) => (at.min(tx.len()), Some(baud_rate)),
+                    None => (tx.len(), None),
+                };
+                (tx.drain(..at).collect::<Vec<u8>>(), baud_rate)
+            };
+            if !data.is_empty() {
+                io.write_all(&data).await?;
+                io.flush().await?;
+            }
+            match (baud_rate, set_baud_rate) {
+                (Some(baud_rate), Some(set_baud_rate)) => {
+                    set_baud_rate(io.as_mut(), baud_rate)?;
+                }
+                _ if data.is_empty() => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncChannel;
+    use crate::{Channel, ChannelError};
+    use core::{
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    #[test]
+    fn test_async_channel() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (io, mut remote) = tokio::io::duplex(64);
+            let (mut channel, pump) = AsyncChannel::new(1, io);
+            let readable = channel.readable();
+            let pump = tokio::spawn(pump.run());
+
+            let mut buf = [0u8; 8];
+            assert_eq!(channel.read(&mut buf), Err(ChannelError::WouldBlock));
+            assert!(!readable.wait(Duration::from_millis(10)).await);
+
+            remote.write_all(&[1, 2, 3]).await.unwrap();
+            assert!(readable.wait(Duration::from_secs(1)).await);
+            assert_eq!(channel.read(&mut buf), Ok(3));
+            assert_eq!(&buf[..3], [1, 2, 3]);
+
+            channel.write(&[4, 5]).unwrap();
+            remote.read_exact(&mut buf[..2]).await.unwrap();
+            assert_eq!(&buf[..2], [4, 5]);
+
+            drop(remote);
+            assert!(pump.await.unwrap().is_ok());
+            assert_eq!(channel.read(&mut buf), Err(ChannelError::TransportError));
+            assert_eq!(channel.write(&[6]), Err(ChannelError::TransportError));
+        });
+    }
+
+    /// Never has anything to read; records what is written to it and the
+    /// baud rate switches in between
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Result<u8, u32>>>>);
+
+    impl AsyncRead for Recorder {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().extend(buf.iter().map(|b| Ok(*b)));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_baud_rate() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut plain, _) = AsyncChannel::new(1, tokio::io::empty());
+            assert!(plain.as_reconfigurable().is_none());
+
+            let recorder = Recorder::default();
+            let set_baud_rate = |port: Pin<&mut Recorder>, baud_rate| {
+                port.0.lock().unwrap().push(Err(baud_rate));
+                Ok(())
+            };
+            let (mut channel, pump) =
+                AsyncChannel::with_baud_rate(1, recorder.clone(), Some(set_baud_rate));
+            let reconfigurable = channel.as_reconfigurable().unwrap();
+            reconfigurable.write(&[1, 2]).unwrap();
+            reconfigurable.set_baud_rate(9600).unwrap();
+            reconfigurable.write(&[3]).unwrap();
+            let pump = tokio::spawn(pump.run());
+            while recorder.0.lock().unwrap().len() < 4 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(
+                *recorder.0.lock().unwrap(),
+                [Ok(1), Ok(2), Err(9600), Ok(3)]
+            );
+
+            pump.abort();
+            _ = pump.await;
+            let reconfigurable = channel.as_reconfigurable().unwrap();
+            assert!(reconfigurable.set_baud_rate(19200).is_err());
+        });
+    }
+}
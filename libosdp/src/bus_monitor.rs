@@ -0,0 +1,106 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A passive, listen-only, observer of an OSDP bus. [`BusMonitor`] never
+//! writes to the channel it is given; it only reads the bytes exchanged
+//! between the CP and PDs and decodes them into [`Packet`]s. This is the
+//! building block for protocol analyzers and troubleshooting tools.
+
+use crate::{
+    wire::{Packet, PacketDecoder},
+    Channel, ChannelError, OsdpCommand, OsdpError, OsdpEvent,
+};
+use std::time::{Duration, Instant};
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// A packet observed on the bus along with the time it was seen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MonitorRecord {
+    /// Time since the monitor was created at which the packet was completed
+    pub timestamp: Duration,
+    /// The decoded packet
+    pub packet: Packet,
+}
+
+impl MonitorRecord {
+    /// Decode the packet as a command from the CP (if it is one).
+    pub fn command(&self) -> Option<OsdpCommand> {
+        self.packet.command()
+    }
+
+    /// Decode the packet as an event from a PD (if it is one).
+    pub fn event(&self) -> Option<OsdpEvent> {
+        self.packet.event()
+    }
+}
+
+/// Passive OSDP bus sniffer. See module documentation for more details.
+#[derive(Debug)]
+pub struct BusMonitor {
+    channel: Box<dyn Channel>,
+    decoder: PacketDecoder,
+    start: Instant,
+}
+
+impl BusMonitor {
+    /// Create a new monitor that listens on `channel`.
+    pub fn new(channel: Box<dyn Channel>) -> Self {
+        Self {
+            channel,
+            decoder: PacketDecoder::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Number of bytes that were dropped because they could not be decoded
+    /// as part of a valid packet.
+    pub fn discarded(&self) -> usize {
+        self.decoder.discarded()
+    }
+
+    /// Read any pending bytes from the channel and return the next packet
+    /// observed on the bus. This method does not block; it returns
+    /// `Ok(None)` when there is nothing to report yet.
+    pub fn poll(&mut self) -> Result<Option<MonitorRecord>> {
+        if let Some(record) = self.next_record() {
+            return Ok(Some(record));
+        }
+        let mut buf = [0u8; 256];
+        match self.channel.read(&mut buf) {
+            Ok(0) | Err(ChannelError::WouldBlock) => Ok(None),
+            Ok(n) => {
+                self.decoder.push(&buf[..n]);
+                Ok(self.next_record())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn next_record(&mut self) -> Option<MonitorRecord> {
+        self.decoder.next_packet().map(|packet| MonitorRecord {
+            timestamp: self.start.elapsed(),
+            packet,
+        })
+    }
+}
+
+impl Iterator for BusMonitor {
+    type Item = Result<MonitorRecord>;
+
+    /// Wait on the channel until the next packet arrives. Use
+    /// [`BusMonitor::poll`] to integrate with an existing event loop.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {
+                    let _ = self.channel.wait_readable(Duration::from_millis(1));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
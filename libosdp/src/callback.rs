@@ -0,0 +1,283 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP holds a single callback (and an opaque pointer to pass to it) per
+//! context. Instead of handing it a pointer to the user's closure, each CP/PD
+//! instance registers a trampoline once, at setup time, with a pointer to a
+//! [`Callback`] slot that it owns. Closures can then be installed, replaced and
+//! removed from the Rust side at any time without leaking the previous one or
+//! leaving LibOSDP with a dangling pointer.
+
+use alloc::boxed::Box;
+use core::ffi::c_void;
+
+#[cfg(feature = "std")]
+type Shared<T> = std::sync::Arc<T>;
+#[cfg(feature = "std")]
+type Lock<T> = std::sync::Mutex<T>;
+#[cfg(feature = "std")]
+type SharedSlot = Shared<dyn Unregister + Send + Sync>;
+
+// Without std, LibOSDP contexts are expected to be driven from a single
+// execution context (see logger.rs). Reference counting and locking don't
+// need atomics then, which keeps this usable on targets without CAS.
+#[cfg(not(feature = "std"))]
+type Shared<T> = alloc::rc::Rc<T>;
+#[cfg(not(feature = "std"))]
+type Lock<T> = core::cell::RefCell<T>;
+#[cfg(not(feature = "std"))]
+type SharedSlot = Shared<dyn Unregister>;
+
+struct State<F: ?Sized> {
+    /// Bumped every time a closure is installed; lets a [`CallbackGuard`]
+    /// (and an in-flight call) tell if the closure it knows about is still
+    /// the current one.
+    id: u32,
+    closure: Option<Box<F>>,
+}
+
+struct Slot<F: ?Sized> {
+    state: Lock<State<F>>,
+}
+
+impl<F: ?Sized> Slot<F> {
+    fn with<R>(&self, f: impl FnOnce(&mut State<F>) -> R) -> R {
+        #[cfg(feature = "std")]
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        #[cfg(not(feature = "std"))]
+        let mut state = self.state.borrow_mut();
+        f(&mut state)
+    }
+}
+
+trait Unregister {
+    fn unregister(&self, id: u32);
+}
+
+impl<F: ?Sized> Unregister for Slot<F> {
+    fn unregister(&self, id: u32) {
+        let closure = self.with(|s| if s.id == id { s.closure.take() } else { None });
+        // Dropped outside the lock; the closure may own just about anything.
+        drop(closure);
+    }
+}
+
+/// A callback slot owned by a CP/PD instance. LibOSDP is given
+/// [`Callback::as_ptr`] as the opaque data pointer of its callback.
+pub(crate) struct Callback<F: ?Sized> {
+    slot: Shared<Slot<F>>,
+}
+
+impl<F: ?Sized> core::fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let registered = self.slot.with(|s| s.closure.is_some());
+        f.debug_struct("Callback")
+            .field("registered", &registered)
+            .finish()
+    }
+}
+
+/// Another handle to the same slot, which keeps it alive (but not the
+/// instance that owns it).
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<F: ?Sized + Send + 'static> Callback<F> {
+    pub fn new() -> Self {
+        Self {
+            slot: Shared::new(Slot {
+                state: Lock::new(State {
+                    id: 0,
+                    closure: None,
+                }),
+            }),
+        }
+    }
+
+    /// Pointer to pass to LibOSDP; valid for as long as `self` is alive.
+    pub fn as_ptr(&self) -> *mut c_void {
+        Shared::as_ptr(&self.slot) as *mut c_void
+    }
+
+    /// Install `closure`, dropping the one it replaces (if any).
+    pub fn set(&self, closure: Box<F>) -> CallbackGuard {
+        let (id, old) = self.slot.with(|s| {
+            s.id = s.id.wrapping_add(1);
+            (s.id, s.closure.replace(closure))
+        });
+        drop(old);
+        CallbackGuard {
+            slot: Some(self.slot.clone()),
+            id,
+        }
+    }
+
+    /// Whether a closure is installed (and not being called right now)
+    pub fn is_registered(&self) -> bool {
+        self.slot.with(|s| s.closure.is_some())
+    }
+
+    /// Invoke the closure (from the Rust side) or return `default` if there
+    /// isn't one. See [`Callback::call`].
+    pub fn invoke<R>(&self, default: R, f: impl FnOnce(&mut F) -> R) -> R {
+        unsafe { Self::call(self.as_ptr(), default, f) }
+    }
+
+    /// Invoke the closure behind `data` (see [`Callback::as_ptr`]) or return
+    /// `default` if there isn't one.
+    ///
+    /// The closure is taken out of the slot for the duration of the call so
+    /// that it can replace or unregister itself (or be replaced by another
+    /// thread) without deadlocking.
+    ///
+    /// # Safety
+    ///
+    /// `data` must have been obtained from a [`Callback<F>`] that is still
+    /// alive.
+    pub unsafe fn call<R>(data: *mut c_void, default: R, f: impl FnOnce(&mut F) -> R) -> R {
+        let slot = &*(data as *const Slot<F>);
+        let Some((id, mut closure)) = slot.with(|s| Some((s.id, s.closure.take()?))) else {
+            return default;
+        };
+        let ret = f(&mut closure);
+        let stale = slot.with(|s| {
+            if s.id == id && s.closure.is_none() {
+                s.closure = Some(closure);
+                None
+            } else {
+                Some(closure)
+            }
+        });
+        drop(stale);
+        ret
+    }
+}
+
+/// Handle returned when a callback is registered with a
+/// [`crate::ControlPanel`] or a [`crate::PeripheralDevice`].
+///
+/// The callback is unregistered (and the closure dropped) when this guard is
+/// dropped, unless it was replaced by another callback already. Use
+/// [`CallbackGuard::detach`] to keep it registered for the lifetime of the
+/// instance instead.
+#[must_use = "the callback is unregistered when this guard is dropped"]
+pub struct CallbackGuard {
+    slot: Option<SharedSlot>,
+    id: u32,
+}
+
+impl core::fmt::Debug for CallbackGuard {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CallbackGuard")
+            .field("id", &self.id)
+            .field("detached", &self.slot.is_none())
+            .finish()
+    }
+}
+
+impl CallbackGuard {
+    /// Keep the callback registered until it is replaced or the owning
+    /// instance is dropped.
+    pub fn detach(mut self) {
+        self.slot = None;
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.unregister(self.id);
+        }
+    }
+}
+
+/// Run `f`, which calls into application code on behalf of LibOSDP. A panic
+/// must not unwind into C so, with `std`, it is caught and logged and
+/// `on_panic` is returned instead. Closures registered with [`Callback`] that
+/// panic are dropped (they are taken out of their slot while being called).
+///
+/// Without `std` panics can't be caught; such targets are expected to abort
+/// on panic.
+pub(crate) fn catch_panic<R>(what: &str, on_panic: R, f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "std")]
+    {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|_payload| {
+            #[cfg(feature = "log")]
+            {
+                let reason = _payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| _payload.downcast_ref::<String>().map(|s| s.as_str()))
+                    .unwrap_or("unknown");
+                log::error!("Panic in {what}: {reason}");
+            }
+            on_panic
+        })
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        let _ = (what, on_panic);
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{catch_panic, Callback};
+    use alloc::boxed::Box;
+
+    type F = dyn FnMut(u32) -> u32 + Send;
+
+    fn call(cb: &Callback<F>, arg: u32) -> u32 {
+        unsafe { Callback::<F>::call(cb.as_ptr(), 0, |f| f(arg)) }
+    }
+
+    #[test]
+    fn test_callback_guard() {
+        let cb: Callback<F> = Callback::new();
+        assert_eq!(call(&cb, 1), 0);
+
+        let first = cb.set(Box::new(|x| x + 1));
+        assert_eq!(call(&cb, 1), 2);
+
+        // Replacing drops the first closure; its guard is now a no-op
+        let second = cb.set(Box::new(|x| x + 2));
+        drop(first);
+        assert_eq!(call(&cb, 1), 3);
+
+        drop(second);
+        assert_eq!(call(&cb, 1), 0);
+
+        cb.set(Box::new(|x| x + 3)).detach();
+        assert_eq!(call(&cb, 1), 4);
+    }
+
+    #[test]
+    fn test_callback_outlives_owner() {
+        let cb: Callback<F> = Callback::new();
+        assert!(!cb.is_registered());
+        cb.set(Box::new(|x| x + 1)).detach();
+        assert!(cb.is_registered());
+        // A clone keeps the slot (and the closure) alive
+        let handle = cb.clone();
+        drop(cb);
+        assert_eq!(handle.invoke(0, |f| f(1)), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_catch_panic() {
+        let cb: Callback<F> = Callback::new();
+        cb.set(Box::new(|_| panic!("oops"))).detach();
+        assert_eq!(catch_panic("test", 42, || call(&cb, 1)), 42);
+        // The closure that panicked is gone
+        assert_eq!(call(&cb, 1), 0);
+    }
+}
@@ -0,0 +1,122 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Packet capture in the pcap format, as produced by LibOSDP's (compile time)
+//! `packet_trace` feature; the captures can be opened in Wireshark with the
+//! OSDP dissector that comes with LibOSDP.
+//!
+//! Unlike the C implementation, this can be started and stopped at any time
+//! on a running CP (see [`crate::ControlPanel::start_packet_capture`]).
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Link type LibOSDP uses for its captures (LINKTYPE_USER15)
+const LINKTYPE_OSDP: u32 = 162;
+
+/// Longest packet that is captured; LibOSDP doesn't send larger ones.
+const SNAPLEN: u32 = 1024;
+
+/// An open pcap file that packets are being written to
+#[derive(Debug)]
+pub(crate) struct PacketCapture {
+    file: BufWriter<File>,
+}
+
+impl PacketCapture {
+    /// Create (or truncate) `path` and write the pcap file header to it.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&0xa1b2c3d4u32.to_le_bytes())?; // magic
+        file.write_all(&2u16.to_le_bytes())?; // major version
+        file.write_all(&4u16.to_le_bytes())?; // minor version
+        file.write_all(&0i32.to_le_bytes())?; // timezone offset
+        file.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+        file.write_all(&SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_OSDP.to_le_bytes())?;
+        Ok(Self { file })
+    }
+
+    /// Write a record for `packet`, timestamped now.
+    pub fn record(&mut self, packet: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = packet.len().min(SNAPLEN as usize);
+        self.file.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(len as u32).to_le_bytes())?;
+        self.file.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.file.write_all(&packet[..len])
+    }
+
+    /// Flush buffered records to the file and close it.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The capture of a CP, if one is running, shared with the channels that
+/// feed it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CaptureHandle(Arc<Mutex<Option<PacketCapture>>>);
+
+impl CaptureHandle {
+    /// Start writing packets to `capture`, returning the previous capture (if
+    /// there was one).
+    pub fn start(&self, capture: PacketCapture) -> Option<PacketCapture> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(capture)
+    }
+
+    /// Stop capturing, returning the capture that was running (if any).
+    pub fn stop(&self) -> Option<PacketCapture> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    /// Record `packet` if a capture is running. A capture that fails to be
+    /// written to is stopped.
+    pub fn record(&self, packet: &[u8]) {
+        let mut capture = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(c) = capture.as_mut() {
+            if c.record(packet).is_err() {
+                *capture = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaptureHandle, PacketCapture};
+
+    #[test]
+    fn test_packet_capture() {
+        let path = std::env::temp_dir().join(format!("osdp-test-{}.pcap", std::process::id()));
+        let handle = CaptureHandle::default();
+        handle.record(&[0x53, 0x65]); // Not capturing yet
+        assert!(handle
+            .start(PacketCapture::create(&path).unwrap())
+            .is_none());
+        handle.record(&[0x53, 0x65, 0x08, 0x00]);
+        handle.stop().unwrap().finish().unwrap();
+        handle.record(&[0x53, 0x65]);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 24 + 16 + 4);
+        assert_eq!(data[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(data[20..24], 162u32.to_le_bytes());
+        assert_eq!(data[32..36], 4u32.to_le_bytes());
+        assert_eq!(data[40..], [0x53, 0x65, 0x08, 0x00]);
+    }
+}
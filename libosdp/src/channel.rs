@@ -14,13 +14,38 @@
 //! Unix socket and message queues.
 //!
 //! This module provides a way to define an OSDP channel and export it to
-//! LibOSDP.
+//! LibOSDP. With `std`, it also provides an in-memory [`MemoryChannel`] to
+//! connect devices within a process, for testing, a [`ThreadBus`] to simulate
+//! multi-drop buses the same way and (on unix) a [`UnixChannel`] over unix
+//! domain sockets. On `wasm32-unknown-unknown`, `JsChannel` hands the bytes
+//! to JavaScript.
 
+use crate::callback::catch_panic;
 use alloc::{boxed::Box, vec};
-use core::ffi::c_void;
+use core::{ffi::c_void, time::Duration};
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod js;
+#[cfg(feature = "std")]
+mod memory;
+
+#[cfg(feature = "std")]
+mod threadbus;
+#[cfg(all(feature = "std", unix))]
+mod unix;
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub use js::*;
+#[cfg(feature = "std")]
+pub use memory::*;
+#[cfg(feature = "std")]
+pub use threadbus::*;
+#[cfg(all(feature = "std", unix))]
+pub use unix::*;
 
 /// OSDP channel errors
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum ChannelError {
     /// Channel is temporarily unavailable (could have blocked until it was
     /// ready but LibOSDP required channel to be non-blocking so return "I would
@@ -30,6 +55,17 @@ pub enum ChannelError {
     TransportError,
 }
 
+impl core::fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChannelError::WouldBlock => write!(f, "Channel would block"),
+            ChannelError::TransportError => write!(f, "Channel transport error"),
+        }
+    }
+}
+
+impl core::error::Error for ChannelError {}
+
 #[cfg(feature = "std")]
 impl From<std::io::Error> for ChannelError {
     fn from(value: std::io::Error) -> Self {
@@ -69,6 +105,159 @@ pub trait Channel: Send {
     /// Flush this output stream, ensuring that all intermediately buffered
     /// contents reach their destination.
     fn flush(&mut self) -> Result<(), ChannelError>;
+
+    /// Block until there are bytes to be read or `timeout` elapses. Returns
+    /// `Ok(true)` if the channel is (likely) readable and `Ok(false)` on
+    /// timeout. This is used by code that drives `refresh()` to sleep instead
+    /// of waking up periodically to find nothing to do.
+    ///
+    /// Channels that can't tell don't need to implement this; the default
+    /// implementation sleeps for `timeout` (or returns immediately without
+    /// `std`) and returns `Ok(true)`, which is equivalent to polling.
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        #[cfg(feature = "std")]
+        std::thread::sleep(timeout);
+        #[cfg(not(feature = "std"))]
+        let _ = timeout;
+        Ok(true)
+    }
+
+    /// Channels that implement [`ReconfigurableChannel`] should return
+    /// `Some(self)` here; channels that wrap other channels should forward
+    /// this call.
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        None
+    }
+}
+
+/// Extension of [`Channel`] for transports whose parameters can be changed at
+/// runtime (such as a serial port). When a PD accepts an `osdp_COMSET` command
+/// from the CP, it replies with the current settings and then switches over to
+/// the new ones; a channel that implements this trait (and returns itself from
+/// [`Channel::as_reconfigurable`]) is reconfigured accordingly so that the link
+/// is not lost.
+pub trait ReconfigurableChannel: Channel {
+    /// Switch the channel to a new baud rate.
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), ChannelError>;
+
+    /// Only accept packets addressed to `address` from now on. This is
+    /// useful for transports that filter by address (for instance, RS-485
+    /// transceivers with hardware address matching); the default
+    /// implementation does nothing.
+    fn set_address_filter(&mut self, address: u8) -> Result<(), ChannelError> {
+        let _ = address;
+        Ok(())
+    }
+}
+
+impl<T: Channel + ?Sized> Channel for Box<T> {
+    fn get_id(&self) -> i32 {
+        (**self).get_id()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        (**self).read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        (**self).flush()
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        (**self).wait_readable(timeout)
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        (**self).as_reconfigurable()
+    }
+}
+
+impl<T: Channel + ?Sized> Channel for &mut T {
+    fn get_id(&self) -> i32 {
+        (**self).get_id()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        (**self).read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        (**self).flush()
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        (**self).wait_readable(timeout)
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        (**self).as_reconfigurable()
+    }
+}
+
+/// A channel shared with the application; the lock is held for the duration
+/// of each call (including [`Channel::wait_readable`]). A poisoned lock is
+/// reported as [`ChannelError::TransportError`].
+#[cfg(feature = "std")]
+impl<T: Channel + ?Sized> Channel for std::sync::Arc<std::sync::Mutex<T>> {
+    fn get_id(&self) -> i32 {
+        match self.lock() {
+            Ok(channel) => channel.get_id(),
+            Err(e) => e.into_inner().get_id(),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        lock(self)?.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        lock(self)?.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        lock(self)?.flush()
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        lock(self)?.wait_readable(timeout)
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        lock(self).ok()?.as_reconfigurable()?;
+        Some(self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Channel + ?Sized> ReconfigurableChannel for std::sync::Arc<std::sync::Mutex<T>> {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), ChannelError> {
+        match lock(self)?.as_reconfigurable() {
+            Some(channel) => channel.set_baud_rate(baud_rate),
+            None => Err(ChannelError::TransportError),
+        }
+    }
+
+    fn set_address_filter(&mut self, address: u8) -> Result<(), ChannelError> {
+        match lock(self)?.as_reconfigurable() {
+            Some(channel) => channel.set_address_filter(address),
+            None => Err(ChannelError::TransportError),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn lock<T: ?Sized>(
+    channel: &std::sync::Mutex<T>,
+) -> Result<std::sync::MutexGuard<'_, T>, ChannelError> {
+    channel.lock().map_err(|_| ChannelError::TransportError)
 }
 
 impl core::fmt::Debug for dyn Channel {
@@ -79,11 +268,50 @@ impl core::fmt::Debug for dyn Channel {
     }
 }
 
+/// Rust side handle to a channel that has been handed over to LibOSDP. It is
+/// valid for as long as the LibOSDP context that owns the channel is alive and
+/// must only be used from outside calls into LibOSDP (which may be using the
+/// channel at that time).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChannelHandle(*mut Box<dyn Channel>);
+
+impl ChannelHandle {
+    pub fn new(channel: &libosdp_sys::osdp_channel) -> Self {
+        Self(channel.data as *mut _)
+    }
+
+    /// # Safety
+    ///
+    /// See [`ChannelHandle`].
+    pub unsafe fn get(&mut self) -> &mut dyn Channel {
+        &mut **self.0
+    }
+
+    /// Take back the channel that was handed over to LibOSDP (as it was
+    /// before it was converted into an `osdp_channel`).
+    ///
+    /// # Safety
+    ///
+    /// The LibOSDP context that used this channel must have been torn down (or
+    /// never set up) and no other handle to it may be used after this.
+    pub unsafe fn into_channel(self) -> Box<dyn Channel> {
+        let channel = *Box::from_raw(self.0);
+        #[cfg(feature = "metrics")]
+        let channel =
+            Box::from_raw(Box::into_raw(channel) as *mut crate::MeteredChannel<Box<dyn Channel>>)
+                .into_inner();
+        channel
+    }
+}
+
 unsafe extern "C" fn raw_read(data: *mut c_void, buf: *mut u8, len: i32) -> i32 {
     let channel: *mut Box<dyn Channel> = data as *mut _;
     let channel = channel.as_mut().unwrap();
     let mut read_buf = vec![0u8; len as usize];
-    match channel.read(&mut read_buf) {
+    let res = catch_panic("Channel::read", Err(ChannelError::TransportError), || {
+        channel.read(&mut read_buf)
+    });
+    match res {
         Ok(n) => {
             let src_ptr = read_buf.as_mut_ptr();
             core::ptr::copy_nonoverlapping(src_ptr, buf, len as usize);
@@ -99,7 +327,10 @@ unsafe extern "C" fn raw_write(data: *mut c_void, buf: *mut u8, len: i32) -> i32
     let channel = channel.as_mut().unwrap();
     let mut write_buf = vec![0u8; len as usize];
     core::ptr::copy_nonoverlapping(buf, write_buf.as_mut_ptr(), len as usize);
-    match channel.as_mut().write(&write_buf) {
+    let res = catch_panic("Channel::write", Err(ChannelError::TransportError), || {
+        channel.as_mut().write(&write_buf)
+    });
+    match res {
         Ok(n) => n as i32,
         Err(ChannelError::WouldBlock) => 0,
         Err(_) => -1,
@@ -109,11 +340,15 @@ unsafe extern "C" fn raw_write(data: *mut c_void, buf: *mut u8, len: i32) -> i32
 unsafe extern "C" fn raw_flush(data: *mut c_void) {
     let channel: *mut Box<dyn Channel> = data as *mut _;
     let channel = channel.as_mut().unwrap();
-    let _ = channel.as_mut().flush();
+    let _ = catch_panic("Channel::flush", Err(ChannelError::TransportError), || {
+        channel.as_mut().flush()
+    });
 }
 
 impl From<Box<dyn Channel>> for libosdp_sys::osdp_channel {
     fn from(val: Box<dyn Channel>) -> Self {
+        #[cfg(feature = "metrics")]
+        let val: Box<dyn Channel> = Box::new(crate::MeteredChannel::with_metrics(val));
         let id = val.get_id();
         let data = Box::into_raw(Box::new(val));
         libosdp_sys::osdp_channel {
@@ -125,3 +360,71 @@ impl From<Box<dyn Channel>> for libosdp_sys::osdp_channel {
         }
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{Channel, ChannelError, ReconfigurableChannel};
+    use alloc::{boxed::Box, vec::Vec};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct TestChannel {
+        written: Vec<u8>,
+        baud_rate: u32,
+    }
+
+    impl Channel for TestChannel {
+        fn get_id(&self) -> i32 {
+            7
+        }
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize, ChannelError> {
+            Err(ChannelError::WouldBlock)
+        }
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), ChannelError> {
+            Ok(())
+        }
+        fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+            Some(self)
+        }
+    }
+
+    impl ReconfigurableChannel for TestChannel {
+        fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), ChannelError> {
+            self.baud_rate = baud_rate;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shared_channel() {
+        let shared = Arc::new(Mutex::new(TestChannel::default()));
+        let mut channel: Box<dyn Channel> = Box::new(Box::new(shared.clone()));
+        assert_eq!(channel.get_id(), 7);
+        channel.write(&[1, 2, 3]).unwrap();
+        channel
+            .as_reconfigurable()
+            .unwrap()
+            .set_baud_rate(9600)
+            .unwrap();
+
+        let inner = shared.lock().unwrap();
+        assert_eq!(inner.written, [1, 2, 3]);
+        assert_eq!(inner.baud_rate, 9600);
+    }
+    #[test]
+    fn test_channel_error() {
+        let err = crate::OsdpError::from(ChannelError::WouldBlock);
+        assert!(matches!(
+            err,
+            crate::OsdpError::Channel(ChannelError::WouldBlock)
+        ));
+        assert!(err.is_retryable());
+        let err = crate::OsdpError::from(ChannelError::TransportError);
+        assert!(!err.is_retryable());
+        assert_eq!(err.kind(), crate::OsdpErrorKind::Io);
+    }
+}
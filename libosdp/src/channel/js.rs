@@ -0,0 +1,83 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Channel, ChannelError};
+
+// Provided by JavaScript in the `libosdp` import module. Each returns the
+// number of bytes moved (0 when there is nothing to read or no room to
+// write) or a negative value when the transport has failed.
+#[link(wasm_import_module = "libosdp")]
+extern "C" {
+    fn channel_read(id: i32, buf: *mut u8, len: usize) -> i32;
+    fn channel_write(id: i32, buf: *const u8, len: usize) -> i32;
+    fn channel_flush(id: i32) -> i32;
+}
+
+/// An OSDP channel for `wasm32-unknown-unknown` whose bytes are moved by
+/// JavaScript, for simulators and training tools running in a browser (over a
+/// WebSocket, Web Serial or a channel to another simulated device in the same
+/// page, for instance).
+///
+/// The host supplies `channel_read`, `channel_write` and `channel_flush` in
+/// the `libosdp` import module when instantiating the wasm module; `id` tells
+/// it which of its transports is meant. Reads and writes copy bytes to and
+/// from the module's memory and must not block:
+///
+/// ```js
+/// const imports = {
+///   libosdp: {
+///     channel_read: (id, ptr, len) => {
+///       const bytes = rx[id].splice(0, len);
+///       new Uint8Array(memory.buffer, ptr, bytes.length).set(bytes);
+///       return bytes.length;
+///     },
+///     channel_write: (id, ptr, len) => {
+///       sockets[id].send(new Uint8Array(memory.buffer, ptr, len).slice());
+///       return len;
+///     },
+///     channel_flush: (id) => 0,
+///   },
+/// };
+/// ```
+#[derive(Debug)]
+pub struct JsChannel {
+    id: i32,
+}
+
+impl JsChannel {
+    /// Create a channel over the JavaScript transport identified by `id`.
+    pub fn new(id: i32) -> Self {
+        Self { id }
+    }
+}
+
+impl Channel for JsChannel {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        match unsafe { channel_read(self.id, buf.as_mut_ptr(), buf.len()) } {
+            0 => Err(ChannelError::WouldBlock),
+            n if n < 0 => Err(ChannelError::TransportError),
+            n => Ok((n as usize).min(buf.len())),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        match unsafe { channel_write(self.id, buf.as_ptr(), buf.len()) } {
+            0 if !buf.is_empty() => Err(ChannelError::WouldBlock),
+            n if n < 0 => Err(ChannelError::TransportError),
+            n => Ok((n as usize).min(buf.len())),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        match unsafe { channel_flush(self.id) } {
+            n if n < 0 => Err(ChannelError::TransportError),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//
+// Copyright (c) 2023-2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Channel, ChannelError};
+use alloc::collections::VecDeque;
+use core::time::Duration;
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+/// One direction of a [`MemoryChannel`]
+#[derive(Debug)]
+struct Pipe {
+    buf: Mutex<VecDeque<u8>>,
+    readable: Condvar,
+    capacity: usize,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            buf: Mutex::new(VecDeque::with_capacity(capacity)),
+            readable: Condvar::new(),
+            capacity,
+        })
+    }
+}
+
+/// An in-memory OSDP channel that connects a CP to a PD in the same process;
+/// this is meant to be used in tests.
+///
+/// Each direction is backed by a buffer of a fixed capacity. Reads return
+/// [`ChannelError::WouldBlock`] when there is nothing to read and writes
+/// return it when the buffer is full.
+///
+/// ```
+/// use libosdp::MemoryChannel;
+///
+/// let (cp_channel, pd_channel) = MemoryChannel::new();
+/// ```
+#[derive(Debug)]
+pub struct MemoryChannel {
+    id: i32,
+    tx: Arc<Pipe>,
+    rx: Arc<Pipe>,
+}
+
+impl MemoryChannel {
+    /// Create a connected pair of channels, with buffers of 1024 bytes.
+    pub fn new() -> (Self, Self) {
+        Self::with_capacity(1024)
+    }
+
+    /// Create a connected pair of channels, with buffers of `capacity` bytes
+    /// in each direction.
+    pub fn with_capacity(capacity: usize) -> (Self, Self) {
+        let (a, b) = (Pipe::new(capacity), Pipe::new(capacity));
+        (
+            Self {
+                id: 0,
+                tx: a.clone(),
+                rx: b.clone(),
+            },
+            Self {
+                id: 1,
+                tx: b,
+                rx: a,
+            },
+        )
+    }
+}
+
+impl Channel for MemoryChannel {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        let mut rx = self.rx.buf.lock().unwrap_or_else(|e| e.into_inner());
+        if rx.is_empty() {
+            return Err(ChannelError::WouldBlock);
+        }
+        let len = buf.len().min(rx.len());
+        for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        let mut tx = self.tx.buf.lock().unwrap_or_else(|e| e.into_inner());
+        let len = buf.len().min(self.tx.capacity - tx.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(ChannelError::WouldBlock);
+        }
+        tx.extend(&buf[..len]);
+        self.tx.readable.notify_all();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        Ok(())
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        let deadline = Instant::now() + timeout;
+        let mut rx = self.rx.buf.lock().unwrap_or_else(|e| e.into_inner());
+        while rx.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            rx = match self.rx.readable.wait_timeout(rx, deadline - now) {
+                Ok((rx, _)) => rx,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryChannel;
+    use crate::{Channel, ChannelError};
+    use core::time::Duration;
+
+    #[test]
+    fn test_memory_channel() {
+        let (mut a, mut b) = MemoryChannel::with_capacity(4);
+        let mut buf = [0u8; 8];
+        assert_eq!(b.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(b.wait_readable(Duration::from_millis(1)), Ok(false));
+
+        assert_eq!(a.write(&[1, 2, 3, 4, 5]), Ok(4));
+        assert_eq!(a.write(&[5]), Err(ChannelError::WouldBlock));
+        assert_eq!(b.wait_readable(Duration::from_millis(1)), Ok(true));
+        assert_eq!(b.read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], [1, 2, 3, 4]);
+
+        // Each end only reads what the other wrote
+        assert_eq!(b.write(&[6]), Ok(1));
+        assert_eq!(b.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(a.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 6);
+
+        let waiter = std::thread::spawn(move || b.wait_readable(Duration::from_secs(5)));
+        std::thread::sleep(Duration::from_millis(10));
+        a.write(&[7]).unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(true));
+    }
+}
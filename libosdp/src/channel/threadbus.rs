@@ -0,0 +1,217 @@
+//
+// Copyright (c) 2023-2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Channel, ChannelError};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::time::Duration;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+#[derive(Debug)]
+struct Bus {
+    /// One receive buffer per member of the bus, indexed by member number;
+    /// `None` for members that have been dropped.
+    members: Mutex<Vec<Option<VecDeque<u8>>>>,
+    readable: Condvar,
+    capacity: usize,
+}
+
+/// An in-process, broadcast OSDP channel; this is meant to be used to
+/// simulate multi-drop buses (one CP and many PDs on the same wire) in tests.
+///
+/// Every clone of a `ThreadBus` is a member of the same bus. Whatever one
+/// member writes is received by all the other members (but not by itself),
+/// each of them reading it from its own buffer, independent of when (or
+/// whether) the other members read theirs.
+///
+/// Reads return [`ChannelError::WouldBlock`] when there is nothing to read
+/// and writes return it when the buffer of any other member doesn't have
+/// room for all of the bytes being written; in that case, nothing is written.
+///
+/// ```
+/// use libosdp::ThreadBus;
+///
+/// let cp_channel = ThreadBus::new("bus-0");
+/// let pd0_channel = cp_channel.clone();
+/// let pd1_channel = cp_channel.clone();
+/// ```
+#[derive(Debug)]
+pub struct ThreadBus {
+    name: String,
+    id: i32,
+    member: usize,
+    bus: Arc<Bus>,
+}
+
+fn str_to_channel_id(key: &str) -> i32 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let id = hasher.finish();
+    ((id >> 32) ^ (id & 0xffffffff)) as i32
+}
+
+impl ThreadBus {
+    /// Create a bus called `name`, with receive buffers of 1024 bytes. The
+    /// channel ID is derived from `name`.
+    pub fn new(name: &str) -> Self {
+        Self::with_capacity(name, 1024)
+    }
+
+    /// Create a bus called `name`, with receive buffers of `capacity` bytes
+    /// for each member.
+    pub fn with_capacity(name: &str, capacity: usize) -> Self {
+        Self {
+            name: name.into(),
+            id: str_to_channel_id(name),
+            member: 0,
+            bus: Arc::new(Bus {
+                members: Mutex::new(alloc::vec![Some(VecDeque::with_capacity(capacity))]),
+                readable: Condvar::new(),
+                capacity,
+            }),
+        }
+    }
+
+    /// Name of this bus
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn members(&self) -> std::sync::MutexGuard<'_, Vec<Option<VecDeque<u8>>>> {
+        self.bus.members.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clone for ThreadBus {
+    /// Add a new member to the bus; it receives only what is written after
+    /// it joined.
+    fn clone(&self) -> Self {
+        let mut members = self.members();
+        members.push(Some(VecDeque::with_capacity(self.bus.capacity)));
+        Self {
+            name: self.name.clone(),
+            id: self.id,
+            member: members.len() - 1,
+            bus: self.bus.clone(),
+        }
+    }
+}
+
+impl Drop for ThreadBus {
+    fn drop(&mut self) {
+        self.members()[self.member] = None;
+    }
+}
+
+impl Channel for ThreadBus {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        let mut members = self.members();
+        let rx = members[self.member].as_mut().unwrap();
+        if rx.is_empty() {
+            return Err(ChannelError::WouldBlock);
+        }
+        let len = buf.len().min(rx.len());
+        for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        let mut members = self.members();
+        let others = || {
+            members
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != self.member)
+                .filter_map(|(_, rx)| rx.as_ref())
+        };
+        if others().any(|rx| self.bus.capacity - rx.len() < buf.len()) {
+            return Err(ChannelError::WouldBlock);
+        }
+        for (i, rx) in members.iter_mut().enumerate() {
+            if let Some(rx) = rx.as_mut().filter(|_| i != self.member) {
+                rx.extend(buf);
+            }
+        }
+        self.bus.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        Ok(())
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        let deadline = Instant::now() + timeout;
+        let mut members = self.members();
+        while members[self.member].as_ref().unwrap().is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            members = match self.bus.readable.wait_timeout(members, deadline - now) {
+                Ok((members, _)) => members,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadBus;
+    use crate::{Channel, ChannelError};
+    use core::time::Duration;
+
+    #[test]
+    fn test_thread_bus() {
+        let mut a = ThreadBus::with_capacity("bus-0", 4);
+        let mut b = a.clone();
+        let mut c = a.clone();
+        assert_eq!(a.get_id(), c.get_id());
+        assert_ne!(a.get_id(), ThreadBus::new("bus-1").get_id());
+
+        let mut buf = [0u8; 8];
+        assert_eq!(b.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(a.write(&[1, 2, 3]), Ok(3));
+        assert_eq!(a.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(b.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], [1, 2, 3]);
+
+        // `c` hasn't read yet, so there is no room for two more bytes
+        assert_eq!(b.write(&[4, 5]), Err(ChannelError::WouldBlock));
+        assert_eq!(b.write(&[4]), Ok(1));
+        assert_eq!(c.read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], [1, 2, 3, 4]);
+        assert_eq!(a.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 4);
+
+        // Dropped members don't hold up the bus
+        drop(c);
+        assert_eq!(a.write(&[6, 7, 8, 9]), Ok(4));
+        assert_eq!(b.wait_readable(Duration::from_millis(1)), Ok(true));
+        assert_eq!(a.wait_readable(Duration::from_millis(1)), Ok(false));
+
+        let d = a.clone();
+        let waiter = std::thread::spawn(move || {
+            let mut d = d;
+            d.wait_readable(Duration::from_secs(5))
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(b.read(&mut buf), Ok(4));
+        b.write(&[10]).unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(true));
+    }
+}
@@ -0,0 +1,324 @@
+//
+// Copyright (c) 2023-2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Channel, ChannelError};
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// How often a disconnected channel retries connecting to its peer
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often [`UnixChannel::wait_readable`] checks for a new peer while the
+/// channel is disconnected
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+enum Peer {
+    /// Accepts connections on a socket that this channel bound
+    Listener(UnixListener, PathBuf),
+    /// Connects to a socket bound by someone else
+    Connector(PathBuf, Option<Instant>),
+    /// One end of an anonymous pair; can't be reconnected
+    Pair,
+}
+
+/// A reference OSDP channel over a unix domain (stream) socket.
+///
+/// One end of the link listens on a socket path (see [`UnixChannel::listen`])
+/// and the other connects to it (see [`UnixChannel::connect`]). The channel
+/// never blocks: reads return [`ChannelError::WouldBlock`] when there is
+/// nothing to read and so do reads and writes while there is no peer on the
+/// other end.
+///
+/// When the peer goes away, the listening end accepts the next connection
+/// and the connecting end periodically tries to connect again; meanwhile,
+/// LibOSDP sees the link as silent (so the PD goes offline for the CP until
+/// the link comes back).
+#[derive(Debug)]
+pub struct UnixChannel {
+    id: i32,
+    peer: Peer,
+    stream: Option<UnixStream>,
+    /// Bytes read by [`UnixChannel::wait_readable`] that are yet to be read
+    pending: Vec<u8>,
+}
+
+fn path_to_channel_id(path: &Path) -> i32 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let id = hasher.finish();
+    ((id >> 32) ^ (id & 0xffffffff)) as i32
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+    )
+}
+
+impl UnixChannel {
+    /// Connect to a channel that is listening on `path`.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            id: path_to_channel_id(path),
+            peer: Peer::Connector(path.into(), None),
+            stream: Some(stream),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Listen for a connection on `path`, replacing any stale socket that is
+    /// already there. This does not wait for the peer to connect; the channel
+    /// accepts it when LibOSDP first tries to use the channel after that.
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            id: path_to_channel_id(path),
+            peer: Peer::Listener(listener, path.into()),
+            stream: None,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Create a connected pair of channels that are not bound to any path;
+    /// this is useful for tests or to run a CP and PD in the same process.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixStream::pair()?;
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+        let channel = |id, stream| Self {
+            id,
+            peer: Peer::Pair,
+            stream: Some(stream),
+            pending: Vec::new(),
+        };
+        Ok((channel(0, a), channel(1, b)))
+    }
+
+    /// Whether there currently is a peer on the other end of this channel
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Try to get a peer if there isn't one; returns the connected stream.
+    fn stream(&mut self) -> Result<&mut UnixStream, ChannelError> {
+        if self.stream.is_none() {
+            let stream = match &mut self.peer {
+                Peer::Listener(listener, _) => match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Err(ChannelError::WouldBlock)
+                    }
+                    Err(_) => return Err(ChannelError::TransportError),
+                },
+                Peer::Connector(path, last_attempt) => {
+                    if last_attempt.is_some_and(|t| t.elapsed() < RECONNECT_INTERVAL) {
+                        return Err(ChannelError::WouldBlock);
+                    }
+                    *last_attempt = Some(Instant::now());
+                    UnixStream::connect(path).map_err(|_| ChannelError::WouldBlock)?
+                }
+                Peer::Pair => return Err(ChannelError::TransportError),
+            };
+            stream
+                .set_nonblocking(true)
+                .map_err(|_| ChannelError::TransportError)?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Handle the result of an I/O operation on the stream, dropping it if
+    /// the peer went away.
+    fn check<T>(&mut self, res: io::Result<T>) -> Result<T, ChannelError> {
+        match res {
+            Ok(v) => Ok(v),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(ChannelError::WouldBlock),
+            Err(e) if is_disconnect(&e) => self.disconnected(),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn disconnected<T>(&mut self) -> Result<T, ChannelError> {
+        self.stream = None;
+        match self.peer {
+            Peer::Pair => Err(ChannelError::TransportError),
+            _ => Err(ChannelError::WouldBlock),
+        }
+    }
+}
+
+impl Drop for UnixChannel {
+    fn drop(&mut self) {
+        if let Peer::Listener(_, path) = &self.peer {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Channel for UnixChannel {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        if !self.pending.is_empty() {
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            return Ok(len);
+        }
+        let res = self.stream()?.read(buf);
+        match self.check(res)? {
+            0 if !buf.is_empty() => self.disconnected(),
+            len => Ok(len),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        let res = self.stream()?.write(buf);
+        self.check(res)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        match self.stream.as_mut() {
+            Some(stream) => {
+                let res = stream.flush();
+                self.check(res)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        if !self.pending.is_empty() {
+            return Ok(true);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.stream() {
+                Ok(_) => break,
+                Err(ChannelError::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Ok(false);
+        }
+        // Block on a read for the rest of the timeout, keeping what it gets
+        // for the next read, then go back to being non-blocking.
+        let mut buf = [0u8; 256];
+        let stream = self.stream.as_mut().unwrap();
+        let res = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(timeout)))
+            .and_then(|_| stream.read(&mut buf));
+        let restored = stream.set_nonblocking(true);
+        let res = match res {
+            Ok(0) => self.disconnected(),
+            Ok(len) => {
+                self.pending.extend_from_slice(&buf[..len]);
+                restored.map_err(ChannelError::from)?;
+                Ok(true)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                restored.map_err(ChannelError::from)?;
+                Ok(false)
+            }
+            Err(e) => self.check(Err(e)),
+        };
+        // Losing the peer while waiting is not an error of the channel
+        match res {
+            Err(ChannelError::WouldBlock) => Ok(false),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnixChannel;
+    use crate::{Channel, ChannelError};
+    use core::time::Duration;
+
+    #[test]
+    fn test_unix_channel_pair() {
+        let (mut a, mut b) = UnixChannel::pair().unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(b.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(b.wait_readable(Duration::from_millis(1)), Ok(false));
+        assert_eq!(a.write(&[1, 2, 3]), Ok(3));
+        assert_eq!(b.wait_readable(Duration::from_secs(1)), Ok(true));
+        assert_eq!(b.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], [1, 2, 3]);
+
+        drop(a);
+        assert_eq!(b.read(&mut buf), Err(ChannelError::TransportError));
+    }
+
+    #[test]
+    fn test_unix_channel_reconnect() {
+        let path = std::env::temp_dir().join(format!("osdp-test-{}.sock", std::process::id()));
+        let mut server = UnixChannel::listen(&path).unwrap();
+        let mut buf = [0u8; 8];
+        assert!(!server.is_connected());
+        assert_eq!(server.read(&mut buf), Err(ChannelError::WouldBlock));
+
+        let mut client = UnixChannel::connect(&path).unwrap();
+        assert_eq!(client.get_id(), server.get_id());
+        assert_eq!(client.write(&[1, 2]), Ok(2));
+        assert_eq!(server.wait_readable(Duration::from_secs(1)), Ok(true));
+        assert_eq!(server.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], [1, 2]);
+
+        // The server accepts the next client once the first one goes away
+        drop(client);
+        assert_eq!(server.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert!(!server.is_connected());
+        let mut client = UnixChannel::connect(&path).unwrap();
+        assert_eq!(server.write(&[3]), Ok(1));
+        assert_eq!(client.wait_readable(Duration::from_secs(1)), Ok(true));
+        assert_eq!(client.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 3);
+
+        // ... and the client connects again to a new server
+        drop(server);
+        assert_eq!(client.read(&mut buf), Err(ChannelError::WouldBlock));
+        let mut server = UnixChannel::listen(&path).unwrap();
+        assert_eq!(client.write(&[4]), Ok(1));
+        assert_eq!(server.wait_readable(Duration::from_secs(1)), Ok(true));
+        assert_eq!(server.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 4);
+    }
+}
@@ -7,14 +7,16 @@
 //! are specified by OSDP specification. This module is responsible to handling
 //! such commands though [`OsdpCommand`].
 
-use crate::OsdpStatusReport;
+use crate::{OsdpError, OsdpStatusReport, PdCapability};
 use alloc::vec::Vec;
+use core::time::Duration;
 use serde::{Deserialize, Serialize};
 
 use super::ConvertEndian;
 
 /// LED Colors as specified in OSDP for the on_color/off_color parameters.
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum OsdpLedColor {
     /// No Color
     #[default]
@@ -37,6 +39,9 @@ pub enum OsdpLedColor {
 
     /// Cyan Color
     Cyan,
+
+    /// White Color
+    White,
 }
 
 impl From<u8> for OsdpLedColor {
@@ -49,6 +54,7 @@ impl From<u8> for OsdpLedColor {
             libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_BLUE => OsdpLedColor::Blue,
             libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_MAGENTA => OsdpLedColor::Magenta,
             libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_CYAN => OsdpLedColor::Cyan,
+            libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_WHITE => OsdpLedColor::White,
             _ => panic!("Invalid LED color code"),
         }
     }
@@ -64,12 +70,14 @@ impl From<OsdpLedColor> for u8 {
             OsdpLedColor::Blue => libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_BLUE as u8,
             OsdpLedColor::Magenta => libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_MAGENTA as u8,
             OsdpLedColor::Cyan => libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_CYAN as u8,
+            OsdpLedColor::White => libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_WHITE as u8,
         }
     }
 }
 
 /// LED params sub-structure. Part of LED command: OsdpCommandLed
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpLedParams {
     /// Control code serves different purposes based on which member of
     /// [`OsdpCommandLed`] it is used with. They are,
@@ -102,6 +110,138 @@ pub struct OsdpLedParams {
     pub timer_count: u16,
 }
 
+impl OsdpLedParams {
+    fn pattern(&self) -> OsdpLedPattern {
+        OsdpLedPattern {
+            on_color: self.on_color,
+            off_color: self.off_color,
+            on_count: self.on_count,
+            off_count: self.off_count,
+        }
+    }
+}
+
+/// A pattern that an LED flashes in; it alternates between `on_color` for
+/// `on_count` and `off_color` for `off_count` (both in units of 100 ms).
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpLedPattern {
+    /// Color to set during the ON timer
+    pub on_color: OsdpLedColor,
+
+    /// Color to set during the Off timer
+    pub off_color: OsdpLedColor,
+
+    /// The ON duration of the flash, in units of 100 ms
+    pub on_count: u8,
+
+    /// The OFF duration of the flash, in units of 100 ms
+    pub off_count: u8,
+}
+
+impl OsdpLedPattern {
+    /// Steady `color`, without flashing
+    pub fn steady(color: OsdpLedColor) -> Self {
+        Self {
+            on_color: color,
+            off_color: OsdpLedColor::None,
+            on_count: 1,
+            off_count: 0,
+        }
+    }
+
+    /// Flash between `on_color` and `off_color`, for `on_count` and
+    /// `off_count` (in units of 100 ms) respectively.
+    pub fn flash(
+        on_color: OsdpLedColor,
+        off_color: OsdpLedColor,
+        on_count: u8,
+        off_count: u8,
+    ) -> Self {
+        Self {
+            on_color,
+            off_color,
+            on_count,
+            off_count,
+        }
+    }
+}
+
+/// What to do with the temporary state of an LED; see
+/// [`OsdpCommandLed::temporary`].
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpLedTemporaryControl {
+    /// Do not alter the temporary state
+    #[default]
+    Nop,
+
+    /// Cancel any temporary operation and display the permanent state
+    /// immediately
+    Cancel,
+
+    /// Show this pattern for `timer_count` (in units of 100 ms), then go back
+    /// to the permanent state
+    Set {
+        /// Pattern to show
+        pattern: OsdpLedPattern,
+        /// How long to show it for, in units of 100 ms
+        timer_count: u16,
+    },
+}
+
+impl From<OsdpLedTemporaryControl> for OsdpLedParams {
+    fn from(value: OsdpLedTemporaryControl) -> Self {
+        match value {
+            OsdpLedTemporaryControl::Nop => OsdpLedParams::default(),
+            OsdpLedTemporaryControl::Cancel => OsdpLedParams {
+                control_code: 1,
+                ..Default::default()
+            },
+            OsdpLedTemporaryControl::Set {
+                pattern,
+                timer_count,
+            } => OsdpLedParams {
+                control_code: 2,
+                on_count: pattern.on_count,
+                off_count: pattern.off_count,
+                on_color: pattern.on_color,
+                off_color: pattern.off_color,
+                timer_count,
+            },
+        }
+    }
+}
+
+/// What to do with the permanent state of an LED; see
+/// [`OsdpCommandLed::permanent`].
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpLedPermanentControl {
+    /// Do not alter the permanent state
+    #[default]
+    Nop,
+
+    /// Show this pattern until another permanent state is set
+    Set(OsdpLedPattern),
+}
+
+impl From<OsdpLedPermanentControl> for OsdpLedParams {
+    fn from(value: OsdpLedPermanentControl) -> Self {
+        match value {
+            OsdpLedPermanentControl::Nop => OsdpLedParams::default(),
+            OsdpLedPermanentControl::Set(pattern) => OsdpLedParams {
+                control_code: 1,
+                on_count: pattern.on_count,
+                off_count: pattern.off_count,
+                on_color: pattern.on_color,
+                off_color: pattern.off_color,
+                timer_count: 0,
+            },
+        }
+    }
+}
+
 impl From<libosdp_sys::osdp_cmd_led_params> for OsdpLedParams {
     fn from(value: libosdp_sys::osdp_cmd_led_params) -> Self {
         OsdpLedParams {
@@ -130,6 +270,7 @@ impl From<OsdpLedParams> for libosdp_sys::osdp_cmd_led_params {
 
 /// Command to control the behavior of it's on-board LEDs
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandLed {
     /// Reader (another device connected to this PD) for which this command is
     /// issued for.
@@ -152,6 +293,67 @@ pub struct OsdpCommandLed {
     pub permanent: OsdpLedParams,
 }
 
+impl OsdpCommandLed {
+    /// Create a command for LED `led_number` of `reader` (0 for the PD
+    /// itself) that applies `temporary` and `permanent` to it.
+    pub fn new(
+        reader: u8,
+        led_number: u8,
+        temporary: OsdpLedTemporaryControl,
+        permanent: OsdpLedPermanentControl,
+    ) -> Self {
+        Self {
+            reader,
+            led_number,
+            temporary: temporary.into(),
+            permanent: permanent.into(),
+        }
+    }
+
+    /// Check that the reader and LED this command is for exist on a PD that
+    /// advertised `caps` (see [`crate::ControlPanel::get_capabilities`]). A PD
+    /// that reports [`PdCapability::LedControl`] with N items has LEDs 0 to
+    /// N - 1 on itself and on each of the readers it reports with
+    /// [`PdCapability::Readers`].
+    pub fn validate(&self, caps: &[PdCapability]) -> Result<(), OsdpError> {
+        let num_items = |kind: fn(&PdCapability) -> bool| {
+            caps.iter()
+                .find(|c| kind(c))
+                .map_or(0, |c| c.entity().num_items())
+        };
+        let num_leds = num_items(|c| matches!(c, PdCapability::LedControl(_)));
+        let num_readers = num_items(|c| matches!(c, PdCapability::Readers(_)));
+        if self.reader > num_readers || self.led_number >= num_leds {
+            return Err(OsdpError::Command);
+        }
+        Ok(())
+    }
+
+    /// What this command does to the temporary state of the LED; `None` for
+    /// control codes that OSDP does not define.
+    pub fn temporary_control(&self) -> Option<OsdpLedTemporaryControl> {
+        match self.temporary.control_code {
+            0 => Some(OsdpLedTemporaryControl::Nop),
+            1 => Some(OsdpLedTemporaryControl::Cancel),
+            2 => Some(OsdpLedTemporaryControl::Set {
+                pattern: self.temporary.pattern(),
+                timer_count: self.temporary.timer_count,
+            }),
+            _ => None,
+        }
+    }
+
+    /// What this command does to the permanent state of the LED; `None` for
+    /// control codes that OSDP does not define.
+    pub fn permanent_control(&self) -> Option<OsdpLedPermanentControl> {
+        match self.permanent.control_code {
+            0 => Some(OsdpLedPermanentControl::Nop),
+            1 => Some(OsdpLedPermanentControl::Set(self.permanent.pattern())),
+            _ => None,
+        }
+    }
+}
+
 impl From<libosdp_sys::osdp_cmd_led> for OsdpCommandLed {
     fn from(value: libosdp_sys::osdp_cmd_led) -> Self {
         OsdpCommandLed {
@@ -176,6 +378,7 @@ impl From<OsdpCommandLed> for libosdp_sys::osdp_cmd_led {
 
 /// Command to control the behavior of a buzzer in the PD
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandBuzzer {
     /// Reader (another device connected to this PD) for which this command is
     /// issued for.
@@ -205,6 +408,186 @@ pub struct OsdpCommandBuzzer {
     pub rep_count: u8,
 }
 
+/// Tones that [`OsdpCommandBuzzer::control_code`] can select
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpBuzzerTone {
+    /// No tone
+    None,
+
+    /// Turn the buzzer off
+    Off,
+
+    /// The default tone of the PD
+    #[default]
+    Default,
+
+    /// A tone code that OSDP leaves for future use
+    Other(u8),
+}
+
+impl From<u8> for OsdpBuzzerTone {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => OsdpBuzzerTone::None,
+            1 => OsdpBuzzerTone::Off,
+            2 => OsdpBuzzerTone::Default,
+            code => OsdpBuzzerTone::Other(code),
+        }
+    }
+}
+
+impl From<OsdpBuzzerTone> for u8 {
+    fn from(value: OsdpBuzzerTone) -> Self {
+        match value {
+            OsdpBuzzerTone::None => 0,
+            OsdpBuzzerTone::Off => 1,
+            OsdpBuzzerTone::Default => 2,
+            OsdpBuzzerTone::Other(code) => code,
+        }
+    }
+}
+
+impl OsdpCommandBuzzer {
+    /// A single short beep, as is usual when access is granted
+    pub fn access_granted() -> Self {
+        Self {
+            reader: 0,
+            control_code: OsdpBuzzerTone::Default.into(),
+            on_count: 2,
+            off_count: 0,
+            rep_count: 1,
+        }
+    }
+
+    /// Three quick beeps, as is usual when access is denied
+    pub fn access_denied() -> Self {
+        Self {
+            reader: 0,
+            control_code: OsdpBuzzerTone::Default.into(),
+            on_count: 1,
+            off_count: 1,
+            rep_count: 3,
+        }
+    }
+
+    /// Silence the buzzer
+    pub fn off() -> Self {
+        Self {
+            reader: 0,
+            control_code: OsdpBuzzerTone::Off.into(),
+            on_count: 0,
+            off_count: 0,
+            rep_count: 0,
+        }
+    }
+
+    /// The tone this command selects
+    pub fn tone(&self) -> OsdpBuzzerTone {
+        self.control_code.into()
+    }
+}
+
+/// Builder for [`OsdpCommandBuzzer`] that takes durations instead of counts
+/// of 100 ms.
+///
+/// ```
+/// use core::time::Duration;
+/// use libosdp::OsdpCommandBuzzerBuilder;
+///
+/// let cmd = OsdpCommandBuzzerBuilder::new()
+///     .on(Duration::from_millis(500))
+///     .off(Duration::from_millis(200))
+///     .repeat(2)
+///     .build()
+///     .unwrap();
+/// assert_eq!((cmd.on_count, cmd.off_count, cmd.rep_count), (5, 2, 2));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OsdpCommandBuzzerBuilder {
+    reader: u8,
+    tone: OsdpBuzzerTone,
+    on: Duration,
+    off: Duration,
+    repeat: Option<u8>,
+}
+
+impl Default for OsdpCommandBuzzerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OsdpCommandBuzzerBuilder {
+    /// Create a builder for a single 100 ms beep of the default tone on the
+    /// PD itself.
+    pub fn new() -> Self {
+        Self {
+            reader: 0,
+            tone: OsdpBuzzerTone::Default,
+            on: Duration::from_millis(100),
+            off: Duration::ZERO,
+            repeat: Some(1),
+        }
+    }
+
+    /// Reader (0 for the PD itself) whose buzzer to sound
+    pub fn reader(mut self, reader: u8) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Tone to sound
+    pub fn tone(mut self, tone: OsdpBuzzerTone) -> Self {
+        self.tone = tone;
+        self
+    }
+
+    /// How long each beep lasts; rounded down to 100 ms, up to 25.5 s.
+    pub fn on(mut self, duration: Duration) -> Self {
+        self.on = duration;
+        self
+    }
+
+    /// How long the buzzer is silent between beeps; rounded down to 100 ms,
+    /// up to 25.5 s.
+    pub fn off(mut self, duration: Duration) -> Self {
+        self.off = duration;
+        self
+    }
+
+    /// Number of beeps
+    pub fn repeat(mut self, count: u8) -> Self {
+        self.repeat = Some(count);
+        self
+    }
+
+    /// Keep beeping until another buzzer command is sent
+    pub fn forever(mut self) -> Self {
+        self.repeat = None;
+        self
+    }
+
+    /// Build the command; fails with [`OsdpError::Command`] if a duration is
+    /// too long or a repeat count of 0 was set (use
+    /// [`OsdpCommandBuzzerBuilder::forever`] for that).
+    pub fn build(self) -> Result<OsdpCommandBuzzer, OsdpError> {
+        let count = |d: Duration| u8::try_from(d.as_millis() / 100).map_err(|_| OsdpError::Command);
+        let rep_count = match self.repeat {
+            Some(0) => return Err(OsdpError::Command),
+            Some(count) => count,
+            None => 0,
+        };
+        Ok(OsdpCommandBuzzer {
+            reader: self.reader,
+            control_code: self.tone.into(),
+            on_count: count(self.on)?,
+            off_count: count(self.off)?,
+            rep_count,
+        })
+    }
+}
+
 impl From<libosdp_sys::osdp_cmd_buzzer> for OsdpCommandBuzzer {
     fn from(value: libosdp_sys::osdp_cmd_buzzer) -> Self {
         OsdpCommandBuzzer {
@@ -232,6 +615,7 @@ impl From<OsdpCommandBuzzer> for libosdp_sys::osdp_cmd_buzzer {
 /// Command to manipulate the on-board display unit (Can be LED, LCD, 7-Segment,
 /// etc.,) on the PD.
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandText {
     /// Reader (another device connected to this PD) for which this command is
     /// issued for.
@@ -296,6 +680,7 @@ impl From<OsdpCommandText> for libosdp_sys::osdp_cmd_text {
 
 /// Command to control digital output exposed by the PD.
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandOutput {
     /// The output number this to apply this action.
     ///
@@ -343,9 +728,10 @@ impl From<OsdpCommandOutput> for libosdp_sys::osdp_cmd_output {
 /// command is expected to be be stored in PD's non-volatile memory as the CP
 /// will expect the PD to be in this state moving forward.
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpComSet {
-    address: u8,
-    baud_rate: u32,
+    pub(crate) address: u8,
+    pub(crate) baud_rate: u32,
 }
 
 impl OsdpComSet {
@@ -381,8 +767,9 @@ impl From<OsdpComSet> for libosdp_sys::osdp_cmd_comset {
 
 /// Command to set secure channel keys to the PD.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandKeyset {
-    key_type: u8,
+    pub(crate) key_type: u8,
     /// Key data
     pub data: Vec<u8>,
 }
@@ -424,6 +811,7 @@ impl From<OsdpCommandKeyset> for libosdp_sys::osdp_cmd_keyset {
 
 /// Command to to act as a wrapper for manufacturer specific commands
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandMfg {
     /// 3-byte IEEE assigned OUI used as vendor code
     pub vendor_code: (u8, u8, u8),
@@ -464,6 +852,7 @@ impl From<OsdpCommandMfg> for libosdp_sys::osdp_cmd_mfg {
 
 /// Command to kick-off a file transfer to the PD.
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpCommandFileTx {
     id: i32,
     flags: u32,
@@ -503,6 +892,7 @@ impl From<OsdpCommandFileTx> for libosdp_sys::osdp_cmd_file_tx {
 /// CP interacts with and controls PDs by sending commands to it. The commands
 /// in this enum are specified by OSDP specification.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum OsdpCommand {
     /// Command to control the behavior of it’s on-board LEDs
     Led(OsdpCommandLed),
@@ -535,6 +925,60 @@ pub enum OsdpCommand {
     Status(OsdpStatusReport),
 }
 
+/// Kind of an [`OsdpCommand`], without its data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpCommandKind {
+    /// [`OsdpCommand::Led`]
+    Led,
+    /// [`OsdpCommand::Buzzer`]
+    Buzzer,
+    /// [`OsdpCommand::Text`]
+    Text,
+    /// [`OsdpCommand::Output`]
+    Output,
+    /// [`OsdpCommand::ComSet`]
+    ComSet,
+    /// [`OsdpCommand::KeySet`]
+    KeySet,
+    /// [`OsdpCommand::Mfg`]
+    Mfg,
+    /// [`OsdpCommand::FileTx`]
+    FileTx,
+    /// [`OsdpCommand::Status`]
+    Status,
+}
+
+impl OsdpCommandKind {
+    /// Number of kinds; `Status` must stay the last one.
+    pub(crate) const COUNT: usize = OsdpCommandKind::Status as usize + 1;
+
+    /// Commands that only drive indicators on the PD; it is safe to ACK
+    /// these without acting on them. See [`crate::PdInfoBuilder::auto_ack`].
+    pub const BENIGN: &'static [OsdpCommandKind] = &[
+        OsdpCommandKind::Led,
+        OsdpCommandKind::Buzzer,
+        OsdpCommandKind::Text,
+    ];
+}
+
+impl OsdpCommand {
+    /// Get the [`OsdpCommandKind`] of this command.
+    pub fn kind(&self) -> OsdpCommandKind {
+        match self {
+            OsdpCommand::Led(_) => OsdpCommandKind::Led,
+            OsdpCommand::Buzzer(_) => OsdpCommandKind::Buzzer,
+            OsdpCommand::Text(_) => OsdpCommandKind::Text,
+            OsdpCommand::Output(_) => OsdpCommandKind::Output,
+            OsdpCommand::ComSet(_) => OsdpCommandKind::ComSet,
+            OsdpCommand::KeySet(_) => OsdpCommandKind::KeySet,
+            OsdpCommand::Mfg(_) => OsdpCommandKind::Mfg,
+            OsdpCommand::FileTx(_) => OsdpCommandKind::FileTx,
+            OsdpCommand::Status(_) => OsdpCommandKind::Status,
+        }
+    }
+}
+
 impl From<OsdpCommand> for libosdp_sys::osdp_cmd {
     fn from(value: OsdpCommand) -> Self {
         match value {
@@ -623,8 +1067,100 @@ impl From<libosdp_sys::osdp_cmd> for OsdpCommand {
 
 #[cfg(test)]
 mod tests {
-    use crate::OsdpCommandMfg;
-    use libosdp_sys::osdp_cmd_mfg;
+    use crate::{
+        OsdpBuzzerTone, OsdpCommandBuzzer, OsdpCommandBuzzerBuilder, OsdpCommandLed,
+        OsdpCommandMfg, OsdpLedColor, OsdpLedPattern, OsdpLedPermanentControl,
+        OsdpLedTemporaryControl, PdCapEntity, PdCapability,
+    };
+    use core::time::Duration;
+    use libosdp_sys::{osdp_cmd_led, osdp_cmd_mfg};
+
+    #[test]
+    fn test_command_buzzer() {
+        let cmd = OsdpCommandBuzzerBuilder::new()
+            .reader(1)
+            .on(Duration::from_millis(250))
+            .off(Duration::from_secs(1))
+            .forever()
+            .build()
+            .unwrap();
+        assert_eq!(cmd.tone(), OsdpBuzzerTone::Default);
+        assert_eq!((cmd.reader, cmd.control_code), (1, 2));
+        assert_eq!((cmd.on_count, cmd.off_count, cmd.rep_count), (2, 10, 0));
+
+        let builder = OsdpCommandBuzzerBuilder::new();
+        assert!(builder.on(Duration::from_secs(26)).build().is_err());
+        assert!(builder.repeat(0).build().is_err());
+        assert_eq!(
+            builder.on(Duration::from_millis(200)).build().unwrap(),
+            OsdpCommandBuzzer::access_granted()
+        );
+        assert_eq!(
+            builder
+                .off(Duration::from_millis(100))
+                .repeat(3)
+                .build()
+                .unwrap(),
+            OsdpCommandBuzzer::access_denied()
+        );
+        assert_eq!(OsdpCommandBuzzer::off().tone(), OsdpBuzzerTone::Off);
+        assert_eq!(u8::from(OsdpBuzzerTone::from(7)), 7);
+    }
+
+    #[test]
+    fn test_command_led() {
+        let temporary = OsdpLedTemporaryControl::Set {
+            pattern: OsdpLedPattern::flash(OsdpLedColor::White, OsdpLedColor::Blue, 2, 3),
+            timer_count: 30,
+        };
+        let permanent = OsdpLedPermanentControl::Set(OsdpLedPattern::steady(OsdpLedColor::Magenta));
+        let cmd = OsdpCommandLed::new(1, 1, temporary, permanent);
+        assert_eq!(cmd.temporary.control_code, 2);
+        assert_eq!(cmd.permanent.control_code, 1);
+        assert_eq!(cmd.permanent.on_color, OsdpLedColor::Magenta);
+        assert_eq!(cmd.temporary_control(), Some(temporary));
+        assert_eq!(cmd.permanent_control(), Some(permanent));
+        let cmd_struct: osdp_cmd_led = cmd.clone().into();
+        assert_eq!(cmd_struct.temporary.on_color, 7);
+        assert_eq!(cmd_struct.temporary.off_color, 4);
+        assert_eq!(cmd_struct.temporary.timer_count, 30);
+
+        let cancel = OsdpCommandLed::new(
+            0,
+            0,
+            OsdpLedTemporaryControl::Cancel,
+            OsdpLedPermanentControl::Nop,
+        );
+        assert_eq!(
+            cancel.temporary_control(),
+            Some(OsdpLedTemporaryControl::Cancel)
+        );
+        assert_eq!(
+            cancel.permanent_control(),
+            Some(OsdpLedPermanentControl::Nop)
+        );
+
+        let caps = [
+            PdCapability::LedControl(PdCapEntity::new(4, 2)),
+            PdCapability::Readers(PdCapEntity::new(0, 1)),
+        ];
+        assert!(cmd.validate(&caps).is_ok());
+        assert!(cancel.validate(&caps).is_ok());
+        assert!(OsdpCommandLed {
+            led_number: 2,
+            ..cmd.clone()
+        }
+        .validate(&caps)
+        .is_err());
+        assert!(OsdpCommandLed {
+            reader: 2,
+            ..cmd.clone()
+        }
+        .validate(&caps)
+        .is_err());
+        assert!(cmd.validate(&caps[..1]).is_err());
+        assert!(cancel.validate(&[]).is_err());
+    }
 
     #[test]
     fn test_command_mfg() {
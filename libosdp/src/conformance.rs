@@ -0,0 +1,608 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pre-certification testing of PDs. [`Conformance`] runs a fixed script of
+//! exchanges whose outcome the OSDP specification mandates (the POLL, ID,
+//! CAP start up sequence, sequence number handling, NAK reasons, secure
+//! channel set up corner cases) against a PD and reports which ones it got
+//! right. This does not replace certification, but catches most of what it
+//! would fail on, on the bench, with any [`Channel`] to the PD.
+//!
+//! As with [`crate::Discovery`], the exchanges are done with raw packets
+//! (see [`crate::wire`]) so that malformed and out of order commands can be
+//! sent; the channel must not be in use by a [`crate::ControlPanel`]
+//! meanwhile.
+
+use crate::{
+    discover::exchange,
+    wire::{Packet, ScBlock},
+    Channel, OsdpError, PdCapability,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::time::Duration;
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+const CMD_POLL: u8 = 0x60;
+const CMD_ID: u8 = 0x61;
+const CMD_CAP: u8 = 0x62;
+const CMD_BUZ: u8 = 0x6A;
+const CMD_CHLNG: u8 = 0x76;
+/// Not assigned to any command by the specification
+const CMD_UNASSIGNED: u8 = 0x7F;
+
+const REPLY_NAK: u8 = 0x41;
+const REPLY_PDID: u8 = 0x45;
+const REPLY_PDCAP: u8 = 0x46;
+const REPLY_CCRYPT: u8 = 0x76;
+const REPLY_BUSY: u8 = 0x79;
+
+const NAK_CHECK_CHARACTER: u8 = 0x01;
+const NAK_CMD_LEN: u8 = 0x02;
+const NAK_UNKNOWN_CMD: u8 = 0x03;
+const NAK_SEQUENCE: u8 = 0x04;
+
+const SCS_11: u8 = 0x11;
+const SCS_12: u8 = 0x12;
+const SCS_15: u8 = 0x15;
+
+/// Function code of [`PdCapability::CommunicationSecurity`]
+const CAP_COMMUNICATION_SECURITY: u8 = 9;
+
+/// Outcome of a conformance check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The PD did what the specification requires
+    Pass,
+    /// It did not; carries what it did instead
+    Fail(String),
+    /// The check does not apply to this PD, or could not be run; carries why
+    Skip(&'static str),
+}
+
+/// A check run by [`Conformance`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConformanceCheck {
+    /// Short name of the check (e.g. "unknown-command")
+    pub name: &'static str,
+    /// What the PD is expected to do
+    pub expectation: &'static str,
+    /// How it went
+    pub verdict: Verdict,
+}
+
+/// Results of a [`Conformance`] run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConformanceReport {
+    /// Address of the PD
+    pub address: i32,
+    /// Checks, in the order they were run
+    pub checks: Vec<ConformanceCheck>,
+}
+
+impl ConformanceReport {
+    /// Whether no check failed (some may have been skipped)
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &ConformanceCheck> {
+        self.checks
+            .iter()
+            .filter(|c| matches!(c.verdict, Verdict::Fail(_)))
+    }
+}
+
+impl core::fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "PD at address {}", self.address)?;
+        for check in &self.checks {
+            match &check.verdict {
+                Verdict::Pass => writeln!(f, "  PASS  {}", check.name)?,
+                Verdict::Fail(why) => writeln!(
+                    f,
+                    "  FAIL  {}: expected {}; {}",
+                    check.name, check.expectation, why
+                )?,
+                Verdict::Skip(why) => writeln!(f, "  SKIP  {}: {}", check.name, why)?,
+            }
+        }
+        let failed = self.failures().count();
+        write!(f, "{} checks, {} failed", self.checks.len(), failed)
+    }
+}
+
+type CheckFn<'a> = fn(&mut Script<'a>) -> Result<Verdict>;
+
+/// The checks, in the order they are run. Later ones rely on earlier ones
+/// (capabilities, for instance), so the order matters.
+#[rustfmt::skip]
+fn checks<'a>() -> [(&'static str, &'static str, CheckFn<'a>); 13] {
+    [
+        ("poll", "a POLL with sequence number 0 to be answered, other than with a NAK", Script::poll),
+        ("id", "ID to be answered with a 12 byte PDID", Script::id),
+        ("capabilities", "CAP to be answered with a PDCAP of valid capabilities", Script::capabilities),
+        ("repeated-sequence", "a command sent again with the same sequence number to get the same reply", Script::repeated_sequence),
+        ("sequence-error", "a command with an unexpected sequence number to be NAK'd with reason 0x04", Script::sequence_error),
+        ("unknown-command", "an unknown command to be NAK'd with reason 0x03", Script::unknown_command),
+        ("command-length", "a command of the wrong length to be NAK'd with reason 0x02", Script::command_length),
+        ("bad-crc", "a command with a bad CRC to be ignored or NAK'd with reason 0x01", Script::bad_crc),
+        ("checksum", "a command with a checksum to be answered with a checksum", Script::checksum),
+        ("sc-without-session", "a secure channel command without a session to be NAK'd", Script::sc_without_session),
+        ("sc-challenge", "CHLNG to be answered with a 32 byte CCRYPT", Script::sc_challenge),
+        ("sc-abandoned", "plain commands to be answered after an unfinished secure channel set up", Script::sc_abandoned),
+        ("reply-sequence", "replies to carry the sequence number of their command", Script::reply_sequence),
+    ]
+}
+
+fn describe(reply: &Option<Packet>) -> String {
+    match reply {
+        None => "got no reply".into(),
+        Some(r) if r.code == REPLY_NAK => match r.data.first() {
+            Some(reason) => format!("got NAK with reason {reason:#04x}"),
+            None => "got NAK without a reason".into(),
+        },
+        Some(r) => format!("got {}", r.name()),
+    }
+}
+
+fn expect_nak(reply: &Option<Packet>, reason: u8) -> Verdict {
+    match reply {
+        Some(r) if r.code == REPLY_NAK && r.data.first() == Some(&reason) => Verdict::Pass,
+        r => Verdict::Fail(describe(r)),
+    }
+}
+
+/// State of a [`Conformance`] run
+struct Script<'a> {
+    channel: &'a mut dyn Channel,
+    address: u8,
+    timeout: Duration,
+    sequence: u8,
+    answered: bool,
+    capabilities: Option<Vec<PdCapability>>,
+    challenged: bool,
+    /// Replies that did not carry the sequence number of their command
+    misnumbered: Vec<String>,
+}
+
+impl Script<'_> {
+    fn packet(&self, code: u8, data: Vec<u8>) -> Packet {
+        Packet {
+            address: self.address,
+            is_reply: false,
+            sequence: self.sequence,
+            use_crc: true,
+            sc_block: None,
+            code,
+            data,
+            mac: None,
+        }
+    }
+
+    /// Send `packet` and wait for its reply, sending it again while the PD
+    /// is BUSY. The sequence number moves on once the PD answers.
+    fn send(&mut self, packet: &Packet) -> Result<Option<Packet>> {
+        let bytes = packet.to_bytes();
+        let mut reply = None;
+        for _ in 0..3 {
+            reply = exchange(&mut *self.channel, &bytes, self.address, self.timeout)?;
+            match &reply {
+                Some(r) if r.code == REPLY_BUSY => std::thread::sleep(Duration::from_millis(20)),
+                _ => break,
+            }
+        }
+        if let Some(r) = &reply {
+            if r.sequence != packet.sequence {
+                self.misnumbered.push(format!(
+                    "{} to {} had sequence number {} instead of {}",
+                    r.name(),
+                    packet.name(),
+                    r.sequence,
+                    packet.sequence
+                ));
+            }
+            self.answered = true;
+            self.sequence = self.sequence % 3 + 1;
+        }
+        Ok(reply)
+    }
+
+    fn command(&mut self, code: u8, data: Vec<u8>) -> Result<Option<Packet>> {
+        let packet = self.packet(code, data);
+        self.send(&packet)
+    }
+
+    fn poll(&mut self) -> Result<Verdict> {
+        self.sequence = 0;
+        Ok(match self.command(CMD_POLL, vec![])? {
+            Some(r) if r.code != REPLY_NAK => Verdict::Pass,
+            r => Verdict::Fail(describe(&r)),
+        })
+    }
+
+    fn id(&mut self) -> Result<Verdict> {
+        Ok(match self.command(CMD_ID, vec![0x00])? {
+            Some(r) if r.code == REPLY_PDID && r.data.len() == 12 => Verdict::Pass,
+            Some(r) if r.code == REPLY_PDID => {
+                Verdict::Fail(format!("got a PDID of {} bytes", r.data.len()))
+            }
+            r => Verdict::Fail(describe(&r)),
+        })
+    }
+
+    fn capabilities(&mut self) -> Result<Verdict> {
+        let reply = match self.command(CMD_CAP, vec![0x00])? {
+            Some(r) if r.code == REPLY_PDCAP => r,
+            r => return Ok(Verdict::Fail(describe(&r))),
+        };
+        if reply.data.is_empty() || reply.data.len() % 3 != 0 {
+            return Ok(Verdict::Fail(format!(
+                "got a PDCAP of {} bytes",
+                reply.data.len()
+            )));
+        }
+        let capabilities: Vec<PdCapability> = reply
+            .data
+            .chunks_exact(3)
+            .map(|c| PdCapability::from((c[0], c[1], c[2])))
+            .collect();
+        let invalid = capabilities
+            .iter()
+            .find_map(|cap| cap.validate().err().map(|e| format!("{cap:?}: {e}")));
+        self.capabilities = Some(capabilities);
+        Ok(match invalid {
+            Some(why) => Verdict::Fail(why),
+            None => Verdict::Pass,
+        })
+    }
+
+    fn repeated_sequence(&mut self) -> Result<Verdict> {
+        let packet = self.packet(CMD_POLL, vec![]);
+        let Some(first) = self.send(&packet)? else {
+            return Ok(Verdict::Fail("got no reply to the first POLL".into()));
+        };
+        // As if the reply was lost and the CP tries again
+        let again = exchange(
+            &mut *self.channel,
+            &packet.to_bytes(),
+            self.address,
+            self.timeout,
+        )?;
+        Ok(match again {
+            Some(r) if r.code == first.code && r.data == first.data => Verdict::Pass,
+            Some(r) => Verdict::Fail(format!("got {} after {}", r.name(), first.name())),
+            None => Verdict::Fail("got no reply the second time".into()),
+        })
+    }
+
+    fn sequence_error(&mut self) -> Result<Verdict> {
+        // One past the expected sequence number
+        let mut packet = self.packet(CMD_POLL, vec![]);
+        packet.sequence = self.sequence % 3 + 1;
+        let reply = self.send(&packet)?;
+        // Start over, as a CP does after a sequence error
+        self.sequence = 0;
+        let _ = self.command(CMD_POLL, vec![])?;
+        Ok(expect_nak(&reply, NAK_SEQUENCE))
+    }
+
+    fn unknown_command(&mut self) -> Result<Verdict> {
+        let reply = self.command(CMD_UNASSIGNED, vec![])?;
+        Ok(expect_nak(&reply, NAK_UNKNOWN_CMD))
+    }
+
+    fn command_length(&mut self) -> Result<Verdict> {
+        // A BUZ has 5 bytes of data
+        let reply = self.command(CMD_BUZ, vec![0x00, 0x02])?;
+        Ok(expect_nak(&reply, NAK_CMD_LEN))
+    }
+
+    fn bad_crc(&mut self) -> Result<Verdict> {
+        let mut bytes = self.packet(CMD_POLL, vec![]).to_bytes();
+        if let Some(last) = bytes.last_mut() {
+            *last ^= 0xFF;
+        }
+        // The PD did not take the command in, so the sequence number stays
+        let reply = exchange(&mut *self.channel, &bytes, self.address, self.timeout)?;
+        Ok(match reply {
+            None => Verdict::Pass,
+            r => expect_nak(&r, NAK_CHECK_CHARACTER),
+        })
+    }
+
+    fn checksum(&mut self) -> Result<Verdict> {
+        let mut packet = self.packet(CMD_POLL, vec![]);
+        packet.use_crc = false;
+        Ok(match self.send(&packet)? {
+            Some(r) if !r.use_crc => Verdict::Pass,
+            Some(r) => Verdict::Fail(format!("got {} with a CRC", r.name())),
+            None => Verdict::Fail(describe(&None)),
+        })
+    }
+
+    fn sc_without_session(&mut self) -> Result<Verdict> {
+        let mut packet = self.packet(CMD_POLL, vec![]);
+        packet.sc_block = Some(ScBlock {
+            block_type: SCS_15,
+            data: vec![],
+        });
+        packet.mac = Some([0; 4]);
+        Ok(match self.send(&packet)? {
+            Some(r) if r.code == REPLY_NAK => Verdict::Pass,
+            r => Verdict::Fail(describe(&r)),
+        })
+    }
+
+    fn sc_challenge(&mut self) -> Result<Verdict> {
+        let Some(capabilities) = &self.capabilities else {
+            return Ok(Verdict::Skip("capabilities of the PD are not known"));
+        };
+        let secure = capabilities.iter().any(|cap| {
+            cap.function_code() == CAP_COMMUNICATION_SECURITY && cap.entity().compliance() & 1 != 0
+        });
+        if !secure {
+            return Ok(Verdict::Skip("PD does not support secure channel"));
+        }
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let rnd_a: [u8; 8] = core::array::from_fn(|i| (nanos >> (i * 8)) as u8);
+        // With the SCBK and, should the PD NAK that, the SCBK-D (which a PD in
+        // install mode would expect)
+        let mut reply = None;
+        for key in [0x01, 0x00] {
+            let mut packet = self.packet(CMD_CHLNG, rnd_a.to_vec());
+            packet.sc_block = Some(ScBlock {
+                block_type: SCS_11,
+                data: vec![key],
+            });
+            reply = self.send(&packet)?;
+            if !matches!(&reply, Some(r) if r.code == REPLY_NAK) {
+                break;
+            }
+        }
+        Ok(match reply {
+            Some(r) if r.code == REPLY_CCRYPT => {
+                self.challenged = true;
+                let block_type = r.sc_block.as_ref().map(|sb| sb.block_type);
+                if block_type != Some(SCS_12) {
+                    Verdict::Fail(format!("got CCRYPT with security block {block_type:02x?}"))
+                } else if r.data.len() != 32 {
+                    Verdict::Fail(format!("got a CCRYPT of {} bytes", r.data.len()))
+                } else {
+                    Verdict::Pass
+                }
+            }
+            r => Verdict::Fail(describe(&r)),
+        })
+    }
+
+    fn sc_abandoned(&mut self) -> Result<Verdict> {
+        if !self.challenged {
+            return Ok(Verdict::Skip("no secure channel set up was started"));
+        }
+        Ok(match self.command(CMD_POLL, vec![])? {
+            Some(r) if r.code != REPLY_NAK => Verdict::Pass,
+            r => Verdict::Fail(describe(&r)),
+        })
+    }
+
+    fn reply_sequence(&mut self) -> Result<Verdict> {
+        Ok(match self.misnumbered.first() {
+            None => Verdict::Pass,
+            Some(first) => {
+                Verdict::Fail(format!("{first} (and {} more)", self.misnumbered.len() - 1))
+            }
+        })
+    }
+}
+
+/// Builder for conformance testing a PD; see the [module
+/// documentation](self) for what it does.
+///
+/// # Example
+///
+/// ```ignore
+/// let report = Conformance::new(101)?.run(&mut channel)?;
+/// println!("{report}");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Conformance {
+    address: u8,
+    timeout: Duration,
+}
+
+impl Conformance {
+    /// Test the PD at `address`
+    pub fn new(address: i32) -> Result<Self> {
+        if !(0..=126).contains(&address) {
+            return Err(OsdpError::InvalidPd(address));
+        }
+        Ok(Self {
+            address: address as u8,
+            timeout: Duration::from_millis(200),
+        })
+    }
+
+    /// How long to wait for each reply of the PD (200ms by default). The
+    /// specification allows a PD 200ms to start replying.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run all checks against the PD behind `channel`. Failed checks are
+    /// reported, not returned as errors; those are left for the channel
+    /// failing. If the PD does not answer at all, the checks after the
+    /// first are skipped.
+    pub fn run(&self, channel: &mut dyn Channel) -> Result<ConformanceReport> {
+        let mut script = Script {
+            channel,
+            address: self.address,
+            timeout: self.timeout,
+            sequence: 0,
+            answered: false,
+            capabilities: None,
+            challenged: false,
+            misnumbered: Vec::new(),
+        };
+        let mut results = Vec::new();
+        for (i, (name, expectation, check)) in checks().into_iter().enumerate() {
+            let verdict = if i > 0 && !script.answered {
+                Verdict::Skip("PD did not answer")
+            } else {
+                check(&mut script)?
+            };
+            log::debug!("Conformance: {name}: {verdict:?}");
+            results.push(ConformanceCheck {
+                name,
+                expectation,
+                verdict,
+            });
+        }
+        Ok(ConformanceReport {
+            address: self.address as i32,
+            checks: results,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        wire::{PacketDecoder, MARK},
+        ChannelError,
+    };
+
+    /// A PD that answers the way the specification says it should, unless
+    /// told to always reply with a CRC
+    #[derive(Default)]
+    struct FakePd {
+        decoder: PacketDecoder,
+        replies: Vec<u8>,
+        last: Option<Packet>,
+        crc_only: bool,
+    }
+
+    impl FakePd {
+        fn answer(&mut self, cmd: &Packet) -> Packet {
+            let mut reply = Packet {
+                is_reply: true,
+                use_crc: cmd.use_crc || self.crc_only,
+                sc_block: None,
+                data: vec![],
+                mac: None,
+                ..cmd.clone()
+            };
+            let expected = self.last.as_ref().map(|r| r.sequence % 3 + 1);
+            match (&self.last, cmd.sequence) {
+                (Some(last), seq) if seq != 0 && seq == last.sequence => return last.clone(),
+                (_, seq) if seq != 0 && Some(seq) != expected => {
+                    reply.code = REPLY_NAK;
+                    reply.data = vec![NAK_SEQUENCE];
+                    return reply;
+                }
+                _ => {}
+            }
+            (reply.code, reply.data) = match (cmd.code, &cmd.sc_block) {
+                (CMD_CHLNG, Some(sb)) if sb.block_type == SCS_11 => {
+                    reply.sc_block = Some(ScBlock {
+                        block_type: SCS_12,
+                        data: vec![sb.data[0]],
+                    });
+                    (REPLY_CCRYPT, vec![0; 32])
+                }
+                (_, Some(_)) => (REPLY_NAK, vec![0x05]),
+                (CMD_POLL, None) => (0x40, vec![]),
+                (CMD_ID, None) => (REPLY_PDID, vec![0; 12]),
+                (CMD_CAP, None) => (REPLY_PDCAP, vec![4, 1, 2, 9, 1, 1, 10, 0x80, 0]),
+                (CMD_BUZ, None) if cmd.data.len() != 5 => (REPLY_NAK, vec![NAK_CMD_LEN]),
+                _ => (REPLY_NAK, vec![NAK_UNKNOWN_CMD]),
+            };
+            reply
+        }
+    }
+
+    impl Channel for FakePd {
+        fn get_id(&self) -> i32 {
+            0
+        }
+        fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, ChannelError> {
+            if self.replies.is_empty() {
+                return Err(ChannelError::WouldBlock);
+            }
+            let n = self.replies.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.replies[..n]);
+            self.replies.drain(..n);
+            Ok(n)
+        }
+        fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, ChannelError> {
+            self.decoder.push(buf);
+            while let Some(cmd) = self.decoder.next_packet() {
+                let reply = self.answer(&cmd);
+                self.replies.push(MARK);
+                self.replies.extend(reply.to_bytes());
+                self.last = Some(reply).filter(|r| r.data != [NAK_SEQUENCE]);
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> core::result::Result<(), ChannelError> {
+            Ok(())
+        }
+    }
+
+    fn run(pd: &mut FakePd) -> ConformanceReport {
+        Conformance::new(5)
+            .unwrap()
+            .timeout(Duration::from_millis(5))
+            .run(pd)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_conformance() {
+        let report = run(&mut FakePd::default());
+        assert_eq!(report.checks.len(), checks().len());
+        assert!(report.passed(), "{report}");
+        assert!(report.checks.iter().all(|c| c.verdict == Verdict::Pass));
+
+        let report = run(&mut FakePd {
+            crc_only: true,
+            ..Default::default()
+        });
+        let failures: Vec<_> = report.failures().map(|c| c.name).collect();
+        assert_eq!(failures, ["checksum"]);
+
+        // Nobody home
+        struct Silent;
+        impl Channel for Silent {
+            fn get_id(&self) -> i32 {
+                0
+            }
+            fn read(&mut self, _: &mut [u8]) -> core::result::Result<usize, ChannelError> {
+                Err(ChannelError::WouldBlock)
+            }
+            fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, ChannelError> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> core::result::Result<(), ChannelError> {
+                Ok(())
+            }
+        }
+        let report = Conformance::new(5)
+            .unwrap()
+            .timeout(Duration::from_millis(1))
+            .run(&mut Silent)
+            .unwrap();
+        assert_eq!(report.failures().count(), 1);
+        assert!(report.checks[1..]
+            .iter()
+            .all(|c| matches!(c.verdict, Verdict::Skip(_))));
+    }
+}
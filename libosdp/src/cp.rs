@@ -7,59 +7,136 @@
 //! (PD) on the OSDP bus. It can send commands to and receive events from PDs.
 
 use crate::{
-    file::OsdpFileOps, Channel, OsdpCommand, OsdpError, OsdpEvent, OsdpFlag, PdCapability, PdId,
-    PdInfoBuilder,
+    callback::{catch_panic, Callback},
+    channel::ChannelHandle,
+    file::OsdpFileOps,
+    history::History,
+    logger::LogContext,
+    pending::{CommandTap, Outcome, PendingCommands},
+    runtime_state::Resume,
+    Activity, ActivityRecord, CallbackGuard, Channel, CpRuntimeState, EventContext, LogLevel,
+    LogSink, OsdpComSet, OsdpCommand, OsdpError, OsdpErrorKind, OsdpEvent, OsdpEventKind, OsdpFlag,
+    PdBitSet, PdCapEntity, PdCapability, PdError, PdId, PdInfo, PdInfoBuilder, PdRuntimeState,
+    RefreshReport, RESUME_GRACE,
+};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    ffi::c_void,
 };
-use alloc::{boxed::Box, vec::Vec};
-use core::ffi::c_void;
 #[cfg(feature = "defmt-03")]
-use defmt::{debug, error, info, warn};
+use defmt::error;
 #[cfg(all(feature = "log", not(feature = "defmt-03")))]
-use log::{debug, error, info, warn};
+use log::error;
 
 type Result<T> = core::result::Result<T, OsdpError>;
 
-unsafe extern "C" fn log_handler(
-    _log_level: ::core::ffi::c_int,
-    _file: *const ::core::ffi::c_char,
-    _line: ::core::ffi::c_ulong,
-    _msg: *const ::core::ffi::c_char,
-) {
-    #[cfg(any(feature = "log", feature = "defmt-03"))]
-    {
-        let msg = crate::cstr_to_string(_msg);
-        let msg = msg.trim();
-        match _log_level as libosdp_sys::osdp_log_level_e {
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_EMERG => error!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_ALERT => error!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_CRIT => error!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_ERROR => error!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_WARNING => warn!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_NOTICE => warn!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_INFO => info!("CP: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_DEBUG => debug!("CP: {}", msg),
-            _ => panic!("Unknown log level"),
-        };
+type EventCallback = dyn FnMut(&EventContext<'_>, OsdpEvent) -> i32 + Send;
+type ScStatusCallback = dyn FnMut(i32, bool) + Send;
+type IdMismatchCallback = dyn FnMut(i32, PdId, PdId) + Send;
+type EventHandler = dyn FnMut(&mut ControlPanel, i32, OsdpEvent) + Send;
+#[cfg(feature = "std")]
+type CommandFailedCallback = dyn FnMut(i32, OsdpCommand, crate::PdErrorKind) + Send;
+
+/// Number of events kept for [`ControlPanel::poll_events`]
+const MAX_UNCLAIMED_EVENTS: usize = 64;
+
+/// LibOSDP polls PDs that are online this often, so it needs to be refreshed
+/// at least as often to meet OSDP timing.
+#[cfg(feature = "std")]
+const MAX_REFRESH_INTERVAL: core::time::Duration =
+    core::time::Duration::from_millis(libosdp_sys::OSDP_PD_POLL_TIMEOUT_MS as u64);
+
+/// How long [`ControlPanel::comset`] waits for the PD to reply.
+#[cfg(feature = "std")]
+const COMSET_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(10);
+
+/// Event callbacks of a CP; LibOSDP is given a pointer to this. Events go to
+/// the callback subscribed to their kind, if any, or the catch-all one.
+/// Events (and commands sent by the CP) are also recorded in `history`.
+///
+/// Once an event handler is set (see [`ControlPanel::set_event_handler`]),
+/// events are put in `deferred` instead, to be delivered after LibOSDP
+/// returns. Events that no callback takes are kept in `unclaimed`.
+///
+/// Callbacks are given the name and address of the PD, from `identities`.
+#[derive(Debug)]
+struct EventCallbacks {
+    any: Callback<EventCallback>,
+    by_kind: [Callback<EventCallback>; OsdpEventKind::COUNT],
+    history: RefCell<History>,
+    defer: Cell<bool>,
+    deferred: RefCell<VecDeque<(i32, OsdpEvent)>>,
+    unclaimed: RefCell<VecDeque<(i32, OsdpEvent)>>,
+    /// Number of events received since it was last reset
+    received: Cell<usize>,
+    identities: RefCell<Vec<(String, i32)>>,
+}
+
+impl EventCallbacks {
+    fn new(history: History, identities: Vec<(String, i32)>) -> Box<Self> {
+        Box::new(Self {
+            any: Callback::new(),
+            by_kind: core::array::from_fn(|_| Callback::new()),
+            history: RefCell::new(history),
+            defer: Cell::new(false),
+            deferred: RefCell::new(VecDeque::new()),
+            unclaimed: RefCell::new(VecDeque::new()),
+            received: Cell::new(0),
+            identities: RefCell::new(identities),
+        })
+    }
+
+    fn kind(&self, kind: OsdpEventKind) -> &Callback<EventCallback> {
+        &self.by_kind[kind as usize]
     }
 }
 
-extern "C" fn trampoline<F>(data: *mut c_void, pd: i32, event: *mut libosdp_sys::osdp_event) -> i32
-where
-    F: FnMut(i32, OsdpEvent) -> i32,
-{
+extern "C" fn trampoline(data: *mut c_void, pd: i32, event: *mut libosdp_sys::osdp_event) -> i32 {
     let event: OsdpEvent = unsafe { (*event).into() };
-    let callback: &mut F = unsafe { &mut *(data as *mut F) };
-    callback(pd, event)
+    let callbacks = unsafe { &*(data as *const EventCallbacks) };
+    catch_panic("event callback", -1, || dispatch(callbacks, pd, event))
 }
 
-type EventCallback =
-    unsafe extern "C" fn(data: *mut c_void, pd: i32, event: *mut libosdp_sys::osdp_event) -> i32;
+fn dispatch(callbacks: &EventCallbacks, pd: i32, event: OsdpEvent) -> i32 {
+    callbacks.received.set(callbacks.received.get() + 1);
+    {
+        let mut history = callbacks.history.borrow_mut();
+        if history.is_enabled() {
+            history.record(pd, Activity::Event(event.clone()));
+        }
+    }
+    if callbacks.defer.get() {
+        callbacks.deferred.borrow_mut().push_back((pd, event));
+        return 0;
+    }
+    deliver(callbacks, pd, event)
+}
 
-fn get_trampoline<F>(_closure: &F) -> EventCallback
-where
-    F: FnMut(i32, OsdpEvent) -> i32,
-{
-    trampoline::<F>
+fn deliver(callbacks: &EventCallbacks, pd: i32, event: OsdpEvent) -> i32 {
+    let identities = callbacks.identities.borrow();
+    let (name, address) = &identities[pd as usize];
+    let context = EventContext {
+        pd,
+        name,
+        address: *address,
+    };
+    let subscriber = callbacks.kind(event.kind());
+    if let Some(rc) = subscriber.invoke(None, |callback| Some(callback(&context, event.clone()))) {
+        return rc;
+    }
+    if let Some(rc) = callbacks
+        .any
+        .invoke(None, |callback| Some(callback(&context, event.clone())))
+    {
+        return rc;
+    }
+    let mut unclaimed = callbacks.unclaimed.borrow_mut();
+    if unclaimed.len() == MAX_UNCLAIMED_EVENTS {
+        unclaimed.pop_front();
+    }
+    unclaimed.push_back((pd, event));
+    0
 }
 
 fn cp_setup(info: Vec<crate::OsdpPdInfoHandle>) -> Result<*mut c_void> {
@@ -74,17 +151,87 @@ fn cp_setup(info: Vec<crate::OsdpPdInfoHandle>) -> Result<*mut c_void> {
 /// Builder for creating a new `ControlPanel`.
 #[derive(Debug, Default)]
 pub struct ControlPanelBuilder {
+    name: Option<String>,
+    log_level: Option<LogLevel>,
+    history: usize,
+    default_flags: OsdpFlag,
     channel_pds: Vec<(Box<dyn Channel>, Vec<PdInfoBuilder>)>,
+    restored: Option<CpRuntimeState>,
+    #[cfg(feature = "std")]
+    offline_queue: Option<(usize, core::time::Duration)>,
 }
 
 impl ControlPanelBuilder {
     /// Create a new instance of [`ControlPanelBuilder`].
     pub const fn new() -> Self {
         Self {
+            name: None,
+            log_level: None,
+            history: 0,
+            default_flags: OsdpFlag::empty(),
             channel_pds: Vec::new(),
+            restored: None,
+            #[cfg(feature = "std")]
+            offline_queue: None,
         }
     }
 
+    /// Set a name for this CP instance. This is used to attribute log messages
+    /// to this instance. Defaults to "CP".
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the log level for this CP instance. This overrides the process
+    /// wide level set with [`crate::set_log_level`].
+    pub fn log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Keep a record of the last `len` commands sent to and events received
+    /// from each PD; see [`ControlPanel::recent_activity`]. This is disabled
+    /// (0) by default.
+    pub fn history(mut self, len: usize) -> Self {
+        self.history = len;
+        self
+    }
+
+    /// Set `flags` for every PD of this CP (for instance,
+    /// [`OsdpFlag::EnforceSecure`] fleet-wide). Individual PDs can opt out
+    /// with [`PdInfoBuilder::clear_flag`].
+    pub fn default_flags(mut self, flags: OsdpFlag) -> Self {
+        self.default_flags = flags;
+        self
+    }
+
+    /// Hold on to commands sent to PDs that are offline, instead of failing
+    /// with [`OsdpErrorKind::Offline`], and send them when the PD comes back
+    /// online. Up to `capacity` commands are kept per PD (older ones are
+    /// dropped to make room for new ones) and those that are older than
+    /// `ttl` when the PD comes back are dropped. This is disabled by default.
+    ///
+    /// This is meant for commands that are still useful late, such as LED
+    /// and text commands. Commands that were already queued by LibOSDP when
+    /// the PD went offline are lost.
+    #[cfg(feature = "std")]
+    pub fn offline_queue(mut self, capacity: usize, ttl: core::time::Duration) -> Self {
+        self.offline_queue = Some((capacity, ttl));
+        self
+    }
+
+    /// Set the PDs up with the address, baud rate and secure channel key they
+    /// had in `state` (exported by [`ControlPanel::export_state`] before a
+    /// restart) instead of those in their [`PdInfoBuilder`]. `state` must be
+    /// of the same PDs, added in the same order. PDs that were online are
+    /// not reported as going offline while the CP gets back in touch with
+    /// them; see [`crate::CpRuntimeState`].
+    pub fn restore_state(mut self, state: CpRuntimeState) -> Self {
+        self.restored = Some(state);
+        self
+    }
+
     /// Add a new PDs and their shared channel to the CP.
     pub fn add_channel(mut self, channel: Box<dyn Channel>, pd_info: Vec<PdInfoBuilder>) -> Self {
         self.channel_pds.push((channel, pd_info));
@@ -92,24 +239,129 @@ impl ControlPanelBuilder {
     }
 
     /// Build the [`ControlPanel`] instance.
-    pub fn build(self) -> Result<ControlPanel> {
+    pub fn build(mut self) -> Result<ControlPanel> {
         if self.channel_pds.len() > 126 {
             return Err(OsdpError::PdInfo("max PD count exceeded"));
         }
-        let info: Vec<crate::OsdpPdInfoHandle> = self
-            .channel_pds
-            .into_iter()
-            .map(|(channel, pd_info)| {
-                let channel: libosdp_sys::osdp_channel = channel.into();
-                pd_info
-                    .into_iter()
-                    .map(move |pd| pd.channel(channel).build().into())
-            })
-            .flatten()
-            .collect();
-        unsafe { libosdp_sys::osdp_set_log_callback(Some(log_handler)) };
+        let mut resume = Resume::default();
+        if let Some(state) = self.restored.take() {
+            let pds = self.channel_pds.iter_mut().flat_map(|(_, pds)| pds);
+            if pds.count() != state.pds.len() {
+                return Err(OsdpError::PdInfo("restored state is of other PDs"));
+            }
+            resume = Resume::new(&state, RESUME_GRACE);
+            let pds = self.channel_pds.iter_mut().flat_map(|(_, pds)| pds);
+            for (pd, restored) in pds.zip(state.pds) {
+                *pd = restored.restore(core::mem::take(pd))?;
+            }
+        }
+        let default_flags = self.default_flags;
+        for (_, pd_info) in &mut self.channel_pds {
+            *pd_info = core::mem::take(pd_info)
+                .into_iter()
+                .map(|pd| pd.default_flags(default_flags))
+                .collect();
+        }
+        for (_, pd_info) in &self.channel_pds {
+            for pd in pd_info {
+                pd.validate()?;
+            }
+        }
+        let num_pd = self.channel_pds.iter().map(|(_, pds)| pds.len()).sum();
+        let pending = Box::new(PendingCommands::new(num_pd));
+        #[cfg(feature = "std")]
+        let capture = crate::capture::CaptureHandle::default();
+        let mut info: Vec<crate::OsdpPdInfoHandle> = Vec::with_capacity(num_pd);
+        let mut channels = Vec::with_capacity(self.channel_pds.len());
+        let mut pd_channels = Vec::with_capacity(num_pd);
+        let mut expected_ids = Vec::with_capacity(num_pd);
+        let mut policies = Vec::with_capacity(num_pd);
+        let mut timings = Vec::with_capacity(num_pd);
+        let mut retained = Vec::with_capacity(num_pd);
+        for (channel, pd_info) in self.channel_pds {
+            expected_ids.extend(pd_info.iter().map(|pd| pd.expected_pd_id()));
+            policies.extend(pd_info.iter().map(|pd| pd.integrity_policy()));
+            timings.extend(pd_info.iter().map(|pd| pd.timing()));
+            let pd_info: Vec<PdInfo> = pd_info.into_iter().map(|pd| pd.build()).collect();
+            let pds = (info.len()..info.len() + pd_info.len()).collect();
+            for (i, pd) in pd_info.iter().enumerate() {
+                pending.set_address(info.len() + i, pd.address() as u8);
+                pending.set_policy(info.len() + i, policies[info.len() + i]);
+                pending.set_timing(info.len() + i, timings[info.len() + i]);
+                pd_channels.push(channels.len());
+            }
+            let tap = CommandTap::new(channel, pds, &*pending);
+            #[cfg(feature = "std")]
+            let tap = tap.with_capture(capture.clone());
+            let channel: Box<dyn Channel> = Box::new(tap);
+            let channel: libosdp_sys::osdp_channel = channel.into();
+            channels.push(ChannelHandle::new(&channel));
+            for mut pd in pd_info {
+                pd.set_channel(channel);
+                retained.push(pd.clone());
+                info.push(pd.into());
+            }
+        }
+        let mut log = LogContext::new(self.name.unwrap_or_else(|| "CP".into()));
+        log.set_level(self.log_level);
+        let ctx = {
+            let _scope = log.enter();
+            cp_setup(info)
+        };
+        let ctx = match ctx {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                for channel in channels {
+                    drop(unsafe { channel.into_channel() });
+                }
+                return Err(e);
+            }
+        };
+        let identities = retained.iter().map(|pd| (pd.name(), pd.address()));
+        let event_callbacks =
+            EventCallbacks::new(History::new(num_pd, self.history), identities.collect());
+        unsafe {
+            libosdp_sys::osdp_cp_set_event_callback(
+                ctx,
+                Some(trampoline),
+                &*event_callbacks as *const EventCallbacks as *mut c_void,
+            );
+        }
         Ok(ControlPanel {
-            ctx: cp_setup(info)?,
+            ctx,
+            log,
+            num_pd: num_pd as i32,
+            pending,
+            channels,
+            pd_channels,
+            comsets: Vec::new(),
+            keysets: Vec::new(),
+            pd_info: retained,
+            file_ops: Vec::new(),
+            event_callbacks,
+            online: PdBitSet::default(),
+            sc_status: resume.update_sc_active(PdBitSet::default()),
+            resume,
+            sc_status_callback: Callback::new(),
+            id_mismatches: alloc::vec![None; expected_ids.len()],
+            expected_ids,
+            id_mismatch_callback: Callback::new(),
+            event_handler: Callback::new(),
+            delivering: false,
+            #[cfg(feature = "std")]
+            capture,
+            #[cfg(feature = "std")]
+            last_refresh: std::time::Instant::now(),
+            #[cfg(feature = "std")]
+            scheduler: Default::default(),
+            #[cfg(feature = "std")]
+            retries: crate::retry::Retries::new(num_pd),
+            #[cfg(feature = "std")]
+            command_failed_callback: Callback::new(),
+            #[cfg(feature = "std")]
+            offline_queue: self
+                .offline_queue
+                .map(|(capacity, ttl)| crate::offline::OfflineQueue::new(num_pd, capacity, ttl)),
         })
     }
 }
@@ -118,6 +370,51 @@ impl ControlPanelBuilder {
 #[derive(Debug)]
 pub struct ControlPanel {
     ctx: *mut core::ffi::c_void,
+    log: Box<LogContext>,
+    num_pd: i32,
+    pending: Box<PendingCommands>,
+    channels: Vec<ChannelHandle>,
+    /// Offset (in `channels`) of the channel of each PD
+    pd_channels: Vec<usize>,
+    /// COMSETs that the PD is yet to reply to, by PD and ticket
+    comsets: Vec<(i32, usize, OsdpComSet)>,
+    /// KEYSETs that the PD is yet to reply to, by PD and ticket
+    keysets: Vec<(i32, usize, [u8; 16])>,
+    /// The PDs as they were set up, kept up to date with the COMSETs and
+    /// KEYSETs they accepted (and flags changed since), to set LibOSDP up
+    /// with again; see [`ControlPanel::set_secure_channel_key`]
+    pd_info: Vec<PdInfo>,
+    /// File operations registered for PDs, for the same reason
+    file_ops: Vec<(i32, libosdp_sys::osdp_file_ops)>,
+    event_callbacks: Box<EventCallbacks>,
+    /// Online status as of the last refresh
+    online: PdBitSet,
+    /// Secure channel status as last reported to the application
+    sc_status: PdBitSet,
+    /// PDs taken to be online, or to have a secure channel session, since
+    /// the state this CP was restored from was exported
+    resume: Resume,
+    sc_status_callback: Callback<ScStatusCallback>,
+    /// Identity each PD is expected to report, if any
+    expected_ids: Vec<Option<PdId>>,
+    /// Identity reported by PDs that did not match the expected one
+    id_mismatches: Vec<Option<PdId>>,
+    id_mismatch_callback: Callback<IdMismatchCallback>,
+    event_handler: Callback<EventHandler>,
+    /// Set while deferred events are being delivered
+    delivering: bool,
+    #[cfg(feature = "std")]
+    capture: crate::capture::CaptureHandle,
+    #[cfg(feature = "std")]
+    last_refresh: std::time::Instant,
+    #[cfg(feature = "std")]
+    scheduler: crate::schedule::Scheduler,
+    #[cfg(feature = "std")]
+    retries: crate::retry::Retries,
+    #[cfg(feature = "std")]
+    command_failed_callback: Callback<CommandFailedCallback>,
+    #[cfg(feature = "std")]
+    offline_queue: Option<crate::offline::OfflineQueue>,
 }
 
 unsafe impl Send for ControlPanel {}
@@ -127,34 +424,763 @@ impl ControlPanel {
     /// underlying LibOSDP state. To meet the OSDP timing guarantees, this
     /// function must be called at least once every 50ms. This method does not
     /// block and returns early if there is nothing to be done.
-    pub fn refresh(&mut self) {
-        unsafe { libosdp_sys::osdp_cp_refresh(self.ctx) }
+    ///
+    /// Returns a summary of what happened; applications can, for instance,
+    /// sleep for longer when [`RefreshReport::is_idle`].
+    pub fn refresh(&mut self) -> RefreshReport {
+        let _scope = self.log.enter();
+        #[cfg(feature = "std")]
+        {
+            self.last_refresh = std::time::Instant::now();
+        }
+        unsafe { libosdp_sys::osdp_cp_refresh(self.ctx) };
+        let baud_rate_failed = self.apply_comsets();
+        self.apply_keysets();
+        let online_mask = self.online_mask();
+        let (resumed, not_resumed) = self.resume.update_online(online_mask);
+        let came_online = online_mask.difference(&self.online);
+        let traffic = self.pending.take_traffic();
+        let report = RefreshReport {
+            bytes_read: traffic.bytes_read,
+            bytes_written: traffic.bytes_written,
+            commands_sent: traffic.commands_sent,
+            events_received: self.event_callbacks.received.take(),
+            came_online: came_online.difference(&resumed),
+            went_offline: self.online.difference(&online_mask).union(&not_resumed),
+            baud_rate_failed,
+        };
+        self.online = online_mask;
+        self.verify_pd_ids(came_online);
+        #[cfg(feature = "std")]
+        self.replay_offline_queue(online_mask);
+        #[cfg(feature = "std")]
+        self.run_retries(online_mask);
+        #[cfg(feature = "std")]
+        self.run_schedule(online_mask);
+        #[cfg(feature = "metrics")]
+        let (reported_online, sc_active_mask) = (
+            online_mask.union(&self.resume.online()),
+            self.sc_active_mask().union(&self.resume.sc_active()),
+        );
+        for pd in 0..self.num_pd {
+            let online = online_mask.contains(pd);
+            self.pending.set_online(pd as usize, online);
+            if !online {
+                self.pending.flush(pd as usize);
+            }
+            #[cfg(feature = "metrics")]
+            {
+                let address = self.pd_info[pd as usize].address();
+                crate::telemetry::record_pd_status(
+                    address,
+                    reported_online.contains(pd),
+                    sc_active_mask.contains(pd),
+                );
+                crate::telemetry::record_pending_commands(
+                    address,
+                    self.pending.pending(pd as usize),
+                    self.pending.high_water(pd as usize),
+                );
+            }
+        }
+        self.notify_sc_status();
+        self.deliver_events();
+        report
+    }
+
+    /// How long the application can wait before the next call to
+    /// [`ControlPanel::refresh`]: until a scheduled command or a retry is due
+    /// for a PD that is online, and no later than LibOSDP's next poll (its
+    /// poll interval, `OSDP_PD_POLL_TIMEOUT_MS`, after the last refresh).
+    /// LibOSDP does not expose its own timers, so this can't be any later.
+    /// Commands for PDs that are offline wait until they come back, which a
+    /// refresh reports.
+    ///
+    /// Replies from PDs can come in earlier; pass this to
+    /// [`ControlPanel::wait_readable`] to be woken up for those:
+    ///
+    /// ```ignore
+    /// loop {
+    ///     cp.refresh();
+    ///     cp.wait_readable(cp.time_until_next_work());
+    /// }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn time_until_next_work(&self) -> core::time::Duration {
+        let deadline = [
+            self.scheduler.next_due(self.online),
+            self.retries.next_due(self.online),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(self.last_refresh + MAX_REFRESH_INTERVAL, Ord::min);
+        deadline.saturating_duration_since(std::time::Instant::now())
+    }
+
+    /// Deliver events held back for the event handler. Events that arrive
+    /// while the handler runs (if it refreshes this CP) are delivered after
+    /// it returns, in order.
+    fn deliver_events(&mut self) {
+        if self.delivering {
+            return;
+        }
+        self.delivering = true;
+        loop {
+            let next = self.event_callbacks.deferred.borrow_mut().pop_front();
+            let Some((pd, event)) = next else {
+                break;
+            };
+            let mut event = Some(event);
+            // Hold on to the slot for the call; the handler is given `self`
+            // and may replace or drop anything `self` owns, the slot included.
+            let handler = self.event_handler.clone();
+            catch_panic("event handler", (), || {
+                handler.invoke((), |handler| handler(self, pd, event.take().unwrap()))
+            });
+            // Without a handler (anymore), fall back to the event callbacks
+            if let Some(event) = event {
+                deliver(&self.event_callbacks, pd, event);
+            }
+        }
+        // Once the handler is gone, events go straight to the callbacks again
+        self.event_callbacks
+            .defer
+            .set(self.event_handler.is_registered());
+        self.delivering = false;
+    }
+
+    /// Send scheduled commands that are due.
+    #[cfg(feature = "std")]
+    fn run_schedule(&mut self, online_mask: PdBitSet) {
+        let mut scheduler = core::mem::take(&mut self.scheduler);
+        scheduler.run(std::time::Instant::now(), online_mask, |pd, cmd| {
+            self.queue_command(pd, cmd.clone()).is_ok()
+        });
+        self.scheduler = scheduler;
+    }
+
+    /// Send commands held back for PDs that came online.
+    #[cfg(feature = "std")]
+    fn replay_offline_queue(&mut self, online_mask: PdBitSet) {
+        let Some(mut queue) = self.offline_queue.take() else {
+            return;
+        };
+        queue.replay(std::time::Instant::now(), online_mask, |pd, cmd| {
+            self.queue_command(pd, cmd.clone()).is_ok()
+        });
+        self.offline_queue = Some(queue);
+    }
+
+    /// Number of commands held back for a PD, identified by the offset number
+    /// (in the order PDs were added to [`ControlPanelBuilder`]), until it
+    /// comes online; see [`ControlPanelBuilder::offline_queue`].
+    #[cfg(feature = "std")]
+    pub fn offline_commands(&self, pd: i32) -> usize {
+        self.offline_queue.as_ref().map_or(0, |q| q.len(pd))
+    }
+
+    /// Follow up on commands sent to PDs that have a retry policy.
+    #[cfg(feature = "std")]
+    fn run_retries(&mut self, online_mask: PdBitSet) {
+        let now = std::time::Instant::now();
+        let mut retries = core::mem::take(&mut self.retries);
+        retries.resolve(
+            now,
+            |pd, ticket| self.pending.outcome(pd as usize, ticket),
+            |pd, cmd, kind| {
+                self.command_failed_callback
+                    .invoke((), |callback| callback(pd, cmd, kind))
+            },
+        );
+        retries.resend(now, online_mask, |pd, cmd| {
+            let ticket = self.enqueue_command(pd, cmd.clone()).ok()??;
+            self.pending.watch(pd as usize, ticket);
+            Some(ticket)
+        });
+        self.retries = retries;
+    }
+
+    /// Set how commands to a PD, identified by the offset number (in the
+    /// order PDs were added to [`ControlPanelBuilder`]), that time out or are
+    /// NAK'd are sent again; `None` (the default) leaves it to LibOSDP, which
+    /// retransmits a command a few times before taking the PD offline and
+    /// never resends a NAK'd one.
+    ///
+    /// Retries are sent from [`ControlPanel::refresh`], once the PD is online.
+    /// Commands that fail for good are passed to the closure set with
+    /// [`ControlPanel::set_command_failed_callback`]. File transfers are not
+    /// retried.
+    #[cfg(feature = "std")]
+    pub fn set_retry_policy(&mut self, pd: i32, policy: Option<crate::RetryPolicy>) -> Result<()> {
+        self.check_pd(pd)?;
+        self.retries.set_policy(pd, policy);
+        Ok(())
+    }
+
+    /// Set a closure that gets called with `(pd, command, error)` when a
+    /// command to a PD that has a retry policy (see
+    /// [`ControlPanel::set_retry_policy`]) has failed and won't be sent
+    /// again. `error` is [`crate::PdErrorKind::Timeout`] if the PD went
+    /// offline before replying. This replaces (and drops) the previously set
+    /// closure, if any.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    #[cfg(feature = "std")]
+    pub fn set_command_failed_callback<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(i32, OsdpCommand, crate::PdErrorKind) + Send + 'static,
+    {
+        self.command_failed_callback.set(Box::new(closure))
+    }
+
+    /// Send `cmd` to a PD, identified by the offset number (in the order PDs
+    /// were added to [`ControlPanelBuilder`]), now and every `interval` after
+    /// that, for as long as the returned guard is kept around. The command is
+    /// sent from [`ControlPanel::refresh`] and only while the PD is online; if
+    /// the PD was offline (or its command queue was full) when the command
+    /// was due, it is sent as soon as possible.
+    ///
+    /// Returns [`OsdpError::Command`] for a zero `interval` and for file
+    /// transfers, which can't be repeated.
+    #[cfg(feature = "std")]
+    pub fn schedule(
+        &mut self,
+        pd: i32,
+        cmd: OsdpCommand,
+        interval: core::time::Duration,
+    ) -> Result<crate::ScheduleGuard> {
+        self.check_pd(pd)?;
+        if interval.is_zero() || matches!(cmd, OsdpCommand::FileTx(_)) {
+            return Err(OsdpError::Command);
+        }
+        Ok(self
+            .scheduler
+            .add(pd, cmd, interval, std::time::Instant::now()))
+    }
+
+    /// LibOSDP switches to the new address/baud rate of a PD when it replies
+    /// to a COMSET; follow it so that commands to the PD can still be tracked
+    /// and the channel talks at the right speed. Returns the PDs whose channel
+    /// could not be switched to their new baud rate.
+    fn apply_comsets(&mut self) -> PdBitSet {
+        let mut failed = [0; 16];
+        let mut i = 0;
+        while i < self.comsets.len() {
+            let (pd, ticket, comset) = self.comsets[i];
+            match self.pending.outcome(pd as usize, ticket) {
+                None => i += 1,
+                Some(outcome) => {
+                    self.comsets.swap_remove(i);
+                    if outcome == Outcome::Ack && self.apply_comset(pd, comset).is_err() {
+                        failed[(pd / 8) as usize] |= 1 << (pd % 8);
+                    }
+                }
+            }
+        }
+        PdBitSet::new(failed, self.num_pd)
+    }
+
+    fn apply_keysets(&mut self) {
+        let mut i = 0;
+        while i < self.keysets.len() {
+            let (pd, ticket, key) = self.keysets[i];
+            match self.pending.outcome(pd as usize, ticket) {
+                None => i += 1,
+                Some(outcome) => {
+                    self.keysets.swap_remove(i);
+                    if outcome == Outcome::Ack {
+                        self.pd_info[pd as usize].set_secure_channel_key(key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply_comset(&mut self, pd: i32, comset: OsdpComSet) -> Result<()> {
+        self.pending.set_address(pd as usize, comset.address);
+        self.event_callbacks.identities.borrow_mut()[pd as usize].1 = comset.address as i32;
+        let info = &mut self.pd_info[pd as usize];
+        info.set_address(comset.address as i32);
+        info.set_baud_rate(comset.baud_rate as i32);
+        let channel = &mut self.channels[self.pd_channels[pd as usize]];
+        if let Some(channel) = unsafe { channel.get() }.as_reconfigurable() {
+            if let Err(e) = channel.set_baud_rate(comset.baud_rate) {
+                #[cfg(any(feature = "log", feature = "defmt-03"))]
+                error!(
+                    "Failed to switch PD-{} channel to {} baud: {:?}",
+                    pd, comset.baud_rate, e
+                );
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the identity of PDs that just came online against the one they
+    /// are expected to have. LibOSDP reads it (with `osdp_ID`) before taking
+    /// a PD online.
+    fn verify_pd_ids(&mut self, came_online: PdBitSet) {
+        for pd in 0..self.num_pd {
+            let Some(expected) = self.expected_ids[pd as usize] else {
+                continue;
+            };
+            if !came_online.contains(pd) {
+                continue;
+            }
+            let Ok(reported) = self.get_pd_id(pd) else {
+                continue;
+            };
+            if expected.same_device(&reported) {
+                self.id_mismatches[pd as usize] = None;
+            } else {
+                self.id_mismatches[pd as usize] = Some(reported);
+                self.id_mismatch_callback
+                    .invoke((), |callback| callback(pd, expected, reported));
+            }
+        }
+    }
+
+    fn check_pd_id(&self, pd: i32) -> Result<()> {
+        match (
+            self.expected_ids[pd as usize],
+            self.id_mismatches[pd as usize],
+        ) {
+            (Some(expected), Some(reported)) => Err(OsdpError::IdMismatch {
+                pd,
+                expected,
+                reported,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn notify_sc_status(&mut self) {
+        let sc_status = self.resume.update_sc_active(self.sc_active_mask());
+        if sc_status == self.sc_status {
+            return;
+        }
+        let prev = core::mem::replace(&mut self.sc_status, sc_status);
+        for pd in 0..self.num_pd {
+            let active = sc_status.contains(pd);
+            if prev.contains(pd) != active {
+                self.sc_status_callback
+                    .invoke((), |callback| callback(pd, active));
+            }
+        }
+    }
+
+    /// Block until a PD has sent something to this CP or `timeout` elapses;
+    /// returns false on timeout. Applications can call this instead of
+    /// sleeping between two calls to [`ControlPanel::refresh`] to process
+    /// replies as soon as they arrive. See [`Channel::wait_readable`].
+    ///
+    /// This can only wait on a single channel; a CP with more than one
+    /// channel sleeps for `timeout` (or returns immediately without `std`).
+    pub fn wait_readable(&mut self, timeout: core::time::Duration) -> bool {
+        match self.channels.as_mut_slice() {
+            [channel] => unsafe { channel.get() }
+                .wait_readable(timeout)
+                .unwrap_or(true),
+            _ => {
+                #[cfg(feature = "std")]
+                std::thread::sleep(timeout);
+                true
+            }
+        }
     }
 
     /// Send [`OsdpCommand`] to a PD identified by the offset number (in PdInfo
     /// vector in [`ControlPanel::new`]).
+    ///
+    /// Returns [`OsdpError::InvalidPd`] if there is no such PD,
+    /// [`OsdpError::IdMismatch`] if the PD is not the one it was expected to
+    /// be and [`OsdpError::Refused`] if LibOSDP did not take the command; see
+    /// [`OsdpError::kind`] to tell a PD that is offline from a full queue.
+    /// With [`ControlPanelBuilder::offline_queue`], commands (other than file
+    /// transfers) to a PD that is offline are held back and this returns
+    /// `Ok(())`.
     pub fn send_command(&mut self, pd: i32, cmd: OsdpCommand) -> Result<()> {
+        #[cfg(feature = "std")]
+        if self.offline_queue.is_some()
+            && !matches!(cmd, OsdpCommand::FileTx(_))
+            && matches!(self.is_online(pd), Ok(false))
+        {
+            if let Some(queue) = self.offline_queue.as_mut() {
+                queue.push(pd, cmd, std::time::Instant::now());
+            }
+            return Ok(());
+        }
+        self.queue_command(pd, cmd).map(|_| ())
+    }
+
+    /// Send [`OsdpCommand`] to the PD at `address`; see
+    /// [`ControlPanel::index_of_address`] and [`ControlPanel::send_command`].
+    /// Returns [`OsdpError::InvalidAddress`] if there is no (single) PD at
+    /// `address`.
+    pub fn send_command_to_address(&mut self, address: i32, cmd: OsdpCommand) -> Result<()> {
+        let pd = self.index_of_address(address)?;
+        self.send_command(pd, cmd)
+    }
+
+    /// Like [`ControlPanel::send_command`] but returns a ticket that can be
+    /// passed to [`ControlPanel::command_outcome`] to find out how the PD
+    /// responded. File transfers are initiated immediately and have no ticket.
+    #[cfg(feature = "std")]
+    pub(crate) fn send_command_tracked(
+        &mut self,
+        pd: i32,
+        cmd: OsdpCommand,
+    ) -> Result<Option<usize>> {
+        let ticket = self.queue_command(pd, cmd)?;
+        if let Some(ticket) = ticket {
+            self.pending.watch(pd as usize, ticket);
+        }
+        Ok(ticket)
+    }
+
+    /// Outcome of a command sent with [`ControlPanel::send_command_tracked`],
+    /// once the PD has replied to it (or has gone offline).
+    #[cfg(feature = "std")]
+    pub(crate) fn command_outcome(&self, pd: i32, ticket: usize) -> Option<Outcome> {
+        self.pending.outcome(pd as usize, ticket)
+    }
+
+    /// Send an [`OsdpCommand`] to a PD and refresh this CP until the PD
+    /// responds to it or `timeout` elapses. This is the single threaded
+    /// counterpart of [`crate::Commander::send_command_sync`], for
+    /// applications (and scripts) that do one thing at a time.
+    ///
+    /// Returns [`OsdpError::Nak`] (with the NAK reason code) if the PD
+    /// rejected the command, [`OsdpError::Timeout`] if it did not respond in
+    /// time and [`OsdpError::Command`] if the PD went offline before
+    /// responding. Errors of [`ControlPanel::send_command`] are returned as
+    /// is. File transfer commands return as soon as the transfer is
+    /// initiated.
+    #[cfg(feature = "std")]
+    pub fn send_command_sync(
+        &mut self,
+        pd: i32,
+        cmd: OsdpCommand,
+        timeout: core::time::Duration,
+    ) -> Result<()> {
+        let Some(ticket) = self.send_command_tracked(pd, cmd)? else {
+            return Ok(());
+        };
+        self.refresh_until(pd, timeout, |cp, pd| match cp.command_outcome(pd, ticket) {
+            Some(Outcome::Ack) => Ok(true),
+            Some(Outcome::Nak(reason)) => Err(OsdpError::Nak(reason)),
+            Some(Outcome::Dropped) => Err(OsdpError::Command),
+            None => Ok(false),
+        })
+    }
+
+    /// Refresh the CP until `done` returns true for `pd` or `timeout`
+    /// elapses.
+    #[cfg(feature = "std")]
+    pub(crate) fn refresh_until<F>(
+        &mut self,
+        pd: i32,
+        timeout: core::time::Duration,
+        mut done: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&ControlPanel, i32) -> Result<bool>,
+    {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            self.refresh();
+            if done(self, pd)? {
+                return Ok(());
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Err(OsdpError::Timeout);
+            }
+            self.wait_readable((deadline - now).min(core::time::Duration::from_millis(50)));
+        }
+    }
+
+    fn queue_command(&mut self, pd: i32, cmd: OsdpCommand) -> Result<Option<usize>> {
+        #[cfg(feature = "std")]
+        let retry = self.retries.is_enabled(pd).then(|| cmd.clone());
+        let ticket = self.enqueue_command(pd, cmd)?;
+        #[cfg(feature = "std")]
+        if let (Some(cmd), Some(ticket)) = (retry, ticket) {
+            self.pending.watch(pd as usize, ticket);
+            self.retries.track(pd, cmd, ticket);
+        }
+        Ok(ticket)
+    }
+
+    fn enqueue_command(&mut self, pd: i32, cmd: OsdpCommand) -> Result<Option<usize>> {
+        self.check_pd(pd)?;
+        self.check_pd_id(pd)?;
+        let _scope = self.log.enter();
+        // File transfers are initiated immediately; they are not queued.
+        let queued = !matches!(cmd, OsdpCommand::FileTx(_));
+        let comset = match cmd {
+            OsdpCommand::ComSet(comset) => Some(comset),
+            _ => None,
+        };
+        let keyset = match &cmd {
+            OsdpCommand::KeySet(keyset) if keyset.key_type == 1 => {
+                keyset.data.as_slice().try_into().ok()
+            }
+            _ => None,
+        };
+        let history = &self.event_callbacks.history;
+        let record = history.borrow().is_enabled().then(|| cmd.clone());
         let rc = unsafe { libosdp_sys::osdp_cp_send_command(self.ctx, pd, &cmd.into()) };
         if rc < 0 {
-            Err(OsdpError::Command)
-        } else {
-            Ok(())
+            // LibOSDP doesn't say why; find out from what it would have
+            // checked. The command itself is valid by construction, so the
+            // rest is down to running out of command slots.
+            let kind = if !self.online_mask().contains(pd) {
+                OsdpErrorKind::Offline
+            } else if keyset.is_some() && !self.sc_active_mask().contains(pd) {
+                OsdpErrorKind::NotPermitted
+            } else {
+                OsdpErrorKind::QueueFull
+            };
+            return Err(OsdpError::Refused { kind, rc });
+        }
+        if let Some(cmd) = record {
+            history.borrow_mut().record(pd, Activity::Command(cmd));
+        }
+        if !queued {
+            return Ok(None);
+        }
+        let ticket = self.pending.enqueued(pd as usize);
+        if let Some(comset) = comset {
+            self.pending.watch(pd as usize, ticket);
+            self.comsets.push((pd, ticket, comset));
+        }
+        if let Some(key) = keyset {
+            self.pending.watch(pd as usize, ticket);
+            self.keysets.push((pd, ticket, key));
         }
+        Ok(Some(ticket))
+    }
+
+    /// Move a PD, identified by the offset number (in the order PDs were
+    /// added to [`ControlPanelBuilder`]), to a new address and baud rate.
+    ///
+    /// This sends a COMSET command and refreshes this CP until the PD replies
+    /// to it. Once the PD has accepted the new settings, this CP addresses it
+    /// at `address` and the PD's channel is switched to `baud_rate` (if it is
+    /// a [`crate::ReconfigurableChannel`]). The same happens when a COMSET is
+    /// sent with [`ControlPanel::send_command`], but without waiting for it.
+    ///
+    /// If the PD accepted the settings but its channel could not be switched
+    /// to `baud_rate`, this returns [`OsdpError::Channel`]; with
+    /// [`ControlPanel::send_command`], this is reported in
+    /// [`RefreshReport::baud_rate_failed`].
+    ///
+    /// Note that all PDs on a multi-drop channel must use the same baud rate.
+    #[cfg(feature = "std")]
+    pub fn comset(&mut self, pd: i32, address: i32, baud_rate: i32) -> Result<()> {
+        // Same restrictions as those of PdInfoBuilder
+        PdInfoBuilder::new()
+            .address(address)?
+            .baud_rate(baud_rate)?;
+        let comset = OsdpComSet::new(address as u8, baud_rate as u32);
+        let ticket = self
+            .send_command_tracked(pd, OsdpCommand::ComSet(comset))?
+            .ok_or(OsdpError::Command)?;
+        let deadline = std::time::Instant::now() + COMSET_TIMEOUT;
+        loop {
+            let report = self.refresh();
+            match self.command_outcome(pd, ticket) {
+                Some(Outcome::Ack) if report.baud_rate_failed.contains(pd) => {
+                    return Err(crate::ChannelError::TransportError.into())
+                }
+                Some(Outcome::Ack) => return Ok(()),
+                Some(Outcome::Nak(reason)) => return Err(OsdpError::Nak(reason)),
+                Some(Outcome::Dropped) => return Err(OsdpError::Command),
+                None => {}
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Err(OsdpError::Timeout);
+            }
+            self.wait_readable((deadline - now).min(core::time::Duration::from_millis(50)));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn num_pd(&self) -> i32 {
+        self.num_pd
+    }
+
+    /// Commands sent to and events received from a PD, identified by the
+    /// offset number (in the order PDs were added to [`ControlPanelBuilder`]),
+    /// oldest first. Only the last few are kept, as configured with
+    /// [`ControlPanelBuilder::history`].
+    pub fn recent_activity(&self, pd: i32) -> Vec<ActivityRecord> {
+        self.event_callbacks.history.borrow().recent(pd)
+    }
+
+    /// Number of commands queued for a PD identified by the offset number (in
+    /// the order PDs were added to [`ControlPanelBuilder`]) that are yet to be
+    /// sent to it.
+    /// Applications can use this to apply backpressure instead of running
+    /// into [`OsdpErrorKind::QueueFull`] when the LibOSDP command queue is
+    /// full.
+    pub fn pending_commands(&self, pd: i32) -> usize {
+        self.pending.pending(pd as usize)
+    }
+
+    /// Largest value [`ControlPanel::pending_commands`] has reached for a PD
+    /// since this CP was created.
+    pub fn pending_commands_high_water(&self, pd: i32) -> usize {
+        self.pending.high_water(pd as usize)
+    }
+
+    /// The last error of a PD, identified by the offset number (in the order
+    /// PDs were added to [`ControlPanelBuilder`]): the last command it NAK'd
+    /// or did not reply to in time. This helps find out why a PD keeps going
+    /// offline.
+    pub fn last_error(&self, pd: i32) -> Result<Option<PdError>> {
+        self.check_pd(pd)?;
+        Ok(self.pending.last_error(pd as usize))
+    }
+
+    /// Round-trip times of the recent commands (POLLs included) sent to a PD,
+    /// identified by the offset number (in the order PDs were added to
+    /// [`ControlPanelBuilder`]); `None` if it has not replied yet. Watch these
+    /// for a PD (or a whole bus) getting slower to reply.
+    pub fn latency_stats(&self, pd: i32) -> Result<Option<crate::LatencyStats>> {
+        self.check_pd(pd)?;
+        Ok(self.pending.latency_stats(pd as usize))
+    }
+
+    /// The integrity check of the last reply of a PD, identified by the offset
+    /// number (in the order PDs were added to [`ControlPanelBuilder`]); `None`
+    /// if it has not replied yet. See [`crate::PdInfoBuilder::integrity`].
+    pub fn integrity(&self, pd: i32) -> Result<Option<crate::OsdpIntegrity>> {
+        self.check_pd(pd)?;
+        Ok(self.pending.integrity(pd as usize))
     }
 
     /// Set a closure that gets called when a PD sends an event to this CP.
-    pub fn set_event_callback<F>(&mut self, closure: F)
+    /// This replaces (and drops) the previously set closure, if any.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_event_callback<F>(&mut self, mut closure: F) -> CallbackGuard
     where
-        F: FnMut(i32, OsdpEvent) -> i32,
+        F: FnMut(i32, OsdpEvent) -> i32 + Send + 'static,
     {
-        unsafe {
-            let callback = get_trampoline(&closure);
-            libosdp_sys::osdp_cp_set_event_callback(
-                self.ctx,
-                Some(callback),
-                Box::into_raw(Box::new(closure)).cast(),
-            );
-        }
+        self.set_event_context_callback(move |context, event| closure(context.pd, event))
+    }
+
+    /// Same as [`ControlPanel::set_event_callback`], except that the closure
+    /// is given the name and address of the PD (see [`EventContext`]) along
+    /// with its offset. The two replace each other.
+    pub fn set_event_context_callback<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(&EventContext<'_>, OsdpEvent) -> i32 + Send + 'static,
+    {
+        self.event_callbacks.any.set(Box::new(closure))
+    }
+
+    /// Take the `(pd, event)` tuples of events that PDs sent to this CP since
+    /// the last call, oldest first. This is an alternative to registering a
+    /// closure for applications that would rather pick up events from their
+    /// main loop, after [`ControlPanel::refresh`].
+    ///
+    /// Only events that were not passed to a closure (see
+    /// [`ControlPanel::set_event_callback`], [`ControlPanel::subscribe`] and
+    /// [`ControlPanel::set_event_handler`]) are returned. Up to 64 events are
+    /// kept; older ones are dropped if this is not called often enough.
+    pub fn poll_events(&mut self) -> Vec<(i32, OsdpEvent)> {
+        self.event_callbacks
+            .unclaimed
+            .borrow_mut()
+            .drain(..)
+            .collect()
+    }
+
+    /// Set a closure that gets called with this CP when a PD sends an event
+    /// to it. Unlike [`ControlPanel::set_event_callback`], the closure can use
+    /// the CP; for instance, to light the LED of a reader that a card was
+    /// just read on.
+    ///
+    /// Events are held back until LibOSDP returns from
+    /// [`ControlPanel::refresh`] and delivered (in order) before `refresh`
+    /// returns. Once this is called, events go to this closure and not to
+    /// the ones set with [`ControlPanel::set_event_callback`] and
+    /// [`ControlPanel::subscribe`], which only get those that arrive when no
+    /// handler is registered. This replaces (and drops) the previously set
+    /// closure, if any.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_event_handler<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(&mut ControlPanel, i32, OsdpEvent) + Send + 'static,
+    {
+        self.event_callbacks.defer.set(true);
+        self.event_handler.set(Box::new(closure))
+    }
+
+    /// Set a closure that gets called for events of a given `kind`, instead
+    /// of the one set with [`ControlPanel::set_event_callback`]. This allows
+    /// handling, for instance, card reads and status reports in different
+    /// places. Each kind can have one subscriber; subscribing again replaces
+    /// (and drops) the previous closure.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn subscribe<F>(&mut self, kind: OsdpEventKind, mut closure: F) -> CallbackGuard
+    where
+        F: FnMut(i32, OsdpEvent) -> i32 + Send + 'static,
+    {
+        let closure = move |context: &EventContext<'_>, event| closure(context.pd, event);
+        self.event_callbacks.kind(kind).set(Box::new(closure))
+    }
+
+    /// Set a closure that gets called with `(pd, active)` when a secure channel
+    /// to a PD is established (`active` is true) or torn down (`active` is
+    /// false). Changes are detected in [`ControlPanel::refresh`]. This
+    /// replaces (and drops) the previously set closure, if any.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_sc_status_callback<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(i32, bool) + Send + 'static,
+    {
+        self.sc_status_callback.set(Box::new(closure))
+    }
+
+    /// Set a closure that gets called with `(pd, expected, reported)` when a
+    /// PD comes online with an identity other than the one set with
+    /// [`PdInfoBuilder::expected_id`]. Commands to such a PD fail with
+    /// [`OsdpError::IdMismatch`] until it comes online with the expected
+    /// identity again. This replaces (and drops) the previously set closure,
+    /// if any.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_id_mismatch_callback<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(i32, PdId, PdId) + Send + 'static,
+    {
+        self.id_mismatch_callback.set(Box::new(closure))
+    }
+
+    /// Route log messages of this CP (and the PDs it manages) to `sink`
+    /// instead of the `log`/`defmt` crate.
+    pub fn set_log_sink(&mut self, sink: impl LogSink + 'static) {
+        self.log.set_sink(Some(Box::new(sink)));
+    }
+
+    /// Change the log level of this CP instance at runtime. Passing `None`
+    /// makes it follow the process wide level (see [`crate::set_log_level`]).
+    pub fn set_log_level(&mut self, level: Option<LogLevel>) {
+        self.log.set_level(level);
     }
 
     /// Get the [`PdId`] from a PD identified by the offset number (in PdInfo
@@ -182,35 +1208,113 @@ impl ControlPanel {
         }
     }
 
+    /// Get all capabilities that a PD, identified by the offset number (in
+    /// PdInfo vector in [`ControlPanel::new`]), has reported. Capabilities
+    /// that the PD did not report (or reported with a zero compliance level
+    /// and item count) are left out.
+    pub fn get_capabilities(&self, pd: i32) -> Result<Vec<PdCapability>> {
+        PdCapability::iter()
+            .map(|cap| self.get_capability(pd, cap))
+            .filter(|cap| !matches!(cap, Ok(cap) if cap.entity() == PdCapEntity::default()))
+            .collect()
+    }
+
+    /// Largest packet, in bytes, that can be sent to a PD identified by the
+    /// offset number (in PdInfo vector in [`ControlPanel::new`]): the
+    /// [`PdCapability::ReceiveBufferSize`] it reported, capped to LibOSDP's
+    /// own packet buffer ([`crate::OSDP_MAX_PACKET_SIZE`]). PDs that have
+    /// not reported one (or have not come online yet) are assumed to take
+    /// [`crate::OSDP_MIN_PACKET_SIZE`] bytes.
+    ///
+    /// Payloads that are split over several packets by the application (such
+    /// as APDUs, see [`crate::TransparentSession::packet_size`]) can use this
+    /// to take as few packets as both ends allow.
+    pub fn max_packet_size(&self, pd: i32) -> Result<usize> {
+        let cap = self.get_capability(pd, PdCapability::ReceiveBufferSize(Default::default()))?;
+        let size = match cap.size() {
+            Some(size) if size as usize >= crate::OSDP_MIN_PACKET_SIZE => size as usize,
+            _ => crate::OSDP_MIN_PACKET_SIZE,
+        };
+        Ok(size.min(crate::OSDP_MAX_PACKET_SIZE))
+    }
+
     /// Set [`OsdpFlag`] for a PD identified by the offset number (in PdInfo
     /// vector in [`ControlPanel::new`]).
-    pub fn set_flag(&mut self, pd: i32, flags: OsdpFlag, value: bool) {
+    ///
+    /// Returns [`OsdpError::InvalidPd`] if there is no such PD and
+    /// [`OsdpError::PdInfo`] if LibOSDP doesn't allow `flags` to be changed
+    /// once the CP is set up.
+    pub fn set_flag(&mut self, pd: i32, flags: OsdpFlag, value: bool) -> Result<()> {
+        self.check_pd(pd)?;
+        let _scope = self.log.enter();
         let rc = unsafe { libosdp_sys::osdp_cp_modify_flag(self.ctx, pd, flags.bits(), value) };
         if rc < 0 {
-            // OsdpFlag should guarantee that we never fail here. If we did,
-            // it's probably best to panic here.
-            panic!("osdp_cp_modify_flag failed!")
+            return Err(OsdpError::PdInfo("flag can't be changed at runtime"));
         }
+        self.pd_info[pd as usize].set_flag(flags, value);
+        Ok(())
+    }
+
+    /// The set of PDs that are online.
+    pub fn online_mask(&self) -> PdBitSet {
+        let mut mask = [0; 16];
+        unsafe { libosdp_sys::osdp_get_status_mask(self.ctx, mask.as_mut_ptr()) };
+        PdBitSet::new(mask, self.num_pd)
+    }
+
+    /// The set of PDs that have an active secure channel session.
+    pub fn sc_active_mask(&self) -> PdBitSet {
+        let mut mask = [0; 16];
+        unsafe { libosdp_sys::osdp_get_sc_status_mask(self.ctx, mask.as_mut_ptr()) };
+        PdBitSet::new(mask, self.num_pd)
     }
 
     /// Check online status of a PD identified by the offset number (in PdInfo
-    /// vector in [`ControlPanel::new`]).
-    pub fn is_online(&self, pd: i32) -> bool {
-        let mut buf: [u8; 16] = [0; 16];
-        unsafe { libosdp_sys::osdp_get_status_mask(self.ctx, &mut buf as *mut u8) };
-        let pos = pd / 8;
-        let idx = pd % 8;
-        buf[pos as usize] & (1 << idx) != 0
+    /// vector in [`ControlPanel::new`]). Returns [`OsdpError::InvalidPd`] if
+    /// there is no such PD.
+    pub fn is_online(&self, pd: i32) -> Result<bool> {
+        self.check_pd(pd)?;
+        Ok(self.online_mask().contains(pd))
     }
 
     /// Check secure channel status of a PD identified by the offset number
-    /// (in PdInfo vector in [`ControlPanel::new`]).
-    pub fn is_sc_active(&self, pd: i32) -> bool {
-        let mut buf: [u8; 16] = [0; 16];
-        unsafe { libosdp_sys::osdp_get_sc_status_mask(self.ctx, &mut buf as *mut u8) };
-        let pos = pd / 8;
-        let idx = pd % 8;
-        buf[pos as usize] & (1 << idx) != 0
+    /// (in PdInfo vector in [`ControlPanel::new`]). Returns
+    /// [`OsdpError::InvalidPd`] if there is no such PD.
+    pub fn is_sc_active(&self, pd: i32) -> Result<bool> {
+        self.check_pd(pd)?;
+        Ok(self.sc_active_mask().contains(pd))
+    }
+
+    /// Address of a PD identified by the offset number (in the order PDs were
+    /// added to [`ControlPanelBuilder`]). This follows COMSETs. Returns
+    /// [`OsdpError::InvalidPd`] if there is no such PD.
+    pub fn address_of(&self, pd: i32) -> Result<i32> {
+        self.check_pd(pd)?;
+        Ok(self.pd_info[pd as usize].address())
+    }
+
+    /// Offset number (in the order PDs were added to [`ControlPanelBuilder`])
+    /// of the PD at `address`; the inverse of [`ControlPanel::address_of`].
+    /// Returns [`OsdpError::InvalidAddress`] if no PD is at `address`, or if
+    /// PDs on different channels are, as the address doesn't tell them apart.
+    pub fn index_of_address(&self, address: i32) -> Result<i32> {
+        let mut pds = self
+            .pd_info
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.address() == address);
+        match (pds.next(), pds.next()) {
+            (Some((pd, _)), None) => Ok(pd as i32),
+            _ => Err(OsdpError::InvalidAddress(address)),
+        }
+    }
+
+    fn check_pd(&self, pd: i32) -> Result<()> {
+        if (0..self.num_pd).contains(&pd) {
+            Ok(())
+        } else {
+            Err(OsdpError::InvalidPd(pd))
+        }
     }
 
     /// Get status of the ongoing file transfer of a PD, identified by the
@@ -234,9 +1338,157 @@ impl ControlPanel {
         }
     }
 
+    /// Start capturing the packets exchanged with all PDs to a pcap file at
+    /// `path` (which is created or truncated). This stops a capture that was
+    /// already running. Captures can be opened in Wireshark with the OSDP
+    /// dissector that comes with LibOSDP.
+    #[cfg(feature = "std")]
+    pub fn start_packet_capture<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        let capture = crate::capture::PacketCapture::create(path.as_ref())?;
+        if let Some(previous) = self.capture.start(capture) {
+            previous.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Stop the packet capture started with
+    /// [`ControlPanel::start_packet_capture`] and flush it to its file. This
+    /// does nothing if no capture is running.
+    #[cfg(feature = "std")]
+    pub fn stop_packet_capture(&mut self) -> Result<()> {
+        if let Some(capture) = self.capture.stop() {
+            capture.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Set a closure that gets called with every packet exchanged with the
+    /// PDs, as it is written to or read from their channel (so, from within
+    /// [`ControlPanel::refresh`]). This replaces (and drops) the previously
+    /// set closure, if any.
+    ///
+    /// Packets exchanged over a secure channel reach the closure encrypted;
+    /// see [`crate::WireFrame::packet`].
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_wire_frame_sink<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(&crate::WireFrame<'_>) + Send + 'static,
+    {
+        self.pending.wire_frame_sink.set(Box::new(closure))
+    }
+
+    /// Tear down this CP and hand back the channels it was built with, in the
+    /// order they were added to [`ControlPanelBuilder`], so that they can be
+    /// reused or closed. Dropping a CP closes its channels.
+    pub fn teardown(mut self) -> Vec<Box<dyn Channel>> {
+        self.teardown_context()
+    }
+
+    fn teardown_context(&mut self) -> Vec<Box<dyn Channel>> {
+        self.teardown_libosdp();
+        self.channels
+            .drain(..)
+            .map(|channel| unsafe { CommandTap::unwrap(channel.into_channel()) })
+            .collect()
+    }
+
+    /// Tear down the LibOSDP context, leaving the channels to be set up with
+    /// again.
+    fn teardown_libosdp(&mut self) {
+        if self.ctx.is_null() {
+            return;
+        }
+        {
+            let _scope = self.log.enter();
+            unsafe { libosdp_sys::osdp_cp_teardown(self.ctx) }
+        }
+        self.ctx = core::ptr::null_mut();
+    }
+
+    /// Runtime state of the PDs, to set up the next CP with after a restart
+    /// (see [`ControlPanelBuilder::restore_state`]). This includes secret
+    /// keys; see [`CpRuntimeState`].
+    pub fn export_state(&self) -> CpRuntimeState {
+        // PDs still being waited for after a restore are not known to be gone
+        let online = self.online_mask().union(&self.resume.online());
+        let sc_active = self.sc_active_mask().union(&self.resume.sc_active());
+        let pds = self
+            .pd_info
+            .iter()
+            .enumerate()
+            .map(|(pd, info)| {
+                PdRuntimeState::new(
+                    info,
+                    online.contains(pd as i32),
+                    sc_active.contains(pd as i32),
+                )
+            })
+            .collect();
+        CpRuntimeState { pds }
+    }
+
+    /// The secure channel base key the CP uses for a PD, as far as it knows
+    #[cfg(feature = "std")]
+    pub(crate) fn secure_channel_key(&self, pd: i32) -> Result<Option<[u8; 16]>> {
+        self.check_pd(pd)?;
+        Ok(self.pd_info[pd as usize].secure_channel_key())
+    }
+
+    /// Make the CP use `key` as the secure channel base key of a PD,
+    /// identified by the offset number (in the order PDs were added to
+    /// [`ControlPanelBuilder`]), from now on. This is for going back to a key
+    /// that the PD is known to (still) have, as when it did not take a KEYSET
+    /// that the CP thinks it did; the PD is not told about it (see
+    /// [`ControlPanel::rotate_key`] for that).
+    ///
+    /// LibOSDP only takes keys when it is set up, so this sets it up again:
+    /// all PDs of this CP go offline and through the secure channel set up
+    /// again, and the commands queued for them are dropped (and not retried).
+    /// Scheduled commands are sent again as soon as their PD is back.
+    /// Callbacks, file operations and commands held back for offline PDs
+    /// stay. If setting LibOSDP up fails, the CP carries on with the key it
+    /// had.
+    pub fn set_secure_channel_key(&mut self, pd: i32, key: [u8; 16]) -> Result<()> {
+        self.check_pd(pd)?;
+        let mut pd_info = self.pd_info.clone();
+        pd_info[pd as usize].set_secure_channel_key(key);
+        let info = pd_info.iter().cloned().map(Into::into).collect();
+        let ctx = {
+            let _scope = self.log.enter();
+            cp_setup(info)?
+        };
+        self.teardown_libosdp();
+        self.ctx = ctx;
+        self.pd_info = pd_info;
+        for pd in 0..self.num_pd as usize {
+            self.pending.flush(pd);
+        }
+        self.comsets.clear();
+        self.keysets.clear();
+        #[cfg(feature = "std")]
+        {
+            self.retries.clear();
+            self.scheduler.restart(std::time::Instant::now());
+        }
+        unsafe {
+            libosdp_sys::osdp_cp_set_event_callback(
+                self.ctx,
+                Some(trampoline),
+                &*self.event_callbacks as *const EventCallbacks as *mut c_void,
+            );
+            for (pd, fops) in &mut self.file_ops {
+                libosdp_sys::osdp_file_register_ops(self.ctx, *pd, fops);
+            }
+        }
+        Ok(())
+    }
+
     /// Register a file operations handler for a PD. See [`crate::OsdpFileOps`]
     /// trait documentation for more details.
     pub fn register_file_ops(&mut self, pd: i32, fops: Box<dyn OsdpFileOps>) -> Result<()> {
+        let _scope = self.log.enter();
         let mut fops: libosdp_sys::osdp_file_ops = fops.into();
         let rc = unsafe {
             libosdp_sys::osdp_file_register_ops(
@@ -248,6 +1500,8 @@ impl ControlPanel {
         if rc < 0 {
             Err(OsdpError::FileTransfer("ops register"))
         } else {
+            self.file_ops.retain(|(p, _)| *p != pd);
+            self.file_ops.push((pd, fops));
             Ok(())
         }
     }
@@ -255,6 +1509,6 @@ impl ControlPanel {
 
 impl Drop for ControlPanel {
     fn drop(&mut self) {
-        unsafe { libosdp_sys::osdp_cp_teardown(self.ctx) }
+        drop(self.teardown_context());
     }
 }
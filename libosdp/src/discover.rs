@@ -0,0 +1,254 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Finding the PDs on a bus whose addressing (and baud rate) is not known.
+//! Each address is sent an `osdp_ID` command, at each candidate baud rate,
+//! and PDs that answer in time are reported with the identity they sent
+//! back. Management software can then set up a [`crate::ControlPanel`] for
+//! them with [`DiscoveredPd::pd_info`] instead of having every reader
+//! configured by hand.
+//!
+//! Probing is done with raw packets (see [`crate::wire`]) rather than through
+//! LibOSDP so that an address that does not answer costs one timeout, and not
+//! LibOSDP's retry and back-off cycle. Even so, a full scan takes up to 127
+//! timeouts per baud rate.
+
+use crate::{
+    wire::{Packet, PacketDecoder, MARK},
+    Channel, ChannelError, ControlPanel, OsdpError, PdId, PdInfoBuilder,
+};
+use alloc::{vec, vec::Vec};
+use core::time::Duration;
+use std::time::Instant;
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// Baud rates that OSDP allows; [`Discovery`] tries all of them by default.
+pub const OSDP_BAUD_RATES: [u32; 6] = [9600, 19200, 38400, 57600, 115200, 230400];
+
+const CMD_ID: u8 = 0x61;
+const REPLY_PDID: u8 = 0x45;
+
+/// A PD that answered a [`Discovery`] probe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DiscoveredPd {
+    /// Address the PD answered at
+    pub address: i32,
+    /// Baud rate the PD answered at
+    pub baud_rate: u32,
+    /// Identity of the PD; `None` if it did not reply to `osdp_ID` with one
+    /// (some PDs NAK commands while they are, for instance, secure channel
+    /// only)
+    pub pd_id: Option<PdId>,
+    /// Code of the reply that the PD sent
+    pub reply_code: u8,
+}
+
+impl DiscoveredPd {
+    /// A [`PdInfoBuilder`] with the address and baud rate of this PD, to be
+    /// completed (with a name, key, etc.,) and added to a
+    /// [`crate::ControlPanelBuilder`].
+    pub fn pd_info(&self) -> Result<PdInfoBuilder> {
+        PdInfoBuilder::new()
+            .address(self.address)?
+            .baud_rate(self.baud_rate as i32)
+    }
+
+    /// Name of the reply that the PD sent (e.g. "PDID" or "NAK")
+    pub fn reply_name(&self) -> &'static str {
+        crate::wire::reply_name(self.reply_code).unwrap_or("UNKNOWN")
+    }
+}
+
+fn decode_pd_id(data: &[u8]) -> Option<PdId> {
+    let data: &[u8; 12] = data.get(..12)?.try_into().ok()?;
+    Some(PdId {
+        vendor_code: (data[0], data[1], data[2]),
+        model: data[3] as i32,
+        version: data[4] as i32,
+        serial_number: [data[8], data[7], data[6], data[5]],
+        firmware_version: (data[9], data[10], data[11]),
+    })
+}
+
+fn write_all(channel: &mut dyn Channel, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match channel.write(buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(ChannelError::WouldBlock) => std::thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(channel.flush()?)
+}
+
+/// Send a plain (non secure channel) command to `address` and wait up to
+/// `timeout` for its reply.
+pub(crate) fn transact(
+    channel: &mut dyn Channel,
+    address: u8,
+    sequence: u8,
+    code: u8,
+    data: Vec<u8>,
+    timeout: Duration,
+) -> Result<Option<Packet>> {
+    let packet = Packet {
+        address,
+        is_reply: false,
+        sequence,
+        use_crc: true,
+        sc_block: None,
+        code,
+        data,
+        mac: None,
+    };
+    exchange(channel, &packet.to_bytes(), address, timeout)
+}
+
+/// Send the packet in `bytes` (which need not be valid) and wait up to
+/// `timeout` for a reply from `address`.
+pub(crate) fn exchange(
+    channel: &mut dyn Channel,
+    bytes: &[u8],
+    address: u8,
+    timeout: Duration,
+) -> Result<Option<Packet>> {
+    let mut packet = vec![MARK];
+    packet.extend_from_slice(bytes);
+
+    // Drop whatever was left over from the previous probe
+    let mut buf = [0u8; 256];
+    while matches!(channel.read(&mut buf), Ok(n) if n > 0) {}
+    write_all(channel, &packet)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut decoder = PacketDecoder::new();
+    loop {
+        match channel.read(&mut buf) {
+            Ok(n) => decoder.push(&buf[..n]),
+            Err(ChannelError::WouldBlock) => {}
+            Err(e) => return Err(e.into()),
+        }
+        while let Some(reply) = decoder.next_packet() {
+            if reply.is_reply && reply.address == address {
+                return Ok(Some(reply));
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        _ = channel.wait_readable(deadline - now);
+    }
+}
+
+/// Builder for probing a bus for PDs; see [`ControlPanel::discover`] for the
+/// common case.
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    baud_rates: Vec<u32>,
+    addresses: Vec<i32>,
+    timeout: Duration,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Discovery {
+    /// Probe all addresses (0-126) at all [`OSDP_BAUD_RATES`], waiting 200ms
+    /// for each address to answer.
+    pub fn new() -> Self {
+        Self {
+            baud_rates: OSDP_BAUD_RATES.to_vec(),
+            addresses: (0..=126).collect(),
+            timeout: Duration::from_millis(200),
+        }
+    }
+
+    /// Probe at these baud rates, in this order. Switching baud rates needs a
+    /// channel that implements [`crate::ReconfigurableChannel`]; with a single
+    /// baud rate, the channel is expected to already be at it.
+    pub fn baud_rates(mut self, baud_rates: &[u32]) -> Self {
+        self.baud_rates = baud_rates.to_vec();
+        self
+    }
+
+    /// Probe only these addresses
+    pub fn addresses(mut self, addresses: impl IntoIterator<Item = i32>) -> Self {
+        self.addresses = addresses.into_iter().collect();
+        self
+    }
+
+    /// How long to wait for each address to answer
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Probe `channel`; `on_found` is called with each PD as soon as it is
+    /// found (scans take a while). Returns all PDs that were found, or
+    /// [`OsdpError::Channel`] if the channel failed (or can't switch between
+    /// the baud rates to probe).
+    pub fn run<F>(&self, channel: &mut dyn Channel, mut on_found: F) -> Result<Vec<DiscoveredPd>>
+    where
+        F: FnMut(&DiscoveredPd),
+    {
+        if let Some(address) = self.addresses.iter().find(|a| !(0..=126).contains(*a)) {
+            return Err(OsdpError::InvalidPd(*address));
+        }
+        let mut found = Vec::new();
+        for &baud_rate in &self.baud_rates {
+            if self.baud_rates.len() > 1 {
+                channel
+                    .as_reconfigurable()
+                    .ok_or(OsdpError::Channel(ChannelError::TransportError))?
+                    .set_baud_rate(baud_rate)?;
+            }
+            log::debug!("Discovery: probing at {baud_rate} baud");
+            for &address in &self.addresses {
+                let probe = transact(channel, address as u8, 0, CMD_ID, vec![0x00], self.timeout)?;
+                let Some(reply) = probe else {
+                    continue;
+                };
+                let pd = DiscoveredPd {
+                    address,
+                    baud_rate,
+                    pd_id: match reply.code {
+                        REPLY_PDID => decode_pd_id(&reply.data),
+                        _ => None,
+                    },
+                    reply_code: reply.code,
+                };
+                on_found(&pd);
+                found.push(pd);
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl ControlPanel {
+    /// Find the PDs on the bus behind `channel`, trying each of
+    /// `baud_candidates` in turn (all [`OSDP_BAUD_RATES`] if empty). This
+    /// probes every address and so takes a while; see [`Discovery`] to narrow
+    /// it down or to be told about PDs as they are found.
+    ///
+    /// The channel must not be in use by a CP meanwhile. Once done, it can be
+    /// passed to [`crate::ControlPanelBuilder::add_channel`] along with
+    /// [`DiscoveredPd::pd_info`] of the PDs that were found.
+    pub fn discover(
+        channel: &mut dyn Channel,
+        baud_candidates: &[u32],
+    ) -> Result<Vec<DiscoveredPd>> {
+        let mut discovery = Discovery::new();
+        if !baud_candidates.is_empty() {
+            discovery = discovery.baud_rates(baud_candidates);
+        }
+        discovery.run(channel, |_| {})
+    }
+}
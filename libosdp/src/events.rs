@@ -91,11 +91,19 @@ pub struct OsdpEventCardRead {
     /// field is set to zero.
     pub nr_bits: usize,
 
-    /// Card data; bytes or bits depending on [`OsdpCardFormats`]
+    /// Card data; bytes or bits depending on [`OsdpCardFormats`]. Raw card
+    /// data is packed MSB first, so the last byte is padded with zeros in its
+    /// low order bits when `nr_bits` is not a multiple of 8.
     pub data: Vec<u8>,
 }
 
 impl OsdpEventCardRead {
+    /// Largest card data, in bytes, that LibOSDP can carry in a card read
+    /// event; this is up to 512 bits of raw card data. Events with more data
+    /// than this can still be encoded and decoded with [`crate::wire`] but
+    /// [`crate::PeripheralDevice::notify_event`] rejects them.
+    pub const MAX_DATA_LEN: usize = libosdp_sys::OSDP_EVENT_CARDREAD_MAX_DATALEN as usize;
+
     /// Create an ASCII card read event for self and direction set to forward
     pub fn new_ascii(data: Vec<u8>) -> Self {
         Self {
@@ -120,6 +128,78 @@ impl OsdpEventCardRead {
             data,
         })
     }
+
+    /// Create a raw card read event of an unspecified format (such as the
+    /// CHUID of a PIV card) for self and direction set to forward
+    pub fn new_raw(nr_bits: usize, data: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            format: OsdpCardFormats::Unspecified,
+            ..Self::new_wiegand(nr_bits, data)?
+        })
+    }
+
+    /// Create a Wiegand card read event for a 26-bit (H10301) card: an even
+    /// parity bit, 8 bits of facility code, 16 bits of card number and an odd
+    /// parity bit.
+    pub fn from_wiegand26(facility: u8, card: u16) -> Self {
+        Self::from_wiegand((facility as u64) << 16 | card as u64, 24)
+    }
+
+    /// Create a Wiegand card read event for a 34-bit (H10306) card: an even
+    /// parity bit, 16 bits of facility code, 16 bits of card number and an
+    /// odd parity bit.
+    pub fn from_wiegand34(facility: u16, card: u16) -> Self {
+        Self::from_wiegand((facility as u64) << 16 | card as u64, 32)
+    }
+
+    /// Create a Wiegand card read event for a 37-bit (H10304) card: an even
+    /// parity bit, 16 bits of facility code, 19 bits of card number and an
+    /// odd parity bit. Returns [`OsdpError::Event`] if `card` does not fit in
+    /// 19 bits.
+    pub fn from_wiegand37(facility: u16, card: u32) -> Result<Self> {
+        if card >= 1 << 19 {
+            return Err(OsdpError::Event);
+        }
+        Ok(Self::from_wiegand(
+            (facility as u64) << 19 | card as u64,
+            35,
+        ))
+    }
+
+    /// Wrap `nr_bits` of `payload` in parity bits: the leading (even) one
+    /// covers the first half of the payload and the trailing (odd) one the
+    /// second half; they overlap by a bit when `nr_bits` is odd.
+    fn from_wiegand(payload: u64, nr_bits: usize) -> Self {
+        let half = nr_bits.div_ceil(2);
+        let even = (payload >> (nr_bits - half)).count_ones() as u64 % 2;
+        let odd = 1 - (payload & ((1 << half) - 1)).count_ones() as u64 % 2;
+        let bits = even << (nr_bits + 1) | payload << 1 | odd;
+        let nr_bits = nr_bits + 2;
+        let data = (bits << (64 - nr_bits)).to_be_bytes()[..nr_bits.div_ceil(8)].to_vec();
+        Self {
+            reader_no: 0,
+            format: OsdpCardFormats::Wiegand,
+            direction: false,
+            nr_bits,
+            data,
+        }
+    }
+
+    /// Number of bits of card data; for [`OsdpCardFormats::Ascii`], this is 8
+    /// bits per character.
+    pub fn bit_len(&self) -> usize {
+        match self.format {
+            OsdpCardFormats::Ascii => self.data.len() * 8,
+            _ => self.nr_bits,
+        }
+    }
+
+    /// Iterate over the bits of card data, MSB of the first byte first. Only
+    /// [`OsdpEventCardRead::bit_len`] bits are returned; padding bits are not.
+    pub fn bits(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.bit_len().min(self.data.len() * 8))
+            .map(|i| self.data[i / 8] & (0x80 >> (i % 8)) != 0)
+    }
 }
 
 impl From<libosdp_sys::osdp_event_cardread> for OsdpEventCardRead {
@@ -131,7 +211,7 @@ impl From<libosdp_sys::osdp_event_cardread> for OsdpEventCardRead {
             OsdpCardFormats::Ascii => (0, len),
             _ => (len, len.div_ceil(8)),
         };
-        let data = value.data[0..nr_bytes].to_vec();
+        let data = value.data[0..nr_bytes.min(value.data.len())].to_vec();
         OsdpEventCardRead {
             reader_no: value.reader_no,
             format,
@@ -142,21 +222,28 @@ impl From<libosdp_sys::osdp_event_cardread> for OsdpEventCardRead {
     }
 }
 
-impl From<OsdpEventCardRead> for libosdp_sys::osdp_event_cardread {
-    fn from(value: OsdpEventCardRead) -> Self {
+impl TryFrom<OsdpEventCardRead> for libosdp_sys::osdp_event_cardread {
+    type Error = OsdpError;
+
+    /// Fails with [`OsdpError::Event`] if the card data is longer than
+    /// [`OsdpEventCardRead::MAX_DATA_LEN`].
+    fn try_from(value: OsdpEventCardRead) -> Result<Self> {
+        if value.data.len() > OsdpEventCardRead::MAX_DATA_LEN {
+            return Err(OsdpError::Event);
+        }
         let mut data = [0; libosdp_sys::OSDP_EVENT_CARDREAD_MAX_DATALEN as usize];
         let length = match value.format {
             OsdpCardFormats::Ascii => value.data.len() as i32,
             _ => value.nr_bits as i32,
         };
         data[..value.data.len()].copy_from_slice(&value.data[..]);
-        libosdp_sys::osdp_event_cardread {
+        Ok(libosdp_sys::osdp_event_cardread {
             reader_no: value.reader_no,
             format: value.format.into(),
             direction: value.direction as i32,
             length,
             data,
-        }
+        })
     }
 }
 
@@ -311,9 +398,9 @@ impl From<OsdpStatusReportType> for libosdp_sys::osdp_status_report_type {
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct OsdpStatusReport {
-    type_: OsdpStatusReportType,
-    nr_entries: usize,
-    mask: u32,
+    pub(crate) type_: OsdpStatusReportType,
+    pub(crate) nr_entries: usize,
+    pub(crate) mask: u32,
 }
 
 impl OsdpStatusReport {
@@ -334,6 +421,34 @@ impl OsdpStatusReport {
             mask,
         }
     }
+
+    /// Create a local status event with given bit mask; bit 0 is the tamper
+    /// status and bit 1 the power status of the PD (1 meaning tampered or
+    /// power failure).
+    pub fn new_local(mask: u32) -> Self {
+        Self {
+            type_: OsdpStatusReportType::Local,
+            nr_entries: 2,
+            mask,
+        }
+    }
+
+    /// Kind of status this report is about
+    pub fn report_type(&self) -> OsdpStatusReportType {
+        self.type_
+    }
+
+    /// Number of status bits in [`OsdpStatusReport::mask`]
+    pub fn nr_entries(&self) -> usize {
+        self.nr_entries
+    }
+
+    /// Status bits; bit N is the status of the Nth input, output, etc.,. See
+    /// [`OsdpStatusReport::new_local`] for what the bits of local status
+    /// reports mean.
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
 }
 
 impl From<libosdp_sys::osdp_status_report> for OsdpStatusReport {
@@ -376,13 +491,65 @@ pub enum OsdpEvent {
     Status(OsdpStatusReport),
 }
 
-impl From<OsdpEvent> for libosdp_sys::osdp_event {
-    fn from(value: OsdpEvent) -> Self {
-        match value {
+/// Kind of an [`OsdpEvent`], without its data. See
+/// [`crate::ControlPanel::subscribe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpEventKind {
+    /// [`OsdpEvent::CardRead`]
+    CardRead,
+    /// [`OsdpEvent::KeyPress`]
+    KeyPress,
+    /// [`OsdpEvent::MfgReply`]
+    MfgReply,
+    /// [`OsdpEvent::Status`]
+    Status,
+}
+
+impl OsdpEventKind {
+    /// Number of kinds; `Status` must stay the last one.
+    pub(crate) const COUNT: usize = OsdpEventKind::Status as usize + 1;
+}
+
+/// The PD an [`OsdpEvent`] came from, as passed to the closure set with
+/// [`crate::ControlPanel::set_event_context_callback`]. Log lines and
+/// messages built from this make sense on their own, without a lookup of the
+/// PD offset in the application's configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct EventContext<'a> {
+    /// Offset of the PD, in the order PDs were added to
+    /// [`crate::ControlPanelBuilder`]
+    pub pd: i32,
+    /// Name of the PD (see [`crate::PdInfoBuilder::name`])
+    pub name: &'a str,
+    /// Address of the PD; this follows COMSETs
+    pub address: i32,
+}
+
+impl OsdpEvent {
+    /// Get the [`OsdpEventKind`] of this event.
+    pub fn kind(&self) -> OsdpEventKind {
+        match self {
+            OsdpEvent::CardRead(_) => OsdpEventKind::CardRead,
+            OsdpEvent::KeyPress(_) => OsdpEventKind::KeyPress,
+            OsdpEvent::MfgReply(_) => OsdpEventKind::MfgReply,
+            OsdpEvent::Status(_) => OsdpEventKind::Status,
+        }
+    }
+}
+
+impl TryFrom<OsdpEvent> for libosdp_sys::osdp_event {
+    type Error = OsdpError;
+
+    /// Fails with [`OsdpError::Event`] for card reads with more data than
+    /// LibOSDP can carry (see [`OsdpEventCardRead::MAX_DATA_LEN`]).
+    fn try_from(value: OsdpEvent) -> Result<Self> {
+        Ok(match value {
             OsdpEvent::CardRead(e) => libosdp_sys::osdp_event {
                 type_: libosdp_sys::osdp_event_type_OSDP_EVENT_CARDREAD,
                 __bindgen_anon_1: libosdp_sys::osdp_event__bindgen_ty_1 {
-                    cardread: e.clone().into(),
+                    cardread: e.try_into()?,
                 },
             },
             OsdpEvent::KeyPress(e) => libosdp_sys::osdp_event {
@@ -401,7 +568,7 @@ impl From<OsdpEvent> for libosdp_sys::osdp_event {
                 type_: libosdp_sys::osdp_event_type_OSDP_EVENT_STATUS,
                 __bindgen_anon_1: libosdp_sys::osdp_event__bindgen_ty_1 { status: e.into() },
             },
-        }
+        })
     }
 }
 
@@ -428,6 +595,7 @@ impl From<libosdp_sys::osdp_event> for OsdpEvent {
 #[cfg(test)]
 mod tests {
     use super::OsdpEventCardRead;
+    use alloc::vec::Vec;
     use libosdp_sys::{
         osdp_event_cardread, osdp_event_cardread_format_e_OSDP_CARD_FMT_ASCII,
         osdp_event_cardread_format_e_OSDP_CARD_FMT_RAW_WIEGAND,
@@ -436,7 +604,7 @@ mod tests {
     #[test]
     fn test_event_cardread() {
         let event = OsdpEventCardRead::new_ascii(vec![0x55, 0xAA]);
-        let event_struct: osdp_event_cardread = event.clone().into();
+        let event_struct: osdp_event_cardread = event.clone().try_into().unwrap();
 
         assert_eq!(event_struct.length, 2);
         assert_eq!(event_struct.direction, 0);
@@ -450,7 +618,7 @@ mod tests {
         assert_eq!(event, event_struct.into());
 
         let event = OsdpEventCardRead::new_wiegand(15, vec![0x55, 0xAA]).unwrap();
-        let event_struct: osdp_event_cardread = event.clone().into();
+        let event_struct: osdp_event_cardread = event.clone().try_into().unwrap();
 
         assert_eq!(event_struct.length, 15);
         assert_eq!(event_struct.direction, 0);
@@ -463,4 +631,46 @@ mod tests {
 
         assert_eq!(event, event_struct.into());
     }
+
+    #[test]
+    fn test_event_cardread_bits() {
+        let event = OsdpEventCardRead::new_wiegand(10, vec![0xA5, 0xC0]).unwrap();
+        assert_eq!(event.bit_len(), 10);
+        let bits: Vec<bool> = event.bits().collect();
+        assert_eq!(
+            bits,
+            [true, false, true, false, false, true, false, true, true, true]
+        );
+        assert_eq!(OsdpEventCardRead::new_ascii(vec![b'1', b'2']).bit_len(), 16);
+        assert!(OsdpEventCardRead::new_raw(17, vec![0; 2]).is_err());
+
+        // 1024 bit credential
+        let event = OsdpEventCardRead::new_raw(1024, vec![0x5A; 128]).unwrap();
+        assert_eq!(event.bits().count(), 1024);
+        assert!(osdp_event_cardread::try_from(event).is_err());
+        let event = OsdpEventCardRead::new_raw(512, vec![0x5A; 64]).unwrap();
+        let event_struct: osdp_event_cardread = event.clone().try_into().unwrap();
+        assert_eq!(event_struct.length, 512);
+        assert_eq!(event, event_struct.into());
+    }
+
+    #[test]
+    fn test_event_cardread_wiegand() {
+        let event = OsdpEventCardRead::from_wiegand26(18, 12345);
+        assert_eq!(event.bit_len(), 26);
+        assert_eq!(event.data, [0x09, 0x18, 0x1C, 0xC0]);
+        assert_eq!(
+            OsdpEventCardRead::from_wiegand26(0, 0).data,
+            [0x00, 0x00, 0x00, 0x40]
+        );
+
+        let event = OsdpEventCardRead::from_wiegand34(1, 1);
+        assert_eq!(event.bit_len(), 34);
+        assert_eq!(event.data, [0x80, 0x00, 0x80, 0x00, 0x80]);
+
+        let event = OsdpEventCardRead::from_wiegand37(0x1234, 0x5678).unwrap();
+        assert_eq!(event.bit_len(), 37);
+        assert_eq!(event.data, [0x89, 0x1A, 0x05, 0x67, 0x88]);
+        assert!(OsdpEventCardRead::from_wiegand37(0, 1 << 19).is_err());
+    }
 }
@@ -6,7 +6,8 @@
 //! OSDP provides a means to send files from CP to a Peripheral Device (PD).
 //! This module adds the required components to achieve this effect.
 
-use alloc::{boxed::Box, vec};
+use crate::callback::catch_panic;
+use alloc::boxed::Box;
 use core::ffi::c_void;
 #[cfg(feature = "defmt-03")]
 use defmt::error;
@@ -37,7 +38,11 @@ unsafe extern "C" fn file_open(data: *mut c_void, file_id: i32, size: *mut i32)
     let ctx: *mut Box<dyn OsdpFileOps> = data as *mut _;
     let ctx = ctx.as_mut().unwrap();
     let read_only = *size == 0;
-    match ctx.open(file_id, read_only) {
+    match catch_panic(
+        "OsdpFileOps::open",
+        Err(crate::OsdpError::FileTransfer("panicked")),
+        || ctx.open(file_id, read_only),
+    ) {
         Ok(file_size) => {
             if read_only {
                 *size = file_size as i32;
@@ -52,20 +57,44 @@ unsafe extern "C" fn file_open(data: *mut c_void, file_id: i32, size: *mut i32)
     }
 }
 
+/// Length of the buffer LibOSDP hands to a file operation; `None` if `buf`
+/// and `size` don't describe a valid one. File operations work on this buffer
+/// directly.
+fn buf_len(buf: *const c_void, size: i32) -> Option<usize> {
+    match usize::try_from(size) {
+        Ok(size) if size == 0 || !buf.is_null() => Some(size),
+        _ => None,
+    }
+}
+
 unsafe extern "C" fn file_read(data: *mut c_void, buf: *mut c_void, size: i32, offset: i32) -> i32 {
     let ctx: *mut Box<dyn OsdpFileOps> = data as *mut _;
     let ctx = ctx.as_ref().unwrap();
-    let mut read_buf = vec![0u8; size as usize];
-    let len = match ctx.offset_read(&mut read_buf, offset as u64) {
-        Ok(len) => len as i32,
+    let (Some(len), Ok(offset)) = (buf_len(buf, size), u64::try_from(offset)) else {
+        return -1;
+    };
+    let buf: &mut [u8] = match len {
+        0 => &mut [],
+        len => core::slice::from_raw_parts_mut(buf as *mut u8, len),
+    };
+    let res = catch_panic(
+        "OsdpFileOps::offset_read",
+        Err(crate::OsdpError::FileTransfer("panicked")),
+        || ctx.offset_read(buf, offset),
+    );
+    match res {
+        Ok(len) if len <= size as usize => len as i32,
+        Ok(_) => {
+            #[cfg(any(feature = "log", feature = "defmt-03"))]
+            error!("file_read: read past the end of the buffer");
+            -1
+        }
         Err(_e) => {
             #[cfg(any(feature = "log", feature = "defmt-03"))]
             error!("file_read: {:?}", _e);
             -1
         }
-    };
-    core::ptr::copy_nonoverlapping(read_buf.as_mut_ptr(), buf as *mut u8, len as usize);
-    len
+    }
 }
 
 unsafe extern "C" fn file_write(
@@ -76,10 +105,25 @@ unsafe extern "C" fn file_write(
 ) -> i32 {
     let ctx: *mut Box<dyn OsdpFileOps> = data as *mut _;
     let ctx = ctx.as_ref().unwrap();
-    let mut write_buf = vec![0u8; size as usize];
-    core::ptr::copy_nonoverlapping(buf as *mut u8, write_buf.as_mut_ptr(), size as usize);
-    match ctx.offset_write(&write_buf, offset as u64) {
-        Ok(len) => len as i32,
+    let (Some(len), Ok(offset)) = (buf_len(buf, size), u64::try_from(offset)) else {
+        return -1;
+    };
+    let buf: &[u8] = match len {
+        0 => &[],
+        len => core::slice::from_raw_parts(buf as *const u8, len),
+    };
+    let res = catch_panic(
+        "OsdpFileOps::offset_write",
+        Err(crate::OsdpError::FileTransfer("panicked")),
+        || ctx.offset_write(buf, offset),
+    );
+    match res {
+        Ok(len) if len <= size as usize => len as i32,
+        Ok(_) => {
+            #[cfg(any(feature = "log", feature = "defmt-03"))]
+            error!("file_write: wrote past the end of the buffer");
+            -1
+        }
         Err(_e) => {
             #[cfg(any(feature = "log", feature = "defmt-03"))]
             error!("file_write: {:?}", _e);
@@ -91,7 +135,11 @@ unsafe extern "C" fn file_write(
 unsafe extern "C" fn file_close(data: *mut c_void) -> i32 {
     let ctx: *mut Box<dyn OsdpFileOps> = data as *mut _;
     let ctx = ctx.as_mut().unwrap();
-    match ctx.close() {
+    match catch_panic(
+        "OsdpFileOps::close",
+        Err(crate::OsdpError::FileTransfer("panicked")),
+        || ctx.close(),
+    ) {
         Ok(_) => 0,
         Err(_e) => {
             #[cfg(any(feature = "log", feature = "defmt-03"))]
@@ -113,3 +161,55 @@ impl From<Box<dyn OsdpFileOps>> for libosdp_sys::osdp_file_ops {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{file_read, file_write, OsdpFileOps, Result};
+    use crate::OsdpError;
+    use alloc::boxed::Box;
+    use core::ffi::c_void;
+
+    /// A file of `len` bytes of 0xAA that fails reads past its end
+    struct TestFile {
+        len: usize,
+    }
+
+    impl OsdpFileOps for TestFile {
+        fn open(&mut self, _: i32, _: bool) -> Result<usize> {
+            Ok(self.len)
+        }
+        fn offset_read(&self, buf: &mut [u8], off: u64) -> Result<usize> {
+            let n = self
+                .len
+                .checked_sub(off as usize)
+                .ok_or(OsdpError::FileTransfer("eof"))?;
+            let n = n.min(buf.len());
+            buf[..n].fill(0xAA);
+            Ok(n)
+        }
+        fn offset_write(&self, buf: &[u8], _: u64) -> Result<usize> {
+            Ok(buf.len() + 1)
+        }
+        fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_file_ops() {
+        let mut ops: Box<dyn OsdpFileOps> = Box::new(TestFile { len: 6 });
+        let data = &mut ops as *mut Box<dyn OsdpFileOps> as *mut c_void;
+        let mut buf = [0u8; 4];
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        unsafe {
+            assert_eq!(file_read(data, ptr, 4, 4), 2);
+            // Failed reads leave the buffer alone
+            assert_eq!(file_read(data, ptr, 4, 8), -1);
+            assert_eq!(file_read(data, ptr, -1, 0), -1);
+            assert_eq!(file_read(data, core::ptr::null_mut(), 4, 0), -1);
+            // Writing more than what was given is an error
+            assert_eq!(file_write(data, ptr, 4, 0), -1);
+        }
+        assert_eq!(buf, [0xAA, 0xAA, 0, 0]);
+    }
+}
@@ -0,0 +1,110 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A CP can keep a bounded, in-memory record of the last few commands it sent
+//! to and events it received from each PD. This is meant for post-incident
+//! debugging and inspection (from a CLI, for instance); see
+//! [`crate::ControlPanelBuilder::history`].
+
+use crate::{OsdpCommand, OsdpEvent};
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// Something that went on between a CP and one of its PDs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum Activity {
+    /// A command that was sent to the PD
+    Command(OsdpCommand),
+    /// An event that was received from the PD
+    Event(OsdpEvent),
+}
+
+/// An entry in the activity history of a PD.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActivityRecord {
+    /// Sequence number of this record; it is incremented for every record of
+    /// the CP (across all PDs) so it can be used to order records of
+    /// different PDs.
+    pub seq: u64,
+    /// Time at which this record was made
+    #[cfg(feature = "std")]
+    pub time: std::time::SystemTime,
+    /// What happened
+    pub activity: Activity,
+}
+
+#[derive(Debug)]
+pub(crate) struct History {
+    capacity: usize,
+    seq: u64,
+    pds: Vec<VecDeque<ActivityRecord>>,
+}
+
+impl History {
+    pub fn new(num_pd: usize, capacity: usize) -> Self {
+        Self {
+            capacity,
+            seq: 0,
+            pds: (0..num_pd).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&mut self, pd: i32, activity: Activity) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(records) = self.pds.get_mut(pd as usize) else {
+            return;
+        };
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        self.seq += 1;
+        records.push_back(ActivityRecord {
+            seq: self.seq,
+            #[cfg(feature = "std")]
+            time: std::time::SystemTime::now(),
+            activity,
+        });
+    }
+
+    pub fn recent(&self, pd: i32) -> Vec<ActivityRecord> {
+        self.pds
+            .get(pd as usize)
+            .map_or_else(Vec::new, |records| records.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, History};
+    use crate::{OsdpCommand, OsdpCommandBuzzer, OsdpCommandOutput};
+
+    #[test]
+    fn test_history() {
+        let buzzer = Activity::Command(OsdpCommand::Buzzer(OsdpCommandBuzzer::default()));
+        let output = Activity::Command(OsdpCommand::Output(OsdpCommandOutput::default()));
+
+        let mut disabled = History::new(1, 0);
+        disabled.record(0, buzzer.clone());
+        assert!(disabled.recent(0).is_empty());
+
+        let mut history = History::new(2, 2);
+        history.record(0, buzzer.clone());
+        history.record(1, buzzer.clone());
+        history.record(0, output.clone());
+        history.record(0, output.clone());
+        let recent = history.recent(0);
+        let activity: alloc::vec::Vec<_> = recent.iter().map(|r| &r.activity).collect();
+        assert_eq!(activity, [&output, &output]);
+        assert_eq!(recent[0].seq, 3);
+        assert_eq!(history.recent(1).len(), 1);
+        assert!(history.recent(5).is_empty());
+    }
+}
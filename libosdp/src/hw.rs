@@ -0,0 +1,528 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Most readers do little more with LED, buzzer and output commands than
+//! drive a few GPIOs. [`Hardware`] does that for a [`crate::PeripheralDevice`]
+//! given the [`embedded_hal`] pins it should drive; it plays out flash
+//! patterns, beeps and timed outputs as time passes and turns changes of input
+//! pins into OSDP input status events.
+//!
+//! ```ignore
+//! let mut hw = Hardware::new()
+//!     .led(0, 0, RgbLed::new(red_pin, green_pin, blue_pin))
+//!     .buzzer(0, PinBuzzer::new(buzzer_pin))
+//!     .output(strike_pin)
+//!     .input(door_contact_pin, PinState::Low);
+//!
+//! // Pass commands to it from the PD's command callback
+//! hw.handle_command(&cmd)?;
+//!
+//! // And, from the main loop
+//! hw.tick(elapsed)?;
+//! if let Some(event) = hw.poll_inputs()? {
+//!     pd.notify_event(event)?;
+//! }
+//! ```
+
+use crate::{
+    pdstate::{BuzzerState, LedState, OutputState},
+    OsdpCommand, OsdpError, OsdpEvent, OsdpLedColor, OsdpStatusReport, PdCapEntity, PdCapability,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+use embedded_hal::{
+    digital::{InputPin, OutputPin, PinState},
+    pwm::SetDutyCycle,
+};
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// OSDP supports up to 32 inputs/outputs in a status report
+const MAX_IO: usize = 32;
+
+/// An LED that [`Hardware`] drives
+pub trait Led {
+    /// Show `color`; [`OsdpLedColor::None`] turns the LED off.
+    fn set_color(&mut self, color: OsdpLedColor) -> Result<()>;
+
+    /// OSDP compliance level of this LED: 1 for on/off only, 2 for timed
+    /// (any single color LED driven by [`Hardware`]), 3 for bi-color and 4
+    /// for tri-color.
+    fn compliance(&self) -> u8 {
+        2
+    }
+}
+
+/// A single color LED on an output pin; it lights up for any color.
+#[derive(Debug)]
+pub struct MonoLed<P>(P);
+
+impl<P: OutputPin> MonoLed<P> {
+    /// Create an LED that lights up when `pin` is driven high.
+    pub fn new(pin: P) -> Self {
+        Self(pin)
+    }
+}
+
+impl<P: OutputPin> Led for MonoLed<P> {
+    fn set_color(&mut self, color: OsdpLedColor) -> Result<()> {
+        let state = PinState::from(color != OsdpLedColor::None);
+        self.0
+            .set_state(state)
+            .map_err(|_| OsdpError::Hardware("LED pin"))
+    }
+}
+
+/// An RGB LED on three output pins; colors other than red, green and blue
+/// are mixed from them.
+#[derive(Debug)]
+pub struct RgbLed<R, G, B> {
+    red: R,
+    green: G,
+    blue: B,
+}
+
+impl<R: OutputPin, G: OutputPin, B: OutputPin> RgbLed<R, G, B> {
+    /// Create an LED whose colors light up when their pins are driven high.
+    pub fn new(red: R, green: G, blue: B) -> Self {
+        Self { red, green, blue }
+    }
+}
+
+impl<R: OutputPin, G: OutputPin, B: OutputPin> Led for RgbLed<R, G, B> {
+    fn set_color(&mut self, color: OsdpLedColor) -> Result<()> {
+        use OsdpLedColor::*;
+        let red = matches!(color, Red | Amber | Magenta | White);
+        let green = matches!(color, Green | Amber | Cyan | White);
+        let blue = matches!(color, Blue | Magenta | Cyan | White);
+        fn err<E>(_: E) -> OsdpError {
+            OsdpError::Hardware("LED pin")
+        }
+        self.red.set_state(red.into()).map_err(err)?;
+        self.green.set_state(green.into()).map_err(err)?;
+        self.blue.set_state(blue.into()).map_err(err)
+    }
+
+    fn compliance(&self) -> u8 {
+        4
+    }
+}
+
+/// A buzzer that [`Hardware`] drives
+pub trait Buzzer {
+    /// Start or stop sounding.
+    fn set_sounding(&mut self, on: bool) -> Result<()>;
+}
+
+/// An active buzzer (one that makes its own tone) on an output pin
+#[derive(Debug)]
+pub struct PinBuzzer<P>(P);
+
+impl<P: OutputPin> PinBuzzer<P> {
+    /// Create a buzzer that sounds when `pin` is driven high.
+    pub fn new(pin: P) -> Self {
+        Self(pin)
+    }
+}
+
+impl<P: OutputPin> Buzzer for PinBuzzer<P> {
+    fn set_sounding(&mut self, on: bool) -> Result<()> {
+        self.0
+            .set_state(on.into())
+            .map_err(|_| OsdpError::Hardware("buzzer pin"))
+    }
+}
+
+/// A passive buzzer on a PWM channel; it is driven at 50% duty cycle of the
+/// frequency the channel is set up with.
+#[derive(Debug)]
+pub struct PwmBuzzer<P>(P);
+
+impl<P: SetDutyCycle> PwmBuzzer<P> {
+    /// Create a buzzer on the PWM channel `pwm`.
+    pub fn new(pwm: P) -> Self {
+        Self(pwm)
+    }
+}
+
+impl<P: SetDutyCycle> Buzzer for PwmBuzzer<P> {
+    fn set_sounding(&mut self, on: bool) -> Result<()> {
+        let percent = if on { 50 } else { 0 };
+        self.0
+            .set_duty_cycle_percent(percent)
+            .map_err(|_| OsdpError::Hardware("buzzer PWM"))
+    }
+}
+
+/// Output pins with their pin errors erased
+trait Output: Send {
+    fn set_active(&mut self, active: bool) -> Result<()>;
+}
+
+impl<P: OutputPin + Send> Output for P {
+    fn set_active(&mut self, active: bool) -> Result<()> {
+        self.set_state(active.into())
+            .map_err(|_| OsdpError::Hardware("output pin"))
+    }
+}
+
+/// Input pins with their pin errors erased
+trait Input: Send {
+    fn is_high(&mut self) -> Result<bool>;
+}
+
+impl<P: InputPin + Send> Input for P {
+    fn is_high(&mut self) -> Result<bool> {
+        InputPin::is_high(self).map_err(|_| OsdpError::Hardware("input pin"))
+    }
+}
+
+/// A device driven by [`Hardware`]; `driven` is what it was last set to.
+struct Device<D: ?Sized, S, V> {
+    state: S,
+    driven: Option<V>,
+    device: Box<D>,
+}
+
+impl<D: ?Sized, S: Default, V> Device<D, S, V> {
+    fn new(device: Box<D>) -> Self {
+        Self {
+            state: S::default(),
+            driven: None,
+            device,
+        }
+    }
+}
+
+type LedDevice = Device<dyn Led + Send, LedState, OsdpLedColor>;
+type BuzzerDevice = Device<dyn Buzzer + Send, BuzzerState, bool>;
+type OutputDevice = Device<dyn Output, OutputState, bool>;
+
+/// LEDs, buzzers, outputs and inputs of a PD; see module documentation.
+#[derive(Default)]
+pub struct Hardware {
+    /// LEDs by reader and LED number
+    leds: Vec<(u8, u8, LedDevice)>,
+    /// Buzzers by reader
+    buzzers: Vec<(u8, BuzzerDevice)>,
+    outputs: Vec<OutputDevice>,
+    /// Input pins and the level at which each is active
+    inputs: Vec<(Box<dyn Input>, PinState)>,
+    /// Input status as of the last [`Hardware::poll_inputs`]
+    input_mask: Option<u32>,
+}
+
+impl core::fmt::Debug for Hardware {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Hardware")
+            .field("leds", &self.leds.len())
+            .field("buzzers", &self.buzzers.len())
+            .field("outputs", &self.outputs.len())
+            .field("inputs", &self.inputs.len())
+            .finish()
+    }
+}
+
+impl Hardware {
+    /// Create a PD backend without any hardware; add some with the other
+    /// methods.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add LED `led_number` of `reader` (0 for the PD itself).
+    pub fn led(mut self, reader: u8, led_number: u8, led: impl Led + Send + 'static) -> Self {
+        self.leds
+            .push((reader, led_number, Device::new(Box::new(led))));
+        self
+    }
+
+    /// Add the buzzer of `reader` (0 for the PD itself).
+    pub fn buzzer(mut self, reader: u8, buzzer: impl Buzzer + Send + 'static) -> Self {
+        self.buzzers.push((reader, Device::new(Box::new(buzzer))));
+        self
+    }
+
+    /// Add an output that is active when `pin` is driven high. Outputs are
+    /// numbered in the order they are added, from 0.
+    pub fn output(mut self, pin: impl OutputPin + Send + 'static) -> Self {
+        if self.outputs.len() < MAX_IO {
+            self.outputs.push(Device::new(Box::new(pin)));
+        }
+        self
+    }
+
+    /// Add an input that is active when `pin` is at level `active`. Inputs
+    /// are numbered in the order they are added, from 0.
+    pub fn input(mut self, pin: impl InputPin + Send + 'static, active: PinState) -> Self {
+        if self.inputs.len() < MAX_IO {
+            self.inputs.push((Box::new(pin), active));
+        }
+        self
+    }
+
+    /// Capabilities to advertise for this hardware; add these to the
+    /// [`crate::PdInfoBuilder`] of the PD.
+    pub fn capabilities(&self) -> Vec<PdCapability> {
+        let mut caps = Vec::new();
+        if let Some(max_led) = self.leds.iter().map(|(_, n, _)| *n).max() {
+            let compliance = self
+                .leds
+                .iter()
+                .map(|(_, _, led)| led.device.compliance())
+                .min()
+                .unwrap_or(1);
+            caps.push(PdCapability::LedControl(PdCapEntity::new(
+                compliance,
+                max_led.saturating_add(1),
+            )));
+        }
+        if !self.buzzers.is_empty() {
+            caps.push(PdCapability::AudibleOutput(PdCapEntity::new(
+                2,
+                self.buzzers.len() as u8,
+            )));
+        }
+        if !self.outputs.is_empty() {
+            caps.push(PdCapability::OutputControl(PdCapEntity::new(
+                2,
+                self.outputs.len() as u8,
+            )));
+        }
+        if !self.inputs.is_empty() {
+            caps.push(PdCapability::ContactStatusMonitoring(PdCapEntity::new(
+                1,
+                self.inputs.len() as u8,
+            )));
+        }
+        caps
+    }
+
+    /// Apply an LED, buzzer or output command and update the hardware.
+    /// Returns [`OsdpError::Command`] for other commands and for ones that
+    /// address hardware that wasn't added.
+    pub fn handle_command(&mut self, cmd: &OsdpCommand) -> Result<()> {
+        match cmd {
+            OsdpCommand::Led(c) => self
+                .leds
+                .iter_mut()
+                .find(|(reader, n, _)| *reader == c.reader && *n == c.led_number)
+                .ok_or(OsdpError::Command)?
+                .2
+                .state
+                .apply(c),
+            OsdpCommand::Buzzer(c) => self
+                .buzzers
+                .iter_mut()
+                .find(|(reader, _)| *reader == c.reader)
+                .ok_or(OsdpError::Command)?
+                .1
+                .state
+                .apply(c),
+            OsdpCommand::Output(c) => self
+                .outputs
+                .get_mut(c.output_no as usize)
+                .ok_or(OsdpError::Command)?
+                .state
+                .apply(c),
+            _ => return Err(OsdpError::Command),
+        }
+        self.drive()
+    }
+
+    /// Let `elapsed` time pass since the last call and update the hardware.
+    /// Call this often enough for flash patterns and beeps to play out
+    /// smoothly; every 50 ms or so.
+    pub fn tick(&mut self, elapsed: Duration) -> Result<()> {
+        self.leds
+            .iter_mut()
+            .for_each(|(_, _, led)| led.state.tick(elapsed));
+        self.buzzers
+            .iter_mut()
+            .for_each(|(_, buzzer)| buzzer.state.tick(elapsed));
+        self.outputs
+            .iter_mut()
+            .for_each(|output| output.state.tick(elapsed));
+        self.drive()
+    }
+
+    fn drive(&mut self) -> Result<()> {
+        for (_, _, led) in self.leds.iter_mut() {
+            let color = led.state.color();
+            if led.driven != Some(color) {
+                led.device.set_color(color)?;
+                led.driven = Some(color);
+            }
+        }
+        for (_, buzzer) in self.buzzers.iter_mut() {
+            let on = buzzer.state.is_sounding();
+            if buzzer.driven != Some(on) {
+                buzzer.device.set_sounding(on)?;
+                buzzer.driven = Some(on);
+            }
+        }
+        for output in self.outputs.iter_mut() {
+            let active = output.state.is_active();
+            if output.driven != Some(active) {
+                output.device.set_active(active)?;
+                output.driven = Some(active);
+            }
+        }
+        Ok(())
+    }
+
+    /// Status of all inputs, to reply to an input status query with.
+    pub fn input_status(&mut self) -> Result<OsdpStatusReport> {
+        let mut mask = 0;
+        for (i, (pin, active)) in self.inputs.iter_mut().enumerate() {
+            if pin.is_high()? == (*active == PinState::High) {
+                mask |= 1 << i;
+            }
+        }
+        Ok(OsdpStatusReport::new_input(self.inputs.len(), mask))
+    }
+
+    /// Status of all outputs, to reply to an output status query with.
+    pub fn output_status(&self) -> OsdpStatusReport {
+        let mask = self
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.state.is_active())
+            .fold(0, |mask, (i, _)| mask | 1 << i);
+        OsdpStatusReport::new_output(self.outputs.len(), mask)
+    }
+
+    /// Read the inputs and return an input status event for the CP if any of
+    /// them changed since the last call (or if this is the first call).
+    pub fn poll_inputs(&mut self) -> Result<Option<OsdpEvent>> {
+        if self.inputs.is_empty() {
+            return Ok(None);
+        }
+        let report = self.input_status()?;
+        if self.input_mask == Some(report.mask) {
+            return Ok(None);
+        }
+        self.input_mask = Some(report.mask);
+        Ok(Some(OsdpEvent::Status(report)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hardware, MonoLed, PinBuzzer, RgbLed};
+    use crate::{
+        OsdpCommand, OsdpCommandBuzzer, OsdpCommandLed, OsdpCommandOutput, OsdpEvent, OsdpLedColor,
+        OsdpLedPattern, OsdpLedPermanentControl, OsdpLedTemporaryControl, PdCapEntity,
+        PdCapability,
+    };
+    use core::{convert::Infallible, time::Duration};
+    use embedded_hal::digital::{ErrorType, InputPin, OutputPin, PinState};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[derive(Clone, Default)]
+    struct Pin(Arc<AtomicBool>);
+
+    impl Pin {
+        fn get(&self) -> bool {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl ErrorType for Pin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Pin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Pin {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            Ok(self.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.get())
+        }
+    }
+
+    #[test]
+    fn test_hardware() {
+        let rgb: [Pin; 3] = Default::default();
+        let [mono, buzzer, strike, mut contact] = <[Pin; 4]>::default();
+        let mut hw = Hardware::new()
+            .led(
+                0,
+                0,
+                RgbLed::new(rgb[0].clone(), rgb[1].clone(), rgb[2].clone()),
+            )
+            .led(0, 1, MonoLed::new(mono.clone()))
+            .buzzer(0, PinBuzzer::new(buzzer.clone()))
+            .output(strike.clone())
+            .input(contact.clone(), PinState::Low);
+        assert_eq!(
+            hw.capabilities(),
+            [
+                PdCapability::LedControl(PdCapEntity::new(2, 2)),
+                PdCapability::AudibleOutput(PdCapEntity::new(2, 1)),
+                PdCapability::OutputControl(PdCapEntity::new(2, 1)),
+                PdCapability::ContactStatusMonitoring(PdCapEntity::new(1, 1)),
+            ]
+        );
+
+        let led = |n, color| {
+            OsdpCommand::Led(OsdpCommandLed::new(
+                0,
+                n,
+                OsdpLedTemporaryControl::Nop,
+                OsdpLedPermanentControl::Set(OsdpLedPattern::steady(color)),
+            ))
+        };
+        hw.handle_command(&led(0, OsdpLedColor::Amber)).unwrap();
+        hw.handle_command(&led(1, OsdpLedColor::Red)).unwrap();
+        assert!(rgb[0].get() && rgb[1].get() && !rgb[2].get() && mono.get());
+        assert!(hw.handle_command(&led(2, OsdpLedColor::Red)).is_err());
+
+        let buz = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+        hw.handle_command(&buz).unwrap();
+        assert!(buzzer.get());
+        hw.tick(Duration::from_millis(200)).unwrap();
+        assert!(!buzzer.get());
+
+        let out = OsdpCommand::Output(OsdpCommandOutput {
+            output_no: 0,
+            control_code: 5,
+            timer_count: 30,
+        });
+        hw.handle_command(&out).unwrap();
+        assert!(strike.get());
+        assert_eq!(hw.output_status().mask, 1);
+        hw.tick(Duration::from_secs(3)).unwrap();
+        assert!(!strike.get());
+
+        // Door contact is closed (active) while low
+        let event = hw.poll_inputs().unwrap();
+        assert!(matches!(event, Some(OsdpEvent::Status(s)) if s.mask == 1));
+        assert!(hw.poll_inputs().unwrap().is_none());
+        contact.set_high().unwrap();
+        let event = hw.poll_inputs().unwrap();
+        assert!(matches!(event, Some(OsdpEvent::Status(s)) if s.mask == 0));
+
+        let text = OsdpCommand::Text(Default::default());
+        assert!(hw.handle_command(&text).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! OSDP packets end with either an 8-bit checksum or a CRC-16, as chosen by
+//! the sender. LibOSDP always sends CRC-16 and accepts replies either way
+//! (the PD is expected to use whatever the CP used). Some old readers only
+//! know the checksum, and some sites want nothing less than CRC-16; a CP
+//! handles both, per PD, in the channel wrapper that watches its traffic (see
+//! [`crate::PdInfoBuilder::integrity`]).
+
+use crate::wire::{parse_frame, Frame, Packet, SOM};
+use alloc::vec::Vec;
+
+/// Integrity check of OSDP packets
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpIntegrity {
+    /// 8-bit checksum
+    Checksum,
+    /// 16-bit CRC
+    Crc16,
+}
+
+impl OsdpIntegrity {
+    pub(crate) fn of(packet: &Packet) -> Self {
+        if packet.use_crc {
+            OsdpIntegrity::Crc16
+        } else {
+            OsdpIntegrity::Checksum
+        }
+    }
+}
+
+/// Holds back the bytes read from a channel until they make up whole packets,
+/// so that packets can be taken out before LibOSDP gets to see them. Bytes
+/// that are not part of a packet are passed through as they are.
+#[derive(Debug, Default)]
+pub(crate) struct ReplyFilter {
+    held: Vec<u8>,
+}
+
+impl ReplyFilter {
+    /// Feed `bytes` read from the channel. Returns the bytes that can be
+    /// passed on, less the packets that `reject` returns true for.
+    pub fn filter(&mut self, bytes: &[u8], mut reject: impl FnMut(&Packet) -> bool) -> Vec<u8> {
+        self.held.extend_from_slice(bytes);
+        let mut passed = Vec::new();
+        loop {
+            let start = self
+                .held
+                .iter()
+                .position(|b| *b == SOM)
+                .unwrap_or(self.held.len());
+            passed.extend(self.held.drain(..start));
+            if self.held.is_empty() {
+                break;
+            }
+            match parse_frame(&self.held) {
+                Ok(Frame::Complete(packet, len)) => {
+                    let frame = self.held.drain(..len);
+                    if !reject(&packet) {
+                        passed.extend(frame);
+                    }
+                }
+                Ok(Frame::Incomplete) => break,
+                Err(_) => passed.push(self.held.remove(0)),
+            }
+        }
+        passed
+    }
+}
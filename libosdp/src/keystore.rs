@@ -0,0 +1,130 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A PD gets its secure channel base key (SCBK) from the CP with a KEYSET
+//! command. If the PD forgets it on a reboot, it comes back either in install
+//! mode or with the key it was built with, neither of which the CP accepts
+//! under [`crate::OsdpFlag::EnforceSecure`]; the PD has to be provisioned
+//! again. A [`SecureKeyStore`] set with [`crate::PdInfoBuilder::key_store`]
+//! keeps the key across reboots: the PD stores each key it accepts and starts
+//! up with the stored one.
+//!
+//! The key is all there is to keep. Secure channel sessions don't survive a
+//! reboot of either side (their keys are derived from random numbers
+//! exchanged in the set up, and are not exposed by LibOSDP); the CP sets up a
+//! new one with the stored key once the PD is back.
+
+use crate::OsdpError;
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// Persistent storage for the secure channel base key of a PD; see the
+/// [module documentation](self).
+///
+/// Implementations decide how (and how securely) the key is stored: flash,
+/// a secure element, a file, ... Errors are reported as
+/// [`OsdpError::KeyStore`].
+pub trait SecureKeyStore: Send {
+    /// Load the key that was last stored, or `None` if there isn't one (the
+    /// PD was never provisioned).
+    fn load(&mut self) -> Result<Option<[u8; 16]>>;
+
+    /// Store `key`, replacing the one stored before. The key must be durable
+    /// once this returns; a KEYSET is only ACK'd if this succeeds.
+    fn store(&mut self, key: &[u8; 16]) -> Result<()>;
+}
+
+impl core::fmt::Debug for dyn SecureKeyStore {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SecureKeyStore")
+    }
+}
+
+/// A [`SecureKeyStore`] that keeps the key in a file, as 16 raw bytes. The
+/// file is replaced atomically (written next to it, then renamed over it, and
+/// the directory synced) so that a power loss while storing leaves either the
+/// old key or the new one.
+///
+/// The key is stored in plain text. On unix, the file is only readable and
+/// writable by its owner (mode 0600); elsewhere, it must be kept from other
+/// users by other means.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct FileKeyStore {
+    path: std::path::PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileKeyStore {
+    /// Create a key store that keeps the key at `path`
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl SecureKeyStore for FileKeyStore {
+    fn load(&mut self) -> Result<Option<[u8; 16]>> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => bytes
+                .try_into()
+                .map(Some)
+                .map_err(|_| OsdpError::KeyStore("stored key is not 16 bytes")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store(&mut self, key: &[u8; 16]) -> Result<()> {
+        use std::io::Write;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(key)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        // The rename is only durable once the directory is synced
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => std::path::Path::new("."),
+            };
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_key_store() {
+        let path = std::env::temp_dir().join(format!("libosdp-scbk-{}", std::process::id()));
+        let mut store = FileKeyStore::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+
+        store.store(&[0x5a; 16]).unwrap();
+        store.store(&[0xa5; 16]).unwrap();
+        assert_eq!(FileKeyStore::new(&path).load().unwrap(), Some([0xa5; 16]));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::write(&path, [0u8; 8]).unwrap();
+        assert!(matches!(store.load(), Err(OsdpError::KeyStore(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
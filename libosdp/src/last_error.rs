@@ -0,0 +1,62 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A PD that keeps going offline and coming back is usually either rejecting
+//! what the CP sends it or not replying in time. The CP remembers the last
+//! such error of each PD (see [`crate::ControlPanel::last_error`]) to tell
+//! these apart.
+
+/// NAK reason code for a sequence number error
+const NAK_SEQUENCE_ERROR: u8 = 0x04;
+
+/// NAK reason codes that may go away if the command is sent again: a message
+/// check character error, a sequence number error and "unable to process
+/// command record" (usually a busy PD).
+pub(crate) const RETRYABLE_NAKS: [u8; 3] = [0x01, NAK_SEQUENCE_ERROR, 0x09];
+
+/// What went wrong with a PD
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum PdErrorKind {
+    /// The PD NAK'd a command with this reason code (0 if it could not be read
+    /// as the reply was encrypted)
+    Nak(u8),
+    /// The PD NAK'd a command as it was out of sequence
+    Sequence,
+    /// The PD did not reply in time, so the CP had to send the command again
+    Timeout,
+    /// The PD replied with a checksum where CRC-16 is required (see
+    /// [`crate::PdInfoBuilder::integrity`]); the reply was dropped
+    Checksum,
+}
+
+impl PdErrorKind {
+    pub(crate) fn from_nak(reason: u8) -> Self {
+        match reason {
+            NAK_SEQUENCE_ERROR => PdErrorKind::Sequence,
+            reason => PdErrorKind::Nak(reason),
+        }
+    }
+}
+
+/// The last error of a PD, as returned by [`crate::ControlPanel::last_error`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PdError {
+    /// What went wrong
+    pub kind: PdErrorKind,
+    /// When it went wrong
+    #[cfg(feature = "std")]
+    pub time: std::time::SystemTime,
+}
+
+impl PdError {
+    pub(crate) fn now(kind: PdErrorKind) -> Self {
+        Self {
+            kind,
+            #[cfg(feature = "std")]
+            time: std::time::SystemTime::now(),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rising round-trip times are usually the first sign of trouble on a bus:
+//! failing wiring (and the retransmissions that come with it), or a
+//! multi-drop segment with more PDs (or more traffic) than its baud rate can
+//! carry. The CP times each command, from the time its last byte is written
+//! until the last byte of the reply is read, and keeps the most recent ones
+//! per PD (see [`crate::ControlPanel::latency_stats`]).
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::time::Duration;
+
+/// Number of round trips that [`LatencyStats`] are computed over
+pub const LATENCY_WINDOW: usize = 256;
+
+/// Round-trip times of the last (up to) [`LATENCY_WINDOW`] commands sent to a
+/// PD, including POLLs, and the replies to them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct LatencyStats {
+    /// Number of round trips these are computed over
+    pub samples: usize,
+    /// Shortest round trip
+    pub min: Duration,
+    /// Median round trip
+    pub p50: Duration,
+    /// 90th percentile round trip
+    pub p90: Duration,
+    /// 99th percentile round trip
+    pub p99: Duration,
+    /// Longest round trip
+    pub max: Duration,
+}
+
+/// A point in time to measure round trips from. Without `std`, this is read
+/// from the [`crate::TimeSource`] and so has millisecond resolution.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Stamp {
+    #[cfg(feature = "std")]
+    at: std::time::Instant,
+    #[cfg(not(feature = "std"))]
+    at: u64,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            at: std::time::Instant::now(),
+            #[cfg(not(feature = "std"))]
+            at: crate::time::millis_now().unwrap_or(0),
+        }
+    }
+
+    /// Time from `earlier` to this one; zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Stamp) -> Duration {
+        #[cfg(feature = "std")]
+        return self.at.saturating_duration_since(earlier.at);
+        #[cfg(not(feature = "std"))]
+        return Duration::from_millis(self.at.saturating_sub(earlier.at));
+    }
+
+    #[cfg(test)]
+    pub fn later(&self, by: Duration) -> Self {
+        Self { at: self.at + by }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        #[cfg(feature = "std")]
+        return self.at.elapsed();
+        #[cfg(not(feature = "std"))]
+        return Duration::from_millis(
+            crate::time::millis_now()
+                .unwrap_or(0)
+                .saturating_sub(self.at),
+        );
+    }
+}
+
+/// The last [`LATENCY_WINDOW`] round trips of a PD
+#[derive(Debug, Default)]
+pub(crate) struct LatencyWindow {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyWindow {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    pub fn stats(&self) -> Option<LatencyStats> {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        // Nearest rank
+        let percentile = |p: usize| sorted[(p * sorted.len()).div_ceil(100).max(1) - 1];
+        Some(LatencyStats {
+            samples: sorted.len(),
+            min: *sorted.first()?,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_window() {
+        let mut window = LatencyWindow::default();
+        assert_eq!(window.stats(), None);
+
+        window.record(Duration::from_millis(7));
+        let stats = window.stats().unwrap();
+        assert_eq!(stats.samples, 1);
+        assert_eq!(stats.p50, Duration::from_millis(7));
+        assert_eq!(stats.p99, Duration::from_millis(7));
+
+        // Older round trips fall out of the window
+        for ms in (1..=LATENCY_WINDOW + 100).rev() {
+            window.record(Duration::from_millis(ms as u64));
+        }
+        let stats = window.stats().unwrap();
+        assert_eq!(stats.samples, LATENCY_WINDOW);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(256));
+        assert_eq!(stats.p50, Duration::from_millis(128));
+        assert_eq!(stats.p90, Duration::from_millis(231));
+        assert_eq!(stats.p99, Duration::from_millis(254));
+    }
+}
@@ -52,6 +52,46 @@
 //!
 //! You can find a template implementation for CP app [here][3] and PD app [here][4].
 //!
+//! ## `no_std` support
+//!
+//! This crate only needs `alloc`; disable the default features to use it in
+//! firmware (for instance, a card reader running as a PD or an RTOS based
+//! door controller running as a CP):
+//!
+//! ```toml
+//! libosdp = { version = "0.1", default-features = false, features = ["defmt-03"] }
+//! ```
+//!
+//! Without `std`, LibOSDP log messages are forwarded to `defmt` (with the
+//! `defmt-03` feature) or the `log` crate (with the `log` feature). They can
+//! also be routed to any other sink, such as a UART, with
+//! [`PeripheralDevice::set_log_sink`] or [`ControlPanel::set_log_sink`].
+//! LibOSDP also needs a millisecond clock and, for the secure channel, a
+//! random number generator, which firmware provides with `set_time_source`
+//! and `set_rng_source` before creating a CP or PD:
+//!
+//! ```ignore
+//! libosdp::set_time_source(|| systick_millis());
+//! libosdp::set_rng_source(|buf: &mut [u8]| trng_fill(buf));
+//! ```
+//!
+//! The same goes for `wasm32-unknown-unknown`, where the standard library has
+//! no clock; simulators running in a browser provide one from JavaScript
+//! (`performance.now()`, for instance) and move the bytes with a `JsChannel`.
+//! `wasm32-wasip1` works with `std`.
+//!
+//! Types that depend on threads or the system clock, such as `BusMonitor`,
+//! are only available with `std`.
+//!
+//! The secure channel uses a software AES implementation. With the
+//! `aes-backend` feature, firmware can hand it over to a crypto accelerator
+//! with `set_aes_backend`.
+//!
+//! Commands and events carry their data in `Vec`s. With the `heapless`
+//! feature, [`no_alloc`] has fixed-capacity versions of them, with which
+//! PDs take commands and send events without allocating. The crate still
+//! needs a global allocator, for setup.
+//!
 //! [1]: https://libosdp.sidcha.dev/protocol/
 //! [2]: https://www.securityindustry.org/industry-standards/open-supervised-device-protocol/
 //! [3]: https://docs.rs/crate/libosdp/latest/source/examples/cp.rs
@@ -63,85 +103,208 @@
 
 extern crate alloc;
 
+#[cfg(feature = "aes-backend")]
+mod aes;
+#[cfg(feature = "tokio")]
+mod async_channel;
+#[cfg(feature = "std")]
+mod bus_monitor;
+mod callback;
+#[cfg(feature = "std")]
+mod capture;
 mod channel;
 mod commands;
+#[cfg(feature = "std")]
+mod conformance;
 mod cp;
+#[cfg(feature = "std")]
+mod discover;
 mod events;
 mod file;
+mod history;
+#[cfg(feature = "embedded-hal")]
+pub mod hw;
+mod integrity;
+mod keystore;
+mod last_error;
+mod latency;
+mod logger;
+mod mqtt;
+#[cfg(feature = "heapless")]
+pub mod no_alloc;
+#[cfg(feature = "std")]
+mod offline;
 mod pd;
+mod pdbitset;
 mod pdcap;
 mod pdid;
 mod pdinfo;
+mod pdstate;
+mod pending;
+#[cfg(feature = "std")]
+mod provisioning;
+mod report;
+#[cfg(feature = "std")]
+mod retry;
+#[cfg(not(feature = "std"))]
+mod rng;
+mod runtime_state;
+#[cfg(feature = "std")]
+mod schedule;
+mod smart_card;
+#[cfg(feature = "std")]
+mod split;
+#[cfg(feature = "std")]
+mod sync;
+#[cfg(feature = "std")]
+mod telemetry;
+// Also built for tests so they run on the host; LibOSDP only reads the
+// clock from it without std.
+#[cfg(any(test, not(feature = "std")))]
+mod time;
+mod timing;
+mod trace;
+pub mod wire;
 
 // Re-export for convenience
+#[cfg(feature = "aes-backend")]
+pub use aes::*;
+#[cfg(feature = "tokio")]
+pub use async_channel::*;
+#[cfg(feature = "std")]
+pub use bus_monitor::*;
+pub use callback::*;
 pub use channel::*;
 pub use commands::*;
+#[cfg(feature = "std")]
+pub use conformance::*;
+#[cfg(feature = "std")]
+pub use discover::*;
 pub use events::*;
 pub use file::*;
+pub use history::*;
+pub use integrity::*;
+pub use keystore::*;
+pub use last_error::*;
+pub use latency::*;
+pub use logger::*;
+pub use mqtt::*;
+pub use pdbitset::*;
 pub use pdcap::*;
 pub use pdid::*;
 pub use pdinfo::*;
+pub use pdstate::*;
+#[cfg(feature = "std")]
+pub use provisioning::*;
+pub use report::*;
+#[cfg(feature = "std")]
+pub use retry::*;
+#[cfg(not(feature = "std"))]
+pub use rng::*;
+pub use runtime_state::*;
+#[cfg(feature = "std")]
+pub use schedule::*;
+pub use smart_card::*;
+#[cfg(feature = "std")]
+pub use split::*;
+#[cfg(feature = "std")]
+pub use sync::*;
+#[cfg(feature = "std")]
+pub use telemetry::*;
+#[cfg(not(feature = "std"))]
+pub use time::*;
+pub use trace::*;
 
 #[allow(unused_imports)]
 use alloc::{borrow::ToOwned, boxed::Box, format, string::String};
 
-#[cfg(feature = "std")]
-use thiserror::Error;
-
 pub use cp::{ControlPanel, ControlPanelBuilder};
 pub use pd::PeripheralDevice;
 
 /// OSDP public errors
 #[derive(Debug, Default)]
-#[cfg_attr(feature = "std", derive(Error))]
 pub enum OsdpError {
     /// PD info error
-    #[cfg_attr(feature = "std", error("Invalid PdInfo {0}"))]
     PdInfo(&'static str),
 
     /// Command build/send error
-    #[cfg_attr(feature = "std", error("Invalid OsdpCommand"))]
     Command,
 
     /// Event build/send error
-    #[cfg_attr(feature = "std", error("Invalid OsdpEvent"))]
     Event,
 
     /// PD/CP status query error
-    #[cfg_attr(feature = "std", error("Failed to query {0} from device"))]
     Query(&'static str),
 
     /// File transfer errors
-    #[cfg_attr(feature = "std", error("File transfer failed: {0}"))]
     FileTransfer(&'static str),
 
     /// CP/PD device setup failed.
-    #[cfg_attr(feature = "std", error("Failed to setup device"))]
     Setup,
 
     /// String parse error
-    #[cfg_attr(feature = "std", error("Type {0} parse error"))]
     Parse(String),
 
     /// OSDP channel error
-    #[cfg_attr(feature = "std", error("Channel error: {0}"))]
-    Channel(&'static str),
+    Channel(ChannelError),
 
     /// String conversion error
-    #[cfg_attr(feature = "std", error("PD info build error: {0}"))]
     PdInfoBuilder(&'static str),
 
+    /// Malformed OSDP packet
+    Wire(&'static str),
+
+    /// PD NAK'd a command; carries the NAK reason code (0 if it could not be
+    /// read as the reply was encrypted)
+    Nak(u8),
+
+    /// Timed out waiting for a PD
+    Timeout,
+
+    /// A smart card exchange (see [`crate::TransparentSession`]) failed
+    SmartCard(&'static str),
+
+    /// There is no PD at this offset
+    InvalidPd(i32),
+
+    /// There is no PD at this address, or there are several (on different
+    /// channels)
+    InvalidAddress(i32),
+
+    /// The PD reported an identity other than the one it was expected to
+    /// have (see [`crate::PdInfoBuilder::expected_id`])
+    IdMismatch {
+        /// Offset of the PD
+        pd: i32,
+        /// Identity that was expected
+        expected: PdId,
+        /// Identity that the PD reported
+        reported: PdId,
+    },
+
+    /// A pin (or other peripheral) driven by [`crate::hw::Hardware`] failed
+    Hardware(&'static str),
+
+    /// A [`crate::SecureKeyStore`] failed to load or store a key
+    KeyStore(&'static str),
+
+    /// LibOSDP refused to take a command or event
+    Refused {
+        /// Why it was refused, as far as it can be told
+        kind: OsdpErrorKind,
+        /// Return code of the LibOSDP call
+        rc: i32,
+    },
+
     /// IO Error
     #[cfg(feature = "std")]
-    #[error("IO Error")]
-    IO(#[from] std::io::Error),
+    IO(std::io::Error),
     /// IO Error
     #[cfg(not(feature = "std"))]
     IO(Box<dyn embedded_io::Error>),
 
     /// Unknown error
     #[default]
-    #[cfg_attr(feature = "std", error("Unknown/Unspecified error"))]
     Unknown,
 }
 
@@ -158,12 +321,143 @@ impl defmt::Format for OsdpError {
             OsdpError::Parse(e) => defmt::write!(f, "OsdpError::Parse({0})", e.as_str()),
             OsdpError::Channel(e) => defmt::write!(f, "OsdpError::Channel({0})", e),
             OsdpError::PdInfoBuilder(e) => defmt::write!(f, "OsdpError::PdInfoBuilder({0})", e),
+            OsdpError::Wire(e) => defmt::write!(f, "OsdpError::Wire({0})", e),
+            OsdpError::Nak(e) => defmt::write!(f, "OsdpError::Nak({0})", e),
+            OsdpError::Timeout => defmt::write!(f, "OsdpError::Timeout"),
+            OsdpError::SmartCard(e) => defmt::write!(f, "OsdpError::SmartCard({0})", e),
+            OsdpError::InvalidPd(e) => defmt::write!(f, "OsdpError::InvalidPd({0})", e),
+            OsdpError::InvalidAddress(e) => defmt::write!(f, "OsdpError::InvalidAddress({0})", e),
+            OsdpError::IdMismatch { pd, .. } => defmt::write!(f, "OsdpError::IdMismatch({0})", pd),
+            OsdpError::Hardware(e) => defmt::write!(f, "OsdpError::Hardware({0})", e),
+            OsdpError::KeyStore(e) => defmt::write!(f, "OsdpError::KeyStore({0})", e),
+            OsdpError::Refused { kind, rc } => {
+                defmt::write!(f, "OsdpError::Refused({0}, {1})", kind, rc)
+            }
             OsdpError::IO(_) => defmt::write!(f, "OsdpError::IO"), // Error cannot be formatted, because there is no way to set defmt::Format as a bound
             OsdpError::Unknown => defmt::write!(f, "OsdpError::Unknown"),
         }
     }
 }
 
+impl core::fmt::Display for OsdpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OsdpError::PdInfo(e) => write!(f, "Invalid PdInfo {e}"),
+            OsdpError::Command => write!(f, "Invalid OsdpCommand"),
+            OsdpError::Event => write!(f, "Invalid OsdpEvent"),
+            OsdpError::Query(e) => write!(f, "Failed to query {e} from device"),
+            OsdpError::FileTransfer(e) => write!(f, "File transfer failed: {e}"),
+            OsdpError::Setup => write!(f, "Failed to setup device"),
+            OsdpError::Parse(e) => write!(f, "Type {e} parse error"),
+            OsdpError::Channel(e) => write!(f, "Channel error: {e}"),
+            OsdpError::PdInfoBuilder(e) => write!(f, "PD info build error: {e}"),
+            OsdpError::Wire(e) => write!(f, "Malformed packet: {e}"),
+            OsdpError::Nak(e) => write!(f, "Command NAK'd by PD with reason {e:#04x}"),
+            OsdpError::Timeout => write!(f, "Timed out"),
+            OsdpError::SmartCard(e) => write!(f, "Smart card error: {e}"),
+            OsdpError::InvalidPd(e) => write!(f, "Invalid PD offset {e}"),
+            OsdpError::InvalidAddress(e) => write!(f, "No single PD at address {e}"),
+            OsdpError::IdMismatch {
+                pd,
+                expected,
+                reported,
+            } => write!(f, "PD-{pd} reported '{reported}' instead of '{expected}'"),
+            OsdpError::Hardware(e) => write!(f, "Hardware error: {e}"),
+            OsdpError::KeyStore(e) => write!(f, "Key store error: {e}"),
+            OsdpError::Refused { kind, rc } => write!(f, "Refused by LibOSDP ({kind:?}, rc {rc})"),
+            OsdpError::IO(_) => write!(f, "IO Error"),
+            OsdpError::Unknown => write!(f, "Unknown/Unspecified error"),
+        }
+    }
+}
+
+// `core::error::Error` is what `std::error::Error` re-exports, so this works
+// the same with and without std.
+impl core::error::Error for OsdpError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            OsdpError::IO(e) => Some(e),
+            OsdpError::Channel(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for OsdpError {
+    fn from(value: std::io::Error) -> Self {
+        OsdpError::IO(value)
+    }
+}
+
+/// Broad classes of [`OsdpError`]; see [`OsdpError::kind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpErrorKind {
+    /// Something passed by the application is not valid
+    InvalidArgument,
+    /// Something received from a peer (or read from a file) is not valid
+    InvalidData,
+    /// The PD is offline
+    Offline,
+    /// The command (or event) queue is full
+    QueueFull,
+    /// The PD is not in a state that allows this; for instance, a KEYSET
+    /// without a secure channel
+    NotPermitted,
+    /// The PD NAK'd a command
+    Nak,
+    /// Timed out waiting for a PD
+    Timeout,
+    /// The channel (or other IO) failed
+    Io,
+    /// Anything else
+    Other,
+}
+
+impl OsdpError {
+    /// The class of this error.
+    pub fn kind(&self) -> OsdpErrorKind {
+        match self {
+            OsdpError::PdInfo(_)
+            | OsdpError::PdInfoBuilder(_)
+            | OsdpError::Command
+            | OsdpError::Event
+            | OsdpError::InvalidPd(_)
+            | OsdpError::InvalidAddress(_) => OsdpErrorKind::InvalidArgument,
+            OsdpError::Parse(_) | OsdpError::Wire(_) => OsdpErrorKind::InvalidData,
+            OsdpError::Nak(_) => OsdpErrorKind::Nak,
+            OsdpError::Timeout => OsdpErrorKind::Timeout,
+            OsdpError::IdMismatch { .. } => OsdpErrorKind::NotPermitted,
+            OsdpError::Channel(_)
+            | OsdpError::Hardware(_)
+            | OsdpError::KeyStore(_)
+            | OsdpError::IO(_) => OsdpErrorKind::Io,
+            OsdpError::Refused { kind, .. } => *kind,
+            OsdpError::Query(_)
+            | OsdpError::FileTransfer(_)
+            | OsdpError::SmartCard(_)
+            | OsdpError::Setup
+            | OsdpError::Unknown => OsdpErrorKind::Other,
+        }
+    }
+
+    /// Whether doing the same thing again later may succeed: the PD was
+    /// offline, busy or did not reply in time, a queue was full, or it NAK'd
+    /// a command for a reason that may go away.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OsdpError::Nak(reason) => last_error::RETRYABLE_NAKS.contains(reason),
+            OsdpError::Channel(e) => *e == ChannelError::WouldBlock,
+            e => matches!(
+                e.kind(),
+                OsdpErrorKind::Offline | OsdpErrorKind::QueueFull | OsdpErrorKind::Timeout
+            ),
+        }
+    }
+}
+
 impl From<core::convert::Infallible> for OsdpError {
     fn from(_: core::convert::Infallible) -> Self {
         unreachable!()
@@ -172,10 +466,7 @@ impl From<core::convert::Infallible> for OsdpError {
 
 impl From<ChannelError> for OsdpError {
     fn from(value: ChannelError) -> OsdpError {
-        match value {
-            ChannelError::WouldBlock => OsdpError::Channel("WouldBlock"),
-            ChannelError::TransportError => OsdpError::Channel("TransportError"),
-        }
+        OsdpError::Channel(value)
     }
 }
 
@@ -214,6 +505,13 @@ bitflags::bitflags! {
     }
 }
 
+#[cfg(feature = "defmt-03")]
+impl defmt::Format for OsdpFlag {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "OsdpFlag({=u32:#x})", self.bits())
+    }
+}
+
 impl core::str::FromStr for OsdpFlag {
     type Err = OsdpError;
 
@@ -227,12 +525,6 @@ impl core::str::FromStr for OsdpFlag {
     }
 }
 
-#[allow(dead_code)]
-fn cstr_to_string(s: *const ::core::ffi::c_char) -> String {
-    let s = unsafe { core::ffi::CStr::from_ptr(s) };
-    s.to_str().unwrap().to_owned()
-}
-
 /// Get LibOSDP version
 pub fn get_version() -> &'static str {
     let s = unsafe { libosdp_sys::osdp_get_version() };
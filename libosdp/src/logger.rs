@@ -0,0 +1,343 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP (the C library) has a single, process wide, log callback. To allow
+//! multiple [`crate::ControlPanel`] and [`crate::PeripheralDevice`] instances
+//! to co-exist in the same process without stomping on each other's logs, this
+//! module keeps track of the instance that is currently executing inside the
+//! C library and routes the log messages it emits to that instance's
+//! [`LogSink`].
+
+use crate::callback::catch_panic;
+use alloc::{boxed::Box, string::String};
+use core::{
+    ffi::{c_char, c_int, c_ulong, CStr},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// The most verbose level set on any instance with [`LogContext::set_level`]
+static INSTANCE_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Emergency as u8);
+
+/// The level LibOSDP was last set up with, in [`init_libosdp`]
+static LIBOSDP_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// Log levels used by LibOSDP. These mirror syslog severities; a level is
+/// "greater" than another if it is more verbose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u8)]
+pub enum LogLevel {
+    /// System is unusable
+    Emergency = 0,
+    /// Action must be taken immediately
+    Alert,
+    /// Critical conditions
+    Critical,
+    /// Error conditions
+    Error,
+    /// Warning conditions
+    Warning,
+    /// Normal but significant conditions
+    Notice,
+    /// Informational messages
+    Info,
+    /// Debug-level messages
+    Debug,
+}
+
+impl LogLevel {
+    fn from_raw(level: c_int) -> Self {
+        match level as libosdp_sys::osdp_log_level_e {
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_EMERG => LogLevel::Emergency,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_ALERT => LogLevel::Alert,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_CRIT => LogLevel::Critical,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_ERROR => LogLevel::Error,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_WARNING => LogLevel::Warning,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_NOTICE => LogLevel::Notice,
+            libosdp_sys::osdp_log_level_e_OSDP_LOG_INFO => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+/// Set the process wide log level. Messages more verbose than `level` are
+/// dropped for all CP and PD instances that don't have their own level set
+/// (see [`crate::ControlPanelBuilder::log_level`] and
+/// [`crate::PeripheralDevice::set_log_level`]). This can be changed at any
+/// time and takes effect immediately.
+///
+/// LibOSDP is set up with this level too (or with a more verbose one, if an
+/// instance has its own level set), so that it doesn't format messages that
+/// would be dropped. LibOSDP only applies it to CPs and PDs set up after the
+/// call; messages of the ones already running are still filtered here.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    init_libosdp();
+}
+
+/// Get the current process wide log level. See [`set_log_level`].
+pub fn log_level() -> LogLevel {
+    LogLevel::from_raw(LOG_LEVEL.load(Ordering::Relaxed) as c_int)
+}
+
+/// Set LibOSDP's logger up with the most verbose of the process wide and the
+/// instance levels, and route its messages to [`log_handler`].
+fn init_libosdp() {
+    let level = INSTANCE_LEVEL
+        .load(Ordering::Relaxed)
+        .max(log_level() as u8);
+    LIBOSDP_LEVEL.store(level, Ordering::Relaxed);
+    unsafe {
+        libosdp_sys::osdp_logger_init(c"OSDP".as_ptr(), level as c_int, None);
+        libosdp_sys::osdp_set_log_callback(Some(log_handler));
+    }
+}
+
+/// A single log message emitted by LibOSDP.
+#[derive(Clone, Copy, Debug)]
+pub struct LogRecord<'a> {
+    /// Severity of this message
+    pub level: LogLevel,
+    /// Source file (in LibOSDP) that emitted this message
+    pub file: &'a str,
+    /// Line number in `file`
+    pub line: u32,
+    /// Name of the CP or PD instance that owns this message
+    pub name: &'a str,
+    /// The log message
+    pub message: &'a str,
+}
+
+/// A destination for log messages of a CP or PD instance.
+///
+/// This trait is implemented for all `Fn(&LogRecord<'_>) + Send` closures so
+/// a simple closure can be used as a sink as well.
+pub trait LogSink: Send {
+    /// Handle a log record
+    fn log(&self, record: &LogRecord<'_>);
+}
+
+impl<F> LogSink for F
+where
+    F: Fn(&LogRecord<'_>) + Send,
+{
+    fn log(&self, record: &LogRecord<'_>) {
+        self(record)
+    }
+}
+
+impl core::fmt::Debug for dyn LogSink {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("LogSink")
+    }
+}
+
+/// Forward a log record to the `log` or `defmt` crate (whichever is enabled).
+/// This is used for instances that don't have a [`LogSink`] set.
+fn default_sink(_record: &LogRecord<'_>) {
+    #[cfg(feature = "defmt-03")]
+    {
+        let (name, msg) = (_record.name, _record.message);
+        match _record.level {
+            LogLevel::Emergency | LogLevel::Alert | LogLevel::Critical | LogLevel::Error => {
+                defmt::error!("{}: {}", name, msg)
+            }
+            LogLevel::Warning | LogLevel::Notice => defmt::warn!("{}: {}", name, msg),
+            LogLevel::Info => defmt::info!("{}: {}", name, msg),
+            LogLevel::Debug => defmt::debug!("{}: {}", name, msg),
+        }
+    }
+    #[cfg(all(feature = "log", not(feature = "defmt-03")))]
+    {
+        let level = match _record.level {
+            LogLevel::Emergency | LogLevel::Alert | LogLevel::Critical | LogLevel::Error => {
+                log::Level::Error
+            }
+            LogLevel::Warning | LogLevel::Notice => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+        };
+        log::log!(level, "{}: {}", _record.name, _record.message);
+    }
+}
+
+/// Per-instance logging state. This must live at a stable address (boxed) for
+/// as long as the owning instance is alive.
+pub(crate) struct LogContext {
+    name: String,
+    sink: Option<Box<dyn LogSink>>,
+    level: Option<LogLevel>,
+}
+
+impl core::fmt::Debug for LogContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("LogContext")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl LogContext {
+    pub fn new(name: String) -> Box<Self> {
+        init_libosdp();
+        Box::new(Self {
+            name,
+            sink: None,
+            level: None,
+        })
+    }
+
+    pub fn set_sink(&mut self, sink: Option<Box<dyn LogSink>>) {
+        self.sink = sink;
+    }
+
+    /// Mark this context as the owner of all logs emitted until the returned
+    /// guard is dropped. Calls into LibOSDP that can emit logs must be made
+    /// while holding this guard.
+    pub fn enter(&self) -> LogScope {
+        LogScope {
+            prev: current::replace(self),
+        }
+    }
+
+    pub fn set_level(&mut self, level: Option<LogLevel>) {
+        self.level = level;
+        // Only loads and stores; see the no_std `current` module
+        if let Some(level) = level {
+            if level as u8 > INSTANCE_LEVEL.load(Ordering::Relaxed) {
+                INSTANCE_LEVEL.store(level as u8, Ordering::Relaxed);
+                init_libosdp();
+            }
+        }
+    }
+
+    fn log(&self, record: &LogRecord<'_>) {
+        if record.level > self.level.unwrap_or_else(log_level) {
+            return;
+        }
+        match &self.sink {
+            Some(sink) => sink.log(record),
+            None => default_sink(record),
+        }
+    }
+}
+
+/// Guard returned by [`LogContext::enter`]; restores the previous owner (if
+/// any) on drop so nested calls (from within callbacks) are attributed
+/// correctly.
+pub(crate) struct LogScope {
+    prev: *const LogContext,
+}
+
+impl Drop for LogScope {
+    fn drop(&mut self) {
+        current::restore(self.prev);
+    }
+}
+
+#[cfg(feature = "std")]
+mod current {
+    use super::LogContext;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static CURRENT: Cell<*const LogContext> = const { Cell::new(core::ptr::null()) };
+    }
+
+    pub fn replace(ctx: &LogContext) -> *const LogContext {
+        CURRENT.with(|c| c.replace(ctx))
+    }
+
+    pub fn restore(prev: *const LogContext) {
+        CURRENT.with(|c| c.set(prev))
+    }
+
+    pub fn get() -> *const LogContext {
+        CURRENT.with(|c| c.get())
+    }
+}
+
+// Without std there are no threads to worry about; LibOSDP contexts are
+// expected to be driven from a single execution context. Only atomic loads
+// and stores are used here as targets such as thumbv6m don't have CAS.
+#[cfg(not(feature = "std"))]
+mod current {
+    use super::LogContext;
+    use core::sync::atomic::{AtomicPtr, Ordering};
+
+    static CURRENT: AtomicPtr<LogContext> = AtomicPtr::new(core::ptr::null_mut());
+
+    pub fn replace(ctx: &LogContext) -> *const LogContext {
+        let prev = CURRENT.load(Ordering::Acquire);
+        CURRENT.store(ctx as *const _ as *mut _, Ordering::Release);
+        prev
+    }
+
+    pub fn restore(prev: *const LogContext) {
+        CURRENT.store(prev as *mut _, Ordering::Release)
+    }
+
+    pub fn get() -> *const LogContext {
+        CURRENT.load(Ordering::Acquire)
+    }
+}
+
+unsafe fn cstr<'a>(s: *const c_char) -> &'a str {
+    if s.is_null() {
+        return "";
+    }
+    CStr::from_ptr(s).to_str().unwrap_or("<non-utf8>")
+}
+
+unsafe extern "C" fn log_handler(
+    level: c_int,
+    file: *const c_char,
+    line: c_ulong,
+    msg: *const c_char,
+) {
+    let ctx = current::get();
+    let record = LogRecord {
+        level: LogLevel::from_raw(level),
+        file: cstr(file),
+        line: line as u32,
+        name: if ctx.is_null() { "OSDP" } else { &(*ctx).name },
+        message: cstr(msg).trim(),
+    };
+    if ctx.is_null() {
+        if record.level <= log_level() {
+            default_sink(&record)
+        }
+    } else {
+        catch_panic("LogSink::log", (), || (*ctx).log(&record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_log_level() {
+        let level = |l: &AtomicU8| LogLevel::from_raw(l.load(Ordering::Relaxed) as c_int);
+        set_log_level(LogLevel::Warning);
+        assert_eq!(log_level(), LogLevel::Warning);
+        assert_eq!(
+            level(&LIBOSDP_LEVEL),
+            LogLevel::Warning.max(level(&INSTANCE_LEVEL))
+        );
+
+        // An instance that wants more keeps LibOSDP at its level
+        let mut ctx = LogContext::new("PD".into());
+        ctx.set_level(Some(LogLevel::Info));
+        assert_eq!(level(&LIBOSDP_LEVEL), LogLevel::Info);
+        set_log_level(LogLevel::Error);
+        assert_eq!(log_level(), LogLevel::Error);
+        assert_eq!(level(&LIBOSDP_LEVEL), LogLevel::Info);
+        set_log_level(LogLevel::Debug);
+        assert_eq!(level(&LIBOSDP_LEVEL), LogLevel::Debug);
+    }
+}
@@ -0,0 +1,277 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! OSDP over MQTT. An [`MqttChannel`] carries the packets of an OSDP link over
+//! a pair of MQTT topics (one for each direction) so that a CP and its PDs can
+//! talk through a broker, for instance when the link is relayed through the
+//! cloud or when the devices already are a part of an MQTT based building
+//! automation system.
+//!
+//! This module does not implement MQTT itself; applications bring their own
+//! client (paho-mqtt, an embedded MQTT stack, ...) by implementing
+//! [`MqttClient`] for it. With the `mqtt` feature, [`RumqttcClient`] is one
+//! over a [rumqttc](https://docs.rs/rumqttc) client.
+
+use crate::{Channel, ChannelError};
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+
+/// A connected MQTT client that a [`MqttChannel`] uses to exchange messages
+/// with a broker.
+pub trait MqttClient: Send {
+    /// Subscribe to `topic`.
+    fn subscribe(&mut self, topic: &str) -> Result<(), ChannelError>;
+
+    /// Publish `payload` to `topic`.
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), ChannelError>;
+
+    /// Return the topic and payload of the next message received on any of
+    /// the subscribed topics, or `None` if there isn't one. This must not
+    /// block.
+    fn poll(&mut self) -> Result<Option<(String, Vec<u8>)>, ChannelError>;
+}
+
+/// A [`Channel`] over a pair of MQTT topics.
+///
+/// LibOSDP writes whole packets at a time and each of those writes is
+/// published as one MQTT message; on the receiving side, the payloads of
+/// messages are read back as a byte stream in the order they arrived.
+#[derive(Debug)]
+pub struct MqttChannel<C: MqttClient> {
+    id: i32,
+    client: C,
+    tx_topic: String,
+    rx_topic: String,
+    rx: VecDeque<u8>,
+}
+
+/// FNV-1a hash of `topic`, used as the channel ID.
+fn topic_to_channel_id(topic: &str) -> i32 {
+    let hash = topic.bytes().fold(0x811c9dc5u32, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    });
+    hash as i32
+}
+
+impl<C: MqttClient> MqttChannel<C> {
+    /// Create a channel that publishes packets to `tx_topic` and reads them
+    /// from `rx_topic` (which it subscribes `client` to).
+    pub fn new(mut client: C, tx_topic: &str, rx_topic: &str) -> Result<Self, ChannelError> {
+        client.subscribe(rx_topic)?;
+        Ok(Self {
+            id: topic_to_channel_id(tx_topic),
+            client,
+            tx_topic: tx_topic.into(),
+            rx_topic: rx_topic.into(),
+            rx: VecDeque::new(),
+        })
+    }
+
+    /// Create the CP end of a link; it publishes to `<prefix>/to-pd` and reads
+    /// from `<prefix>/to-cp`. See [`MqttChannel::for_pd`] for the other end.
+    pub fn for_cp(client: C, prefix: &str) -> Result<Self, ChannelError> {
+        Self::new(
+            client,
+            &format!("{prefix}/to-pd"),
+            &format!("{prefix}/to-cp"),
+        )
+    }
+
+    /// Create the PD end of a link; it publishes to `<prefix>/to-cp` and reads
+    /// from `<prefix>/to-pd`. See [`MqttChannel::for_cp`] for the other end.
+    pub fn for_pd(client: C, prefix: &str) -> Result<Self, ChannelError> {
+        Self::new(
+            client,
+            &format!("{prefix}/to-cp"),
+            &format!("{prefix}/to-pd"),
+        )
+    }
+
+    /// Get back the client of this channel.
+    pub fn into_client(self) -> C {
+        self.client
+    }
+
+    fn receive(&mut self) -> Result<(), ChannelError> {
+        while let Some((topic, payload)) = self.client.poll()? {
+            // The client may be shared with other subscribers
+            if topic == self.rx_topic {
+                self.rx.extend(payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: MqttClient> Channel for MqttChannel<C> {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        self.receive()?;
+        if self.rx.is_empty() {
+            return Err(ChannelError::WouldBlock);
+        }
+        let len = buf.len().min(self.rx.len());
+        for (dst, src) in buf.iter_mut().zip(self.rx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        self.client.publish(&self.tx_topic, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        Ok(())
+    }
+}
+
+/// An [`MqttClient`] over a rumqttc client, which drives its connection from
+/// [`MqttClient::poll`]. Messages are published and subscribed to with QoS 0:
+/// OSDP retries lost packets itself, and a packet delivered twice would be
+/// read twice.
+///
+/// ```ignore
+/// let options = rumqttc::MqttOptions::new("osdp-cp", "localhost", 1883);
+/// let (client, connection) = rumqttc::Client::new(options, 16);
+/// let channel = MqttChannel::for_cp(RumqttcClient::new(client, connection), "osdp/bus-1")?;
+/// ```
+#[cfg(feature = "mqtt")]
+pub struct RumqttcClient {
+    client: rumqttc::Client,
+    connection: rumqttc::Connection,
+}
+
+#[cfg(feature = "mqtt")]
+impl core::fmt::Debug for RumqttcClient {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("RumqttcClient")
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl RumqttcClient {
+    /// Wrap `client` and its `connection`, as returned by
+    /// `rumqttc::Client::new`.
+    pub fn new(client: rumqttc::Client, connection: rumqttc::Connection) -> Self {
+        Self { client, connection }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttClient for RumqttcClient {
+    fn subscribe(&mut self, topic: &str) -> Result<(), ChannelError> {
+        self.client
+            .subscribe(topic, rumqttc::QoS::AtMostOnce)
+            .map_err(|_| ChannelError::TransportError)
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), ChannelError> {
+        self.client
+            .try_publish(topic, rumqttc::QoS::AtMostOnce, false, payload)
+            .map_err(|_| ChannelError::TransportError)
+    }
+
+    fn poll(&mut self) -> Result<Option<(String, Vec<u8>)>, ChannelError> {
+        use rumqttc::{Event, Packet, TryRecvError};
+        loop {
+            match self.connection.try_recv() {
+                Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                    return Ok(Some((publish.topic, publish.payload.to_vec())))
+                }
+                Ok(Ok(_)) => {}
+                Err(TryRecvError::Empty) => return Ok(None),
+                // rumqttc reconnects on the next poll after a connection error
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    return Err(ChannelError::TransportError)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{MqttChannel, MqttClient};
+    use crate::{Channel, ChannelError};
+    use std::sync::{Arc, Mutex};
+
+    type Inbox = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// A "broker" that delivers every message to every client subscribed to
+    /// its topic.
+    #[derive(Default)]
+    struct Broker {
+        clients: Vec<(Vec<String>, Inbox)>,
+    }
+
+    struct TestClient {
+        broker: Arc<Mutex<Broker>>,
+        index: usize,
+        inbox: Inbox,
+    }
+
+    impl TestClient {
+        fn new(broker: &Arc<Mutex<Broker>>) -> Self {
+            let inbox = Arc::new(Mutex::new(Vec::new()));
+            let mut b = broker.lock().unwrap();
+            b.clients.push((Vec::new(), inbox.clone()));
+            Self {
+                broker: broker.clone(),
+                index: b.clients.len() - 1,
+                inbox,
+            }
+        }
+    }
+
+    impl MqttClient for TestClient {
+        fn subscribe(&mut self, topic: &str) -> Result<(), ChannelError> {
+            let mut broker = self.broker.lock().unwrap();
+            broker.clients[self.index].0.push(topic.into());
+            Ok(())
+        }
+
+        fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), ChannelError> {
+            let broker = self.broker.lock().unwrap();
+            for (topics, inbox) in &broker.clients {
+                if topics.iter().any(|t| t == topic) {
+                    inbox.lock().unwrap().push((topic.into(), payload.to_vec()));
+                }
+            }
+            Ok(())
+        }
+
+        fn poll(&mut self) -> Result<Option<(String, Vec<u8>)>, ChannelError> {
+            let mut inbox = self.inbox.lock().unwrap();
+            Ok((!inbox.is_empty()).then(|| inbox.remove(0)))
+        }
+    }
+
+    #[test]
+    fn test_mqtt_channel() {
+        let broker = Arc::new(Mutex::new(Broker::default()));
+        let mut cp = MqttChannel::for_cp(TestClient::new(&broker), "osdp/door-1").unwrap();
+        let mut pd = MqttChannel::for_pd(TestClient::new(&broker), "osdp/door-1").unwrap();
+        assert_ne!(cp.get_id(), pd.get_id());
+
+        let mut buf = [0u8; 4];
+        assert_eq!(pd.read(&mut buf), Err(ChannelError::WouldBlock));
+        cp.write(&[1, 2, 3]).unwrap();
+        cp.write(&[4, 5]).unwrap();
+        assert_eq!(pd.read(&mut buf), Ok(4));
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(pd.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 5);
+
+        // Nothing echoes back to the sender
+        pd.write(&[6]).unwrap();
+        assert_eq!(pd.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(cp.read(&mut buf), Ok(1));
+        assert_eq!(buf[0], 6);
+    }
+}
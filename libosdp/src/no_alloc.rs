@@ -0,0 +1,611 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixed-capacity versions of the commands and events that carry data:
+//! [`crate::OsdpCommand`] and [`crate::OsdpEvent`] hold it in a `Vec`, these
+//! hold it in a [`heapless::Vec`] as large as LibOSDP's own buffer for it.
+//! They convert to and from LibOSDP's structures without allocating; a PD
+//! gets commands as such with
+//! [`crate::PeripheralDevice::set_fixed_command_callback`] and sends events
+//! with [`crate::PeripheralDevice::notify_fixed_event`], and can keep them in
+//! fixed queues (such as `heapless::Deque`) in between.
+//!
+//! A PD still needs a global allocator: the crate depends on `alloc`, and the
+//! PD keeps its configuration and callbacks on the heap. With these types,
+//! it is only used at setup (and for [`crate::PeripheralDevice::state`], once
+//! per LED, buzzer or output), so it can be a small, fixed arena.
+//!
+//! Commands and events without data have no versions of their own here;
+//! [`OsdpCommand`] and [`OsdpEvent`] carry the ones of the crate root.
+
+use crate::{
+    OsdpCardFormats, OsdpComSet, OsdpCommandBuzzer, OsdpCommandFileTx, OsdpCommandLed,
+    OsdpCommandOutput, OsdpError, OsdpStatusReport,
+};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Capacity of [`OsdpCommandText::data`]
+pub const TEXT_MAX_LEN: usize = libosdp_sys::OSDP_CMD_TEXT_MAX_LEN as usize;
+/// Capacity of [`OsdpCommandKeyset::data`]
+pub const KEYSET_MAX_LEN: usize = libosdp_sys::OSDP_CMD_KEYSET_KEY_MAX_LEN as usize;
+/// Capacity of [`OsdpCommandMfg::data`]
+pub const MFG_MAX_LEN: usize = libosdp_sys::OSDP_CMD_MFG_MAX_DATALEN as usize;
+/// Capacity of [`OsdpEventCardRead::data`]
+pub const CARD_READ_MAX_LEN: usize = libosdp_sys::OSDP_EVENT_CARDREAD_MAX_DATALEN as usize;
+/// Capacity of [`OsdpEventKeyPress::data`]
+pub const KEY_PRESS_MAX_LEN: usize = libosdp_sys::OSDP_EVENT_KEYPRESS_MAX_DATALEN as usize;
+/// Capacity of [`OsdpEventMfgReply::data`]
+pub const MFG_REPLY_MAX_LEN: usize = libosdp_sys::OSDP_EVENT_MFGREP_MAX_DATALEN as usize;
+
+/// The first `len` bytes of a LibOSDP buffer; lengths past its end (which
+/// LibOSDP never reports) are cut short.
+fn fixed<const N: usize>(buf: &[u8], len: usize) -> Vec<u8, N> {
+    Vec::from_slice(&buf[..len.min(buf.len()).min(N)]).unwrap_or_default()
+}
+
+/// `data` in a LibOSDP buffer of the same capacity
+fn padded<const N: usize>(data: &Vec<u8, N>) -> [u8; N] {
+    let mut buf = [0; N];
+    buf[..data.len()].copy_from_slice(data);
+    buf
+}
+
+/// `data` in a [`heapless::Vec`] of capacity `N`, or `err` if it is larger
+fn bounded<const N: usize>(data: &[u8], err: OsdpError) -> Result<Vec<u8, N>, OsdpError> {
+    Vec::from_slice(data).map_err(|_| err)
+}
+
+/// [`crate::OsdpCommandText`] with at most [`TEXT_MAX_LEN`] bytes of text
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpCommandText {
+    /// See [`crate::OsdpCommandText::reader`]
+    pub reader: u8,
+    /// See [`crate::OsdpCommandText::control_code`]
+    pub control_code: u8,
+    /// See [`crate::OsdpCommandText::temp_time`]
+    pub temp_time: u8,
+    /// See [`crate::OsdpCommandText::offset_row`]
+    pub offset_row: u8,
+    /// See [`crate::OsdpCommandText::offset_col`]
+    pub offset_col: u8,
+    /// The string to display (ASCII codes)
+    pub data: Vec<u8, TEXT_MAX_LEN>,
+}
+
+impl From<libosdp_sys::osdp_cmd_text> for OsdpCommandText {
+    fn from(value: libosdp_sys::osdp_cmd_text) -> Self {
+        Self {
+            reader: value.reader,
+            control_code: value.control_code,
+            temp_time: value.temp_time,
+            offset_row: value.offset_row,
+            offset_col: value.offset_col,
+            data: fixed(&value.data, value.length as usize),
+        }
+    }
+}
+
+impl From<OsdpCommandText> for libosdp_sys::osdp_cmd_text {
+    fn from(value: OsdpCommandText) -> Self {
+        libosdp_sys::osdp_cmd_text {
+            reader: value.reader,
+            control_code: value.control_code,
+            temp_time: value.temp_time,
+            offset_row: value.offset_row,
+            offset_col: value.offset_col,
+            length: value.data.len() as u8,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpCommandKeyset`] with at most [`KEYSET_MAX_LEN`] bytes of key
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpCommandKeyset {
+    pub(crate) key_type: u8,
+    /// Key data
+    pub data: Vec<u8, KEYSET_MAX_LEN>,
+}
+
+impl OsdpCommandKeyset {
+    /// Create a new SCBK KeySet command for a given key
+    pub fn new_scbk(key: [u8; 16]) -> Self {
+        Self {
+            key_type: 1,
+            data: fixed(&key, key.len()),
+        }
+    }
+}
+
+impl From<libosdp_sys::osdp_cmd_keyset> for OsdpCommandKeyset {
+    fn from(value: libosdp_sys::osdp_cmd_keyset) -> Self {
+        Self {
+            key_type: value.type_,
+            data: fixed(&value.data, value.length as usize),
+        }
+    }
+}
+
+impl From<OsdpCommandKeyset> for libosdp_sys::osdp_cmd_keyset {
+    fn from(value: OsdpCommandKeyset) -> Self {
+        libosdp_sys::osdp_cmd_keyset {
+            type_: value.key_type,
+            length: value.data.len() as u8,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpCommandMfg`] with at most [`MFG_MAX_LEN`] bytes of data
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpCommandMfg {
+    /// 3-byte IEEE assigned OUI used as vendor code
+    pub vendor_code: (u8, u8, u8),
+    /// 1-byte manufacturer defined command ID
+    pub command: u8,
+    /// Command data (if any)
+    pub data: Vec<u8, MFG_MAX_LEN>,
+}
+
+impl From<libosdp_sys::osdp_cmd_mfg> for OsdpCommandMfg {
+    fn from(value: libosdp_sys::osdp_cmd_mfg) -> Self {
+        let bytes = value.vendor_code.to_le_bytes();
+        Self {
+            vendor_code: (bytes[0], bytes[1], bytes[2]),
+            command: value.command,
+            data: fixed(&value.data, value.length as usize),
+        }
+    }
+}
+
+impl From<OsdpCommandMfg> for libosdp_sys::osdp_cmd_mfg {
+    fn from(value: OsdpCommandMfg) -> Self {
+        let (a, b, c) = value.vendor_code;
+        libosdp_sys::osdp_cmd_mfg {
+            vendor_code: u32::from_le_bytes([a, b, c, 0]),
+            command: value.command,
+            length: value.data.len() as u8,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpCommand`] with fixed-capacity data; see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpCommand {
+    /// See [`crate::OsdpCommand::Led`]
+    Led(OsdpCommandLed),
+    /// See [`crate::OsdpCommand::Buzzer`]
+    Buzzer(OsdpCommandBuzzer),
+    /// See [`crate::OsdpCommand::Text`]
+    Text(OsdpCommandText),
+    /// See [`crate::OsdpCommand::Output`]
+    Output(OsdpCommandOutput),
+    /// See [`crate::OsdpCommand::ComSet`]
+    ComSet(OsdpComSet),
+    /// See [`crate::OsdpCommand::KeySet`]
+    KeySet(OsdpCommandKeyset),
+    /// See [`crate::OsdpCommand::Mfg`]
+    Mfg(OsdpCommandMfg),
+    /// See [`crate::OsdpCommand::FileTx`]
+    FileTx(OsdpCommandFileTx),
+    /// See [`crate::OsdpCommand::Status`]
+    Status(OsdpStatusReport),
+}
+
+impl OsdpCommand {
+    /// Get the [`crate::OsdpCommandKind`] of this command.
+    pub fn kind(&self) -> crate::OsdpCommandKind {
+        use crate::OsdpCommandKind as Kind;
+        match self {
+            OsdpCommand::Led(_) => Kind::Led,
+            OsdpCommand::Buzzer(_) => Kind::Buzzer,
+            OsdpCommand::Text(_) => Kind::Text,
+            OsdpCommand::Output(_) => Kind::Output,
+            OsdpCommand::ComSet(_) => Kind::ComSet,
+            OsdpCommand::KeySet(_) => Kind::KeySet,
+            OsdpCommand::Mfg(_) => Kind::Mfg,
+            OsdpCommand::FileTx(_) => Kind::FileTx,
+            OsdpCommand::Status(_) => Kind::Status,
+        }
+    }
+}
+
+impl From<OsdpCommand> for libosdp_sys::osdp_cmd {
+    fn from(value: OsdpCommand) -> Self {
+        use libosdp_sys::osdp_cmd__bindgen_ty_1 as Data;
+        let (id, data) = match value {
+            OsdpCommand::Led(c) => (libosdp_sys::osdp_cmd_e_OSDP_CMD_LED, Data { led: c.into() }),
+            OsdpCommand::Buzzer(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_BUZZER,
+                Data { buzzer: c.into() },
+            ),
+            OsdpCommand::Text(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_TEXT,
+                Data { text: c.into() },
+            ),
+            OsdpCommand::Output(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_OUTPUT,
+                Data { output: c.into() },
+            ),
+            OsdpCommand::ComSet(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_COMSET,
+                Data { comset: c.into() },
+            ),
+            OsdpCommand::KeySet(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_KEYSET,
+                Data { keyset: c.into() },
+            ),
+            OsdpCommand::Mfg(c) => (libosdp_sys::osdp_cmd_e_OSDP_CMD_MFG, Data { mfg: c.into() }),
+            OsdpCommand::FileTx(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_FILE_TX,
+                Data { file_tx: c.into() },
+            ),
+            OsdpCommand::Status(c) => (
+                libosdp_sys::osdp_cmd_e_OSDP_CMD_STATUS,
+                Data { status: c.into() },
+            ),
+        };
+        libosdp_sys::osdp_cmd {
+            id,
+            __bindgen_anon_1: data,
+        }
+    }
+}
+
+impl From<libosdp_sys::osdp_cmd> for OsdpCommand {
+    fn from(value: libosdp_sys::osdp_cmd) -> Self {
+        let data = value.__bindgen_anon_1;
+        match value.id {
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_LED => OsdpCommand::Led(unsafe { data.led.into() }),
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_BUZZER => {
+                OsdpCommand::Buzzer(unsafe { data.buzzer.into() })
+            }
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_TEXT => OsdpCommand::Text(unsafe { data.text.into() }),
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_OUTPUT => {
+                OsdpCommand::Output(unsafe { data.output.into() })
+            }
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_COMSET => {
+                OsdpCommand::ComSet(unsafe { data.comset.into() })
+            }
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_KEYSET => {
+                OsdpCommand::KeySet(unsafe { data.keyset.into() })
+            }
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_MFG => OsdpCommand::Mfg(unsafe { data.mfg.into() }),
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_FILE_TX => {
+                OsdpCommand::FileTx(unsafe { data.file_tx.into() })
+            }
+            libosdp_sys::osdp_cmd_e_OSDP_CMD_STATUS => {
+                OsdpCommand::Status(unsafe { data.status.into() })
+            }
+            _ => panic!("Unknown command"),
+        }
+    }
+}
+
+impl From<OsdpCommand> for crate::OsdpCommand {
+    fn from(value: OsdpCommand) -> Self {
+        libosdp_sys::osdp_cmd::from(value).into()
+    }
+}
+
+impl TryFrom<crate::OsdpCommand> for OsdpCommand {
+    type Error = OsdpError;
+
+    /// Fails with [`OsdpError::Command`] if the data doesn't fit
+    fn try_from(value: crate::OsdpCommand) -> Result<Self, Self::Error> {
+        let cmd = match value {
+            crate::OsdpCommand::Led(c) => OsdpCommand::Led(c),
+            crate::OsdpCommand::Buzzer(c) => OsdpCommand::Buzzer(c),
+            crate::OsdpCommand::Text(c) => OsdpCommand::Text(OsdpCommandText {
+                reader: c.reader,
+                control_code: c.control_code,
+                temp_time: c.temp_time,
+                offset_row: c.offset_row,
+                offset_col: c.offset_col,
+                data: bounded(&c.data, OsdpError::Command)?,
+            }),
+            crate::OsdpCommand::Output(c) => OsdpCommand::Output(c),
+            crate::OsdpCommand::ComSet(c) => OsdpCommand::ComSet(c),
+            crate::OsdpCommand::KeySet(c) => OsdpCommand::KeySet(OsdpCommandKeyset {
+                key_type: c.key_type,
+                data: bounded(&c.data, OsdpError::Command)?,
+            }),
+            crate::OsdpCommand::Mfg(c) => OsdpCommand::Mfg(OsdpCommandMfg {
+                vendor_code: c.vendor_code,
+                command: c.command,
+                data: bounded(&c.data, OsdpError::Command)?,
+            }),
+            crate::OsdpCommand::FileTx(c) => OsdpCommand::FileTx(c),
+            crate::OsdpCommand::Status(c) => OsdpCommand::Status(c),
+        };
+        Ok(cmd)
+    }
+}
+
+/// [`crate::OsdpEventCardRead`] with at most [`CARD_READ_MAX_LEN`] bytes of
+/// card data
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpEventCardRead {
+    /// See [`crate::OsdpEventCardRead::reader_no`]
+    pub reader_no: i32,
+    /// Format of the card that was read
+    pub format: OsdpCardFormats,
+    /// See [`crate::OsdpEventCardRead::direction`]
+    pub direction: bool,
+    /// See [`crate::OsdpEventCardRead::nr_bits`]
+    pub nr_bits: usize,
+    /// See [`crate::OsdpEventCardRead::data`]
+    pub data: Vec<u8, CARD_READ_MAX_LEN>,
+}
+
+impl From<libosdp_sys::osdp_event_cardread> for OsdpEventCardRead {
+    fn from(value: libosdp_sys::osdp_event_cardread) -> Self {
+        let format = value.format.into();
+        let len = value.length as usize;
+        let (nr_bits, nr_bytes) = match format {
+            OsdpCardFormats::Ascii => (0, len),
+            _ => (len, len.div_ceil(8)),
+        };
+        Self {
+            reader_no: value.reader_no,
+            format,
+            direction: value.direction == 1,
+            nr_bits,
+            data: fixed(&value.data, nr_bytes),
+        }
+    }
+}
+
+impl From<OsdpEventCardRead> for libosdp_sys::osdp_event_cardread {
+    fn from(value: OsdpEventCardRead) -> Self {
+        let length = match value.format {
+            OsdpCardFormats::Ascii => value.data.len() as i32,
+            _ => value.nr_bits as i32,
+        };
+        libosdp_sys::osdp_event_cardread {
+            reader_no: value.reader_no,
+            format: value.format.into(),
+            direction: value.direction as i32,
+            length,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpEventKeyPress`] with at most [`KEY_PRESS_MAX_LEN`] keys
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpEventKeyPress {
+    /// See [`crate::OsdpEventKeyPress::reader_no`]
+    pub reader_no: i32,
+    /// Key data
+    pub data: Vec<u8, KEY_PRESS_MAX_LEN>,
+}
+
+impl From<libosdp_sys::osdp_event_keypress> for OsdpEventKeyPress {
+    fn from(value: libosdp_sys::osdp_event_keypress) -> Self {
+        Self {
+            reader_no: value.reader_no,
+            data: fixed(&value.data, value.length as usize),
+        }
+    }
+}
+
+impl From<OsdpEventKeyPress> for libosdp_sys::osdp_event_keypress {
+    fn from(value: OsdpEventKeyPress) -> Self {
+        libosdp_sys::osdp_event_keypress {
+            reader_no: value.reader_no,
+            length: value.data.len() as i32,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpEventMfgReply`] with at most [`MFG_REPLY_MAX_LEN`] bytes of
+/// data
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OsdpEventMfgReply {
+    /// 3-byte IEEE assigned OUI used as vendor code
+    pub vendor_code: (u8, u8, u8),
+    /// 1-byte reply code
+    pub reply: u8,
+    /// Reply data (if any)
+    pub data: Vec<u8, MFG_REPLY_MAX_LEN>,
+}
+
+impl From<libosdp_sys::osdp_event_mfgrep> for OsdpEventMfgReply {
+    fn from(value: libosdp_sys::osdp_event_mfgrep) -> Self {
+        let bytes = value.vendor_code.to_le_bytes();
+        Self {
+            vendor_code: (bytes[0], bytes[1], bytes[2]),
+            reply: value.command,
+            data: fixed(&value.data, value.length as usize),
+        }
+    }
+}
+
+impl From<OsdpEventMfgReply> for libosdp_sys::osdp_event_mfgrep {
+    fn from(value: OsdpEventMfgReply) -> Self {
+        let (a, b, c) = value.vendor_code;
+        libosdp_sys::osdp_event_mfgrep {
+            vendor_code: u32::from_le_bytes([a, b, c, 0]),
+            command: value.reply,
+            length: value.data.len() as u8,
+            data: padded(&value.data),
+        }
+    }
+}
+
+/// [`crate::OsdpEvent`] with fixed-capacity data; see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OsdpEvent {
+    /// See [`crate::OsdpEvent::CardRead`]
+    CardRead(OsdpEventCardRead),
+    /// See [`crate::OsdpEvent::KeyPress`]
+    KeyPress(OsdpEventKeyPress),
+    /// See [`crate::OsdpEvent::MfgReply`]
+    MfgReply(OsdpEventMfgReply),
+    /// See [`crate::OsdpEvent::Status`]
+    Status(OsdpStatusReport),
+}
+
+impl OsdpEvent {
+    /// Get the [`crate::OsdpEventKind`] of this event.
+    pub fn kind(&self) -> crate::OsdpEventKind {
+        use crate::OsdpEventKind as Kind;
+        match self {
+            OsdpEvent::CardRead(_) => Kind::CardRead,
+            OsdpEvent::KeyPress(_) => Kind::KeyPress,
+            OsdpEvent::MfgReply(_) => Kind::MfgReply,
+            OsdpEvent::Status(_) => Kind::Status,
+        }
+    }
+}
+
+impl From<OsdpEvent> for libosdp_sys::osdp_event {
+    fn from(value: OsdpEvent) -> Self {
+        use libosdp_sys::osdp_event__bindgen_ty_1 as Data;
+        let (type_, data) = match value {
+            OsdpEvent::CardRead(e) => (
+                libosdp_sys::osdp_event_type_OSDP_EVENT_CARDREAD,
+                Data { cardread: e.into() },
+            ),
+            OsdpEvent::KeyPress(e) => (
+                libosdp_sys::osdp_event_type_OSDP_EVENT_KEYPRESS,
+                Data { keypress: e.into() },
+            ),
+            OsdpEvent::MfgReply(e) => (
+                libosdp_sys::osdp_event_type_OSDP_EVENT_MFGREP,
+                Data { mfgrep: e.into() },
+            ),
+            OsdpEvent::Status(e) => (
+                libosdp_sys::osdp_event_type_OSDP_EVENT_STATUS,
+                Data { status: e.into() },
+            ),
+        };
+        libosdp_sys::osdp_event {
+            type_,
+            __bindgen_anon_1: data,
+        }
+    }
+}
+
+impl From<libosdp_sys::osdp_event> for OsdpEvent {
+    fn from(value: libosdp_sys::osdp_event) -> Self {
+        let data = value.__bindgen_anon_1;
+        match value.type_ {
+            libosdp_sys::osdp_event_type_OSDP_EVENT_CARDREAD => {
+                OsdpEvent::CardRead(unsafe { data.cardread.into() })
+            }
+            libosdp_sys::osdp_event_type_OSDP_EVENT_KEYPRESS => {
+                OsdpEvent::KeyPress(unsafe { data.keypress.into() })
+            }
+            libosdp_sys::osdp_event_type_OSDP_EVENT_MFGREP => {
+                OsdpEvent::MfgReply(unsafe { data.mfgrep.into() })
+            }
+            libosdp_sys::osdp_event_type_OSDP_EVENT_STATUS => {
+                OsdpEvent::Status(unsafe { data.status.into() })
+            }
+            _ => panic!("Unknown event"),
+        }
+    }
+}
+
+impl From<OsdpEvent> for crate::OsdpEvent {
+    fn from(value: OsdpEvent) -> Self {
+        libosdp_sys::osdp_event::from(value).into()
+    }
+}
+
+impl TryFrom<crate::OsdpEvent> for OsdpEvent {
+    type Error = OsdpError;
+
+    /// Fails with [`OsdpError::Event`] if the data doesn't fit
+    fn try_from(value: crate::OsdpEvent) -> Result<Self, Self::Error> {
+        let event = match value {
+            crate::OsdpEvent::CardRead(e) => OsdpEvent::CardRead(OsdpEventCardRead {
+                reader_no: e.reader_no,
+                format: e.format,
+                direction: e.direction,
+                nr_bits: e.nr_bits,
+                data: bounded(&e.data, OsdpError::Event)?,
+            }),
+            crate::OsdpEvent::KeyPress(e) => OsdpEvent::KeyPress(OsdpEventKeyPress {
+                reader_no: e.reader_no,
+                data: bounded(&e.data, OsdpError::Event)?,
+            }),
+            crate::OsdpEvent::MfgReply(e) => OsdpEvent::MfgReply(OsdpEventMfgReply {
+                vendor_code: e.vendor_code,
+                reply: e.reply,
+                data: bounded(&e.data, OsdpError::Event)?,
+            }),
+            crate::OsdpEvent::Status(e) => OsdpEvent::Status(e),
+        };
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_fixed_command() {
+        let text = crate::OsdpCommand::Text(crate::OsdpCommandText {
+            reader: 1,
+            control_code: 3,
+            temp_time: 5,
+            offset_row: 1,
+            offset_col: 2,
+            data: b"Welcome".to_vec(),
+        });
+        let fixed = OsdpCommand::try_from(text.clone()).unwrap();
+        assert_eq!(fixed.kind(), crate::OsdpCommandKind::Text);
+        let cmd: libosdp_sys::osdp_cmd = fixed.clone().into();
+        assert_eq!(OsdpCommand::from(cmd), fixed);
+        assert_eq!(crate::OsdpCommand::from(fixed), text);
+
+        let mfg = crate::OsdpCommand::Mfg(crate::OsdpCommandMfg {
+            vendor_code: (0x0a, 0x0b, 0x0c),
+            command: 0x10,
+            data: vec![0x55; MFG_MAX_LEN + 1],
+        });
+        assert!(matches!(
+            OsdpCommand::try_from(mfg),
+            Err(OsdpError::Command)
+        ));
+    }
+
+    #[test]
+    fn test_fixed_event() {
+        let card = crate::OsdpEventCardRead::new_raw(20, vec![0xA5, 0x5A, 0xF0]).unwrap();
+        let fixed = OsdpEvent::try_from(crate::OsdpEvent::CardRead(card.clone())).unwrap();
+        let event: libosdp_sys::osdp_event = fixed.clone().into();
+        assert_eq!(unsafe { event.__bindgen_anon_1.cardread.length }, 20);
+        assert_eq!(OsdpEvent::from(event), fixed);
+        assert_eq!(
+            crate::OsdpEvent::from(fixed),
+            crate::OsdpEvent::CardRead(card)
+        );
+
+        let keys = crate::OsdpEventKeyPress::new(vec![b'1'; KEY_PRESS_MAX_LEN + 1]);
+        assert!(matches!(
+            OsdpEvent::try_from(crate::OsdpEvent::KeyPress(keys)),
+            Err(OsdpError::Event)
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP refuses commands for a PD that is offline. Access control
+//! head-ends usually expect non-critical commands (such as the LED state of a
+//! reader) to reach the PD once it is back. When enabled with
+//! [`crate::ControlPanelBuilder::offline_queue`], the CP holds on to such
+//! commands and sends them from [`crate::ControlPanel::refresh`] when the PD
+//! comes online.
+
+use crate::{OsdpCommand, PdBitSet};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::time::Duration;
+use std::time::Instant;
+
+/// Commands held back for PDs that are offline
+#[derive(Debug)]
+pub(crate) struct OfflineQueue {
+    capacity: usize,
+    ttl: Duration,
+    /// Commands of each PD and when they were sent, oldest first
+    pds: Vec<VecDeque<(Instant, OsdpCommand)>>,
+}
+
+impl OfflineQueue {
+    pub fn new(num_pd: usize, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            pds: (0..num_pd).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    /// Hold on to `cmd` until `pd` comes online. The oldest command of the PD
+    /// is dropped to make room if it already has `capacity` of them.
+    pub fn push(&mut self, pd: i32, cmd: OsdpCommand, now: Instant) {
+        let Some(queue) = self.pds.get_mut(pd as usize) else {
+            return;
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        if queue.len() == self.capacity {
+            queue.pop_front();
+        }
+        queue.push_back((now, cmd));
+    }
+
+    /// Number of commands held back for `pd`, including those that expired
+    /// since the last call to [`OfflineQueue::replay`].
+    pub fn len(&self, pd: i32) -> usize {
+        self.pds.get(pd as usize).map_or(0, |q| q.len())
+    }
+
+    /// Drop commands that are older than the TTL and pass the rest, for PDs
+    /// that are `online`, to `send` in the order they were pushed. A command
+    /// that `send` returns false for (and those after it) is kept for the
+    /// next call.
+    pub fn replay(
+        &mut self,
+        now: Instant,
+        online: PdBitSet,
+        mut send: impl FnMut(i32, &OsdpCommand) -> bool,
+    ) {
+        for (pd, queue) in self.pds.iter_mut().enumerate() {
+            let pd = pd as i32;
+            queue.retain(|(time, _)| now.duration_since(*time) <= self.ttl);
+            if !online.contains(pd) {
+                continue;
+            }
+            while let Some((_, cmd)) = queue.front() {
+                if !send(pd, cmd) {
+                    break;
+                }
+                queue.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OfflineQueue;
+    use crate::{OsdpCommand, OsdpCommandBuzzer, PdBitSet};
+    use core::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn test_offline_queue() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut queue = OfflineQueue::new(2, 2, second * 5);
+        let off = OsdpCommand::Buzzer(OsdpCommandBuzzer::off());
+        let on = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+        let denied = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_denied());
+
+        // Bounded; the oldest one goes
+        queue.push(1, off.clone(), start);
+        queue.push(1, on.clone(), start + second);
+        queue.push(1, denied.clone(), start + second * 2);
+        assert_eq!(queue.len(1), 2);
+
+        let mut sent = Vec::new();
+        let only_0 = PdBitSet::new([0b01; 16], 2);
+        queue.replay(start + second * 2, only_0, |pd, cmd| {
+            sent.push((pd, cmd.clone()));
+            true
+        });
+        assert!(sent.is_empty());
+
+        // Sent in order once online; what can't be sent is kept
+        let online = PdBitSet::new([0b11; 16], 2);
+        queue.replay(start + second * 3, online, |_, _| false);
+        assert_eq!(queue.len(1), 2);
+        queue.replay(start + second * 3, online, |pd, cmd| {
+            sent.push((pd, cmd.clone()));
+            true
+        });
+        assert_eq!(sent, [(1, on), (1, denied)]);
+        assert_eq!(queue.len(1), 0);
+
+        // Expired commands are never sent
+        queue.push(0, off, start);
+        queue.replay(start + second * 6, only_0, |_, _| panic!("expired"));
+        assert_eq!(queue.len(0), 0);
+    }
+}
@@ -13,57 +13,197 @@
 //! to the CP.
 
 use crate::{
-    Channel, OsdpCommand, OsdpError, OsdpEvent, OsdpFileOps, PdCapability, PdInfo, PdInfoBuilder,
+    callback::{catch_panic, Callback},
+    channel::ChannelHandle,
+    logger::LogContext,
+    CallbackGuard, Channel, LogLevel, LogSink, OsdpComSet, OsdpCommand, OsdpCommandKind, OsdpError,
+    OsdpErrorKind, OsdpEvent, OsdpFileOps, OsdpStatusReport, OsdpStatusReportType, PdCapability,
+    PdInfo, PdInfoBuilder, PdState, SecureKeyStore,
 };
 use alloc::{boxed::Box, vec::Vec};
-use core::ffi::c_void;
+use core::{
+    cell::{Cell, RefCell},
+    ffi::c_void,
+};
 #[cfg(feature = "defmt-03")]
-use defmt::{debug, error, info, warn};
+use defmt::error;
 #[cfg(all(feature = "log", not(feature = "defmt-03")))]
-use log::{debug, error, info, warn};
+use log::error;
 
 type Result<T> = core::result::Result<T, OsdpError>;
-type CommandCallback =
-    unsafe extern "C" fn(data: *mut c_void, event: *mut libosdp_sys::osdp_cmd) -> i32;
-
-unsafe extern "C" fn log_handler(
-    _log_level: ::core::ffi::c_int,
-    _file: *const ::core::ffi::c_char,
-    _line: ::core::ffi::c_ulong,
-    _msg: *const ::core::ffi::c_char,
-) {
-    #[cfg(any(feature = "log", feature = "defmt-03"))]
-    {
-        let msg = crate::cstr_to_string(_msg);
-        let msg = msg.trim();
-        match _log_level as libosdp_sys::osdp_log_level_e {
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_EMERG => error!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_ALERT => error!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_CRIT => error!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_ERROR => error!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_WARNING => warn!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_NOTICE => warn!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_INFO => info!("PD: {}", msg),
-            libosdp_sys::osdp_log_level_e_OSDP_LOG_DEBUG => debug!("PD: {}", msg),
-            _ => panic!("Unknown log level"),
-        };
+type CommandCallback = dyn FnMut(OsdpCommand) -> i32 + Send;
+#[cfg(feature = "heapless")]
+type FixedCommandCallback = dyn FnMut(crate::no_alloc::OsdpCommand) -> i32 + Send;
+
+/// Bits of the local status of a PD; see [`OsdpStatusReport::new_local`]
+const LOCAL_STATUS_TAMPER: u32 = 1 << 0;
+const LOCAL_STATUS_POWER: u32 = 1 << 1;
+
+/// Command callbacks of a PD; LibOSDP is given a pointer to this. Commands go
+/// to the callback subscribed to their kind, if any, or the catch-all one
+/// (or all of them to `fixed`, when that is set). Commands that none handles
+/// are ACK'd, unless there is an `auto_ack`
+/// list and their kind is not in it. ACK'd LED, buzzer and output commands are applied to
+/// `state`. Local status queries are answered with `local_status`. Keys of
+/// KEYSETs are stored in `key_store`, if any, before they are ACK'd.
+#[derive(Debug)]
+struct CommandCallbacks {
+    any: Callback<CommandCallback>,
+    by_kind: [Callback<CommandCallback>; OsdpCommandKind::COUNT],
+    #[cfg(feature = "heapless")]
+    fixed: Callback<FixedCommandCallback>,
+    auto_ack: Option<Vec<OsdpCommandKind>>,
+    /// Last COMSET that was ACK'd; applied to the channel once the reply has
+    /// gone out (at the old settings).
+    comset: Cell<Option<OsdpComSet>>,
+    state: RefCell<PdState>,
+    local_status: Cell<u32>,
+    key_store: RefCell<Option<Box<dyn SecureKeyStore>>>,
+}
+
+impl CommandCallbacks {
+    fn new(
+        auto_ack: Option<Vec<OsdpCommandKind>>,
+        key_store: Option<Box<dyn SecureKeyStore>>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            any: Callback::new(),
+            by_kind: core::array::from_fn(|_| Callback::new()),
+            #[cfg(feature = "heapless")]
+            fixed: Callback::new(),
+            auto_ack,
+            comset: Cell::new(None),
+            state: RefCell::new(PdState::default()),
+            local_status: Cell::new(0),
+            key_store: RefCell::new(key_store),
+        })
+    }
+
+    fn kind(&self, kind: OsdpCommandKind) -> &Callback<CommandCallback> {
+        &self.by_kind[kind as usize]
+    }
+
+    /// The reply to a command that the application returned `rc` for (`None`
+    /// if no callback handled it), applying its `effects` if it is ACK'd.
+    fn conclude(&self, effects: Effects, rc: Option<i32>) -> i32 {
+        let stores_key = effects.scbk.is_some() && self.key_store.borrow().is_some();
+        let mut rc = rc.unwrap_or(match &self.auto_ack {
+            Some(kinds) if !kinds.contains(&effects.kind) && !stores_key => -1,
+            _ => 0,
+        });
+        if let (0, Some(key), Some(store)) =
+            (rc, effects.scbk, self.key_store.borrow_mut().as_mut())
+        {
+            // NAK keys that can't be kept; the CP goes on with the old one
+            if let Err(_e) = store.store(&key) {
+                #[cfg(any(feature = "log", feature = "defmt-03"))]
+                error!("Failed to store secure channel key: {:?}", _e);
+                rc = -1;
+            }
+        }
+        if rc == 0 && effects.comset.is_some() {
+            self.comset.set(effects.comset);
+        }
+        if let (0, Some(cmd)) = (rc, effects.applied) {
+            self.state.borrow_mut().apply(&cmd);
+        }
+        rc
+    }
+}
+
+/// What the PD does with a command itself, once it is ACK'd
+struct Effects {
+    kind: OsdpCommandKind,
+    comset: Option<OsdpComSet>,
+    /// LED, buzzer and output commands, to apply to the [`PdState`]
+    applied: Option<OsdpCommand>,
+    /// Key of an SCBK KEYSET, to store in the key store
+    scbk: Option<[u8; 16]>,
+}
+
+impl From<&OsdpCommand> for Effects {
+    fn from(cmd: &OsdpCommand) -> Self {
+        let stateful = matches!(
+            cmd,
+            OsdpCommand::Led(_) | OsdpCommand::Buzzer(_) | OsdpCommand::Output(_)
+        );
+        Self {
+            kind: cmd.kind(),
+            comset: match cmd {
+                OsdpCommand::ComSet(comset) => Some(*comset),
+                _ => None,
+            },
+            applied: stateful.then(|| cmd.clone()),
+            scbk: match cmd {
+                OsdpCommand::KeySet(keyset) if keyset.key_type == 1 => {
+                    keyset.data[..].try_into().ok()
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl From<&crate::no_alloc::OsdpCommand> for Effects {
+    fn from(cmd: &crate::no_alloc::OsdpCommand) -> Self {
+        use crate::no_alloc::OsdpCommand as Fixed;
+        Self {
+            kind: cmd.kind(),
+            comset: match cmd {
+                Fixed::ComSet(comset) => Some(*comset),
+                _ => None,
+            },
+            // None of these carry data, so this does not allocate either
+            applied: match cmd {
+                Fixed::Led(c) => Some(OsdpCommand::Led(c.clone())),
+                Fixed::Buzzer(c) => Some(OsdpCommand::Buzzer(*c)),
+                Fixed::Output(c) => Some(OsdpCommand::Output(*c)),
+                _ => None,
+            },
+            scbk: match cmd {
+                Fixed::KeySet(keyset) if keyset.key_type == 1 => keyset.data[..].try_into().ok(),
+                _ => None,
+            },
+        }
     }
 }
 
-extern "C" fn trampoline<F>(data: *mut c_void, cmd: *mut libosdp_sys::osdp_cmd) -> i32
-where
-    F: FnMut(OsdpCommand) -> i32,
-{
+extern "C" fn trampoline(data: *mut c_void, cmd: *mut libosdp_sys::osdp_cmd) -> i32 {
+    let callbacks = unsafe { &*(data as *const CommandCallbacks) };
+    // LibOSDP replies to a status query with the report the callback leaves
+    // in `cmd`; the PD keeps track of its local status, so answer it here.
+    if unsafe { (*cmd).id } == libosdp_sys::osdp_cmd_e_OSDP_CMD_STATUS {
+        let status = unsafe { &mut (*cmd).__bindgen_anon_1.status };
+        if OsdpStatusReport::from(*status).report_type() == OsdpStatusReportType::Local {
+            *status = OsdpStatusReport::new_local(callbacks.local_status.get()).into();
+            return 0;
+        }
+    }
+    #[cfg(feature = "heapless")]
+    if callbacks.fixed.is_registered() {
+        let cmd = crate::no_alloc::OsdpCommand::from(unsafe { *cmd });
+        return catch_panic("command callback", -1, || dispatch_fixed(callbacks, cmd));
+    }
     let cmd: OsdpCommand = unsafe { (*cmd).into() };
-    let callback: &mut F = unsafe { &mut *(data as *mut F) };
-    callback(cmd)
+    // A command whose handler panicked is NAK'd
+    catch_panic("command callback", -1, || dispatch(callbacks, cmd))
 }
 
-fn get_trampoline<F>(_closure: &F) -> CommandCallback
-where
-    F: FnMut(OsdpCommand) -> i32,
-{
-    trampoline::<F>
+fn dispatch(callbacks: &CommandCallbacks, cmd: OsdpCommand) -> i32 {
+    let effects = Effects::from(&cmd);
+    let rc = callbacks
+        .kind(effects.kind)
+        .invoke(None, |callback| Some(callback(cmd.clone())))
+        .or_else(|| callbacks.any.invoke(None, |callback| Some(callback(cmd))));
+    callbacks.conclude(effects, rc)
+}
+
+#[cfg(feature = "heapless")]
+fn dispatch_fixed(callbacks: &CommandCallbacks, cmd: crate::no_alloc::OsdpCommand) -> i32 {
+    let effects = Effects::from(&cmd);
+    let rc = callbacks.fixed.invoke(None, |callback| Some(callback(cmd)));
+    callbacks.conclude(effects, rc)
 }
 
 fn pd_setup(info: PdInfo) -> Result<*mut c_void> {
@@ -80,6 +220,12 @@ fn pd_setup(info: PdInfo) -> Result<*mut c_void> {
 #[derive(Debug)]
 pub struct PeripheralDevice {
     ctx: *mut libosdp_sys::osdp_t,
+    log: Box<LogContext>,
+    channel: ChannelHandle,
+    command_callbacks: Box<CommandCallbacks>,
+    /// When [`PdState`] was last brought up to date
+    #[cfg(feature = "std")]
+    state_time: std::time::Instant,
 }
 
 unsafe impl Send for PeripheralDevice {}
@@ -87,10 +233,41 @@ unsafe impl Send for PeripheralDevice {}
 impl PeripheralDevice {
     /// Create a new Peripheral panel object for the PD described by the corresponding PdInfo struct.
     pub fn new(info: PdInfoBuilder, channel: Box<dyn Channel>) -> Result<Self> {
-        unsafe { libosdp_sys::osdp_set_log_callback(Some(log_handler)) };
-        let info = info.channel(channel.into()).build();
+        let (info, key_store) = info.take_key_store()?;
+        info.validate()?;
+        let command_callbacks =
+            CommandCallbacks::new(info.auto_ack_kinds().map(<[_]>::to_vec), key_store);
+        let channel: libosdp_sys::osdp_channel = channel.into();
+        let channel_handle = ChannelHandle::new(&channel);
+        let log_level = info.pd_log_level();
+        let info = info.channel(channel).build();
+        let mut log = LogContext::new(info.name());
+        log.set_level(log_level);
+        let ctx = {
+            let _scope = log.enter();
+            pd_setup(info)
+        };
+        let ctx = match ctx {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                drop(unsafe { channel_handle.into_channel() });
+                return Err(e);
+            }
+        };
+        unsafe {
+            libosdp_sys::osdp_pd_set_command_callback(
+                ctx,
+                Some(trampoline),
+                &*command_callbacks as *const CommandCallbacks as *mut c_void,
+            )
+        }
         Ok(Self {
-            ctx: pd_setup(info)?,
+            ctx,
+            log,
+            channel: channel_handle,
+            command_callbacks,
+            #[cfg(feature = "std")]
+            state_time: std::time::Instant::now(),
         })
     }
 
@@ -99,48 +276,221 @@ impl PeripheralDevice {
     /// guarantees, this function must be called at least once every 50ms. This
     /// method does not block and returns early if there is nothing to be done.
     pub fn refresh(&mut self) {
+        let _scope = self.log.enter();
+        #[cfg(feature = "std")]
+        {
+            let now = std::time::Instant::now();
+            let elapsed = now - self.state_time;
+            self.command_callbacks.state.borrow_mut().tick(elapsed);
+            self.state_time = now;
+        }
         unsafe { libosdp_sys::osdp_pd_refresh(self.ctx) }
+        if let Some(comset) = self.command_callbacks.comset.take() {
+            self.apply_comset(comset);
+        }
+    }
+
+    /// Commanded state of the LEDs, buzzers and outputs of this PD, as of the
+    /// last [`PeripheralDevice::refresh`]. Firmware can drive its hardware
+    /// from this instead of handling LED, buzzer and output commands itself
+    /// (they still need to be ACK'd by a command callback or with
+    /// [`PdInfoBuilder::auto_ack`]), and answer output status queries with
+    /// [`PdState::output_status`].
+    pub fn state(&self) -> PdState {
+        self.command_callbacks.state.borrow().clone()
+    }
+
+    /// Let `elapsed` time pass for the timers of [`PeripheralDevice::state`].
+    /// With `std`, this is done by [`PeripheralDevice::refresh`]; without it,
+    /// the application must call this with the time since the last call.
+    #[cfg(not(feature = "std"))]
+    pub fn tick_state(&mut self, elapsed: core::time::Duration) {
+        self.command_callbacks.state.borrow_mut().tick(elapsed);
+    }
+
+    /// Switch the channel over to the communication settings of a COMSET
+    /// that was just accepted, if it is a [`crate::ReconfigurableChannel`].
+    fn apply_comset(&mut self, comset: OsdpComSet) {
+        let channel = unsafe { self.channel.get() };
+        let Some(channel) = channel.as_reconfigurable() else {
+            return;
+        };
+        let res = channel
+            .set_address_filter(comset.address)
+            .and_then(|_| channel.set_baud_rate(comset.baud_rate));
+        if let Err(_e) = res {
+            #[cfg(any(feature = "log", feature = "defmt-03"))]
+            error!("Failed to apply COMSET to channel: {:?}", _e);
+        }
+    }
+
+    /// Block until the CP has sent something to this PD or `timeout`
+    /// elapses; returns false on timeout. A PD has nothing to do until it is
+    /// addressed by the CP so applications (for instance, battery powered
+    /// PDs) can call this between two calls to [`PeripheralDevice::refresh`]
+    /// instead of waking up periodically. See [`Channel::wait_readable`].
+    pub fn wait_readable(&mut self, timeout: core::time::Duration) -> bool {
+        unsafe { self.channel.get() }
+            .wait_readable(timeout)
+            .unwrap_or(true)
     }
 
     /// Set a vector of [`PdCapability`] for this PD.
     pub fn set_capabilities(&mut self, cap: &[PdCapability]) {
-        let cap: Vec<libosdp_sys::osdp_pd_cap> = cap
+        let mut cap: Vec<libosdp_sys::osdp_pd_cap> = cap
             .iter()
             .map(|c| -> libosdp_sys::osdp_pd_cap { c.clone().into() })
             .collect();
+        // LibOSDP walks the list until it finds an invalid function code
+        cap.push(libosdp_sys::osdp_pd_cap {
+            function_code: -1i8 as u8,
+            compliance_level: 0,
+            num_items: 0,
+        });
+        let _scope = self.log.enter();
         unsafe { libosdp_sys::osdp_pd_set_capabilities(self.ctx, cap.as_ptr()) }
     }
 
     /// Flush or drop any events queued in this PD (but not delivered to CP yet)
     pub fn flush_events(&mut self) {
+        let _scope = self.log.enter();
         let _ = unsafe { libosdp_sys::osdp_pd_flush_events(self.ctx) };
     }
 
     /// Queue and a [`OsdpEvent`] for this PD. This will be delivered to CP in
     /// the next POLL.
+    ///
+    /// Returns [`OsdpError::Event`] for card reads with more data than
+    /// LibOSDP can carry (see [`crate::OsdpEventCardRead::MAX_DATA_LEN`]) and
+    /// [`OsdpError::Refused`] if the event queue is full.
     pub fn notify_event(&mut self, event: OsdpEvent) -> Result<()> {
-        let rc = unsafe { libosdp_sys::osdp_pd_notify_event(self.ctx, &event.into()) };
+        self.notify(event.try_into()?)
+    }
+
+    /// Queue a [`crate::no_alloc::OsdpEvent`] for this PD, like
+    /// [`PeripheralDevice::notify_event`] but without allocating. Its data
+    /// always fits; this only fails, with [`OsdpError::Refused`], if the
+    /// event queue is full.
+    #[cfg(feature = "heapless")]
+    pub fn notify_fixed_event(&mut self, event: crate::no_alloc::OsdpEvent) -> Result<()> {
+        self.notify(event.into())
+    }
+
+    fn notify(&mut self, event: libosdp_sys::osdp_event) -> Result<()> {
+        let _scope = self.log.enter();
+        let rc = unsafe { libosdp_sys::osdp_pd_notify_event(self.ctx, &event) };
         if rc < 0 {
-            Err(OsdpError::Event)
+            // Events are checked before they get here; all that's left to
+            // fail is LibOSDP running out of event slots.
+            Err(OsdpError::Refused {
+                kind: OsdpErrorKind::QueueFull,
+                rc,
+            })
         } else {
             Ok(())
         }
     }
 
+    /// Report that the PD has been tampered with (`tampered` is true) or that
+    /// the tamper condition has cleared. This queues the local status event
+    /// for the CP, and replies to local status queries (`osdp_LSTAT`) from
+    /// then on reflect it too. Nothing is sent if the status did not change.
+    ///
+    /// Errors are those of [`PeripheralDevice::notify_event`]; the status is
+    /// updated nonetheless.
+    pub fn notify_tamper(&mut self, tampered: bool) -> Result<()> {
+        self.set_local_status(LOCAL_STATUS_TAMPER, tampered)
+    }
+
+    /// Report that the PD has lost (main) power; see
+    /// [`PeripheralDevice::notify_tamper`], which this works like.
+    pub fn notify_power_failure(&mut self) -> Result<()> {
+        self.set_local_status(LOCAL_STATUS_POWER, true)
+    }
+
+    /// Report that power to the PD has been restored after
+    /// [`PeripheralDevice::notify_power_failure`].
+    pub fn notify_power_restored(&mut self) -> Result<()> {
+        self.set_local_status(LOCAL_STATUS_POWER, false)
+    }
+
+    fn set_local_status(&mut self, bit: u32, value: bool) -> Result<()> {
+        let local_status = &self.command_callbacks.local_status;
+        let prev = local_status.get();
+        let status = if value { prev | bit } else { prev & !bit };
+        if status == prev {
+            return Ok(());
+        }
+        local_status.set(status);
+        self.notify_event(OsdpEvent::Status(OsdpStatusReport::new_local(status)))
+    }
+
+    /// Route log messages of this PD to `sink` instead of the `log`/`defmt`
+    /// crate.
+    pub fn set_log_sink(&mut self, sink: impl LogSink + 'static) {
+        self.log.set_sink(Some(Box::new(sink)));
+    }
+
+    /// Change the log level of this PD at runtime. Passing `None` makes it
+    /// follow the process wide level (see [`crate::set_log_level`]).
+    pub fn set_log_level(&mut self, level: Option<LogLevel>) {
+        self.log.set_level(level);
+    }
+
     /// Set a closure that gets called when this PD receives a command from the
-    /// CP.
-    pub fn set_command_callback<F>(&mut self, closure: F)
+    /// CP. This replaces (and drops) the previously set closure, if any.
+    ///
+    /// The closure's return value decides the reply to the CP: 0 to ACK the
+    /// command and a negative value to NAK it. Commands that are not handled
+    /// by any closure are ACK'd, unless the PD was built with
+    /// [`PdInfoBuilder::auto_ack`] or [`PdInfoBuilder::nak_unhandled`].
+    ///
+    /// When a COMSET is ACK'd, the new baud rate and address are applied to
+    /// the channel after the reply is sent, if it is a
+    /// [`crate::ReconfigurableChannel`].
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn set_command_callback<F>(&mut self, closure: F) -> CallbackGuard
     where
-        F: FnMut(OsdpCommand) -> i32,
+        F: FnMut(OsdpCommand) -> i32 + Send + 'static,
     {
-        unsafe {
-            let callback = get_trampoline(&closure);
-            libosdp_sys::osdp_pd_set_command_callback(
-                self.ctx,
-                Some(callback),
-                Box::into_raw(Box::new(closure)).cast(),
-            )
-        }
+        self.command_callbacks.any.set(Box::new(closure))
+    }
+
+    /// Set a closure that gets called for commands of a given `kind`, instead
+    /// of the one set with [`PeripheralDevice::set_command_callback`]. Each
+    /// kind can have one subscriber; subscribing again replaces (and drops)
+    /// the previous closure.
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    pub fn subscribe<F>(&mut self, kind: OsdpCommandKind, closure: F) -> CallbackGuard
+    where
+        F: FnMut(OsdpCommand) -> i32 + Send + 'static,
+    {
+        self.command_callbacks.kind(kind).set(Box::new(closure))
+    }
+
+    /// Set a closure that gets called with every command from the CP as a
+    /// [`crate::no_alloc::OsdpCommand`], instead of those set with
+    /// [`PeripheralDevice::set_command_callback`] and
+    /// [`PeripheralDevice::subscribe`] (which get no commands while it is
+    /// set). Commands are passed on without allocating; past setup, the only
+    /// allocations of a PD that also sends its events with
+    /// [`PeripheralDevice::notify_fixed_event`] are those of
+    /// [`PeripheralDevice::state`], the first time each LED, buzzer or output
+    /// is commanded. Replies, COMSETs and KEYSETs are handled as with
+    /// [`PeripheralDevice::set_command_callback`].
+    ///
+    /// The closure stays registered until the returned [`CallbackGuard`] is
+    /// dropped; see [`CallbackGuard::detach`].
+    #[cfg(feature = "heapless")]
+    pub fn set_fixed_command_callback<F>(&mut self, closure: F) -> CallbackGuard
+    where
+        F: FnMut(crate::no_alloc::OsdpCommand) -> i32 + Send + 'static,
+    {
+        self.command_callbacks.fixed.set(Box::new(closure))
     }
 
     /// Check online status of a PD identified by the offset number (in PdInfo
@@ -179,9 +529,29 @@ impl PeripheralDevice {
         }
     }
 
+    /// Tear down this PD and hand back the channel it was created with so
+    /// that it can be reused or closed. Dropping a PD closes its channel.
+    pub fn teardown(mut self) -> Box<dyn Channel> {
+        self.teardown_context()
+            .expect("PD context is only torn down once")
+    }
+
+    fn teardown_context(&mut self) -> Option<Box<dyn Channel>> {
+        if self.ctx.is_null() {
+            return None;
+        }
+        {
+            let _scope = self.log.enter();
+            unsafe { libosdp_sys::osdp_pd_teardown(self.ctx) }
+        }
+        self.ctx = core::ptr::null_mut();
+        Some(unsafe { self.channel.into_channel() })
+    }
+
     /// Register a file operations handler for PD. See [`crate::OsdpFileOps`]
     /// trait documentation for more details.
     pub fn register_file_ops(&mut self, fops: Box<dyn OsdpFileOps>) -> Result<()> {
+        let _scope = self.log.enter();
         let mut fops: libosdp_sys::osdp_file_ops = fops.into();
         let rc = unsafe {
             libosdp_sys::osdp_file_register_ops(
@@ -200,6 +570,45 @@ impl PeripheralDevice {
 
 impl Drop for PeripheralDevice {
     fn drop(&mut self) {
-        unsafe { libosdp_sys::osdp_pd_teardown(self.ctx) }
+        drop(self.teardown_context());
+    }
+}
+
+#[cfg(all(test, feature = "heapless"))]
+mod tests {
+    use super::{dispatch_fixed, CommandCallbacks};
+    use crate::{no_alloc::OsdpCommand, OsdpComSet, OsdpCommandBuzzer, OsdpCommandKind};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_fixed_command_callback() {
+        let callbacks = CommandCallbacks::new(Some(vec![OsdpCommandKind::Buzzer]), None);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let guard = callbacks.fixed.set(Box::new(move |cmd: OsdpCommand| {
+            let rc = match cmd.kind() {
+                OsdpCommandKind::Mfg => -1,
+                _ => 0,
+            };
+            sink.lock().unwrap().push(cmd);
+            rc
+        }));
+
+        // Effects are those of ACK'd commands
+        let buzzer = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+        assert_eq!(dispatch_fixed(&callbacks, buzzer.clone()), 0);
+        assert!(callbacks.state.borrow().buzzer(0).is_some());
+        let comset = OsdpCommand::ComSet(OsdpComSet::new(3, 9600));
+        assert_eq!(dispatch_fixed(&callbacks, comset.clone()), 0);
+        assert_eq!(callbacks.comset.take(), Some(OsdpComSet::new(3, 9600)));
+        let mfg = OsdpCommand::Mfg(Default::default());
+        assert_eq!(dispatch_fixed(&callbacks, mfg.clone()), -1);
+        assert_eq!(*seen.lock().unwrap(), [buzzer.clone(), comset.clone(), mfg]);
+
+        // Unhandled commands are ACK'd as per auto_ack
+        drop(guard);
+        assert_eq!(dispatch_fixed(&callbacks, buzzer), 0);
+        assert_eq!(dispatch_fixed(&callbacks, comset), -1);
+        assert_eq!(callbacks.comset.take(), None);
     }
 }
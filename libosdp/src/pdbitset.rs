@@ -0,0 +1,131 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP reports the status of all PDs of a CP at once, as a bitmask with
+//! one bit per PD. [`PdBitSet`] is a typed view of such a bitmask.
+
+use core::fmt;
+
+/// A set of PDs of a [`crate::ControlPanel`], identified by their offset
+/// number (in the order they were added to [`crate::ControlPanelBuilder`]).
+/// See [`crate::ControlPanel::online_mask`] and
+/// [`crate::ControlPanel::sc_active_mask`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PdBitSet {
+    mask: [u8; 16],
+    num_pd: i32,
+}
+
+impl PdBitSet {
+    /// Create a set out of a LibOSDP status mask of a CP with `num_pd` PDs.
+    /// Bits of PDs beyond `num_pd` are ignored.
+    pub(crate) fn new(mut mask: [u8; 16], num_pd: i32) -> Self {
+        for pd in num_pd.max(0)..128 {
+            mask[(pd / 8) as usize] &= !(1 << (pd % 8));
+        }
+        Self { mask, num_pd }
+    }
+
+    /// Create a set of the `pds` of a CP with `num_pd` PDs.
+    pub(crate) fn from_pds(pds: impl IntoIterator<Item = i32>, num_pd: i32) -> Self {
+        let mut mask = [0u8; 16];
+        for pd in pds.into_iter().filter(|pd| (0..128).contains(pd)) {
+            mask[(pd / 8) as usize] |= 1 << (pd % 8);
+        }
+        Self::new(mask, num_pd)
+    }
+
+    /// Whether `pd` is in this set; false for PDs that don't exist.
+    pub fn contains(&self, pd: i32) -> bool {
+        (0..self.num_pd).contains(&pd) && self.mask[(pd / 8) as usize] & (1 << (pd % 8)) != 0
+    }
+
+    /// Number of PDs in this set
+    pub fn len(&self) -> usize {
+        self.mask.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Whether this set has no PDs in it
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of PDs of the CP (whether they are in this set or not)
+    pub fn num_pd(&self) -> i32 {
+        self.num_pd
+    }
+
+    /// Iterate over the PDs in this set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = i32> + '_ {
+        (0..self.num_pd).filter(|pd| self.contains(*pd))
+    }
+
+    /// PDs that are in this set but not in `other`
+    pub fn difference(&self, other: &PdBitSet) -> PdBitSet {
+        let mask = core::array::from_fn(|i| self.mask[i] & !other.mask[i]);
+        PdBitSet::new(mask, self.num_pd)
+    }
+
+    /// PDs that are in this set, `other` or both
+    pub fn union(&self, other: &PdBitSet) -> PdBitSet {
+        let mask = core::array::from_fn(|i| self.mask[i] | other.mask[i]);
+        PdBitSet::new(mask, self.num_pd)
+    }
+
+    /// The raw bitmask; bit `n % 8` of byte `n / 8` is set for PD `n`.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.mask
+    }
+}
+
+impl fmt::Display for PdBitSet {
+    /// Formats as a list of PD offsets, such as `{0, 2, 5}`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (i, pd) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{pd}")?;
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PdBitSet;
+    use alloc::{string::ToString, vec::Vec};
+
+    #[test]
+    fn test_pd_bitset() {
+        let mut mask = [0; 16];
+        mask[0] = 0b0010_0101;
+        mask[1] = 0b0000_0010;
+        mask[15] = 0xff; // beyond num_pd
+        let set = PdBitSet::new(mask, 10);
+        assert!(set.contains(0) && set.contains(2) && set.contains(9));
+        assert!(!set.contains(1) && !set.contains(127) && !set.contains(-1));
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 2, 5, 9]);
+        assert_eq!(set.to_string(), "{0, 2, 5, 9}");
+        assert_eq!(set.as_bytes()[15], 0);
+        let other = PdBitSet::new(
+            [0b0000_0110, 0b10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            10,
+        );
+        assert_eq!(set.difference(&other).iter().collect::<Vec<_>>(), [0, 5]);
+        assert_eq!(
+            set.union(&other).iter().collect::<Vec<_>>(),
+            [0, 1, 2, 5, 9]
+        );
+        assert_eq!(PdBitSet::from_pds([0, 2, 5, 9, 10, 200], 10), set);
+
+        let set = PdBitSet::new([0; 16], 3);
+        assert!(set.is_empty());
+        assert_eq!(set.to_string(), "{}");
+    }
+}
@@ -4,12 +4,24 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use alloc::format;
-use core::str::FromStr;
+use core::{ops::RangeInclusive, str::FromStr};
 
 use crate::OsdpError;
 
+/// Size of packets that a PD which does not report its
+/// [`PdCapability::ReceiveBufferSize`] is assumed to be able to receive; the
+/// smallest receive buffer OSDP allows.
+pub const OSDP_MIN_PACKET_SIZE: usize = 128;
+
+/// Size of LibOSDP's packet buffers, the largest packet it can send or
+/// receive. It is set when LibOSDP is built, with the
+/// `LIBOSDP_OSDP_PACKET_BUF_SIZE` environment variable (see the
+/// `libosdp-sys` crate).
+pub const OSDP_MAX_PACKET_SIZE: usize = libosdp_sys::OSDP_PACKET_BUF_SIZE;
+
 /// PD capability entity to be used inside [`PdCapability`]
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct PdCapEntity {
     compliance: u8,
     num_items: u8,
@@ -32,6 +44,17 @@ impl PdCapEntity {
             num_items,
         }
     }
+
+    /// What the PD can do with this capability; see [`PdCapEntity::new`].
+    pub fn compliance(&self) -> u8 {
+        self.compliance
+    }
+
+    /// Number of units of this capability in the PD; see
+    /// [`PdCapEntity::new`].
+    pub fn num_items(&self) -> u8 {
+        self.num_items
+    }
 }
 
 // From "Compliance:10,NumItems:20" to PdCapEntry { compliance: 10, num_items: 20 }
@@ -70,6 +93,7 @@ impl FromStr for PdCapEntity {
 /// OSDP defined PD capabilities. PDs expose/advertise features they support to
 /// the CP by means of "capabilities".
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum PdCapability {
     /// This function indicates the ability to monitor the status of a switch
     /// using a two-wire electrical connection between the PD and the switch.
@@ -135,8 +159,37 @@ pub enum PdCapability {
     /// This capability indicates the ability of the reader to handle biometric
     /// input.
     Biometrics(PdCapEntity),
+
+    /// This capability indicates whether the PD supports secure PIN entry
+    /// (entered PINs are only ever sent over an encrypted secure channel).
+    SecurePinEntry(PdCapEntity),
+
+    /// This capability indicates the version of the OSDP specification the PD
+    /// complies to.
+    OsdpVersion(PdCapEntity),
+
+    /// A capability with a function code that is not known to this library
+    /// (vendor specific or from a newer revision of the specification). These
+    /// are kept as is when converting to and from `osdp_pd_cap` and raw
+    /// `(function_code, compliance, num_items)` triples, but LibOSDP can't
+    /// advertise them in PD mode (see [`crate::PdInfoBuilder::validate`]).
+    Unknown {
+        /// Function code as reported by the PD
+        code: u8,
+        /// Capability entity as reported by the PD
+        entity: PdCapEntity,
+    },
+}
+
+fn parse_code(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
 }
 
+// From "LedControl:Compliance:1,NumItems:2" (or "0x42:Compliance:1,NumItems:2"
+// for capabilities that are not known by name)
 #[rustfmt::skip]
 impl FromStr for PdCapability {
     type Err = OsdpError;
@@ -186,7 +239,17 @@ impl FromStr for PdCapability {
                 "Biometrics" => {
                     Ok(PdCapability::Biometrics(PdCapEntity::from_str(ent)?))
                 },
-                _ => Err(OsdpError::Parse(format!("PdCapability: {s}"))),
+                "SecurePinEntry" => {
+                    Ok(PdCapability::SecurePinEntry(PdCapEntity::from_str(ent)?))
+                },
+                "OsdpVersion" => {
+                    Ok(PdCapability::OsdpVersion(PdCapEntity::from_str(ent)?))
+                },
+                // Capabilities without a name are given by function code
+                _ => match parse_code(cap) {
+                    Some(code) => Ok(PdCapability::from_code(code, PdCapEntity::from_str(ent)?)),
+                    None => Err(OsdpError::Parse(format!("PdCapability: {s}"))),
+                },
             }
         } else {
             Err(OsdpError::Parse(format!("PdCapability: {s}")))
@@ -194,14 +257,17 @@ impl FromStr for PdCapability {
     }
 }
 
-impl From<libosdp_sys::osdp_pd_cap> for PdCapability {
-    fn from(value: libosdp_sys::osdp_pd_cap) -> Self {
-        let function_code = value.function_code as libosdp_sys::osdp_pd_cap_function_code_e;
-        let e = PdCapEntity {
-            compliance: value.compliance_level,
-            num_items: value.num_items,
-        };
-        match function_code {
+impl PdCapability {
+    /// Highest function code defined by the OSDP specification.
+    const MAX_FUNCTION_CODE: u8 =
+        libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_OSDP_VERSION as u8;
+
+    /// Create a [`PdCapability`] from a raw function code and entity. Codes
+    /// that are not known to this library are mapped to
+    /// [`PdCapability::Unknown`].
+    pub fn from_code(code: u8, entity: PdCapEntity) -> Self {
+        let e = entity;
+        match code as libosdp_sys::osdp_pd_cap_function_code_e {
             libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_CONTACT_STATUS_MONITORING => {
                 PdCapability::ContactStatusMonitoring(e)
             }
@@ -244,15 +310,20 @@ impl From<libosdp_sys::osdp_pd_cap> for PdCapability {
             libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_BIOMETRICS => {
                 PdCapability::Biometrics(e)
             }
-            _ => panic!("Unknown function code"),
+            libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_SECURE_PIN_ENTRY => {
+                PdCapability::SecurePinEntry(e)
+            }
+            libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_OSDP_VERSION => {
+                PdCapability::OsdpVersion(e)
+            }
+            _ => PdCapability::Unknown { code, entity: e },
         }
     }
-}
 
-#[rustfmt::skip]
-impl From<PdCapability> for u8 {
-    fn from(val: PdCapability) -> Self {
-        match val {
+    /// Function code of this capability as defined by the OSDP specification.
+    #[rustfmt::skip]
+    pub fn function_code(&self) -> u8 {
+        match self {
             PdCapability::ContactStatusMonitoring(_) => {
                 libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_CONTACT_STATUS_MONITORING as u8
             }
@@ -295,84 +366,256 @@ impl From<PdCapability> for u8 {
             PdCapability::Biometrics(_) => {
                 libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_BIOMETRICS as u8
             }
+            PdCapability::SecurePinEntry(_) => {
+                libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_SECURE_PIN_ENTRY as u8
+            }
+            PdCapability::OsdpVersion(_) => {
+                libosdp_sys::osdp_pd_cap_function_code_e_OSDP_PD_CAP_OSDP_VERSION as u8
+            }
+            PdCapability::Unknown { code, .. } => *code,
+        }
+    }
+
+    /// The [`PdCapEntity`] carried by this capability.
+    pub fn entity(&self) -> PdCapEntity {
+        match self {
+            PdCapability::ContactStatusMonitoring(e)
+            | PdCapability::OutputControl(e)
+            | PdCapability::CardDataFormat(e)
+            | PdCapability::LedControl(e)
+            | PdCapability::AudibleOutput(e)
+            | PdCapability::TextOutput(e)
+            | PdCapability::TimeKeeping(e)
+            | PdCapability::CheckCharacterSupport(e)
+            | PdCapability::CommunicationSecurity(e)
+            | PdCapability::ReceiveBufferSize(e)
+            | PdCapability::LargestCombinedMessage(e)
+            | PdCapability::SmartCardSupport(e)
+            | PdCapability::Readers(e)
+            | PdCapability::Biometrics(e)
+            | PdCapability::SecurePinEntry(e)
+            | PdCapability::OsdpVersion(e)
+            | PdCapability::Unknown { entity: e, .. } => *e,
+        }
+    }
+
+    /// A [`PdCapability::ReceiveBufferSize`] of `size` bytes
+    pub fn receive_buffer_size(size: u16) -> Self {
+        let [lsb, msb] = size.to_le_bytes();
+        PdCapability::ReceiveBufferSize(PdCapEntity::new(lsb, msb))
+    }
+
+    /// A [`PdCapability::LargestCombinedMessage`] of `size` bytes
+    pub fn largest_combined_message(size: u16) -> Self {
+        let [lsb, msb] = size.to_le_bytes();
+        PdCapability::LargestCombinedMessage(PdCapEntity::new(lsb, msb))
+    }
+
+    /// The size in bytes that a [`PdCapability::ReceiveBufferSize`] or
+    /// [`PdCapability::LargestCombinedMessage`] carries (its compliance and
+    /// item count are the low and high bytes of it); `None` for other
+    /// capabilities.
+    pub fn size(&self) -> Option<u16> {
+        match self {
+            PdCapability::ReceiveBufferSize(e) | PdCapability::LargestCombinedMessage(e) => {
+                Some(u16::from_le_bytes([e.compliance, e.num_items]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check the [`PdCapEntity`] of this capability against what the OSDP
+    /// specification allows for it: the compliance levels it defines and, for
+    /// capabilities that count devices (LEDs, inputs, etc.,), at least one of
+    /// them. Receive buffer and message sizes are not checked and neither are
+    /// [`PdCapability::Unknown`] capabilities.
+    ///
+    /// This is called by [`crate::PdInfoBuilder::validate`] for the
+    /// capabilities of a PD.
+    ///
+    /// # Example
+    /// ```
+    /// # use libosdp::{PdCapEntity, PdCapability};
+    /// assert!(PdCapability::LedControl(PdCapEntity::new(4, 2)).validate().is_ok());
+    /// assert!(PdCapability::LedControl(PdCapEntity::new(5, 2)).validate().is_err());
+    /// assert!(PdCapability::LedControl(PdCapEntity::new(1, 0)).validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), OsdpError> {
+        const ANY: RangeInclusive<u8> = 0..=u8::MAX;
+        const SOME: RangeInclusive<u8> = 1..=u8::MAX;
+        let (compliance, num_items, err) = match self {
+            PdCapability::ContactStatusMonitoring(_) => (
+                1..=4,
+                SOME,
+                "ContactStatusMonitoring needs compliance 1-4 and at least one input",
+            ),
+            PdCapability::OutputControl(_) => (
+                1..=4,
+                SOME,
+                "OutputControl needs compliance 1-4 and at least one output",
+            ),
+            PdCapability::CardDataFormat(_) => (1..=3, ANY, "CardDataFormat needs compliance 1-3"),
+            PdCapability::LedControl(_) => (
+                1..=4,
+                SOME,
+                "LedControl needs compliance 1-4 and at least one LED",
+            ),
+            PdCapability::AudibleOutput(_) => (
+                1..=2,
+                SOME,
+                "AudibleOutput needs compliance 1-2 and at least one buzzer",
+            ),
+            PdCapability::TextOutput(_) => (
+                1..=4,
+                SOME,
+                "TextOutput needs compliance 1-4 and at least one display",
+            ),
+            PdCapability::TimeKeeping(_) => (0..=2, ANY, "TimeKeeping needs compliance 0-2"),
+            PdCapability::CheckCharacterSupport(_) => {
+                (0..=1, ANY, "CheckCharacterSupport needs compliance 0-1")
+            }
+            PdCapability::CommunicationSecurity(_) => (
+                0..=1,
+                0..=1,
+                "CommunicationSecurity needs compliance 0-1 and NumItems 0-1",
+            ),
+            PdCapability::ReceiveBufferSize(_)
+            | PdCapability::LargestCombinedMessage(_)
+            | PdCapability::Unknown { .. } => return Ok(()),
+            PdCapability::SmartCardSupport(_) => {
+                (0..=3, ANY, "SmartCardSupport needs compliance 0-3")
+            }
+            PdCapability::Readers(_) => (0..=0, ANY, "Readers needs compliance 0"),
+            PdCapability::Biometrics(_) => (0..=2, ANY, "Biometrics needs compliance 0-2"),
+            PdCapability::SecurePinEntry(_) => (0..=1, ANY, "SecurePinEntry needs compliance 0-1"),
+            PdCapability::OsdpVersion(_) => (0..=2, ANY, "OsdpVersion needs compliance 0-2"),
+        };
+        let e = self.entity();
+        if compliance.contains(&e.compliance) && num_items.contains(&e.num_items) {
+            Ok(())
+        } else {
+            Err(OsdpError::PdInfoBuilder(err))
         }
     }
+
+    /// Iterate over every capability defined by the OSDP specification (with
+    /// a default [`PdCapEntity`]). See also
+    /// [`crate::ControlPanel::get_capabilities`].
+    pub fn iter() -> impl Iterator<Item = PdCapability> {
+        (1..=Self::MAX_FUNCTION_CODE).map(|code| Self::from_code(code, PdCapEntity::default()))
+    }
+}
+
+impl From<libosdp_sys::osdp_pd_cap> for PdCapability {
+    fn from(value: libosdp_sys::osdp_pd_cap) -> Self {
+        let e = PdCapEntity {
+            compliance: value.compliance_level,
+            num_items: value.num_items,
+        };
+        PdCapability::from_code(value.function_code, e)
+    }
+}
+
+/// From a raw `(function_code, compliance, num_items)` triple, as carried in
+/// an `osdp_PDCAP` reply. See [`PdCapability::from_code`].
+impl From<(u8, u8, u8)> for PdCapability {
+    fn from((code, compliance, num_items): (u8, u8, u8)) -> Self {
+        PdCapability::from_code(code, PdCapEntity::new(compliance, num_items))
+    }
+}
+
+/// To a raw `(function_code, compliance, num_items)` triple
+impl From<PdCapability> for (u8, u8, u8) {
+    fn from(value: PdCapability) -> Self {
+        let e = value.entity();
+        (value.function_code(), e.compliance, e.num_items)
+    }
+}
+
+impl From<PdCapability> for u8 {
+    fn from(val: PdCapability) -> Self {
+        val.function_code()
+    }
 }
 
 impl From<PdCapability> for libosdp_sys::osdp_pd_cap {
     fn from(value: PdCapability) -> Self {
-        let function_code: u8 = value.clone().into();
-        match value {
-            PdCapability::ContactStatusMonitoring(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::OutputControl(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::CardDataFormat(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::LedControl(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::AudibleOutput(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::TextOutput(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::TimeKeeping(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::CheckCharacterSupport(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::CommunicationSecurity(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::ReceiveBufferSize(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::LargestCombinedMessage(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::SmartCardSupport(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::Readers(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
-            PdCapability::Biometrics(e) => libosdp_sys::osdp_pd_cap {
-                function_code,
-                compliance_level: e.compliance,
-                num_items: e.num_items,
-            },
+        let e = value.entity();
+        libosdp_sys::osdp_pd_cap {
+            function_code: value.function_code(),
+            compliance_level: e.compliance,
+            num_items: e.num_items,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PdCapEntity, PdCapability};
+    use core::str::FromStr;
+    use libosdp_sys::osdp_pd_cap;
+
+    #[test]
+    fn test_pd_capability() {
+        let cap = PdCapability::LedControl(PdCapEntity::new(1, 2));
+        let cap_struct: osdp_pd_cap = cap.clone().into();
+        assert_eq!(cap_struct.function_code, 4);
+        assert_eq!(cap_struct.compliance_level, 1);
+        assert_eq!(cap_struct.num_items, 2);
+        assert_eq!(cap, cap_struct.into());
+
+        let cap_struct = osdp_pd_cap {
+            function_code: 0x42,
+            compliance_level: 3,
+            num_items: 4,
+        };
+        let cap: PdCapability = cap_struct.into();
+        assert_eq!(
+            cap,
+            PdCapability::Unknown {
+                code: 0x42,
+                entity: PdCapEntity::new(3, 4)
+            }
+        );
+        assert_eq!(cap.function_code(), 0x42);
+
+        let codes: Vec<u8> = PdCapability::iter().map(|c| c.function_code()).collect();
+        assert_eq!(codes, (1..=16).collect::<Vec<u8>>());
+        assert!(!PdCapability::iter().any(|c| matches!(c, PdCapability::Unknown { .. })));
+    }
+
+    #[test]
+    fn test_pd_capability_raw() {
+        let cap = PdCapability::from((0x42, 3, 4));
+        assert_eq!(
+            cap,
+            PdCapability::from_str("0x42:Compliance:3,NumItems:4").unwrap()
+        );
+        assert_eq!(<(u8, u8, u8)>::from(cap.clone()), (0x42, 3, 4));
+        assert_eq!(PdCapability::from(osdp_pd_cap::from(cap.clone())), cap);
+
+        assert_eq!(
+            PdCapability::from((4, 1, 2)),
+            PdCapability::LedControl(PdCapEntity::new(1, 2))
+        );
+        assert_eq!(
+            PdCapability::from_str("4:Compliance:1,NumItems:2").unwrap(),
+            PdCapability::LedControl(PdCapEntity::new(1, 2))
+        );
+        assert!(PdCapability::from_str("0x142:Compliance:1,NumItems:2").is_err());
+    }
+
+    #[test]
+    fn test_pd_capability_size() {
+        let cap = PdCapability::receive_buffer_size(1024);
+        assert_eq!(cap, PdCapability::ReceiveBufferSize(PdCapEntity::new(0, 4)));
+        assert_eq!(cap.size(), Some(1024));
+        let cap = PdCapability::largest_combined_message(300);
+        assert_eq!(cap.entity(), PdCapEntity::new(44, 1));
+        assert_eq!(cap.size(), Some(300));
+        assert_eq!(
+            PdCapability::LedControl(PdCapEntity::new(1, 2)).size(),
+            None
+        );
+    }
+}
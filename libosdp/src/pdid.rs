@@ -4,9 +4,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::ConvertEndian;
+use crate::OsdpError;
+use alloc::{format, string::String};
+use core::{fmt, str::FromStr};
 
 /// PD ID information advertised by the PD.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct PdId {
     /// 1-Byte Manufacturer's version number
     pub version: i32,
@@ -34,6 +38,117 @@ impl PdId {
             firmware_version: (v_major, v_minor, v_patch),
         }
     }
+
+    /// Vendor code (IEEE OUI) as a 24-bit number
+    pub fn vendor_code_u32(&self) -> u32 {
+        self.vendor_code.as_le()
+    }
+
+    /// Set vendor code (IEEE OUI) from a 24-bit number
+    pub fn set_vendor_code_u32(&mut self, vendor_code: u32) {
+        self.vendor_code = u24_to_tuple(vendor_code);
+    }
+
+    /// Serial number as a 32-bit number
+    pub fn serial_number_u32(&self) -> u32 {
+        self.serial_number.as_le()
+    }
+
+    /// Set serial number from a 32-bit number
+    pub fn set_serial_number_u32(&mut self, serial_number: u32) {
+        self.serial_number = serial_number.to_le_bytes();
+    }
+
+    /// Firmware version as a 24-bit number (major in the least significant
+    /// byte, followed by minor and build)
+    pub fn firmware_version_u32(&self) -> u32 {
+        self.firmware_version.as_le()
+    }
+
+    /// Set firmware version from a 24-bit number. See
+    /// [`PdId::firmware_version_u32`] for the byte layout.
+    pub fn set_firmware_version_u32(&mut self, firmware_version: u32) {
+        self.firmware_version = u24_to_tuple(firmware_version);
+    }
+
+    /// Firmware version as a "major.minor.build" string
+    pub fn firmware_version_semver(&self) -> String {
+        let (major, minor, build) = self.firmware_version;
+        format!("{major}.{minor}.{build}")
+    }
+
+    /// Set firmware version from a "major.minor.build" string; each part must
+    /// fit in a byte.
+    pub fn set_firmware_version_semver(&mut self, version: &str) -> Result<(), OsdpError> {
+        let mut parts = version.split('.').map(|p| p.parse::<u8>());
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(build)), None) => {
+                self.firmware_version = (major, minor, build);
+                Ok(())
+            }
+            _ => Err(OsdpError::Parse(format!("firmware version: {version}"))),
+        }
+    }
+
+    /// Whether `other` identifies the same physical device: the vendor code,
+    /// model and serial number match. Versions are not compared as they
+    /// change with firmware updates.
+    pub fn same_device(&self, other: &PdId) -> bool {
+        self.vendor_code == other.vendor_code
+            && self.model == other.model
+            && self.serial_number == other.serial_number
+    }
+}
+
+fn u24_to_tuple(val: u32) -> (u8, u8, u8) {
+    let bytes = val.to_le_bytes();
+    (bytes[0], bytes[1], bytes[2])
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// "vendor=0x00030F model=12 ver=2 serial=0x00AA55 fw=1.2.3"
+impl fmt::Display for PdId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "vendor=0x{:06X} model={} ver={} serial=0x{:08X} fw={}",
+            self.vendor_code_u32(),
+            self.model,
+            self.version,
+            self.serial_number_u32(),
+            self.firmware_version_semver(),
+        )
+    }
+}
+
+/// Parse the format produced by the [`fmt::Display`] implementation. Keys may
+/// appear in any order and missing keys default to zero. Numbers can be given
+/// in decimal or in hex (with a `0x` prefix).
+impl FromStr for PdId {
+    type Err = OsdpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || OsdpError::Parse(format!("PdId: {s}"));
+        let mut pd_id = PdId::default();
+        for field in s.split_whitespace() {
+            let (key, val) = field.split_once('=').ok_or_else(err)?;
+            match key {
+                "vendor" => pd_id.set_vendor_code_u32(parse_number(val).ok_or_else(err)?),
+                "model" => pd_id.model = parse_number(val).ok_or_else(err)? as i32,
+                "ver" => pd_id.version = parse_number(val).ok_or_else(err)? as i32,
+                "serial" => pd_id.set_serial_number_u32(parse_number(val).ok_or_else(err)?),
+                "fw" => pd_id.set_firmware_version_semver(val).map_err(|_| err())?,
+                _ => return Err(err()),
+            }
+        }
+        Ok(pd_id)
+    }
 }
 
 impl From<libosdp_sys::osdp_pd_id> for PdId {
@@ -89,3 +204,53 @@ impl From<PdId> for libosdp_sys::osdp_pd_id {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PdId;
+    use core::str::FromStr;
+
+    #[test]
+    fn test_pd_id_string() {
+        let pd_id =
+            PdId::from_str("vendor=0x00030F model=12 ver=2 serial=0x00AA55 fw=1.2.3").unwrap();
+        assert_eq!(pd_id.vendor_code, (0x0F, 0x03, 0x00));
+        assert_eq!(pd_id.vendor_code_u32(), 0x00030F);
+        assert_eq!(pd_id.model, 12);
+        assert_eq!(pd_id.version, 2);
+        assert_eq!(pd_id.serial_number_u32(), 0x00AA55);
+        assert_eq!(pd_id.firmware_version, (1, 2, 3));
+        assert_eq!(pd_id.firmware_version_u32(), 0x030201);
+        assert_eq!(pd_id.firmware_version_semver(), "1.2.3");
+        assert_eq!(PdId::from_str(&pd_id.to_string()).unwrap(), pd_id);
+
+        assert!(PdId::from_str("vendor=0x00030F colour=blue").is_err());
+        assert!(PdId::from_str("fw=1.2").is_err());
+    }
+
+    #[test]
+    fn test_pd_id_numbers() {
+        let mut pd_id = PdId::default();
+        pd_id.set_vendor_code_u32(0x00030F);
+        pd_id.set_serial_number_u32(0xDEADBEEF);
+        pd_id.set_firmware_version_semver("2.10.255").unwrap();
+        assert_eq!(pd_id.vendor_code, (0x0F, 0x03, 0x00));
+        assert_eq!(pd_id.serial_number, [0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(pd_id.serial_number_u32(), 0xDEADBEEF);
+        assert_eq!(pd_id.firmware_version, (2, 10, 255));
+        assert_eq!(pd_id.firmware_version_u32(), 0xFF0A02);
+        assert!(pd_id.set_firmware_version_semver("2.10.256").is_err());
+        assert!(pd_id.set_firmware_version_semver("2.10").is_err());
+        assert_eq!(pd_id.firmware_version, (2, 10, 255));
+    }
+
+    #[test]
+    fn test_same_device() {
+        let pd_id = PdId::from_number(1);
+        let mut upgraded = pd_id;
+        upgraded.version += 1;
+        upgraded.firmware_version = (9, 9, 9);
+        assert!(pd_id.same_device(&upgraded));
+        assert!(!pd_id.same_device(&PdId::from_number(2)));
+    }
+}
@@ -3,12 +3,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{OsdpError, OsdpFlag, PdCapability, PdId};
+use crate::{
+    timing::PdTiming, LogLevel, OsdpCommandKind, OsdpError, OsdpFlag, OsdpIntegrity, PdCapEntity,
+    PdCapability, PdId, SecureKeyStore,
+};
 use alloc::{boxed::Box, ffi::CString, string::String, vec::Vec};
-use core::ops::Deref;
+use core::{ops::Deref, time::Duration};
 
 /// OSDP PD Information. This struct is used to describe a PD to LibOSDP
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct PdInfo {
     name: CString,
     address: i32,
@@ -131,19 +134,54 @@ impl PdInfo {
     pub fn secure_channel_key(&self) -> Option<[u8; 16]> {
         self.scbk
     }
+
+    pub(crate) fn set_channel(&mut self, channel: libosdp_sys::osdp_channel) {
+        self.channel = Some(channel);
+    }
+
+    pub(crate) fn set_address(&mut self, address: i32) {
+        self.address = address;
+    }
+
+    pub(crate) fn set_baud_rate(&mut self, baud_rate: i32) {
+        self.baud_rate = baud_rate;
+    }
+
+    pub(crate) fn set_flag(&mut self, flags: OsdpFlag, value: bool) {
+        self.flags.set(flags, value);
+    }
+
+    pub(crate) fn set_secure_channel_key(&mut self, key: [u8; 16]) {
+        self.scbk = Some(key);
+    }
 }
 
 /// OSDP PD Info Builder
+///
+/// Protocol timing is set by LibOSDP for all PDs; a CP can wait longer for
+/// the replies of a PD ([`PdInfoBuilder::response_timeout`]) and back off
+/// more from it while it is offline ([`PdInfoBuilder::offline_backoff`]), but
+/// polls all PDs at LibOSDP's poll interval.
 #[derive(Debug, Default)]
 pub struct PdInfoBuilder {
     name: Option<CString>,
     address: i32,
     baud_rate: i32,
     flags: OsdpFlag,
+    /// Flags explicitly cleared for this PD; these are not set by
+    /// [`crate::ControlPanelBuilder::default_flags`]
+    cleared_flags: OsdpFlag,
     id: PdId,
     cap: Vec<libosdp_sys::osdp_pd_cap>,
     channel: Option<libosdp_sys::osdp_channel>,
     scbk: Option<[u8; 16]>,
+    auto_ack: Option<Vec<OsdpCommandKind>>,
+    log_level: Option<LogLevel>,
+    expected_id: Option<PdId>,
+    integrity: Option<OsdpIntegrity>,
+    response_timeout: Option<Duration>,
+    offline_backoff: Option<(Duration, Duration)>,
+    key_store: Option<Box<dyn SecureKeyStore>>,
 }
 
 impl PdInfoBuilder {
@@ -188,6 +226,21 @@ impl PdInfoBuilder {
     /// Set flags for the PD; used to modify the way the context is setup
     pub fn flag(mut self, flag: OsdpFlag) -> PdInfoBuilder {
         self.flags.set(flag, true);
+        self.cleared_flags.remove(flag);
+        self
+    }
+
+    /// Clear flags for the PD; this overrides flags set for all PDs of a CP
+    /// with [`crate::ControlPanelBuilder::default_flags`].
+    pub fn clear_flag(mut self, flag: OsdpFlag) -> PdInfoBuilder {
+        self.flags.remove(flag);
+        self.cleared_flags.insert(flag);
+        self
+    }
+
+    /// Set `flags`, except for those explicitly cleared for this PD.
+    pub(crate) fn default_flags(mut self, flags: OsdpFlag) -> PdInfoBuilder {
+        self.flags |= flags - self.cleared_flags;
         self
     }
 
@@ -229,6 +282,237 @@ impl PdInfoBuilder {
         self
     }
 
+    /// Keep the secure channel key of this PD in `store` across reboots (see
+    /// [`SecureKeyStore`]). [`crate::PeripheralDevice::new`] sets the PD up
+    /// with the stored key, if there is one, in place of the one set with
+    /// [`PdInfoBuilder::secure_channel_key`] and out of install mode. Keys
+    /// received with KEYSET are stored before the command is ACK'd; KEYSETs
+    /// that no closure handles are ACK'd once stored. For CP mode, this field
+    /// is ignored.
+    pub fn key_store(mut self, store: Box<dyn SecureKeyStore>) -> PdInfoBuilder {
+        self.key_store = Some(store);
+        self
+    }
+
+    /// Take the key store out of this builder and apply the key it has
+    /// stored, if any.
+    pub(crate) fn take_key_store(
+        mut self,
+    ) -> Result<(PdInfoBuilder, Option<Box<dyn SecureKeyStore>>), OsdpError> {
+        let Some(mut store) = self.key_store.take() else {
+            return Ok((self, None));
+        };
+        if let Some(key) = store.load()? {
+            self = self
+                .secure_channel_key(key)
+                .clear_flag(OsdpFlag::InstallMode);
+        }
+        Ok((self, Some(store)))
+    }
+
+    /// Only ACK commands of these kinds when the application has no closure
+    /// to handle them (see [`crate::PeripheralDevice::subscribe`] and
+    /// [`crate::PeripheralDevice::set_command_callback`]) and NAK other
+    /// unhandled commands. By default, all unhandled commands are ACK'd.
+    /// [`OsdpCommandKind::BENIGN`] is a good starting point for minimal PDs.
+    /// For CP mode, this field is ignored.
+    pub fn auto_ack(mut self, kinds: &[OsdpCommandKind]) -> PdInfoBuilder {
+        self.auto_ack = Some(kinds.to_vec());
+        self
+    }
+
+    /// NAK all commands that the application has no closure to handle; same
+    /// as [`PdInfoBuilder::auto_ack`] with no kinds.
+    pub fn nak_unhandled(self) -> PdInfoBuilder {
+        self.auto_ack(&[])
+    }
+
+    pub(crate) fn auto_ack_kinds(&self) -> Option<&[OsdpCommandKind]> {
+        self.auto_ack.as_deref()
+    }
+
+    /// Set the log level for this PD instance, including what LibOSDP logs
+    /// while setting it up. This overrides the process wide level set with
+    /// [`crate::set_log_level`]; it can be changed later with
+    /// [`crate::PeripheralDevice::set_log_level`]. For CP mode, this field is
+    /// ignored (see [`crate::ControlPanelBuilder::log_level`]).
+    pub fn log_level(mut self, level: LogLevel) -> PdInfoBuilder {
+        self.log_level = Some(level);
+        self
+    }
+
+    pub(crate) fn pd_log_level(&self) -> Option<LogLevel> {
+        self.log_level
+    }
+
+    /// Set the identity that the PD is expected to report (see
+    /// [`PdId::same_device`] for what is compared). When the PD that answers
+    /// at this address reports a different one, the CP refuses commands to
+    /// it with [`OsdpError::IdMismatch`] and calls the closure set with
+    /// [`crate::ControlPanel::set_id_mismatch_callback`]. This catches
+    /// readers that were swapped for another device. For PD mode, this field
+    /// is ignored.
+    pub fn expected_id(mut self, id: &PdId) -> PdInfoBuilder {
+        self.expected_id = Some(*id);
+        self
+    }
+
+    pub(crate) fn expected_pd_id(&self) -> Option<PdId> {
+        self.expected_id
+    }
+
+    /// Set the integrity check that the CP uses with this PD. By default, the
+    /// CP sends CRC-16 and takes replies with either. [`OsdpIntegrity::Crc16`]
+    /// has replies with just a checksum dropped (they show up in
+    /// [`crate::ControlPanel::last_error`]); [`OsdpIntegrity::Checksum`] has
+    /// commands sent with a checksum, for legacy readers that don't know
+    /// CRC-16. The latter can't be used with secure channel. For PD mode, this
+    /// field is ignored; PDs reply the way the CP sent.
+    pub fn integrity(mut self, integrity: OsdpIntegrity) -> PdInfoBuilder {
+        self.integrity = Some(integrity);
+        self
+    }
+
+    pub(crate) fn integrity_policy(&self) -> Option<OsdpIntegrity> {
+        self.integrity
+    }
+
+    /// Wait up to `timeout` for this PD to reply to a command before sending
+    /// it again, to accommodate slow radio links or long cable runs. LibOSDP
+    /// waits `OSDP_RESP_TOUT_MS` for all PDs; the CP keeps the
+    /// retransmissions it makes before `timeout` off the wire. LibOSDP still
+    /// takes the PD offline after it has retransmitted a command a few times,
+    /// which bounds how far this can extend its own timeout. For PD mode,
+    /// this field is ignored.
+    pub fn response_timeout(mut self, timeout: Duration) -> PdInfoBuilder {
+        self.response_timeout = Some(timeout);
+        self
+    }
+
+    /// While this PD is offline, let LibOSDP's attempts to reach it on the
+    /// wire `initial` apart at first, and twice as far apart after every
+    /// attempt, up to `max`. This keeps a PD that is gone from taking up a
+    /// slow link; it can only space the attempts out further than LibOSDP
+    /// does. For PD mode, this field is ignored.
+    pub fn offline_backoff(mut self, initial: Duration, max: Duration) -> PdInfoBuilder {
+        self.offline_backoff = Some((initial, max));
+        self
+    }
+
+    pub(crate) fn timing(&self) -> PdTiming {
+        PdTiming::new(self.response_timeout, self.offline_backoff)
+    }
+
+    /// Check the current builder for contradicting settings and capabilities
+    /// with values that the OSDP specification does not allow (see
+    /// [`PdCapability::validate`]), or a [`PdCapability::ReceiveBufferSize`]
+    /// larger than LibOSDP was built for ([`crate::OSDP_MAX_PACKET_SIZE`]).
+    /// Capability checks only apply when capabilities are set (i.e. when
+    /// describing a PD in PD mode); a CP need not know about the capabilities
+    /// of its PDs.
+    ///
+    /// Without `std`, this also fails if no `TimeSource` has been registered,
+    /// or if this PD may set up a secure channel (it has a key, is in install
+    /// mode or advertises CommunicationSecurity) and no `RngSource` has.
+    ///
+    /// This method is called by [`crate::ControlPanelBuilder::build`] and
+    /// [`crate::PeripheralDevice::new`] so such issues are reported before
+    /// the context is set up.
+    ///
+    /// # Example
+    /// ```
+    /// # use libosdp::{OsdpFlag, PdInfoBuilder};
+    /// let pd = PdInfoBuilder::new().flag(OsdpFlag::EnforceSecure);
+    /// assert!(pd.validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), OsdpError> {
+        #[cfg(not(feature = "std"))]
+        self.validate_platform()?;
+        if self.flags.contains(OsdpFlag::EnforceSecure) && self.scbk.is_none() {
+            return Err(OsdpError::PdInfoBuilder(
+                "EnforceSecure flag set without a secure channel key",
+            ));
+        }
+        if self.integrity == Some(OsdpIntegrity::Checksum)
+            && (self.scbk.is_some() || self.flags.contains(OsdpFlag::EnforceSecure))
+        {
+            return Err(OsdpError::PdInfoBuilder(
+                "checksum integrity can't be used with secure channel",
+            ));
+        }
+        if self
+            .offline_backoff
+            .is_some_and(|(initial, max)| initial > max)
+        {
+            return Err(OsdpError::PdInfoBuilder(
+                "offline backoff starts above its maximum",
+            ));
+        }
+        if self.cap.is_empty() {
+            return Ok(());
+        }
+        for (i, cap) in self.cap.iter().enumerate() {
+            if self.cap[..i]
+                .iter()
+                .any(|c| c.function_code == cap.function_code)
+            {
+                return Err(OsdpError::PdInfoBuilder("duplicate capability"));
+            }
+            // LibOSDP stops reading the capabilities at a function code it
+            // does not know, and so can't advertise these.
+            if let PdCapability::Unknown { .. } = PdCapability::from(*cap) {
+                return Err(OsdpError::PdInfoBuilder("unknown capability"));
+            }
+            PdCapability::from(*cap).validate()?;
+            // LibOSDP drops packets that don't fit in its own buffer
+            if let cap @ PdCapability::ReceiveBufferSize(_) = PdCapability::from(*cap) {
+                if cap.size().unwrap() as usize > crate::OSDP_MAX_PACKET_SIZE {
+                    return Err(OsdpError::PdInfoBuilder(
+                        "ReceiveBufferSize is larger than OSDP_MAX_PACKET_SIZE",
+                    ));
+                }
+            }
+        }
+        let has_sc = self.cap.iter().any(|c| {
+            matches!(
+                PdCapability::from(*c),
+                PdCapability::CommunicationSecurity(e) if e != PdCapEntity::default()
+            )
+        });
+        if self.scbk.is_some() && !has_sc {
+            return Err(OsdpError::PdInfoBuilder(
+                "secure channel key set without CommunicationSecurity capability",
+            ));
+        }
+        Ok(())
+    }
+
+    /// LibOSDP reads its clock and random numbers from the application
+    /// without `std`; refuse to set it up without them rather than have its
+    /// time stand still or its secure channel fail mid-handshake.
+    #[cfg(not(feature = "std"))]
+    fn validate_platform(&self) -> Result<(), OsdpError> {
+        if !crate::time::has_time_source() {
+            return Err(OsdpError::PdInfoBuilder(
+                "no TimeSource registered (see set_time_source)",
+            ));
+        }
+        let may_use_sc = self.scbk.is_some()
+            || self.flags.contains(OsdpFlag::InstallMode)
+            || self.cap.iter().any(|c| {
+                matches!(
+                    PdCapability::from(*c),
+                    PdCapability::CommunicationSecurity(e) if e != PdCapEntity::default()
+                )
+            });
+        if may_use_sc && !crate::rng::has_rng_source() {
+            return Err(OsdpError::PdInfoBuilder(
+                "secure channel possible but no RngSource registered (see set_rng_source)",
+            ));
+        }
+        Ok(())
+    }
+
     /// Finalize the PdInfo from the current builder
     pub fn build(self) -> PdInfo {
         let name = self.name.unwrap_or_else(|| {
@@ -323,3 +607,47 @@ impl Drop for OsdpPdInfoHandle {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PdInfoBuilder;
+    use crate::{LogLevel, OsdpFlag};
+    use core::time::Duration;
+
+    #[test]
+    fn test_default_flags() {
+        let defaults = OsdpFlag::EnforceSecure | OsdpFlag::IgnoreUnsolicited;
+        let pd = PdInfoBuilder::new().default_flags(defaults).build();
+        assert_eq!(pd.flag(), defaults);
+
+        let pd = PdInfoBuilder::new()
+            .flag(OsdpFlag::InstallMode)
+            .clear_flag(OsdpFlag::EnforceSecure)
+            .default_flags(defaults)
+            .build();
+        assert_eq!(
+            pd.flag(),
+            OsdpFlag::InstallMode | OsdpFlag::IgnoreUnsolicited
+        );
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(PdInfoBuilder::new().pd_log_level(), None);
+        let pd = PdInfoBuilder::new().log_level(LogLevel::Debug);
+        assert_eq!(pd.pd_log_level(), Some(LogLevel::Debug));
+    }
+
+    #[test]
+    fn test_timing() {
+        let secs = Duration::from_secs;
+        assert!(!PdInfoBuilder::new().timing().is_set());
+        let pd = PdInfoBuilder::new().response_timeout(secs(1));
+        assert!(pd.timing().is_set());
+        let pd = PdInfoBuilder::new().offline_backoff(secs(1), secs(60));
+        assert!(pd.timing().is_set());
+        assert!(pd.validate().is_ok());
+        let pd = PdInfoBuilder::new().offline_backoff(secs(2), secs(1));
+        assert!(pd.validate().is_err());
+    }
+}
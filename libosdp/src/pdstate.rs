@@ -0,0 +1,403 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LED, buzzer and output commands don't just set a state; they set patterns
+//! that play out over time and temporary states that expire. The types here
+//! follow such commands as time passes, to tell what each device should be
+//! doing at any moment. A [`crate::PeripheralDevice`] keeps a [`PdState`] of
+//! all of its devices up to date (see [`crate::PeripheralDevice::state`]) so
+//! that firmware can drive its hardware from it.
+
+use crate::{
+    OsdpCommand, OsdpCommandBuzzer, OsdpCommandLed, OsdpCommandOutput, OsdpLedColor,
+    OsdpLedPattern, OsdpStatusReport,
+};
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+/// Length of the time unit of LED, buzzer and output commands, in ms
+const UNIT_MS: u64 = 100;
+
+fn pattern_color(pattern: &OsdpLedPattern, phase: u64) -> OsdpLedColor {
+    let on = pattern.on_count as u64 * UNIT_MS;
+    let off = pattern.off_count as u64 * UNIT_MS;
+    if off == 0 {
+        pattern.on_color
+    } else if on == 0 || phase % (on + off) >= on {
+        pattern.off_color
+    } else {
+        pattern.on_color
+    }
+}
+
+/// Commanded state of an LED
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LedState {
+    permanent: OsdpLedPattern,
+    /// Temporary pattern and the time (in ms) that is left of it
+    temporary: Option<(OsdpLedPattern, u64)>,
+    /// Time (in ms) since the current pattern started
+    phase: u64,
+}
+
+impl LedState {
+    /// Apply an LED command to this LED.
+    pub fn apply(&mut self, cmd: &OsdpCommandLed) {
+        if cmd.permanent.control_code == 1 {
+            self.permanent = OsdpLedPattern {
+                on_color: cmd.permanent.on_color,
+                off_color: cmd.permanent.off_color,
+                on_count: cmd.permanent.on_count,
+                off_count: cmd.permanent.off_count,
+            };
+            if self.temporary.is_none() {
+                self.phase = 0;
+            }
+        }
+        match cmd.temporary.control_code {
+            1 => {
+                self.temporary = None;
+                self.phase = 0;
+            }
+            2 => {
+                let pattern = OsdpLedPattern {
+                    on_color: cmd.temporary.on_color,
+                    off_color: cmd.temporary.off_color,
+                    on_count: cmd.temporary.on_count,
+                    off_count: cmd.temporary.off_count,
+                };
+                let time = cmd.temporary.timer_count as u64 * UNIT_MS;
+                self.temporary = (time > 0).then_some((pattern, time));
+                self.phase = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Let `elapsed` time pass.
+    pub fn tick(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_millis() as u64;
+        self.phase += elapsed;
+        if let Some((_, left)) = &mut self.temporary {
+            if *left <= elapsed {
+                self.temporary = None;
+                self.phase = 0;
+            } else {
+                *left -= elapsed;
+            }
+        }
+    }
+
+    /// Color the LED should show now
+    pub fn color(&self) -> OsdpLedColor {
+        let pattern = self.temporary.as_ref().map_or(&self.permanent, |(p, _)| p);
+        pattern_color(pattern, self.phase)
+    }
+
+    /// Pattern the LED shows once the temporary one (if any) is over
+    pub fn permanent(&self) -> OsdpLedPattern {
+        self.permanent
+    }
+
+    /// Temporary pattern the LED shows and how much longer it does so, if
+    /// there is one
+    pub fn temporary(&self) -> Option<(OsdpLedPattern, Duration)> {
+        self.temporary
+            .map(|(pattern, left)| (pattern, Duration::from_millis(left)))
+    }
+}
+
+/// Commanded state of a buzzer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuzzerState {
+    /// On and off time (in ms) of each beep, if the buzzer is active
+    beep: Option<(u64, u64)>,
+    /// Number of beeps; 0 for no limit
+    count: u8,
+    /// Time (in ms) since the buzzer was activated
+    phase: u64,
+}
+
+impl BuzzerState {
+    /// Apply a buzzer command to this buzzer.
+    pub fn apply(&mut self, cmd: &OsdpCommandBuzzer) {
+        let on = cmd.on_count as u64 * UNIT_MS;
+        let off = cmd.off_count as u64 * UNIT_MS;
+        self.beep = (cmd.control_code >= 2 && on > 0).then_some((on, off));
+        self.count = cmd.rep_count;
+        self.phase = 0;
+    }
+
+    /// Let `elapsed` time pass.
+    pub fn tick(&mut self, elapsed: Duration) {
+        let Some((on, off)) = self.beep else {
+            return;
+        };
+        self.phase += elapsed.as_millis() as u64;
+        if self.count > 0 && self.phase >= self.count as u64 * (on + off) {
+            self.beep = None;
+        }
+    }
+
+    /// Whether the buzzer should sound now
+    pub fn is_sounding(&self) -> bool {
+        matches!(self.beep, Some((on, off)) if self.phase % (on + off) < on)
+    }
+}
+
+/// Commanded state of an output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputState {
+    permanent: bool,
+    /// Temporary state and the time (in ms) that is left of it
+    temporary: Option<(bool, u64)>,
+}
+
+impl OutputState {
+    /// Apply an output command to this output.
+    pub fn apply(&mut self, cmd: &OsdpCommandOutput) {
+        let time = cmd.timer_count as u64 * UNIT_MS;
+        match cmd.control_code {
+            1 | 2 => {
+                self.permanent = cmd.control_code == 2;
+                self.temporary = None;
+            }
+            3 | 4 => self.permanent = cmd.control_code == 4,
+            5 | 6 => self.temporary = (time > 0).then_some((cmd.control_code == 5, time)),
+            _ => {}
+        }
+    }
+
+    /// Let `elapsed` time pass.
+    pub fn tick(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_millis() as u64;
+        if let Some((_, left)) = &mut self.temporary {
+            if *left <= elapsed {
+                self.temporary = None;
+            } else {
+                *left -= elapsed;
+            }
+        }
+    }
+
+    /// Whether the output should be active now
+    pub fn is_active(&self) -> bool {
+        self.temporary.map_or(self.permanent, |(active, _)| active)
+    }
+
+    /// State the output goes back to once the temporary one (if any) is over
+    pub fn permanent(&self) -> bool {
+        self.permanent
+    }
+
+    /// Temporary state of the output and how much longer it lasts, if there
+    /// is one
+    pub fn temporary(&self) -> Option<(bool, Duration)> {
+        self.temporary
+            .map(|(active, left)| (active, Duration::from_millis(left)))
+    }
+}
+
+/// Commanded state of all LEDs, buzzers and outputs of a PD that the CP has
+/// sent commands to; see [`crate::PeripheralDevice::state`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PdState {
+    leds: BTreeMap<(u8, u8), LedState>,
+    buzzers: BTreeMap<u8, BuzzerState>,
+    outputs: BTreeMap<u8, OutputState>,
+}
+
+impl PdState {
+    /// Apply an LED, buzzer or output command; other commands are ignored.
+    pub fn apply(&mut self, cmd: &OsdpCommand) {
+        match cmd {
+            OsdpCommand::Led(c) => self
+                .leds
+                .entry((c.reader, c.led_number))
+                .or_default()
+                .apply(c),
+            OsdpCommand::Buzzer(c) => self.buzzers.entry(c.reader).or_default().apply(c),
+            OsdpCommand::Output(c) => self.outputs.entry(c.output_no).or_default().apply(c),
+            _ => {}
+        }
+    }
+
+    /// Let `elapsed` time pass.
+    pub fn tick(&mut self, elapsed: Duration) {
+        self.leds.values_mut().for_each(|s| s.tick(elapsed));
+        self.buzzers.values_mut().for_each(|s| s.tick(elapsed));
+        self.outputs.values_mut().for_each(|s| s.tick(elapsed));
+    }
+
+    /// State of LED `led_number` of `reader`; `None` if it was never sent a
+    /// command (it is off then).
+    pub fn led(&self, reader: u8, led_number: u8) -> Option<&LedState> {
+        self.leds.get(&(reader, led_number))
+    }
+
+    /// State of the buzzer of `reader`; `None` if it was never sent a
+    /// command (it is silent then).
+    pub fn buzzer(&self, reader: u8) -> Option<&BuzzerState> {
+        self.buzzers.get(&reader)
+    }
+
+    /// State of output `output_no`; `None` if it was never sent a command (it
+    /// is inactive then).
+    pub fn output(&self, output_no: u8) -> Option<&OutputState> {
+        self.outputs.get(&output_no)
+    }
+
+    /// LEDs that were sent commands, by reader and LED number
+    pub fn leds(&self) -> impl Iterator<Item = ((u8, u8), &LedState)> {
+        self.leds.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Buzzers that were sent commands, by reader
+    pub fn buzzers(&self) -> impl Iterator<Item = (u8, &BuzzerState)> {
+        self.buzzers.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Outputs that were sent commands, by output number
+    pub fn outputs(&self) -> impl Iterator<Item = (u8, &OutputState)> {
+        self.outputs.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Status of the first `nr_outputs` outputs (up to 32), to reply to an
+    /// output status query with.
+    pub fn output_status(&self, nr_outputs: usize) -> OsdpStatusReport {
+        let nr_outputs = nr_outputs.min(32);
+        let mask = self
+            .outputs()
+            .filter(|(n, s)| (*n as usize) < nr_outputs && s.is_active())
+            .fold(0, |mask, (n, _)| mask | 1 << n);
+        OsdpStatusReport::new_output(nr_outputs, mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuzzerState, LedState, OutputState, PdState};
+    use crate::{
+        OsdpCommand, OsdpCommandBuzzer, OsdpCommandLed, OsdpCommandOutput, OsdpLedColor,
+        OsdpLedPattern, OsdpLedPermanentControl, OsdpLedTemporaryControl,
+    };
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    const MS_100: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_led_state() {
+        let mut led = LedState::default();
+        assert_eq!(led.color(), OsdpLedColor::None);
+        led.apply(&OsdpCommandLed::new(
+            0,
+            0,
+            OsdpLedTemporaryControl::Set {
+                pattern: OsdpLedPattern::flash(OsdpLedColor::Red, OsdpLedColor::None, 1, 2),
+                timer_count: 5,
+            },
+            OsdpLedPermanentControl::Set(OsdpLedPattern::steady(OsdpLedColor::Green)),
+        ));
+        let colors: Vec<_> = (0..6)
+            .map(|_| {
+                let color = led.color();
+                led.tick(MS_100);
+                color
+            })
+            .collect();
+        use OsdpLedColor::*;
+        assert_eq!(colors, [Red, None, None, Red, None, Green]);
+        led.apply(&OsdpCommandLed::new(
+            0,
+            0,
+            OsdpLedTemporaryControl::Set {
+                pattern: OsdpLedPattern::steady(Blue),
+                timer_count: 50,
+            },
+            OsdpLedPermanentControl::Nop,
+        ));
+        assert_eq!(led.color(), Blue);
+        led.apply(&OsdpCommandLed::new(
+            0,
+            0,
+            OsdpLedTemporaryControl::Cancel,
+            OsdpLedPermanentControl::Nop,
+        ));
+        assert_eq!(led.color(), Green);
+    }
+
+    #[test]
+    fn test_buzzer_state() {
+        let mut buzzer = BuzzerState::default();
+        buzzer.apply(&OsdpCommandBuzzer::access_denied());
+        let beeps: Vec<_> = (0..8)
+            .map(|_| {
+                let on = buzzer.is_sounding();
+                buzzer.tick(MS_100);
+                on
+            })
+            .collect();
+        assert_eq!(beeps, [true, false, true, false, true, false, false, false]);
+        buzzer.apply(&OsdpCommandBuzzer {
+            rep_count: 0,
+            ..OsdpCommandBuzzer::access_denied()
+        });
+        buzzer.tick(Duration::from_secs(3600));
+        assert!(buzzer.is_sounding());
+        buzzer.apply(&OsdpCommandBuzzer::off());
+        assert!(!buzzer.is_sounding());
+    }
+
+    #[test]
+    fn test_output_state() {
+        let mut output = OutputState::default();
+        let cmd = |control_code, timer_count| OsdpCommandOutput {
+            output_no: 0,
+            control_code,
+            timer_count,
+        };
+        output.apply(&cmd(5, 3));
+        assert!(output.is_active());
+        output.apply(&cmd(4, 0)); // permanent on, temporary carries on
+        output.tick(Duration::from_millis(250));
+        assert!(output.is_active());
+        output.apply(&cmd(6, 2)); // temporary off
+        assert!(!output.is_active());
+        output.tick(Duration::from_millis(200));
+        assert!(output.is_active());
+        output.apply(&cmd(5, 10));
+        output.apply(&cmd(1, 0)); // permanent off, temporary aborted
+        assert!(!output.is_active());
+    }
+
+    #[test]
+    fn test_pd_state() {
+        let mut state = PdState::default();
+        let output = |output_no, control_code| {
+            OsdpCommand::Output(OsdpCommandOutput {
+                output_no,
+                control_code,
+                timer_count: 10,
+            })
+        };
+        state.apply(&output(1, 2));
+        state.apply(&output(3, 5));
+        state.apply(&OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted()));
+        assert!(state.led(0, 0).is_none());
+        assert!(state.buzzer(0).unwrap().is_sounding());
+        assert_eq!(
+            state.output(3).unwrap().temporary(),
+            Some((true, Duration::from_secs(1)))
+        );
+        assert_eq!(state.output_status(4).mask, 0b1010);
+        assert_eq!(state.output_status(2).mask, 0b0010);
+
+        state.tick(Duration::from_secs(1));
+        assert!(!state.buzzer(0).unwrap().is_sounding());
+        assert_eq!(state.output_status(4).mask, 0b0010);
+        assert_eq!(state.outputs().map(|(n, _)| n).collect::<Vec<_>>(), [1, 3]);
+    }
+}
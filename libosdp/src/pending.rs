@@ -0,0 +1,812 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP does not expose the depth of its per-PD command queues. This module
+//! tracks it from the Rust side: a command is counted as pending from the time
+//! it is accepted by [`crate::ControlPanel::send_command`] until the CP puts
+//! it on the wire (observed through a [`CommandTap`] around the channel).
+
+use crate::{
+    callback::{catch_panic, Callback},
+    integrity::ReplyFilter,
+    latency::{LatencyWindow, Stamp},
+    timing::PdTiming,
+    wire::{Packet, PacketDecoder, MARK},
+    Channel, ChannelError, FrameDirection, LatencyStats, OsdpIntegrity, PdError, PdErrorKind,
+    ReconfigurableChannel, WireFrame,
+};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+const REPLY_NAK: u8 = 0x41;
+
+/// Command codes that originate from the application command queue (as
+/// opposed to those generated by LibOSDP itself, such as POLL, ID, CAP and
+/// the secure channel handshake). File transfers are not queued.
+const QUEUED_COMMANDS: [u8; 11] = [
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x6B, 0x6E, 0x75, 0x80,
+];
+
+/// Traffic on the channels of a CP since it was last taken
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Traffic {
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+    /// Command packets written (including retransmissions)
+    pub commands_sent: usize,
+}
+
+/// What became of a command sent to a PD, as observed on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// The PD replied with something other than a NAK
+    Ack,
+    /// The PD NAK'd the command; the reason code is 0 if it was encrypted
+    Nak(u8),
+    /// The PD went offline before the command got a reply
+    Dropped,
+}
+
+#[derive(Debug, Default)]
+struct PdCommands {
+    /// Address of the PD on its channel; changes when it accepts a COMSET
+    address: Cell<u8>,
+    pending: AtomicUsize,
+    high_water: AtomicUsize,
+    /// Number of commands accepted so far; the n-th one gets ticket n
+    accepted: AtomicUsize,
+    /// Number of commands put on the wire so far
+    sent: AtomicUsize,
+    /// Ticket of the command the PD is yet to reply to (0 if none)
+    in_flight: AtomicUsize,
+    /// Tickets someone is waiting on, and their outcome once known
+    watched: RefCell<Vec<(usize, Option<Outcome>)>>,
+    last_error: Cell<Option<PdError>>,
+    /// Integrity check required of the PD (see [`crate::PdInfoBuilder::integrity`])
+    policy: Cell<Option<OsdpIntegrity>>,
+    /// Integrity check of the last reply of the PD
+    integrity: Cell<Option<OsdpIntegrity>>,
+    /// When the command the PD is yet to reply to was written
+    sent_at: Cell<Option<Stamp>>,
+    latency: RefCell<LatencyWindow>,
+    /// Response timeout and offline backoff of the PD
+    timing: Cell<PdTiming>,
+}
+
+impl PdCommands {
+    fn resolve(&self, ticket: usize, outcome: Outcome) {
+        let mut watched = self.watched.borrow_mut();
+        for (_, o) in watched.iter_mut().filter(|(t, _)| *t == ticket) {
+            *o = Some(outcome);
+        }
+    }
+}
+
+/// Per-PD pending command counters, shared between the CP and its channels.
+///
+/// Counters are only ever updated from the thread that drives the CP (either
+/// through `send_command` or from within `refresh`) so plain loads and stores
+/// are sufficient. This also keeps it usable on targets without atomic CAS
+/// (such as thumbv6m) where `Arc` isn't available.
+///
+/// LibOSDP sends queued commands in order, one at a time, so the n-th command
+/// accepted for a PD is the n-th one to be put on the wire and the first reply
+/// that follows it is its response. This allows tracking individual commands
+/// by a ticket number handed out by [`PendingCommands::enqueued`].
+#[derive(Debug)]
+pub(crate) struct PendingCommands {
+    pds: Vec<PdCommands>,
+    traffic: Cell<Traffic>,
+    /// Gets every packet seen by the taps; see [`crate::ControlPanel::set_wire_frame_sink`]
+    pub wire_frame_sink: Callback<dyn FnMut(&WireFrame<'_>) + Send>,
+}
+
+impl PendingCommands {
+    pub fn new(num_pd: usize) -> Self {
+        Self {
+            pds: (0..num_pd).map(|_| PdCommands::default()).collect(),
+            traffic: Cell::new(Traffic::default()),
+            wire_frame_sink: Callback::new(),
+        }
+    }
+
+    fn trace(&self, direction: FrameDirection, pd: Option<usize>, packet: &Packet) {
+        let frame = WireFrame {
+            direction,
+            pd: pd.map(|pd| pd as i32),
+            packet,
+        };
+        catch_panic("frame sink", (), || {
+            self.wire_frame_sink.invoke((), |sink| sink(&frame))
+        });
+    }
+
+    /// Traffic since the last call
+    pub fn take_traffic(&self) -> Traffic {
+        self.traffic.take()
+    }
+
+    fn count(&self, f: impl FnOnce(&mut Traffic)) {
+        let mut traffic = self.traffic.get();
+        f(&mut traffic);
+        self.traffic.set(traffic);
+    }
+
+    pub fn address(&self, pd: usize) -> Option<u8> {
+        self.pds.get(pd).map(|p| p.address.get())
+    }
+
+    pub fn set_address(&self, pd: usize, address: u8) {
+        if let Some(p) = self.pds.get(pd) {
+            p.address.set(address);
+        }
+    }
+
+    pub fn set_policy(&self, pd: usize, policy: Option<OsdpIntegrity>) {
+        if let Some(p) = self.pds.get(pd) {
+            p.policy.set(policy);
+        }
+    }
+
+    fn policy(&self, pd: usize) -> Option<OsdpIntegrity> {
+        self.pds.get(pd)?.policy.get()
+    }
+
+    pub fn integrity(&self, pd: usize) -> Option<OsdpIntegrity> {
+        self.pds.get(pd)?.integrity.get()
+    }
+
+    fn set_integrity(&self, pd: usize, integrity: OsdpIntegrity) {
+        if let Some(p) = self.pds.get(pd) {
+            p.integrity.set(Some(integrity));
+        }
+    }
+
+    pub fn set_timing(&self, pd: usize, timing: PdTiming) {
+        if let Some(p) = self.pds.get(pd) {
+            p.timing.set(timing);
+        }
+    }
+
+    fn has_timing(&self, pd: usize) -> bool {
+        self.pds.get(pd).is_some_and(|p| p.timing.get().is_set())
+    }
+
+    /// Whether a command to `pd` must be kept off the wire; `retry` if it is
+    /// a retransmission. See [`PdTiming`].
+    fn holds(&self, pd: usize, retry: bool) -> bool {
+        let Some(p) = self.pds.get(pd) else {
+            return false;
+        };
+        let mut timing = p.timing.get();
+        let hold = timing.hold(retry, Stamp::now());
+        p.timing.set(timing);
+        hold
+    }
+
+    pub fn set_online(&self, pd: usize, online: bool) {
+        if let Some(p) = self.pds.get(pd) {
+            let mut timing = p.timing.get();
+            timing.set_online(online, Stamp::now());
+            p.timing.set(timing);
+        }
+    }
+
+    /// Whether a reply of `pd` must be kept from LibOSDP
+    fn rejects(&self, pd: usize, packet: &Packet) -> bool {
+        packet.is_reply && !packet.use_crc && self.policy(pd) == Some(OsdpIntegrity::Crc16)
+    }
+
+    pub fn pending(&self, pd: usize) -> usize {
+        self.pds
+            .get(pd)
+            .map_or(0, |p| p.pending.load(Ordering::Relaxed))
+    }
+
+    pub fn high_water(&self, pd: usize) -> usize {
+        self.pds
+            .get(pd)
+            .map_or(0, |p| p.high_water.load(Ordering::Relaxed))
+    }
+
+    /// Account for a command accepted by LibOSDP; returns its ticket.
+    pub fn enqueued(&self, pd: usize) -> usize {
+        let Some(p) = self.pds.get(pd) else {
+            return 0;
+        };
+        let n = p.pending.load(Ordering::Relaxed) + 1;
+        p.pending.store(n, Ordering::Relaxed);
+        if n > p.high_water.load(Ordering::Relaxed) {
+            p.high_water.store(n, Ordering::Relaxed);
+        }
+        let ticket = p.accepted.load(Ordering::Relaxed) + 1;
+        p.accepted.store(ticket, Ordering::Relaxed);
+        ticket
+    }
+
+    /// Start recording the outcome of the command identified by `ticket`.
+    /// A ticket can be watched more than once; each [`PendingCommands::outcome`]
+    /// call consumes one of them.
+    pub fn watch(&self, pd: usize, ticket: usize) {
+        if let Some(p) = self.pds.get(pd) {
+            p.watched.borrow_mut().push((ticket, None));
+        }
+    }
+
+    /// Outcome of a watched command, if known. The command is no longer
+    /// watched after this returns `Some(_)`.
+    pub fn outcome(&self, pd: usize, ticket: usize) -> Option<Outcome> {
+        let mut watched = self.pds.get(pd)?.watched.borrow_mut();
+        let pos = watched
+            .iter()
+            .position(|(t, o)| *t == ticket && o.is_some())?;
+        watched.swap_remove(pos).1
+    }
+
+    fn dequeued(&self, pd: usize) {
+        if let Some(p) = self.pds.get(pd) {
+            let n = p.pending.load(Ordering::Relaxed);
+            p.pending.store(n.saturating_sub(1), Ordering::Relaxed);
+            let ticket = p.sent.load(Ordering::Relaxed) + 1;
+            p.sent.store(ticket, Ordering::Relaxed);
+            p.in_flight.store(ticket, Ordering::Relaxed);
+        }
+    }
+
+    pub fn latency_stats(&self, pd: usize) -> Option<LatencyStats> {
+        self.pds.get(pd)?.latency.borrow().stats()
+    }
+
+    fn command_sent(&self, pd: usize) {
+        if let Some(p) = self.pds.get(pd) {
+            p.sent_at.set(Some(Stamp::now()));
+        }
+    }
+
+    fn reply_received(&self, pd: usize) {
+        if let Some(p) = self.pds.get(pd) {
+            if let Some(sent_at) = p.sent_at.take() {
+                p.latency.borrow_mut().record(sent_at.elapsed());
+            }
+            let mut timing = p.timing.get();
+            timing.replied();
+            p.timing.set(timing);
+        }
+    }
+
+    /// The last NAK or timeout seen for a PD
+    pub fn last_error(&self, pd: usize) -> Option<PdError> {
+        self.pds.get(pd)?.last_error.get()
+    }
+
+    fn failed(&self, pd: usize, kind: PdErrorKind) {
+        if let Some(p) = self.pds.get(pd) {
+            p.last_error.set(Some(PdError::now(kind)));
+        }
+    }
+
+    fn replied(&self, pd: usize, outcome: Outcome) {
+        if let Some(p) = self.pds.get(pd) {
+            let ticket = p.in_flight.load(Ordering::Relaxed);
+            if ticket != 0 {
+                p.in_flight.store(0, Ordering::Relaxed);
+                p.resolve(ticket, outcome);
+            }
+        }
+    }
+
+    /// LibOSDP drops all queued commands of a PD when it goes offline.
+    pub fn flush(&self, pd: usize) {
+        if let Some(p) = self.pds.get(pd) {
+            p.pending.store(0, Ordering::Relaxed);
+            p.in_flight.store(0, Ordering::Relaxed);
+            p.sent
+                .store(p.accepted.load(Ordering::Relaxed), Ordering::Relaxed);
+            for (_, outcome) in p.watched.borrow_mut().iter_mut() {
+                outcome.get_or_insert(Outcome::Dropped);
+            }
+        }
+    }
+}
+
+/// Channel wrapper that watches commands written by the CP (and the replies
+/// read back) to keep [`PendingCommands`] up to date.
+#[derive(Debug)]
+pub(crate) struct CommandTap {
+    inner: Box<dyn Channel>,
+    decoder: PacketDecoder,
+    reply_decoder: PacketDecoder,
+    /// (PD offset, last sequence number seen)
+    pds: Vec<(usize, u8)>,
+    /// Owned (boxed) by the ControlPanel. LibOSDP only calls into channels
+    /// from within the CP's methods and the context is torn down before
+    /// the counters are dropped, so this pointer is valid whenever it's used.
+    pending: *const PendingCommands,
+    /// Packets seen in either direction are also fed to this capture
+    #[cfg(feature = "std")]
+    capture: Option<crate::capture::CaptureHandle>,
+    /// Set when a PD on this channel must reply with CRC-16; replies go
+    /// through it, and then `filtered`, on their way to LibOSDP
+    reply_filter: Option<ReplyFilter>,
+    filtered: VecDeque<u8>,
+    /// Set when a PD on this channel has a response timeout or offline
+    /// backoff; commands are then checked against it before they go out
+    timed: bool,
+}
+
+unsafe impl Send for CommandTap {}
+
+impl CommandTap {
+    pub fn new(inner: Box<dyn Channel>, pds: Vec<usize>, pending: *const PendingCommands) -> Self {
+        let strict = pds
+            .iter()
+            .any(|pd| unsafe { &*pending }.policy(*pd) == Some(OsdpIntegrity::Crc16));
+        let timed = pds.iter().any(|pd| unsafe { &*pending }.has_timing(*pd));
+        Self {
+            inner,
+            decoder: PacketDecoder::new(),
+            reply_decoder: PacketDecoder::new(),
+            pds: pds.into_iter().map(|pd| (pd, 0)).collect(),
+            pending,
+            #[cfg(feature = "std")]
+            capture: None,
+            reply_filter: strict.then(ReplyFilter::default),
+            filtered: VecDeque::new(),
+            timed,
+        }
+    }
+
+    /// Record the packets going through this channel to `capture`, whenever
+    /// it is running.
+    #[cfg(feature = "std")]
+    pub fn with_capture(mut self, capture: crate::capture::CaptureHandle) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    #[cfg(feature = "std")]
+    fn capture(&self, packet: &Packet) {
+        if let Some(capture) = &self.capture {
+            capture.record(&packet.to_bytes());
+        }
+    }
+
+    /// Unwrap a channel that is known to be a `CommandTap`.
+    ///
+    /// # Safety
+    ///
+    /// `channel` must have been created from a `Box<CommandTap>`.
+    pub unsafe fn unwrap(channel: Box<dyn Channel>) -> Box<dyn Channel> {
+        Box::from_raw(Box::into_raw(channel) as *mut CommandTap).inner
+    }
+
+    fn pending(&self) -> &PendingCommands {
+        unsafe { &*self.pending }
+    }
+
+    fn find_pd(&mut self, address: u8) -> Option<&mut (usize, u8)> {
+        let pending = unsafe { &*self.pending };
+        self.pds
+            .iter_mut()
+            .find(|(pd, _)| pending.address(*pd) == Some(address))
+    }
+
+    /// `buf`, if it is a plain command to a PD that only takes checksums,
+    /// with its CRC-16 replaced by a checksum. Secure channel packets are left
+    /// alone as their MAC covers the control byte.
+    fn reframe(&mut self, buf: &[u8]) -> Option<Vec<u8>> {
+        let pending = unsafe { &*self.pending };
+        if !self
+            .pds
+            .iter()
+            .any(|(pd, _)| pending.policy(*pd) == Some(OsdpIntegrity::Checksum))
+        {
+            return None;
+        }
+        let start = buf.iter().position(|b| *b != MARK)?;
+        let mut packet = Packet::from_bytes(buf).ok()?;
+        let (pd, _) = self.find_pd(packet.address)?;
+        if packet.is_reply
+            || !packet.use_crc
+            || packet.sc_block.is_some()
+            || pending.policy(*pd) != Some(OsdpIntegrity::Checksum)
+            || packet.to_bytes().len() != buf.len() - start
+        {
+            return None;
+        }
+        packet.use_crc = false;
+        let mut bytes = buf[..start].to_vec();
+        bytes.extend(packet.to_bytes());
+        Some(bytes)
+    }
+
+    /// Whether `buf` is a command that the [`PdTiming`] of its PD keeps off
+    /// the wire
+    fn holds(&mut self, buf: &[u8]) -> bool {
+        if !self.timed {
+            return false;
+        }
+        let Ok(packet) = Packet::from_bytes(buf) else {
+            return false;
+        };
+        let pending = unsafe { &*self.pending };
+        match self.find_pd(packet.address) {
+            Some((pd, last_seq)) if !packet.is_reply => {
+                let retry = packet.sequence != 0 && packet.sequence == *last_seq;
+                pending.holds(*pd, retry)
+            }
+            _ => false,
+        }
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), ChannelError> {
+        let mut started = false;
+        while !buf.is_empty() {
+            match self.inner.write(buf) {
+                Ok(n) => buf = &buf[n..],
+                // Once part of a packet is out, the rest has to follow
+                Err(ChannelError::WouldBlock) if started => continue,
+                Err(e) => return Err(e),
+            }
+            started = true;
+        }
+        Ok(())
+    }
+}
+
+impl Channel for CommandTap {
+    fn get_id(&self) -> i32 {
+        self.inner.get_id()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        if !self.filtered.is_empty() {
+            return Ok(take(&mut self.filtered, buf));
+        }
+        let n = self.inner.read(buf)?;
+        self.pending().count(|t| t.bytes_read += n);
+        self.reply_decoder.push(&buf[..n]);
+        while let Some(packet) = self.reply_decoder.next_packet() {
+            #[cfg(feature = "std")]
+            self.capture(&packet);
+            let pd = self.find_pd(packet.address).map(|&mut (pd, _)| pd);
+            self.pending().trace(FrameDirection::Received, pd, &packet);
+            let Some(pd) = pd else {
+                continue;
+            };
+            if !packet.is_reply {
+                continue;
+            }
+            if self.pending().rejects(pd, &packet) {
+                self.pending().failed(pd, PdErrorKind::Checksum);
+                continue;
+            }
+            self.pending().set_integrity(pd, OsdpIntegrity::of(&packet));
+            self.pending().reply_received(pd);
+            let outcome = match packet.code {
+                REPLY_NAK if packet.is_encrypted() => Outcome::Nak(0),
+                REPLY_NAK => Outcome::Nak(packet.data.first().copied().unwrap_or(0)),
+                _ => Outcome::Ack,
+            };
+            if let Outcome::Nak(reason) = outcome {
+                self.pending().failed(pd, PdErrorKind::from_nak(reason));
+            }
+            self.pending().replied(pd, outcome);
+        }
+        let Some(filter) = &mut self.reply_filter else {
+            return Ok(n);
+        };
+        let pending = unsafe { &*self.pending };
+        let pds = &self.pds;
+        let passed = filter.filter(&buf[..n], |packet| {
+            pds.iter()
+                .find(|(pd, _)| pending.address(*pd) == Some(packet.address))
+                .is_some_and(|(pd, _)| pending.rejects(*pd, packet))
+        });
+        self.filtered.extend(passed);
+        Ok(take(&mut self.filtered, buf))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        if self.holds(buf) {
+            return Ok(buf.len());
+        }
+        let reframed = self.reframe(buf);
+        let n = match &reframed {
+            Some(bytes) => {
+                self.write_all(bytes)?;
+                buf.len()
+            }
+            None => self.inner.write(buf)?,
+        };
+        let written = reframed.as_deref().unwrap_or(&buf[..n]);
+        self.pending().count(|t| t.bytes_written += written.len());
+        self.decoder.push(written);
+        while let Some(packet) = self.decoder.next_packet() {
+            #[cfg(feature = "std")]
+            self.capture(&packet);
+            if !packet.is_reply {
+                self.pending().count(|t| t.commands_sent += 1);
+            }
+            let pd = self.find_pd(packet.address).map(|&mut (pd, _)| pd);
+            self.pending().trace(FrameDirection::Sent, pd, &packet);
+            let Some((pd, last_seq)) = self.find_pd(packet.address) else {
+                continue;
+            };
+            // Retransmissions reuse the sequence number of the original packet
+            let retry = packet.sequence != 0 && packet.sequence == *last_seq;
+            *last_seq = packet.sequence;
+            let pd = *pd;
+            if !packet.is_reply {
+                self.pending().command_sent(pd);
+            }
+            if retry {
+                self.pending().failed(pd, PdErrorKind::Timeout);
+            }
+            if !retry && QUEUED_COMMANDS.contains(&packet.code) {
+                self.pending().dequeued(pd);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        self.inner.flush()
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        self.inner.wait_readable(timeout)
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        self.inner.as_reconfigurable()
+    }
+}
+
+/// Move as many bytes as fit from `from` to `buf`
+fn take(from: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let n = from.len().min(buf.len());
+    for (dst, src) in buf.iter_mut().zip(from.drain(..n)) {
+        *dst = src;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandTap, Outcome, PendingCommands};
+    use crate::{
+        timing::PdTiming, wire::Packet, Channel, ChannelError, OsdpIntegrity, PdErrorKind,
+    };
+    use alloc::{boxed::Box, vec, vec::Vec};
+    use core::time::Duration;
+
+    /// Swallows writes; reads return whatever was put in `replies`.
+    struct TestChannel {
+        replies: Vec<u8>,
+    }
+
+    impl Channel for TestChannel {
+        fn get_id(&self) -> i32 {
+            0
+        }
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+            if self.replies.is_empty() {
+                return Err(ChannelError::WouldBlock);
+            }
+            let n = self.replies.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.replies[..n]);
+            self.replies.drain(..n);
+            Ok(n)
+        }
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), ChannelError> {
+            Ok(())
+        }
+    }
+
+    fn packet(address: u8, is_reply: bool, sequence: u8, code: u8, data: Vec<u8>) -> Vec<u8> {
+        Packet {
+            address,
+            is_reply,
+            sequence,
+            use_crc: true,
+            sc_block: None,
+            code,
+            data,
+            mac: None,
+        }
+        .to_bytes()
+    }
+
+    fn command(address: u8, sequence: u8, code: u8) -> Vec<u8> {
+        packet(address, false, sequence, code, vec![0; 4])
+    }
+
+    fn tap(replies: Vec<u8>, pending: &PendingCommands) -> CommandTap {
+        pending.set_address(1, 5);
+        CommandTap::new(Box::new(TestChannel { replies }), vec![1], pending)
+    }
+
+    #[test]
+    fn test_pending_commands() {
+        let pending = Box::new(PendingCommands::new(2));
+        let mut tap = tap(vec![], &pending);
+        pending.enqueued(1);
+        pending.enqueued(1);
+        assert_eq!(pending.pending(1), 2);
+
+        // POLL does not dequeue anything
+        tap.write(&command(5, 1, 0x60)).unwrap();
+        assert_eq!(pending.pending(1), 2);
+        // OUT to PD-1 does; its retransmission doesn't.
+        tap.write(&command(5, 2, 0x68)).unwrap();
+        tap.write(&command(5, 2, 0x68)).unwrap();
+        assert_eq!(pending.pending(1), 1);
+        let traffic = pending.take_traffic();
+        assert_eq!(traffic.commands_sent, 3);
+        assert_eq!(traffic.bytes_written, 3 * command(5, 1, 0x60).len());
+        assert_eq!(pending.take_traffic().commands_sent, 0);
+        assert_eq!(pending.pending(0), 0);
+
+        pending.flush(1);
+        assert_eq!(pending.pending(1), 0);
+        assert_eq!(pending.high_water(1), 2);
+    }
+
+    #[test]
+    fn test_command_outcome() {
+        let pending = Box::new(PendingCommands::new(2));
+        let ack = packet(5, true, 1, 0x40, vec![]);
+        let nak = packet(5, true, 2, 0x41, vec![0x03]);
+        let mut tap = tap([ack.clone(), nak.clone()].concat(), &pending);
+
+        let first = pending.enqueued(1);
+        let second = pending.enqueued(1);
+        let third = pending.enqueued(1);
+        pending.watch(1, first);
+        pending.watch(1, second);
+        pending.watch(1, third);
+        assert_eq!(pending.outcome(1, first), None);
+
+        tap.write(&command(5, 1, 0x69)).unwrap();
+        tap.read(&mut vec![0; ack.len()]).unwrap();
+        tap.write(&command(5, 2, 0x6A)).unwrap();
+        tap.read(&mut vec![0; nak.len()]).unwrap();
+        assert_eq!(pending.outcome(1, first), Some(Outcome::Ack));
+        assert_eq!(pending.outcome(1, second), Some(Outcome::Nak(0x03)));
+        assert_eq!(pending.outcome(1, third), None);
+
+        pending.flush(1);
+        assert_eq!(pending.outcome(1, third), Some(Outcome::Dropped));
+        assert_eq!(pending.outcome(1, third), None);
+    }
+
+    #[test]
+    fn test_wire_frame_sink() {
+        use crate::FrameDirection::{Received, Sent};
+        let pending = Box::new(PendingCommands::new(2));
+        let ack = packet(5, true, 1, 0x40, vec![]);
+        let mut tap = tap(ack.clone(), &pending);
+        let frames = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = frames.clone();
+        let guard = pending.wire_frame_sink.set(Box::new(move |frame| {
+            let mut frames = sink.lock().unwrap();
+            frames.push((frame.direction, frame.pd, frame.packet.code));
+        }));
+
+        tap.write(&command(5, 1, 0x60)).unwrap();
+        tap.write(&command(0x7f, 1, 0x60)).unwrap();
+        tap.read(&mut vec![0; ack.len()]).unwrap();
+        drop(guard);
+        tap.write(&command(5, 2, 0x60)).unwrap();
+        assert_eq!(
+            *frames.lock().unwrap(),
+            [
+                (Sent, Some(1), 0x60),
+                (Sent, None, 0x60),
+                (Received, Some(1), 0x40)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_error() {
+        let pending = Box::new(PendingCommands::new(2));
+        let nak = packet(5, true, 1, 0x41, vec![0x04]);
+        let mut tap = tap(nak.clone(), &pending);
+        assert_eq!(pending.last_error(1), None);
+
+        tap.write(&command(5, 1, 0x60)).unwrap();
+        tap.write(&command(5, 1, 0x60)).unwrap();
+        assert_eq!(pending.last_error(1).unwrap().kind, PdErrorKind::Timeout);
+        tap.read(&mut vec![0; nak.len()]).unwrap();
+        assert_eq!(pending.last_error(1).unwrap().kind, PdErrorKind::Sequence);
+        assert_eq!(pending.last_error(0), None);
+        // Timed from the retransmission
+        assert_eq!(pending.latency_stats(1).unwrap().samples, 1);
+        assert_eq!(pending.latency_stats(0), None);
+    }
+
+    #[test]
+    fn test_integrity() {
+        let pending = Box::new(PendingCommands::new(2));
+        let ack = packet(5, true, 1, 0x40, vec![]);
+        let mut ack_checksum = Packet::from_bytes(&ack).unwrap();
+        ack_checksum.use_crc = false;
+        let ack_checksum = ack_checksum.to_bytes();
+
+        // Replies either way are taken by default
+        let mut default = tap([ack_checksum.clone(), ack.clone()].concat(), &pending);
+        assert_eq!(pending.integrity(1), None);
+        let mut buf = vec![0; ack_checksum.len()];
+        assert_eq!(default.read(&mut buf).unwrap(), ack_checksum.len());
+        assert_eq!(pending.integrity(1), Some(OsdpIntegrity::Checksum));
+        let mut buf = vec![0; ack.len()];
+        assert_eq!(default.read(&mut buf).unwrap(), ack.len());
+        assert_eq!(pending.integrity(1), Some(OsdpIntegrity::Crc16));
+
+        // Checksum replies are kept from LibOSDP when CRC-16 is required
+        let pending = Box::new(PendingCommands::new(2));
+        pending.set_policy(1, Some(OsdpIntegrity::Crc16));
+        let mut strict = tap([ack_checksum.clone(), ack.clone()].concat(), &pending);
+        let mut buf = vec![0; 64];
+        let n = strict.read(&mut buf).unwrap();
+        assert_eq!(buf[..n], ack);
+        assert_eq!(pending.last_error(1).unwrap().kind, PdErrorKind::Checksum);
+        assert_eq!(pending.integrity(1), Some(OsdpIntegrity::Crc16));
+
+        // Commands go out with a checksum to PDs that only take that
+        let pending = Box::new(PendingCommands::new(2));
+        pending.set_policy(1, Some(OsdpIntegrity::Checksum));
+        let mut legacy = tap(vec![], &pending);
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = sent.clone();
+        let _guard = pending.wire_frame_sink.set(Box::new(move |frame| {
+            sink.lock().unwrap().push(frame.packet.use_crc);
+        }));
+        let poll = [vec![0xFF], command(5, 1, 0x60)].concat();
+        assert_eq!(legacy.write(&poll).unwrap(), poll.len());
+        legacy.write(&command(6, 1, 0x60)).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [false, true]);
+        assert_eq!(pending.take_traffic().bytes_written, 2 * poll.len() - 2);
+    }
+
+    #[test]
+    fn test_timing() {
+        let minute = Duration::from_secs(60);
+
+        // Retransmissions before the response timeout are kept off the wire
+        // (and are not taken for a timeout)
+        let pending = Box::new(PendingCommands::new(2));
+        pending.set_timing(1, PdTiming::new(Some(minute), None));
+        let mut slow = tap(vec![], &pending);
+        let out = command(5, 2, 0x68);
+        assert_eq!(slow.write(&out).unwrap(), out.len());
+        assert_eq!(slow.write(&out).unwrap(), out.len());
+        assert_eq!(pending.take_traffic().commands_sent, 1);
+        assert_eq!(pending.last_error(1), None);
+
+        // So are attempts to reach an offline PD within the backoff, but not
+        // commands to other PDs on the channel
+        let pending = Box::new(PendingCommands::new(2));
+        pending.set_timing(1, PdTiming::new(None, Some((minute, minute))));
+        let mut gone = tap(vec![], &pending);
+        gone.write(&command(5, 1, 0x60)).unwrap();
+        pending.set_online(1, false);
+        gone.write(&command(5, 1, 0x61)).unwrap();
+        gone.write(&command(6, 1, 0x60)).unwrap();
+        assert_eq!(pending.take_traffic().commands_sent, 2);
+        pending.set_online(1, true);
+        gone.write(&command(5, 2, 0x60)).unwrap();
+        assert_eq!(pending.take_traffic().commands_sent, 1);
+    }
+}
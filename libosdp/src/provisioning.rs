@@ -0,0 +1,256 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A freshly installed PD doesn't have a secure channel base key (SCBK) of its
+//! own; it has to be put in install mode where it accepts a secure channel set
+//! up with the well known SCBK-D, over which the CP sends it a key of its own
+//! with a KEYSET command. Until that is done (and install mode is cleared),
+//! anyone on the bus can talk to the PD.
+//!
+//! [`ControlPanel::provision`] walks a CP through this flow, one step at a
+//! time:
+//!
+//! ```ignore
+//! let key = cp
+//!     .provision(pd)?                    // Provisioning<InstallMode>
+//!     .establish_session(timeout)?       // Provisioning<ScbkdSession>
+//!     .set_key(new_scbk, timeout)?       // Provisioning<KeySet>
+//!     .verify(timeout)?;
+//! ```
+//!
+//! Each step consumes the previous one so they can only be taken in order. If
+//! a step fails, or the [`Provisioning`] is dropped before the PD has been
+//! verified to work with its new key, install mode is cleared for the PD.
+//!
+//! The PD itself needs to be in install mode (see [`crate::OsdpFlag`]) for
+//! this to work; the application on the PD side is responsible for clearing
+//! it there and storing the new key once it gets the KEYSET command.
+//!
+//! Keys of provisioned PDs are replaced the same way, over a session with the
+//! current key. A PD that ACKs a KEYSET but doesn't actually switch to the new
+//! key (or loses it) can't set up a session with the CP anymore, which now
+//! uses the new key. [`ControlPanel::rotate_key_with_rollback`] checks that a
+//! session can be set up with the new key and, if not, goes back to the old
+//! one, so that rotating keys across a fleet doesn't leave readers behind.
+
+use crate::{ControlPanel, OsdpCommand, OsdpCommandKeyset, OsdpError, OsdpFlag};
+use core::time::Duration;
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// Provisioning step: install mode has been enabled for the PD
+#[derive(Debug)]
+pub struct InstallMode;
+
+/// Provisioning step: a secure channel session (usually with SCBK-D) is
+/// active
+#[derive(Debug)]
+pub struct ScbkdSession;
+
+/// Provisioning step: the PD has accepted a new SCBK
+#[derive(Debug)]
+pub struct KeySet {
+    key: [u8; 16],
+}
+
+/// Outcome of [`ControlPanel::rotate_key_with_rollback`]
+#[derive(Debug)]
+pub enum KeyRotation {
+    /// The PD took the new key and a secure channel session was set up with
+    /// it. The application must store the new key.
+    Rotated,
+    /// The PD did not take the new key (it NAK'd or didn't answer the
+    /// KEYSET) and is still using the old one, as is the CP.
+    Rejected(OsdpError),
+    /// The PD ACK'd the new key, but no session could be set up with it; one
+    /// was set up with the old key instead, which the CP went back to.
+    RolledBack(OsdpError),
+    /// No session could be set up with either key. The CP is left with the
+    /// key that the PD most likely has (the new one if it ACK'd the KEYSET);
+    /// the application must hold on to both until the PD is back.
+    Unverified(OsdpError),
+}
+
+/// Clears install mode for a PD when dropped
+#[derive(Debug)]
+struct InstallModeGuard<'a> {
+    cp: &'a mut ControlPanel,
+    pd: i32,
+}
+
+impl InstallModeGuard<'_> {
+    /// Refresh the CP until `done` returns true or `timeout` elapses.
+    fn refresh_until<F>(&mut self, timeout: Duration, done: F) -> Result<()>
+    where
+        F: FnMut(&ControlPanel, i32) -> Result<bool>,
+    {
+        self.cp.refresh_until(self.pd, timeout, done)
+    }
+}
+
+impl Drop for InstallModeGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.cp.set_flag(self.pd, OsdpFlag::InstallMode, false) {
+            log::error!("PD-{}: unable to clear install mode: {e:?}", self.pd);
+        }
+    }
+}
+
+/// A PD that is being provisioned, at step `S`. See module documentation.
+#[derive(Debug)]
+pub struct Provisioning<'a, S> {
+    guard: InstallModeGuard<'a>,
+    step: S,
+}
+
+impl<'a, S> Provisioning<'a, S> {
+    fn next<T>(self, step: T) -> Provisioning<'a, T> {
+        Provisioning {
+            guard: self.guard,
+            step,
+        }
+    }
+}
+
+impl<'a> Provisioning<'a, InstallMode> {
+    /// Wait for a secure channel session to be set up with the PD. A PD in
+    /// install mode accepts one with SCBK-D.
+    pub fn establish_session(
+        mut self,
+        timeout: Duration,
+    ) -> Result<Provisioning<'a, ScbkdSession>> {
+        self.guard
+            .refresh_until(timeout, |cp, pd| Ok(cp.sc_active_mask().contains(pd)))?;
+        Ok(self.next(ScbkdSession))
+    }
+}
+
+impl<'a> Provisioning<'a, ScbkdSession> {
+    /// Send `key` to the PD as its new SCBK, and wait for it to accept it.
+    pub fn set_key(self, key: [u8; 16], timeout: Duration) -> Result<Provisioning<'a, KeySet>> {
+        let cmd = OsdpCommand::KeySet(OsdpCommandKeyset::new_scbk(key));
+        self.guard
+            .cp
+            .send_command_sync(self.guard.pd, cmd, timeout)?;
+        Ok(self.next(KeySet { key }))
+    }
+}
+
+impl Provisioning<'_, KeySet> {
+    /// Wait for the secure channel to be set up again, this time with the new
+    /// key, and clear install mode. Returns the new key, which the
+    /// application must store to use for the PD from now on.
+    pub fn verify(mut self, timeout: Duration) -> Result<[u8; 16]> {
+        // The CP tears down the session that was used to send the key and
+        // starts a new one with it.
+        let mut torn_down = false;
+        self.guard.refresh_until(timeout, |cp, pd| {
+            let active = cp.sc_active_mask().contains(pd);
+            torn_down |= !active;
+            Ok(torn_down && active)
+        })?;
+        Ok(self.step.key)
+    }
+}
+
+impl ControlPanel {
+    /// Start provisioning a PD, identified by the offset number (in the order
+    /// PDs were added to [`crate::ControlPanelBuilder`]), with a secure channel
+    /// key of its own; this enables install mode for the PD until
+    /// provisioning is done or abandoned. See [`crate::Provisioning`].
+    ///
+    /// Returns [`OsdpError::InvalidPd`] if there is no such PD.
+    pub fn provision(&mut self, pd: i32) -> Result<Provisioning<'_, InstallMode>> {
+        self.set_flag(pd, OsdpFlag::InstallMode, true)?;
+        Ok(Provisioning {
+            guard: InstallModeGuard { cp: self, pd },
+            step: InstallMode,
+        })
+    }
+
+    /// Replace the secure channel base key of a PD, identified by the offset
+    /// number (in the order PDs were added to [`crate::ControlPanelBuilder`]),
+    /// with `key`. The key is sent over the secure channel session that is
+    /// active with the PD; this returns once the PD has accepted it, after
+    /// which the CP sets up a new session with it. The application must then
+    /// store the new key to use for the PD from now on.
+    ///
+    /// Returns [`OsdpError::Refused`] if there is no secure channel session
+    /// with the PD, [`OsdpError::Nak`] (with the NAK reason code) if the PD
+    /// rejected the key and [`OsdpError::Timeout`] if it did not reply in
+    /// time. The PD is still using its old key in all these cases, except
+    /// possibly the last.
+    pub fn rotate_key(&mut self, pd: i32, key: [u8; 16], timeout: Duration) -> Result<()> {
+        let cmd = OsdpCommand::KeySet(OsdpCommandKeyset::new_scbk(key));
+        self.send_command_sync(pd, cmd, timeout)
+    }
+
+    /// Like [`ControlPanel::rotate_key`], but then checks that a secure
+    /// channel session can be set up with `key` and, if it can't within
+    /// `timeout`, goes back to the old key (see
+    /// [`ControlPanel::set_secure_channel_key`], which this uses and which
+    /// takes all PDs of the CP through the secure channel set up again). Each
+    /// step waits up to `timeout`.
+    ///
+    /// Trying another key sets LibOSDP up again, so when the first key tried
+    /// doesn't work, all PDs on this CP (not just `pd`) go offline and
+    /// through the secure channel set up again, twice if neither key works.
+    /// Commands queued for them at that point are dropped. Rotate keys when
+    /// the whole bus can take that, one PD at a time.
+    ///
+    /// Returns an error, without sending anything, if the CP doesn't know the
+    /// current key of the PD (it was set up without one) or there is no secure
+    /// channel session with it; what became of the rotation otherwise.
+    pub fn rotate_key_with_rollback(
+        &mut self,
+        pd: i32,
+        key: [u8; 16],
+        timeout: Duration,
+    ) -> Result<KeyRotation> {
+        let previous = self.secure_channel_key(pd)?.ok_or(OsdpError::PdInfo(
+            "secure channel key of the PD is not known",
+        ))?;
+        // The keys to try, most likely first
+        let (keyset_error, candidates) = match self.rotate_key(pd, key, timeout) {
+            Ok(()) => (None, [key, previous]),
+            Err(e @ OsdpError::Refused { .. }) => return Err(e),
+            Err(e @ OsdpError::Nak(_)) => return Ok(KeyRotation::Rejected(e)),
+            // The PD may have taken the key and the ACK got lost
+            Err(e) => (Some(e), [previous, key]),
+        };
+        let mut error = None;
+        for (i, candidate) in candidates.into_iter().enumerate() {
+            if i > 0 {
+                self.set_secure_channel_key(pd, candidate)?;
+            }
+            // After an ACK'd KEYSET, the CP tears down the session that was
+            // used to send the key and starts a new one with it.
+            let mut torn_down = i > 0 || keyset_error.is_some();
+            let verified = self.refresh_until(pd, timeout, |cp, pd| {
+                let active = cp.sc_active_mask().contains(pd);
+                torn_down |= !active;
+                Ok(torn_down && active)
+            });
+            match verified {
+                Ok(()) if candidate == key => return Ok(KeyRotation::Rotated),
+                Ok(()) => {
+                    return Ok(match keyset_error {
+                        Some(e) => KeyRotation::Rejected(e),
+                        None => KeyRotation::RolledBack(error.unwrap_or(OsdpError::Timeout)),
+                    })
+                }
+                Err(e) => {
+                    let which = if candidate == key { "new" } else { "old" };
+                    log::warn!("PD-{pd}: no secure channel session with the {which} key");
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        self.set_secure_channel_key(pd, candidates[0])?;
+        Ok(KeyRotation::Unverified(
+            keyset_error.or(error).unwrap_or(OsdpError::Timeout),
+        ))
+    }
+}
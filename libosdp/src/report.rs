@@ -0,0 +1,42 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! What happened during a call to [`crate::ControlPanel::refresh`]. This lets
+//! applications sleep longer when the bus is quiet and feed metrics without
+//! querying LibOSDP again.
+
+use crate::PdBitSet;
+
+/// Summary of a call to [`crate::ControlPanel::refresh`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    /// Bytes read from the channels of the CP
+    pub bytes_read: usize,
+    /// Bytes written to the channels of the CP
+    pub bytes_written: usize,
+    /// Commands (including POLLs and retransmissions) sent to PDs
+    pub commands_sent: usize,
+    /// Events received from PDs
+    pub events_received: usize,
+    /// PDs that came online
+    pub came_online: PdBitSet,
+    /// PDs that went offline
+    pub went_offline: PdBitSet,
+    /// PDs that accepted a new baud rate (in a COMSET) that their channel
+    /// could not be switched to. LibOSDP talks to them at the new rate regardless, so they
+    /// are likely to go offline until the channel is fixed.
+    pub baud_rate_failed: PdBitSet,
+}
+
+impl RefreshReport {
+    /// Whether nothing was sent or received, and no PD came online or went
+    /// offline.
+    pub fn is_idle(&self) -> bool {
+        self.bytes_read == 0
+            && self.bytes_written == 0
+            && self.came_online.is_empty()
+            && self.went_offline.is_empty()
+    }
+}
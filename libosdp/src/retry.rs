@@ -0,0 +1,322 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP retransmits a command a few times when the PD does not reply and
+//! then marks the PD offline, dropping whatever was queued for it. A NAK'd
+//! command is not sent again at all. For PDs that have a [`RetryPolicy`],
+//! the CP follows commands through [`crate::ControlPanel::refresh`] and sends
+//! them again (after a backoff) when they fail in a way that may go away.
+
+use crate::{last_error::RETRYABLE_NAKS, pending::Outcome, OsdpCommand, PdBitSet, PdErrorKind};
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::time::Instant;
+
+/// How long to wait before sending a failed command again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same amount of time before every retry
+    Fixed(Duration),
+    /// Wait `initial` before the first retry and double that for every retry
+    /// after it, up to `max`
+    Exponential {
+        /// Delay before the first retry
+        initial: Duration,
+        /// Upper bound of the delay
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// Delay before retry number `retry` (0 for the first one)
+    fn delay(&self, retry: u8) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(1 << retry.min(31))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+/// When (and how often) a CP sends a command that failed again; see
+/// [`crate::ControlPanel::set_retry_policy`].
+///
+/// A command is retried when the PD did not reply to it (LibOSDP gave up on
+/// the PD and took it offline) or NAK'd it with one of the retryable reason
+/// codes. Other NAKs, and failures after `max_retries` retries, are final.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_retries: u8,
+    backoff: Backoff,
+    retryable_naks: Vec<u8>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RetryPolicy {
+    /// Retry a failed command up to `max_retries` times, 100ms apart. A policy
+    /// with no retries only reports commands that failed.
+    pub fn new(max_retries: u8) -> Self {
+        Self {
+            max_retries,
+            backoff: Backoff::Fixed(Duration::from_millis(100)),
+            retryable_naks: RETRYABLE_NAKS.to_vec(),
+        }
+    }
+
+    /// Set how long to wait before each retry.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the NAK reason codes to retry commands for, replacing the default
+    /// ones (0x01, 0x04 and 0x09).
+    pub fn retry_naks(mut self, reasons: &[u8]) -> Self {
+        self.retryable_naks = reasons.to_vec();
+        self
+    }
+
+    /// Maximum number of times a command is sent again
+    pub fn max_retries(&self) -> u8 {
+        self.max_retries
+    }
+
+    fn is_retryable(&self, outcome: Outcome) -> bool {
+        match outcome {
+            Outcome::Ack => false,
+            Outcome::Nak(reason) => self.retryable_naks.contains(&reason),
+            Outcome::Dropped => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// Waiting for the PD to reply to the command with this ticket
+    Sent(usize),
+    /// Waiting to be sent again
+    Due(Instant),
+}
+
+#[derive(Debug)]
+struct Entry {
+    pd: i32,
+    cmd: OsdpCommand,
+    retries: u8,
+    state: State,
+}
+
+/// Retry policies of the PDs of a CP and the commands they apply to
+#[derive(Debug, Default)]
+pub(crate) struct Retries {
+    policies: Vec<Option<RetryPolicy>>,
+    entries: Vec<Entry>,
+}
+
+impl Retries {
+    pub fn new(num_pd: usize) -> Self {
+        Self {
+            policies: (0..num_pd).map(|_| None).collect(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Set (or clear) the policy of `pd`. Commands already being followed
+    /// are retried according to the new policy, if any, and forgotten
+    /// otherwise.
+    pub fn set_policy(&mut self, pd: i32, policy: Option<RetryPolicy>) {
+        if policy.is_none() {
+            self.entries.retain(|e| e.pd != pd);
+        }
+        self.policies[pd as usize] = policy;
+    }
+
+    /// Forget the commands being followed; for when LibOSDP dropped them
+    /// (and the tickets they were sent with) for good.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Whether commands to `pd` need to be followed
+    pub fn is_enabled(&self, pd: i32) -> bool {
+        matches!(self.policies.get(pd as usize), Some(Some(_)))
+    }
+
+    /// Follow `cmd`, just sent to `pd` with `ticket`
+    pub fn track(&mut self, pd: i32, cmd: OsdpCommand, ticket: usize) {
+        self.entries.push(Entry {
+            pd,
+            cmd,
+            retries: 0,
+            state: State::Sent(ticket),
+        });
+    }
+
+    /// Look up how the PD replied to the commands being followed (with
+    /// `outcome`) and schedule those that need to be sent again. Commands
+    /// that failed for good are passed to `failed`.
+    pub fn resolve(
+        &mut self,
+        now: Instant,
+        mut outcome: impl FnMut(i32, usize) -> Option<Outcome>,
+        mut failed: impl FnMut(i32, OsdpCommand, PdErrorKind),
+    ) {
+        let mut i = 0;
+        while i < self.entries.len() {
+            let entry = &mut self.entries[i];
+            let State::Sent(ticket) = entry.state else {
+                i += 1;
+                continue;
+            };
+            let Some(policy) = &self.policies[entry.pd as usize] else {
+                i += 1;
+                continue;
+            };
+            match outcome(entry.pd, ticket) {
+                None => {}
+                Some(Outcome::Ack) => {
+                    self.entries.swap_remove(i);
+                    continue;
+                }
+                Some(o) if policy.is_retryable(o) && entry.retries < policy.max_retries => {
+                    entry.state = State::Due(now + policy.backoff.delay(entry.retries));
+                }
+                Some(o) => {
+                    let entry = self.entries.swap_remove(i);
+                    let kind = match o {
+                        Outcome::Nak(reason) => PdErrorKind::from_nak(reason),
+                        _ => PdErrorKind::Timeout,
+                    };
+                    failed(entry.pd, entry.cmd, kind);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// When the next command to a PD that is `online` is due to be sent
+    /// again, if any; commands to PDs that are offline wait for them.
+    pub fn next_due(&self, online: PdBitSet) -> Option<Instant> {
+        self.entries
+            .iter()
+            .filter(|e| online.contains(e.pd))
+            .filter_map(|e| match e.state {
+                State::Due(due) => Some(due),
+                State::Sent(_) => None,
+            })
+            .min()
+    }
+
+    /// Send commands that are due again to PDs that are `online` with
+    /// `send`, which returns their new ticket. A command that can't be queued
+    /// right now is tried again on the next call.
+    pub fn resend(
+        &mut self,
+        now: Instant,
+        online: PdBitSet,
+        mut send: impl FnMut(i32, &OsdpCommand) -> Option<usize>,
+    ) {
+        for entry in self.entries.iter_mut() {
+            let State::Due(due) = entry.state else {
+                continue;
+            };
+            if now < due || !online.contains(entry.pd) {
+                continue;
+            }
+            if let Some(ticket) = send(entry.pd, &entry.cmd) {
+                entry.retries += 1;
+                entry.state = State::Sent(ticket);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Retries, RetryPolicy};
+    use crate::{pending::Outcome, OsdpCommand, OsdpCommandBuzzer, PdBitSet, PdErrorKind};
+    use core::{cell::RefCell, time::Duration};
+    use std::time::Instant;
+
+    #[test]
+    fn test_backoff() {
+        let ms = Duration::from_millis;
+        let backoff = Backoff::Exponential {
+            initial: ms(100),
+            max: ms(1000),
+        };
+        assert_eq!(backoff.delay(0), ms(100));
+        assert_eq!(backoff.delay(3), ms(800));
+        assert_eq!(backoff.delay(4), ms(1000));
+        assert_eq!(backoff.delay(255), ms(1000));
+        assert_eq!(Backoff::Fixed(ms(10)).delay(7), ms(10));
+    }
+
+    #[test]
+    fn test_retries() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let online = PdBitSet::new([0b11; 16], 2);
+        let cmd = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+        let mut retries = Retries::new(2);
+        let policy = RetryPolicy::new(2).backoff(Backoff::Fixed(second));
+        retries.set_policy(1, Some(policy));
+        assert!(!retries.is_enabled(0));
+        assert!(retries.is_enabled(1));
+
+        let sent = RefCell::new(Vec::new());
+        let failures = RefCell::new(Vec::new());
+        let run = |retries: &mut Retries, now, outcome: Option<Outcome>| {
+            retries.resolve(
+                now,
+                |_, _| outcome,
+                |pd, _, kind| failures.borrow_mut().push((pd, kind)),
+            );
+            retries.resend(now, online, |pd, _| {
+                sent.borrow_mut().push(pd);
+                Some(sent.borrow().len())
+            });
+        };
+
+        // Retried after the backoff, until the PD ACKs
+        retries.track(1, cmd.clone(), 1);
+        run(&mut retries, start, None);
+        run(&mut retries, start, Some(Outcome::Nak(0x09)));
+        assert_eq!(retries.next_due(online), Some(start + second));
+        assert_eq!(retries.next_due(PdBitSet::new([0b01; 16], 2)), None);
+        run(&mut retries, start, None);
+        run(&mut retries, start + second, None);
+        run(&mut retries, start + second, Some(Outcome::Ack));
+        run(&mut retries, start + second * 10, None);
+        assert_eq!(*sent.borrow(), [1]);
+
+        // Not retried for a NAK that won't go away
+        retries.track(1, cmd.clone(), 2);
+        run(&mut retries, start, Some(Outcome::Nak(0x03)));
+        assert_eq!(*failures.borrow(), [(1, PdErrorKind::Nak(0x03))]);
+
+        // Given up on after max_retries
+        retries.track(1, cmd.clone(), 3);
+        for i in 0..3 {
+            run(&mut retries, start + second * i, Some(Outcome::Dropped));
+            run(&mut retries, start + second * (i + 1), None);
+        }
+        assert_eq!(*sent.borrow(), [1, 1, 1]);
+        assert_eq!(failures.borrow()[1], (1, PdErrorKind::Timeout));
+
+        // Forgotten when the policy is cleared
+        retries.track(1, cmd, 4);
+        retries.set_policy(1, None);
+        run(&mut retries, start, Some(Outcome::Dropped));
+        assert_eq!(failures.borrow().len(), 2);
+    }
+}
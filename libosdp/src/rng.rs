@@ -0,0 +1,73 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The secure channel handshake starts with random challenges from the CP and
+//! the PD, which LibOSDP gets from `osdp_fill_random()`. Its default
+//! implementation uses the C library's `rand()`, which on bare-metal targets
+//! is rarely seeded and never cryptographically secure. Without `std`, this
+//! crate provides `osdp_fill_random()` and reads from an [`RngSource`] (such
+//! as a hardware TRNG) that the application registers with
+//! [`set_rng_source`].
+
+use alloc::boxed::Box;
+use core::{
+    ffi::c_int,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A cryptographically secure random number generator
+///
+/// Closures of the form `Fn(&mut [u8])` implement this trait.
+pub trait RngSource: Send + Sync {
+    /// Fill `buf` with random bytes
+    fn fill(&self, buf: &mut [u8]);
+}
+
+impl<F> RngSource for F
+where
+    F: Fn(&mut [u8]) + Send + Sync,
+{
+    fn fill(&self, buf: &mut [u8]) {
+        self(buf)
+    }
+}
+
+static RNG_SOURCE: AtomicPtr<Box<dyn RngSource>> = AtomicPtr::new(ptr::null_mut());
+
+/// Register the random number generator that LibOSDP gets secure channel
+/// challenges from. CPs and PDs that may set up a secure channel fail to set
+/// up with [`crate::OsdpError::PdInfoBuilder`] until it is.
+///
+/// The source is never dropped, as LibOSDP may be reading from it while it
+/// is being replaced.
+pub fn set_rng_source(source: impl RngSource + 'static) {
+    let source: Box<Box<dyn RngSource>> = Box::new(Box::new(source));
+    RNG_SOURCE.store(Box::into_raw(source), Ordering::Release);
+}
+
+pub(crate) fn has_rng_source() -> bool {
+    !RNG_SOURCE.load(Ordering::Acquire).is_null()
+}
+
+/// Overrides the (weak) definition in LibOSDP, which has no way to report
+/// that it could not get random numbers. Devices that may set up a secure
+/// channel are not set up without a source, so this never runs without
+/// one; should it anyway, it aborts (this function can't unwind into C)
+/// rather than hand LibOSDP predictable challenges.
+#[no_mangle]
+extern "C" fn osdp_fill_random(buf: *mut u8, len: c_int) {
+    let source = RNG_SOURCE.load(Ordering::Acquire);
+    // SAFETY: only ever set from a leaked box in set_rng_source.
+    let Some(source) = (unsafe { source.as_ref() }) else {
+        panic!("LibOSDP needs random numbers but no RngSource was set");
+    };
+    if buf.is_null() || len <= 0 {
+        return;
+    }
+    // SAFETY: LibOSDP passes a buffer of (at least) len bytes.
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf, len as usize) };
+    source.fill(buf);
+}
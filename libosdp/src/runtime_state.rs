@@ -0,0 +1,305 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! PDs change at runtime: a COMSET moves a PD to another address or baud
+//! rate and a KEYSET gives it another secure channel key. A CP that is
+//! restarted with the configuration it was first set up with can't talk to
+//! such PDs anymore. [`crate::ControlPanel::export_state`] captures what has
+//! changed (along with whether each PD was online, with a secure channel
+//! session) so that it can be saved, by the application, in whatever format
+//! it likes (these types implement `serde`'s traits) and handed to the next
+//! CP with [`crate::ControlPanelBuilder::restore_state`].
+//!
+//! The restored CP talks to each PD at the address and baud rate, and with
+//! the key, it had. PDs that were online (or had a secure channel session)
+//! are taken to still be while the CP gets back in touch with them: they are
+//! not reported as having gone offline and come back online in
+//! [`crate::RefreshReport`], to the closure set with
+//! [`crate::ControlPanel::set_sc_status_callback`] or in metrics. A PD that
+//! isn't back within [`RESUME_GRACE`] is reported offline then.
+//! [`crate::ControlPanel::online_mask`] and friends report the actual status
+//! all along.
+//!
+//! Sequence numbers are not part of it: a CP starts over at 0, which PDs
+//! take as a new session. Neither is the secure channel session itself; its
+//! keys are derived from random numbers exchanged in the set up and are not
+//! exposed by LibOSDP, so each PD goes through the set up again (with the
+//! right key) once the CP is back.
+
+use crate::{latency::Stamp, OsdpError, PdBitSet, PdInfo, PdInfoBuilder};
+use alloc::vec::Vec;
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// How long a restored CP takes PDs that were online to still be, while it
+/// gets back in touch with them; see the [module documentation](self).
+pub const RESUME_GRACE: Duration = Duration::from_secs(10);
+
+/// Runtime state of a PD; see [`CpRuntimeState`]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct PdRuntimeState {
+    /// Address the PD was last known to be at
+    pub address: i32,
+    /// Baud rate the PD was last known to be at
+    pub baud_rate: i32,
+    /// Secure channel base key the CP was using for the PD, if any. This is
+    /// a secret; the exported state must be stored with the same care as
+    /// the keys themselves.
+    pub secure_channel_key: Option<[u8; 16]>,
+    /// Whether the PD was online; a CP restored with this state does not
+    /// report it going offline and coming back
+    pub online: bool,
+    /// Whether a secure channel session was active with the PD; a CP
+    /// restored with this state does not report it going inactive and
+    /// becoming active again
+    pub sc_active: bool,
+}
+
+impl PdRuntimeState {
+    pub(crate) fn new(info: &PdInfo, online: bool, sc_active: bool) -> Self {
+        Self {
+            address: info.address(),
+            baud_rate: info.baud_rate(),
+            secure_channel_key: info.secure_channel_key(),
+            online,
+            sc_active,
+        }
+    }
+
+    /// `info` with the address, baud rate and key of this state in place of
+    /// its own
+    pub(crate) fn restore(&self, info: PdInfoBuilder) -> Result<PdInfoBuilder, OsdpError> {
+        let info = info.address(self.address)?.baud_rate(self.baud_rate)?;
+        Ok(match self.secure_channel_key {
+            Some(key) => info.secure_channel_key(key),
+            None => info,
+        })
+    }
+}
+
+/// Runtime state of the PDs of a CP, in the order they were added to
+/// [`crate::ControlPanelBuilder`]; see the [module documentation](self).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct CpRuntimeState {
+    /// State of each PD
+    pub pds: Vec<PdRuntimeState>,
+}
+
+/// PDs of a restored CP that are taken to be online, or to have a secure
+/// channel session, until they actually are again or `grace` has passed.
+#[derive(Debug)]
+pub(crate) struct Resume {
+    online: PdBitSet,
+    sc_active: PdBitSet,
+    since: Stamp,
+    grace: Duration,
+}
+
+impl Default for Resume {
+    fn default() -> Self {
+        Self {
+            online: PdBitSet::default(),
+            sc_active: PdBitSet::default(),
+            since: Stamp::now(),
+            grace: Duration::ZERO,
+        }
+    }
+}
+
+impl Resume {
+    pub(crate) fn new(state: &CpRuntimeState, grace: Duration) -> Self {
+        let num_pd = state.pds.len() as i32;
+        let pds = || state.pds.iter().zip(0..);
+        let online = pds().filter(|(pd, _)| pd.online).map(|(_, n)| n);
+        let sc_active = pds().filter(|(pd, _)| pd.sc_active).map(|(_, n)| n);
+        Self {
+            online: PdBitSet::from_pds(online, num_pd),
+            sc_active: PdBitSet::from_pds(sc_active, num_pd),
+            since: Stamp::now(),
+            grace,
+        }
+    }
+
+    /// Stop waiting for PDs that are not back in time
+    fn expire(&mut self) {
+        if self.since.elapsed() >= self.grace {
+            self.online = PdBitSet::default();
+            self.sc_active = PdBitSet::default();
+        }
+    }
+
+    /// PDs taken to be online
+    pub(crate) fn online(&self) -> PdBitSet {
+        self.online
+    }
+
+    /// PDs taken to have a secure channel session
+    pub(crate) fn sc_active(&self) -> PdBitSet {
+        self.sc_active
+    }
+
+    /// Stop waiting for the PDs in `online`. Returns the PDs that were
+    /// waited for among them (and so were online all along, as far as the
+    /// application knows) and those that are not back in time.
+    pub(crate) fn update_online(&mut self, online: PdBitSet) -> (PdBitSet, PdBitSet) {
+        let waiting = self.online;
+        self.expire();
+        let back = waiting.difference(&waiting.difference(&online));
+        self.online = self.online.difference(&online);
+        (back, waiting.difference(&back).difference(&self.online))
+    }
+
+    /// The PDs with a secure channel session as the application is to see
+    /// them: those in `sc_active` and those that had one before the restart
+    /// and are not back yet.
+    pub(crate) fn update_sc_active(&mut self, sc_active: PdBitSet) -> PdBitSet {
+        self.expire();
+        self.sc_active = self.sc_active.difference(&sc_active);
+        sc_active.union(&self.sc_active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CpRuntimeState, PdRuntimeState, Resume};
+    use crate::{OsdpError, PdBitSet, PdInfoBuilder};
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    fn pd_info() -> PdInfoBuilder {
+        PdInfoBuilder::new()
+            .name("door")
+            .unwrap()
+            .address(101)
+            .unwrap()
+            .baud_rate(115200)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_restore() {
+        // The PD moved and was given a key after it was set up
+        let key = [0x5a; 16];
+        let moved = pd_info()
+            .address(17)
+            .unwrap()
+            .baud_rate(9600)
+            .unwrap()
+            .secure_channel_key(key)
+            .build();
+        let state = PdRuntimeState::new(&moved, true, false);
+        assert_eq!(state.address, 17);
+        assert_eq!(state.baud_rate, 9600);
+        assert_eq!(state.secure_channel_key, Some(key));
+        assert!(state.online && !state.sc_active);
+
+        let restored = state.restore(pd_info()).unwrap().build();
+        assert_eq!(restored.name(), "door");
+        assert_eq!(restored.address(), 17);
+        assert_eq!(restored.baud_rate(), 9600);
+        assert_eq!(restored.secure_channel_key(), Some(key));
+        assert_eq!(PdRuntimeState::new(&restored, true, false), state);
+    }
+
+    #[test]
+    fn test_restore_keeps_key_without_one() {
+        let key = [0xa5; 16];
+        let state = PdRuntimeState::new(&pd_info().build(), false, false);
+        assert_eq!(state.secure_channel_key, None);
+        let restored = state
+            .restore(pd_info().secure_channel_key(key))
+            .unwrap()
+            .build();
+        assert_eq!(restored.secure_channel_key(), Some(key));
+    }
+
+    #[test]
+    fn test_restore_invalid() {
+        let state = PdRuntimeState {
+            baud_rate: 1234,
+            ..PdRuntimeState::new(&pd_info().build(), false, false)
+        };
+        assert!(matches!(
+            state.restore(pd_info()),
+            Err(OsdpError::PdInfoBuilder(_))
+        ));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let state = CpRuntimeState {
+            pds: vec![
+                PdRuntimeState::new(&pd_info().secure_channel_key([1; 16]).build(), true, true),
+                PdRuntimeState::new(&pd_info().address(102).unwrap().build(), false, false),
+            ],
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: CpRuntimeState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, state);
+    }
+
+    fn resume_state() -> CpRuntimeState {
+        let pd = |online, sc_active| PdRuntimeState {
+            online,
+            sc_active,
+            ..Default::default()
+        };
+        CpRuntimeState {
+            pds: vec![pd(true, true), pd(true, false), pd(false, false)],
+        }
+    }
+
+    fn pds(set: PdBitSet) -> Vec<i32> {
+        set.iter().collect()
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut resume = Resume::new(&resume_state(), Duration::from_secs(3600));
+        assert_eq!(pds(resume.online()), [0, 1]);
+        assert_eq!(pds(resume.sc_active()), [0]);
+
+        // Nobody is back yet, and nobody is given up on
+        let none = PdBitSet::from_pds([], 3);
+        let (back, gone) = resume.update_online(none);
+        assert!(back.is_empty() && gone.is_empty());
+        assert_eq!(pds(resume.update_sc_active(none)), [0]);
+
+        // PD 0 is back, without a secure channel session yet; PD 2, which
+        // was offline, came online too
+        let (back, gone) = resume.update_online(PdBitSet::from_pds([0, 2], 3));
+        assert_eq!(pds(back), [0]);
+        assert!(gone.is_empty());
+        assert_eq!(pds(resume.online()), [1]);
+        assert_eq!(pds(resume.update_sc_active(none)), [0]);
+
+        // and then with one; it's no longer waited for
+        let sc = PdBitSet::from_pds([0], 3);
+        assert_eq!(pds(resume.update_sc_active(sc)), [0]);
+        assert!(resume.sc_active().is_empty());
+        assert!(resume.update_sc_active(none).is_empty());
+    }
+
+    #[test]
+    fn test_resume_grace() {
+        let mut resume = Resume::new(&resume_state(), Duration::ZERO);
+        let (back, gone) = resume.update_online(PdBitSet::from_pds([1], 3));
+        assert_eq!(pds(back), [1]);
+        assert_eq!(pds(gone), [0]);
+        assert!(resume.online().is_empty());
+        assert!(resume
+            .update_sc_active(PdBitSet::from_pds([], 3))
+            .is_empty());
+
+        // Nothing is waited for without a restored state
+        let mut resume = Resume::default();
+        let (back, gone) = resume.update_online(PdBitSet::from_pds([0], 3));
+        assert!(back.is_empty() && gone.is_empty());
+        assert_eq!(
+            pds(resume.update_sc_active(PdBitSet::from_pds([0], 3))),
+            [0]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Some commands need to be sent again and again for as long as the
+//! application runs; a text that has to stay on a display that clears itself,
+//! or a heartbeat LED pattern. Instead of running a timer thread that fights
+//! the refresh loop for the CP, these can be scheduled with
+//! [`crate::ControlPanel::schedule`] and are sent from
+//! [`crate::ControlPanel::refresh`] when they are due.
+
+use crate::{OsdpCommand, PdBitSet};
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+#[derive(Debug)]
+struct Entry {
+    pd: i32,
+    cmd: OsdpCommand,
+    interval: Duration,
+    next: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Commands scheduled on a CP
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    entries: Vec<Entry>,
+}
+
+impl Scheduler {
+    /// Send `cmd` to `pd` at `now` and every `interval` after that.
+    pub fn add(
+        &mut self,
+        pd: i32,
+        cmd: OsdpCommand,
+        interval: Duration,
+        now: Instant,
+    ) -> ScheduleGuard {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.entries.push(Entry {
+            pd,
+            cmd,
+            interval,
+            next: now,
+            cancelled: cancelled.clone(),
+        });
+        ScheduleGuard {
+            cancelled: Some(cancelled),
+        }
+    }
+
+    /// Make all commands due at `now`; for when LibOSDP dropped the ones it
+    /// was sending.
+    pub fn restart(&mut self, now: Instant) {
+        for entry in self.entries.iter_mut() {
+            entry.next = now;
+        }
+    }
+
+    /// When the next command to a PD that is `online` is due, if any.
+    /// Commands to PDs that are offline are held until the PD comes back
+    /// (see [`Scheduler::run`]), so they are not due before then.
+    pub fn next_due(&self, online: PdBitSet) -> Option<Instant> {
+        self.entries
+            .iter()
+            .filter(|e| !e.cancelled.load(Ordering::Relaxed) && online.contains(e.pd))
+            .map(|e| e.next)
+            .min()
+    }
+
+    /// Drop cancelled commands and pass those that are due at `now`, to PDs
+    /// that are `online`, to `send`. A command is due again an interval after
+    /// it was sent; one that `send` returns false for is retried on the next
+    /// call.
+    pub fn run(
+        &mut self,
+        now: Instant,
+        online: PdBitSet,
+        mut send: impl FnMut(i32, &OsdpCommand) -> bool,
+    ) {
+        self.entries
+            .retain(|e| !e.cancelled.load(Ordering::Relaxed));
+        for entry in self.entries.iter_mut() {
+            if now < entry.next || !online.contains(entry.pd) || !send(entry.pd, &entry.cmd) {
+                continue;
+            }
+            entry.next += entry.interval;
+            if entry.next <= now {
+                // Refreshes stalled for more than an interval; don't make up
+                // for the ones that were missed.
+                entry.next = now + entry.interval;
+            }
+        }
+    }
+}
+
+/// Handle returned by [`crate::ControlPanel::schedule`].
+///
+/// The command stops being sent when this guard is dropped. Use
+/// [`ScheduleGuard::detach`] to keep sending it for the lifetime of the CP
+/// instead.
+#[must_use = "the command is no longer sent when this guard is dropped"]
+#[derive(Debug)]
+pub struct ScheduleGuard {
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl ScheduleGuard {
+    /// Stop sending the command; same as dropping this guard.
+    pub fn cancel(self) {}
+
+    /// Keep sending the command for the lifetime of the CP.
+    pub fn detach(mut self) {
+        self.cancelled = None;
+    }
+}
+
+impl Drop for ScheduleGuard {
+    fn drop(&mut self) {
+        if let Some(cancelled) = self.cancelled.take() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::{OsdpCommand, OsdpCommandBuzzer, PdBitSet};
+    use core::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::default();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let cmd = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+        let guard = scheduler.add(1, cmd.clone(), second, start);
+        scheduler.add(0, cmd.clone(), second * 2, start).detach();
+
+        let online = PdBitSet::new([0b11; 16], 2);
+        let run = |scheduler: &mut Scheduler, now, online, accept| {
+            let mut sent = Vec::new();
+            scheduler.run(now, online, |pd, c| {
+                assert_eq!(*c, cmd);
+                sent.push(pd);
+                accept
+            });
+            sent
+        };
+        assert_eq!(run(&mut scheduler, start, online, true), [1, 0]);
+        assert!(run(&mut scheduler, start + second / 2, online, true).is_empty());
+        // PD 1 is offline; it gets the command once it's back
+        let only_0 = PdBitSet::new([0b01; 16], 2);
+        assert!(run(&mut scheduler, start + second, only_0, true).is_empty());
+        // ...and, while it is offline, the command isn't due
+        assert_eq!(scheduler.next_due(online), Some(start + second));
+        assert_eq!(scheduler.next_due(only_0), Some(start + second * 2));
+        assert_eq!(
+            run(&mut scheduler, start + second * 2, online, false),
+            [1, 0]
+        );
+        assert_eq!(
+            run(&mut scheduler, start + second * 2, online, true),
+            [1, 0]
+        );
+        // Missed intervals are not made up for
+        assert_eq!(
+            run(&mut scheduler, start + second * 10, online, true),
+            [1, 0]
+        );
+        assert!(run(&mut scheduler, start + second * 10, online, true).is_empty());
+
+        assert_eq!(scheduler.next_due(online), Some(start + second * 11));
+        guard.cancel();
+        assert_eq!(scheduler.next_due(online), Some(start + second * 12));
+        assert_eq!(run(&mut scheduler, start + second * 20, online, true), [0]);
+        // After a restart, it is due right away again
+        assert_eq!(scheduler.next_due(online), Some(start + second * 22));
+        scheduler.restart(start + second * 21);
+        assert_eq!(scheduler.next_due(online), Some(start + second * 21));
+    }
+}
@@ -0,0 +1,970 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Smart card access through a PD in the transparent mode of OSDP's extended
+//! read/write protocol (`osdp_XWR` commands and `osdp_XRD` replies). Once the
+//! CP has switched a reader to this mode, it exchanges ISO 7816 APDUs with the
+//! card on that reader directly, which is what PKI based credentials (PIV,
+//! FICAM, etc.,) need to be authenticated at the door.
+//!
+//! LibOSDP does not carry these messages, so they are encoded here and sent
+//! as raw packets (see [`crate::wire`]):
+//!
+//! - [`OsdpXwrCommand`] and [`OsdpXrdReply`] are the messages themselves.
+//! - [`TransparentSession`] keeps track of an APDU exchange: it splits long
+//!   commands into a chain and fetches long responses with GET RESPONSE, and
+//!   tells what to send next. It does no IO, so it can be used with any
+//!   transport.
+//! - [`TransparentReader`] runs sessions over a [`crate::Channel`] that is not
+//!   being driven by a [`crate::ControlPanel`], much like [`crate::Discovery`].
+//!
+//! Secure PIN entry (`XRW_PCMND` 0x03) is not supported.
+
+use crate::OsdpError;
+use alloc::{collections::VecDeque, vec, vec::Vec};
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+const CMD_XWR: u8 = 0xA1;
+const REPLY_XRD: u8 = 0xB1;
+
+const MODE_STANDARD: u8 = 0x00;
+const MODE_TRANSPARENT: u8 = 0x01;
+
+const CLA_CHAINING: u8 = 0x10;
+const SW_OK: u16 = 0x9000;
+const SW1_MORE_DATA: u8 = 0x61;
+
+/// Bytes of an `osdp_XWR` packet around the command data of an APDU, at most:
+/// packet header and secure channel block (7), command code and XWR header
+/// (4), APDU header with extended lengths (9), encryption padding (16), MAC
+/// and CRC (6).
+const XWR_OVERHEAD: usize = 42;
+
+/// An ISO 7816-4 command APDU
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Apdu {
+    /// Class byte
+    pub cla: u8,
+    /// Instruction byte
+    pub ins: u8,
+    /// First parameter
+    pub p1: u8,
+    /// Second parameter
+    pub p2: u8,
+    /// Command data
+    pub data: Vec<u8>,
+    /// Number of response bytes expected; `Some(0)` for "as many as there
+    /// are" (encoded as Le = 0)
+    pub le: Option<u16>,
+}
+
+impl Apdu {
+    /// An APDU without data that expects no response data
+    pub fn new(cla: u8, ins: u8, p1: u8, p2: u8) -> Self {
+        Self {
+            cla,
+            ins,
+            p1,
+            p2,
+            data: Vec::new(),
+            le: None,
+        }
+    }
+
+    /// Set the command data
+    pub fn with_data(mut self, data: &[u8]) -> Self {
+        self.data = data.to_vec();
+        self
+    }
+
+    /// Set the number of response bytes expected
+    pub fn with_le(mut self, le: u16) -> Self {
+        self.le = Some(le);
+        self
+    }
+
+    /// SELECT a file or application by name (AID)
+    pub fn select(aid: &[u8]) -> Self {
+        Self::new(0x00, 0xA4, 0x04, 0x00).with_data(aid).with_le(0)
+    }
+
+    fn is_extended(&self) -> bool {
+        self.data.len() > 255 || self.le.is_some_and(|le| le > 256)
+    }
+
+    /// Encode this APDU, using the extended length fields only if the data or
+    /// Le does not fit in the short ones.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.data.len() > 0xFFFF {
+            return Err(OsdpError::Command);
+        }
+        let mut buf = vec![self.cla, self.ins, self.p1, self.p2];
+        if self.is_extended() {
+            buf.push(0);
+            if !self.data.is_empty() {
+                buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+                buf.extend_from_slice(&self.data);
+            }
+            if let Some(le) = self.le {
+                buf.extend_from_slice(&le.to_be_bytes());
+            }
+        } else {
+            if !self.data.is_empty() {
+                buf.push(self.data.len() as u8);
+                buf.extend_from_slice(&self.data);
+            }
+            if let Some(le) = self.le {
+                buf.push(le as u8);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Decode an APDU from its short or extended length encoding
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let (header, body) = buf
+            .split_first_chunk::<4>()
+            .ok_or(OsdpError::Wire("APDU too short"))?;
+        let [cla, ins, p1, p2] = *header;
+        let mut apdu = Self::new(cla, ins, p1, p2);
+        let malformed = OsdpError::Wire("malformed APDU");
+        match body {
+            [] => {}
+            [le] => apdu.le = Some(*le as u16),
+            [0, rest @ ..] if rest.len() >= 2 => {
+                let (lc, rest) = rest.split_at(2);
+                let lc = u16::from_be_bytes([lc[0], lc[1]]) as usize;
+                match rest.len().checked_sub(lc) {
+                    // Le only (the Lc field was Le)
+                    _ if rest.is_empty() => apdu.le = Some(lc as u16),
+                    Some(0) => apdu.data = rest.to_vec(),
+                    Some(2) => {
+                        apdu.data = rest[..lc].to_vec();
+                        apdu.le = Some(u16::from_be_bytes([rest[lc], rest[lc + 1]]));
+                    }
+                    _ => return Err(malformed),
+                }
+            }
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) => apdu.data = rest.to_vec(),
+                    Some(1) => {
+                        apdu.data = rest[..lc].to_vec();
+                        apdu.le = Some(rest[lc] as u16);
+                    }
+                    _ => return Err(malformed),
+                }
+            }
+        }
+        Ok(apdu)
+    }
+
+    /// Split this APDU into a command chain (ISO 7816-4, 5.1.1.1) of APDUs
+    /// carrying at most `max_data` bytes of data each. All but the last have
+    /// the chaining bit set in CLA and expect no response data.
+    pub fn chain(&self, max_data: usize) -> Vec<Apdu> {
+        if self.data.len() <= max_data || max_data == 0 {
+            return vec![self.clone()];
+        }
+        let mut chunks: Vec<Apdu> = self
+            .data
+            .chunks(max_data)
+            .map(|data| Apdu {
+                cla: self.cla | CLA_CHAINING,
+                data: data.to_vec(),
+                le: None,
+                ..*self
+            })
+            .collect();
+        let last = chunks.last_mut().unwrap();
+        last.cla = self.cla;
+        last.le = self.le;
+        chunks
+    }
+}
+
+/// An ISO 7816-4 response APDU
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApduResponse {
+    /// Response data
+    pub data: Vec<u8>,
+    /// Status word (SW1 in the most significant byte, SW2 in the other)
+    pub sw: u16,
+}
+
+impl ApduResponse {
+    /// Decode a response APDU: data followed by SW1 and SW2
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let (data, sw) = buf
+            .split_last_chunk::<2>()
+            .ok_or(OsdpError::Wire("APDU response too short"))?;
+        Ok(Self {
+            data: data.to_vec(),
+            sw: u16::from_be_bytes(*sw),
+        })
+    }
+
+    /// Encode this response: data followed by SW1 and SW2
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.data.clone();
+        buf.extend_from_slice(&self.sw.to_be_bytes());
+        buf
+    }
+
+    /// First byte of the status word
+    pub fn sw1(&self) -> u8 {
+        (self.sw >> 8) as u8
+    }
+
+    /// Second byte of the status word
+    pub fn sw2(&self) -> u8 {
+        self.sw as u8
+    }
+
+    /// Returns true if the card completed the command normally (90 00)
+    pub fn is_ok(&self) -> bool {
+        self.sw == SW_OK
+    }
+}
+
+/// Extended read/write command (`osdp_XWR`) sent by the CP
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OsdpXwrCommand {
+    /// Ask for the current mode; answered with [`OsdpXrdReply::Mode`]
+    GetMode,
+
+    /// Switch the PD to transparent mode, or back to standard (card reads are
+    /// then reported as usual)
+    SetMode {
+        /// Whether to switch to transparent mode
+        transparent: bool,
+        /// In transparent mode, whether the PD should report cards as they
+        /// are presented (with [`OsdpXrdReply::CardPresent`]) without being
+        /// asked to [`OsdpXwrCommand::Scan`]
+        card_present_notify: bool,
+    },
+
+    /// Send an APDU to the card on `reader`; answered with
+    /// [`OsdpXrdReply::Apdu`]
+    Apdu {
+        /// Reader number
+        reader: u8,
+        /// Encoded command APDU
+        apdu: Vec<u8>,
+    },
+
+    /// Done with the card on `reader`; the PD may release it
+    ConnectionDone {
+        /// Reader number
+        reader: u8,
+    },
+
+    /// Look for a card on `reader`; answered with
+    /// [`OsdpXrdReply::CardPresent`] if there is one
+    Scan {
+        /// Reader number
+        reader: u8,
+    },
+}
+
+impl OsdpXwrCommand {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            OsdpXwrCommand::GetMode => vec![MODE_STANDARD, 0x01],
+            OsdpXwrCommand::SetMode {
+                transparent,
+                card_present_notify,
+            } => vec![
+                MODE_STANDARD,
+                0x02,
+                if *transparent {
+                    MODE_TRANSPARENT
+                } else {
+                    MODE_STANDARD
+                },
+                *card_present_notify as u8,
+            ],
+            OsdpXwrCommand::Apdu { reader, apdu } => {
+                let mut buf = vec![MODE_TRANSPARENT, 0x01, *reader];
+                buf.extend_from_slice(apdu);
+                buf
+            }
+            OsdpXwrCommand::ConnectionDone { reader } => vec![MODE_TRANSPARENT, 0x02, *reader],
+            OsdpXwrCommand::Scan { reader } => vec![MODE_TRANSPARENT, 0x04, *reader],
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let cmd = match data {
+            [MODE_STANDARD, 0x01] => OsdpXwrCommand::GetMode,
+            [MODE_STANDARD, 0x02, mode @ (MODE_STANDARD | MODE_TRANSPARENT), notify] => {
+                OsdpXwrCommand::SetMode {
+                    transparent: *mode == MODE_TRANSPARENT,
+                    card_present_notify: *notify != 0,
+                }
+            }
+            [MODE_TRANSPARENT, 0x01, reader, apdu @ ..] => OsdpXwrCommand::Apdu {
+                reader: *reader,
+                apdu: apdu.to_vec(),
+            },
+            [MODE_TRANSPARENT, 0x02, reader] => OsdpXwrCommand::ConnectionDone { reader: *reader },
+            [MODE_TRANSPARENT, 0x04, reader] => OsdpXwrCommand::Scan { reader: *reader },
+            _ => return Err(OsdpError::Wire("unsupported XWR command")),
+        };
+        Ok(cmd)
+    }
+
+    /// Encode this command as it appears in the application data of an OSDP
+    /// packet: the `osdp_XWR` code followed by the command data.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut buf = vec![CMD_XWR];
+        buf.extend(self.encode());
+        buf
+    }
+
+    /// Decode a command from the application data of an OSDP packet (see
+    /// [`OsdpXwrCommand::to_wire`]).
+    pub fn from_wire(buf: &[u8]) -> Result<Self> {
+        match buf.split_first() {
+            Some((&CMD_XWR, data)) => Self::decode(data),
+            _ => Err(OsdpError::Wire("not an XWR command")),
+        }
+    }
+}
+
+/// Extended read/write reply (`osdp_XRD`) sent by the PD
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OsdpXrdReply {
+    /// The current mode
+    Mode {
+        /// Whether the PD is in transparent mode
+        transparent: bool,
+        /// Whether the PD reports cards as they are presented
+        card_present_notify: bool,
+    },
+
+    /// A card is present on `reader`
+    CardPresent {
+        /// Reader number
+        reader: u8,
+        /// Card protocol, as reported by the reader
+        protocol: u8,
+        /// Card identification (such as the CSN or ATR) as reported by the
+        /// reader
+        card_data: Vec<u8>,
+    },
+
+    /// Response of the card on `reader` to an [`OsdpXwrCommand::Apdu`]
+    Apdu {
+        /// Reader number
+        reader: u8,
+        /// 0 if the card was reached; any other value is a reader specific
+        /// error (card removed, etc.,) and `apdu` is then empty
+        status: u8,
+        /// Encoded response APDU
+        apdu: Vec<u8>,
+    },
+}
+
+impl OsdpXrdReply {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            OsdpXrdReply::Mode {
+                transparent,
+                card_present_notify,
+            } => vec![
+                MODE_STANDARD,
+                0x01,
+                if *transparent {
+                    MODE_TRANSPARENT
+                } else {
+                    MODE_STANDARD
+                },
+                *card_present_notify as u8,
+            ],
+            OsdpXrdReply::CardPresent {
+                reader,
+                protocol,
+                card_data,
+            } => {
+                let mut buf = vec![MODE_TRANSPARENT, 0x01, *reader, *protocol];
+                buf.extend_from_slice(card_data);
+                buf
+            }
+            OsdpXrdReply::Apdu {
+                reader,
+                status,
+                apdu,
+            } => {
+                let mut buf = vec![MODE_TRANSPARENT, 0x02, *reader, *status];
+                buf.extend_from_slice(apdu);
+                buf
+            }
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let reply = match data {
+            [MODE_STANDARD, 0x01, mode @ (MODE_STANDARD | MODE_TRANSPARENT), notify] => {
+                OsdpXrdReply::Mode {
+                    transparent: *mode == MODE_TRANSPARENT,
+                    card_present_notify: *notify != 0,
+                }
+            }
+            [MODE_TRANSPARENT, 0x01, reader, protocol, card_data @ ..] => {
+                OsdpXrdReply::CardPresent {
+                    reader: *reader,
+                    protocol: *protocol,
+                    card_data: card_data.to_vec(),
+                }
+            }
+            [MODE_TRANSPARENT, 0x02, reader, status, apdu @ ..] => OsdpXrdReply::Apdu {
+                reader: *reader,
+                status: *status,
+                apdu: apdu.to_vec(),
+            },
+            _ => return Err(OsdpError::Wire("unsupported XRD reply")),
+        };
+        Ok(reply)
+    }
+
+    /// Encode this reply as it appears in the application data of an OSDP
+    /// packet: the `osdp_XRD` code followed by the reply data.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut buf = vec![REPLY_XRD];
+        buf.extend(self.encode());
+        buf
+    }
+
+    /// Decode a reply from the application data of an OSDP packet (see
+    /// [`OsdpXrdReply::to_wire`]).
+    pub fn from_wire(buf: &[u8]) -> Result<Self> {
+        match buf.split_first() {
+            Some((&REPLY_XRD, data)) => Self::decode(data),
+            _ => Err(OsdpError::Wire("not an XRD reply")),
+        }
+    }
+}
+
+/// What a [`TransparentSession`] needs done after a reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransparentStep {
+    /// Send this command and pass its reply to
+    /// [`TransparentSession::on_reply`]
+    Send(OsdpXwrCommand),
+    /// The card has answered the APDU
+    Done(ApduResponse),
+}
+
+/// APDU exchanges with the card on one reader of a PD in transparent mode.
+///
+/// This only decides what to send; the caller sends it (over a
+/// [`TransparentReader`] or any other transport) and hands the replies back:
+///
+/// ```
+/// use libosdp::{Apdu, ApduResponse, OsdpXrdReply, OsdpXwrCommand, TransparentSession, TransparentStep};
+///
+/// let mut session = TransparentSession::new(0);
+/// let cmd = session.transmit(&Apdu::select(&[0xA0, 0x00, 0x00, 0x03, 0x08]))?;
+/// assert!(matches!(cmd, OsdpXwrCommand::Apdu { reader: 0, .. }));
+///
+/// // The card has more data than fits in one response: 61 xx
+/// let reply = OsdpXrdReply::Apdu { reader: 0, status: 0, apdu: vec![0x61, 0x10] };
+/// let TransparentStep::Send(get_response) = session.on_reply(&reply)? else {
+///     unreachable!()
+/// };
+/// assert_eq!(get_response, OsdpXwrCommand::Apdu {
+///     reader: 0,
+///     apdu: vec![0x00, 0xC0, 0x00, 0x00, 0x10],
+/// });
+///
+/// let reply = OsdpXrdReply::Apdu { reader: 0, status: 0, apdu: vec![0x4F, 0x90, 0x00] };
+/// let step = session.on_reply(&reply)?;
+/// assert_eq!(step, TransparentStep::Done(ApduResponse { data: vec![0x4F], sw: 0x9000 }));
+/// # Ok::<(), libosdp::OsdpError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct TransparentSession {
+    reader: u8,
+    max_data: usize,
+    card_present_notify: bool,
+    chain: VecDeque<Apdu>,
+    cla: u8,
+    response: Vec<u8>,
+    busy: bool,
+}
+
+impl TransparentSession {
+    /// A session with the card on `reader`
+    pub fn new(reader: u8) -> Self {
+        Self {
+            reader,
+            max_data: 128,
+            card_present_notify: false,
+            chain: VecDeque::new(),
+            cla: 0,
+            response: Vec::new(),
+            busy: false,
+        }
+    }
+
+    /// Chain APDUs with more than `max_data` bytes of data (128 by default).
+    /// Together with the framing and secure channel overhead, this has to fit
+    /// in the receive buffer of the PD; see [`TransparentSession::packet_size`].
+    pub fn max_data(mut self, max_data: usize) -> Self {
+        self.max_data = max_data.max(1);
+        self
+    }
+
+    /// Chain APDUs so that each one fits in a packet of `size` bytes, such as
+    /// [`crate::ControlPanel::max_packet_size`] of the PD.
+    pub fn packet_size(self, size: usize) -> Self {
+        self.max_data(size.saturating_sub(XWR_OVERHEAD))
+    }
+
+    /// Have the PD report cards as they are presented (see
+    /// [`OsdpXwrCommand::SetMode`])
+    pub fn card_present_notify(mut self, notify: bool) -> Self {
+        self.card_present_notify = notify;
+        self
+    }
+
+    /// Reader that this session is with
+    pub fn reader(&self) -> u8 {
+        self.reader
+    }
+
+    /// Command that switches the PD to transparent mode
+    pub fn begin(&self) -> OsdpXwrCommand {
+        OsdpXwrCommand::SetMode {
+            transparent: true,
+            card_present_notify: self.card_present_notify,
+        }
+    }
+
+    /// Command that asks the PD for the card on this reader
+    pub fn scan(&self) -> OsdpXwrCommand {
+        OsdpXwrCommand::Scan {
+            reader: self.reader,
+        }
+    }
+
+    /// Commands that release the card and switch the PD back to standard mode
+    pub fn end(&self) -> [OsdpXwrCommand; 2] {
+        [
+            OsdpXwrCommand::ConnectionDone {
+                reader: self.reader,
+            },
+            OsdpXwrCommand::SetMode {
+                transparent: false,
+                card_present_notify: false,
+            },
+        ]
+    }
+
+    /// Abandon the APDU in progress, if any (say, when the transport failed
+    /// before the card answered).
+    pub fn reset(&mut self) {
+        self.chain.clear();
+        self.response.clear();
+        self.busy = false;
+    }
+
+    fn send(&self, apdu: &Apdu) -> Result<OsdpXwrCommand> {
+        Ok(OsdpXwrCommand::Apdu {
+            reader: self.reader,
+            apdu: apdu.to_bytes()?,
+        })
+    }
+
+    /// Start sending `apdu` to the card. Returns the (first) command to send;
+    /// replies to it go to [`TransparentSession::on_reply`] until that
+    /// returns [`TransparentStep::Done`].
+    pub fn transmit(&mut self, apdu: &Apdu) -> Result<OsdpXwrCommand> {
+        if self.busy {
+            return Err(OsdpError::SmartCard("previous APDU still in progress"));
+        }
+        self.chain = apdu.chain(self.max_data).into();
+        self.cla = apdu.cla;
+        self.response.clear();
+        let first = self.chain.pop_front().unwrap();
+        let cmd = self.send(&first)?;
+        self.busy = true;
+        Ok(cmd)
+    }
+
+    /// Handle a reply to the last command sent for [`TransparentSession::transmit`]
+    pub fn on_reply(&mut self, reply: &OsdpXrdReply) -> Result<TransparentStep> {
+        let step = self.step(reply);
+        if !matches!(step, Ok(TransparentStep::Send(_))) {
+            self.busy = false;
+        }
+        step
+    }
+
+    fn step(&mut self, reply: &OsdpXrdReply) -> Result<TransparentStep> {
+        if !self.busy {
+            return Err(OsdpError::SmartCard("no APDU in progress"));
+        }
+        let OsdpXrdReply::Apdu {
+            reader,
+            status,
+            apdu,
+        } = reply
+        else {
+            return Err(OsdpError::SmartCard("expected an APDU response"));
+        };
+        if *reader != self.reader {
+            return Err(OsdpError::SmartCard("APDU response from another reader"));
+        }
+        if *status != 0 {
+            return Err(OsdpError::SmartCard("reader could not reach the card"));
+        }
+        let mut response = ApduResponse::from_bytes(apdu)?;
+        if !self.chain.is_empty() {
+            // Each link of a chain must be accepted before the next is sent
+            if !response.is_ok() {
+                self.chain.clear();
+                return Ok(TransparentStep::Done(response));
+            }
+            let next = self.chain.pop_front().unwrap();
+            return self.send(&next).map(TransparentStep::Send);
+        }
+        self.response.append(&mut response.data);
+        if response.sw1() == SW1_MORE_DATA {
+            let get_response =
+                Apdu::new(self.cla & 0x03, 0xC0, 0x00, 0x00).with_le(response.sw2() as u16);
+            return self.send(&get_response).map(TransparentStep::Send);
+        }
+        response.data = core::mem::take(&mut self.response);
+        Ok(TransparentStep::Done(response))
+    }
+}
+
+#[cfg(feature = "std")]
+mod reader {
+    use super::{Apdu, ApduResponse, OsdpXrdReply, OsdpXwrCommand, TransparentSession};
+    use super::{TransparentStep, CMD_XWR, REPLY_XRD};
+    use crate::{discover::transact, Channel, OsdpError};
+    use core::time::Duration;
+
+    type Result<T> = core::result::Result<T, OsdpError>;
+
+    const REPLY_ACK: u8 = 0x40;
+    const REPLY_NAK: u8 = 0x41;
+    const REPLY_BUSY: u8 = 0x79;
+
+    /// Runs a [`TransparentSession`] with a PD over `channel`, with raw
+    /// packets outside of secure channel. The channel must not be in use by a
+    /// [`crate::ControlPanel`] meanwhile.
+    pub struct TransparentReader<'a> {
+        channel: &'a mut dyn Channel,
+        address: u8,
+        sequence: u8,
+        timeout: Duration,
+        session: TransparentSession,
+    }
+
+    impl core::fmt::Debug for TransparentReader<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("TransparentReader")
+                .field("channel", &self.channel.get_id())
+                .field("address", &self.address)
+                .field("timeout", &self.timeout)
+                .field("session", &self.session)
+                .finish()
+        }
+    }
+
+    impl<'a> TransparentReader<'a> {
+        /// Talk to the PD at `address` on `channel`. Nothing is sent until
+        /// [`TransparentReader::begin`].
+        pub fn new(
+            channel: &'a mut dyn Channel,
+            address: i32,
+            session: TransparentSession,
+        ) -> Result<Self> {
+            if !(0..=126).contains(&address) {
+                return Err(OsdpError::InvalidPd(address));
+            }
+            Ok(Self {
+                channel,
+                address: address as u8,
+                sequence: 0,
+                timeout: Duration::from_millis(200),
+                session,
+            })
+        }
+
+        /// How long to wait for each reply of the PD (200ms by default)
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// Send `cmd` and return the reply: `None` for an ACK, the
+        /// [`OsdpXrdReply`] otherwise.
+        fn exchange(&mut self, cmd: &OsdpXwrCommand) -> Result<Option<OsdpXrdReply>> {
+            let data = cmd.encode();
+            for _ in 0..3 {
+                let reply = transact(
+                    &mut *self.channel,
+                    self.address,
+                    self.sequence,
+                    CMD_XWR,
+                    data.clone(),
+                    self.timeout,
+                )?
+                .ok_or(OsdpError::Timeout)?;
+                if reply.code == REPLY_BUSY {
+                    // A busy PD expects the same command, with the same
+                    // sequence number, again
+                    std::thread::sleep(Duration::from_millis(20));
+                    continue;
+                }
+                self.sequence = self.sequence % 3 + 1;
+                return match reply.code {
+                    REPLY_ACK => Ok(None),
+                    REPLY_NAK => Err(OsdpError::Nak(reply.data.first().copied().unwrap_or(0))),
+                    REPLY_XRD => OsdpXrdReply::decode(&reply.data).map(Some),
+                    _ => Err(OsdpError::Wire("unexpected reply to XWR")),
+                };
+            }
+            Err(OsdpError::Timeout)
+        }
+
+        /// Switch the PD to transparent mode
+        pub fn begin(&mut self) -> Result<()> {
+            let cmd = self.session.begin();
+            self.exchange(&cmd).map(|_| ())
+        }
+
+        /// Ask the PD for the card on the reader. Returns the reader's
+        /// [`OsdpXrdReply::CardPresent`] report, or `None` if there is no card.
+        pub fn scan(&mut self) -> Result<Option<OsdpXrdReply>> {
+            let cmd = self.session.scan();
+            match self.exchange(&cmd)? {
+                Some(reply @ OsdpXrdReply::CardPresent { .. }) => Ok(Some(reply)),
+                _ => Ok(None),
+            }
+        }
+
+        /// Send `apdu` to the card and wait for its (complete) response
+        pub fn transmit(&mut self, apdu: &Apdu) -> Result<ApduResponse> {
+            let mut cmd = self.session.transmit(apdu)?;
+            loop {
+                let reply = match self.exchange(&cmd) {
+                    Ok(Some(reply)) => reply,
+                    Ok(None) => {
+                        self.session.reset();
+                        return Err(OsdpError::SmartCard("expected an APDU response"));
+                    }
+                    Err(e) => {
+                        self.session.reset();
+                        return Err(e);
+                    }
+                };
+                match self.session.on_reply(&reply)? {
+                    TransparentStep::Send(next) => cmd = next,
+                    TransparentStep::Done(response) => return Ok(response),
+                }
+            }
+        }
+
+        /// Release the card and switch the PD back to standard mode
+        pub fn end(mut self) -> Result<()> {
+            for cmd in self.session.end() {
+                self.exchange(&cmd)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use reader::TransparentReader;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apdu_encoding() {
+        let apdu = Apdu::select(&[0xA0, 0x00, 0x00, 0x03, 0x08]);
+        let bytes = apdu.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            [0x00, 0xA4, 0x04, 0x00, 0x05, 0xA0, 0x00, 0x00, 0x03, 0x08, 0x00]
+        );
+        assert_eq!(Apdu::from_bytes(&bytes).unwrap(), apdu);
+
+        // Case 1 and 2
+        let apdu = Apdu::new(0x00, 0xCA, 0x9F, 0x7F);
+        assert_eq!(apdu.to_bytes().unwrap(), [0x00, 0xCA, 0x9F, 0x7F]);
+        assert_eq!(Apdu::from_bytes(&[0x00, 0xCA, 0x9F, 0x7F]).unwrap(), apdu);
+        let apdu = apdu.with_le(0);
+        assert_eq!(Apdu::from_bytes(&apdu.to_bytes().unwrap()).unwrap(), apdu);
+
+        // Extended lengths
+        let apdu = Apdu::new(0x00, 0xDB, 0x3F, 0xFF).with_data(&[0x55; 300]);
+        let bytes = apdu.to_bytes().unwrap();
+        assert_eq!(bytes[4..7], [0x00, 0x01, 0x2C]);
+        assert_eq!(bytes.len(), 307);
+        assert_eq!(Apdu::from_bytes(&bytes).unwrap(), apdu);
+        let apdu = apdu.with_le(1024);
+        assert_eq!(Apdu::from_bytes(&apdu.to_bytes().unwrap()).unwrap(), apdu);
+        let apdu = Apdu::new(0x00, 0xCB, 0x3F, 0xFF).with_le(1024);
+        let bytes = apdu.to_bytes().unwrap();
+        assert_eq!(bytes[4..], [0x00, 0x04, 0x00]);
+        assert_eq!(Apdu::from_bytes(&bytes).unwrap(), apdu);
+
+        assert!(Apdu::from_bytes(&[0x00, 0xA4, 0x04]).is_err());
+        assert!(Apdu::from_bytes(&[0x00, 0xA4, 0x04, 0x00, 0x05, 0xA0]).is_err());
+    }
+
+    #[test]
+    fn test_xrw_wire() {
+        let commands = [
+            OsdpXwrCommand::GetMode,
+            OsdpXwrCommand::SetMode {
+                transparent: true,
+                card_present_notify: false,
+            },
+            OsdpXwrCommand::Apdu {
+                reader: 1,
+                apdu: vec![0x00, 0xB0, 0x00, 0x00, 0x00],
+            },
+            OsdpXwrCommand::ConnectionDone { reader: 1 },
+            OsdpXwrCommand::Scan { reader: 0 },
+        ];
+        for cmd in commands {
+            assert_eq!(OsdpXwrCommand::from_wire(&cmd.to_wire()).unwrap(), cmd);
+        }
+        assert_eq!(
+            OsdpXwrCommand::Scan { reader: 2 }.to_wire(),
+            [0xA1, 0x01, 0x04, 0x02]
+        );
+
+        let replies = [
+            OsdpXrdReply::Mode {
+                transparent: false,
+                card_present_notify: true,
+            },
+            OsdpXrdReply::CardPresent {
+                reader: 0,
+                protocol: 1,
+                card_data: vec![0x04, 0x11, 0x22, 0x33],
+            },
+            OsdpXrdReply::Apdu {
+                reader: 0,
+                status: 0,
+                apdu: vec![0x90, 0x00],
+            },
+        ];
+        for reply in replies {
+            assert_eq!(OsdpXrdReply::from_wire(&reply.to_wire()).unwrap(), reply);
+        }
+        assert!(OsdpXrdReply::from_wire(&[0xB1, 0x01, 0x09]).is_err());
+        assert!(OsdpXwrCommand::from_wire(&[0xB1, 0x00, 0x01]).is_err());
+    }
+
+    fn card_reply(apdu: &[u8]) -> OsdpXrdReply {
+        OsdpXrdReply::Apdu {
+            reader: 1,
+            status: 0,
+            apdu: apdu.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_session_chaining() {
+        let mut session = TransparentSession::new(1).packet_size(46);
+        let apdu = Apdu::new(0x00, 0xDB, 0x3F, 0xFF)
+            .with_data(&[1, 2, 3, 4, 5, 6, 7, 8, 9])
+            .with_le(0);
+        let sent = |cmd: &OsdpXwrCommand| match cmd {
+            OsdpXwrCommand::Apdu { reader: 1, apdu } => Apdu::from_bytes(apdu).unwrap(),
+            _ => panic!("not an APDU: {cmd:?}"),
+        };
+
+        let first = sent(&session.transmit(&apdu).unwrap());
+        assert_eq!((first.cla, first.data.len(), first.le), (0x10, 4, None));
+        assert!(session.transmit(&apdu).is_err());
+        let TransparentStep::Send(cmd) = session.on_reply(&card_reply(&[0x90, 0x00])).unwrap()
+        else {
+            panic!("chain ended early");
+        };
+        assert_eq!(sent(&cmd).data, [5, 6, 7, 8]);
+        let TransparentStep::Send(cmd) = session.on_reply(&card_reply(&[0x90, 0x00])).unwrap()
+        else {
+            panic!("chain ended early");
+        };
+        let last = sent(&cmd);
+        assert_eq!(
+            (last.cla, &last.data[..], last.le),
+            (0x00, &[9][..], Some(0))
+        );
+        let step = session.on_reply(&card_reply(&[0x90, 0x00])).unwrap();
+        assert_eq!(
+            step,
+            TransparentStep::Done(ApduResponse {
+                data: vec![],
+                sw: 0x9000
+            })
+        );
+
+        // A link that is rejected ends the chain
+        session.transmit(&apdu).unwrap();
+        let step = session.on_reply(&card_reply(&[0x6A, 0x80])).unwrap();
+        assert_eq!(
+            step,
+            TransparentStep::Done(ApduResponse {
+                data: vec![],
+                sw: 0x6A80
+            })
+        );
+        assert!(session.on_reply(&card_reply(&[0x90, 0x00])).is_err());
+    }
+
+    #[test]
+    fn test_session_get_response() {
+        let mut session = TransparentSession::new(1);
+        session
+            .transmit(&Apdu::new(0x02, 0xCB, 0x3F, 0xFF))
+            .unwrap();
+        let step = session.on_reply(&card_reply(&[1, 2, 0x61, 0x00])).unwrap();
+        assert_eq!(
+            step,
+            TransparentStep::Send(OsdpXwrCommand::Apdu {
+                reader: 1,
+                apdu: vec![0x02, 0xC0, 0x00, 0x00, 0x00],
+            })
+        );
+        let step = session.on_reply(&card_reply(&[3, 0x90, 0x00])).unwrap();
+        assert_eq!(
+            step,
+            TransparentStep::Done(ApduResponse {
+                data: vec![1, 2, 3],
+                sw: 0x9000
+            })
+        );
+
+        session
+            .transmit(&Apdu::new(0x00, 0xCB, 0x3F, 0xFF))
+            .unwrap();
+        let removed = OsdpXrdReply::Apdu {
+            reader: 1,
+            status: 1,
+            apdu: vec![],
+        };
+        assert!(session.on_reply(&removed).is_err());
+        // The session can be used again after an error
+        session
+            .transmit(&Apdu::new(0x00, 0xCB, 0x3F, 0xFF))
+            .unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP contexts are not thread safe so a [`ControlPanel`] has to be
+//! driven from one thread at a time. Instead of sharing it behind an
+//! `Arc<Mutex<ControlPanel>>`, applications can [`ControlPanel::split`] it into
+//! a [`Refresher`] that stays with the thread that calls `refresh()` and any
+//! number of [`Commander`] handles that can be handed out to other threads.
+//!
+//! For applications that don't need control over the refresh loop,
+//! [`ControlPanel::spawn`] does all of this and runs the [`Refresher`] on a
+//! background thread.
+
+use crate::{pending::Outcome, ControlPanel, OsdpCommand, OsdpError, OsdpEvent, RefreshReport};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Interval between two refresh calls made by [`ControlPanel::spawn`]. This
+/// is well within the 50ms bound required to meet OSDP timing.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(10);
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// A command queued by a [`Commander`] for the [`Refresher`] to send.
+#[derive(Debug)]
+struct Request {
+    pd: i32,
+    cmd: OsdpCommand,
+    /// Set for [`Commander::send_command_sync`]; gets the PD's response.
+    reply: Option<Sender<Result<()>>>,
+}
+
+/// PD status as last seen by the [`Refresher`].
+#[derive(Debug)]
+struct PdStatus {
+    online: AtomicBool,
+    sc_active: AtomicBool,
+    /// Commands waiting in the queue between Commander and Refresher
+    queued: AtomicUsize,
+    /// Commands waiting in the LibOSDP command queue
+    pending: AtomicUsize,
+}
+
+impl PdStatus {
+    fn new() -> Self {
+        Self {
+            online: AtomicBool::new(false),
+            sc_active: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// The half of a split [`ControlPanel`] that owns the LibOSDP context. See
+/// module documentation for more details.
+#[derive(Debug)]
+pub struct Refresher {
+    cp: ControlPanel,
+    rx: Receiver<Request>,
+    status: Arc<Vec<PdStatus>>,
+    /// (pd, ticket, reply) of commands sent with [`Commander::send_command_sync`]
+    waiting: Vec<(i32, usize, Sender<Result<()>>)>,
+}
+
+/// A cloneable handle to send commands to (and query the status of) PDs
+/// managed by a [`Refresher`]. See module documentation for more details.
+#[derive(Debug, Clone)]
+pub struct Commander {
+    tx: Sender<Request>,
+    status: Arc<Vec<PdStatus>>,
+}
+
+impl ControlPanel {
+    /// Split this CP into a [`Refresher`] and a [`Commander`]. See
+    /// [`Refresher`] and [`Commander`] for more details.
+    pub fn split(self) -> (Refresher, Commander) {
+        let (tx, rx) = mpsc::channel();
+        let status: Arc<Vec<PdStatus>> =
+            Arc::new((0..self.num_pd()).map(|_| PdStatus::new()).collect());
+        let refresher = Refresher {
+            cp: self,
+            rx,
+            status: status.clone(),
+            waiting: Vec::new(),
+        };
+        (refresher, Commander { tx, status })
+    }
+
+    /// Run this CP on a background thread that refreshes it every 10ms.
+    /// Events from PDs are delivered through [`ControlPanelHandle::events`]
+    /// and commands can be sent through the returned handle (it dereferences
+    /// to a [`Commander`]). The thread is stopped when the handle is dropped.
+    pub fn spawn(self) -> Result<ControlPanelHandle> {
+        let (mut refresher, commander) = self.split();
+        let (event_tx, events) = mpsc::channel();
+        refresher
+            .control_panel()
+            .set_event_callback(move |pd, event| {
+                let _ = event_tx.send((pd, event));
+                0
+            })
+            .detach();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("OSDP CP".into())
+            .spawn(move || {
+                let mut deadline = Instant::now();
+                while !thread_stop.load(Ordering::Relaxed) {
+                    refresher.refresh();
+                    // Refresh early (without moving the deadline) if a PD
+                    // sent something in the meantime.
+                    let now = Instant::now();
+                    if deadline <= now {
+                        deadline = (deadline + REFRESH_INTERVAL).max(now);
+                    }
+                    refresher.cp.wait_readable(deadline - now);
+                }
+            })
+            .map_err(|_| OsdpError::Setup)?;
+        Ok(ControlPanelHandle {
+            commander,
+            events,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Refresher {
+    /// Send commands queued by the [`Commander`]s and refresh the underlying
+    /// LibOSDP state. Like [`ControlPanel::refresh`], this method must be
+    /// called at least once every 50ms.
+    ///
+    /// Commands that LibOSDP refuses to accept are dropped. Returns what
+    /// [`ControlPanel::refresh`] did.
+    pub fn refresh(&mut self) -> RefreshReport {
+        while let Ok(Request { pd, cmd, reply }) = self.rx.try_recv() {
+            if let Some(status) = self.status.get(pd as usize) {
+                status.queued.fetch_sub(1, Ordering::Relaxed);
+            }
+            match (self.cp.send_command_tracked(pd, cmd), reply) {
+                (Ok(Some(ticket)), Some(reply)) => self.waiting.push((pd, ticket, reply)),
+                (Ok(None), Some(reply)) => {
+                    let _ = reply.send(Ok(()));
+                }
+                (Ok(_), None) => {}
+                (Err(e), reply) => {
+                    #[cfg(feature = "log")]
+                    log::warn!("Dropping command for PD-{pd}");
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+        }
+        let report = self.cp.refresh();
+        let cp = &self.cp;
+        self.waiting.retain(|(pd, ticket, reply)| {
+            let result = match cp.command_outcome(*pd, *ticket) {
+                None => return true,
+                Some(Outcome::Ack) => Ok(()),
+                Some(Outcome::Nak(reason)) => Err(OsdpError::Nak(reason)),
+                Some(Outcome::Dropped) => Err(OsdpError::Command),
+            };
+            let _ = reply.send(result);
+            false
+        });
+        let online = self.cp.online_mask();
+        let sc_active = self.cp.sc_active_mask();
+        for (pd, status) in self.status.iter().enumerate() {
+            let pd = pd as i32;
+            status.online.store(online.contains(pd), Ordering::Relaxed);
+            status
+                .sc_active
+                .store(sc_active.contains(pd), Ordering::Relaxed);
+            status
+                .pending
+                .store(self.cp.pending_commands(pd), Ordering::Relaxed);
+        }
+        report
+    }
+
+    /// Access the underlying [`ControlPanel`] (to set callbacks, query PD ID,
+    /// capabilities, etc.,).
+    pub fn control_panel(&mut self) -> &mut ControlPanel {
+        &mut self.cp
+    }
+}
+
+impl Commander {
+    /// Queue an [`OsdpCommand`] for a PD identified by the offset number (in
+    /// the order PDs were added to [`crate::ControlPanelBuilder`]). The
+    /// command is handed over to LibOSDP in the next [`Refresher::refresh`].
+    ///
+    /// Returns [`OsdpError::InvalidPd`] if there is no such PD and
+    /// [`OsdpError::Command`] if the [`Refresher`] has been dropped.
+    pub fn send_command(&self, pd: i32, cmd: OsdpCommand) -> Result<()> {
+        self.queue(pd, cmd, None)
+    }
+
+    /// Send an [`OsdpCommand`] to a PD and block until the PD responds to it
+    /// or `timeout` elapses. This needs the [`Refresher`] to be refreshed
+    /// from another thread in the meantime.
+    ///
+    /// Returns [`OsdpError::Nak`] (with the NAK reason code) if the PD
+    /// rejected the command, [`OsdpError::Timeout`] if it did not respond in
+    /// time and [`OsdpError::Command`] if the command could not be sent (or
+    /// the PD went offline before responding). A command that timed out may
+    /// still be sent to the PD later. File transfer commands return as soon
+    /// as the transfer is initiated.
+    pub fn send_command_sync(&self, pd: i32, cmd: OsdpCommand, timeout: Duration) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        self.queue(pd, cmd, Some(tx))?;
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(OsdpError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(OsdpError::Command),
+        }
+    }
+
+    fn queue(&self, pd: i32, cmd: OsdpCommand, reply: Option<Sender<Result<()>>>) -> Result<()> {
+        let status = self.pd_status(pd)?;
+        status.queued.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(Request { pd, cmd, reply }).map_err(|_| {
+            status.queued.fetch_sub(1, Ordering::Relaxed);
+            OsdpError::Command
+        })
+    }
+
+    /// Online status of a PD, as of the last [`Refresher::refresh`]. Returns
+    /// [`OsdpError::InvalidPd`] if there is no such PD.
+    pub fn is_online(&self, pd: i32) -> Result<bool> {
+        Ok(self.pd_status(pd)?.online.load(Ordering::Relaxed))
+    }
+
+    /// Secure channel status of a PD, as of the last [`Refresher::refresh`].
+    /// Returns [`OsdpError::InvalidPd`] if there is no such PD.
+    pub fn is_sc_active(&self, pd: i32) -> Result<bool> {
+        Ok(self.pd_status(pd)?.sc_active.load(Ordering::Relaxed))
+    }
+
+    fn pd_status(&self, pd: i32) -> Result<&PdStatus> {
+        usize::try_from(pd)
+            .ok()
+            .and_then(|pd| self.status.get(pd))
+            .ok_or(OsdpError::InvalidPd(pd))
+    }
+
+    /// Number of commands for a PD that are yet to be sent to it; this
+    /// includes commands not yet picked up by the [`Refresher`]. See
+    /// [`ControlPanel::pending_commands`].
+    pub fn pending_commands(&self, pd: i32) -> usize {
+        self.status.get(pd as usize).map_or(0, |s| {
+            s.queued.load(Ordering::Relaxed) + s.pending.load(Ordering::Relaxed)
+        })
+    }
+}
+
+/// Handle to a [`ControlPanel`] running on a background thread. See
+/// [`ControlPanel::spawn`].
+#[derive(Debug)]
+pub struct ControlPanelHandle {
+    commander: Commander,
+    events: Receiver<(i32, OsdpEvent)>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlPanelHandle {
+    /// Receiver for `(pd, event)` tuples of events sent by PDs.
+    pub fn events(&self) -> &Receiver<(i32, OsdpEvent)> {
+        &self.events
+    }
+
+    /// Get a [`Commander`] that can be moved to other threads.
+    pub fn commander(&self) -> Commander {
+        self.commander.clone()
+    }
+}
+
+impl Deref for ControlPanelHandle {
+    type Target = Commander;
+
+    fn deref(&self) -> &Self::Target {
+        &self.commander
+    }
+}
+
+impl Drop for ControlPanelHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
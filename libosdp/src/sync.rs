@@ -0,0 +1,125 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A [`ControlPanel`] is `Send` but not `Sync`: LibOSDP contexts must only be
+//! used from one thread at a time. [`SyncControlPanel`] puts one behind a lock
+//! with methods that take `&self`, so that it can be shared (in an `Arc`)
+//! between a thread that refreshes it and any number of threads that send
+//! commands, without each application coming up with its own locking.
+//!
+//! Every method holds the lock only for as long as the call to LibOSDP takes;
+//! [`SyncControlPanel::refresh`] does not block. Unlike
+//! [`ControlPanel::split`], commands are handed to LibOSDP right away and
+//! errors are returned to the thread that sent them.
+
+use crate::{
+    ControlPanel, OsdpCommand, OsdpError, PdBitSet, PdCapability, PdError, PdId, RefreshReport,
+};
+use std::sync::{Mutex, MutexGuard};
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// A [`ControlPanel`] that can be shared between threads. See module
+/// documentation for more details.
+///
+/// Callbacks run with the lock held, from within
+/// [`SyncControlPanel::refresh`]; calling into the same `SyncControlPanel`
+/// from one of them deadlocks. Use [`ControlPanel::set_event_handler`], which
+/// is given the CP, to send commands in response to events.
+#[derive(Debug)]
+pub struct SyncControlPanel {
+    cp: Mutex<ControlPanel>,
+}
+
+impl From<ControlPanel> for SyncControlPanel {
+    fn from(cp: ControlPanel) -> Self {
+        Self::new(cp)
+    }
+}
+
+impl SyncControlPanel {
+    /// Wrap `cp` so that it can be shared between threads.
+    pub fn new(cp: ControlPanel) -> Self {
+        Self { cp: Mutex::new(cp) }
+    }
+
+    /// Lock the underlying [`ControlPanel`], for anything that this type does
+    /// not have a method for (setting callbacks, for instance). Don't hold on
+    /// to it for long; the CP must be refreshed at least once every 50ms.
+    ///
+    /// A callback that panicked is dropped without leaving the CP in a bad
+    /// state (see [`crate::CallbackGuard`]), so a poisoned lock is recovered
+    /// from.
+    pub fn lock(&self) -> MutexGuard<'_, ControlPanel> {
+        self.cp.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Unwrap the underlying [`ControlPanel`].
+    pub fn into_inner(self) -> ControlPanel {
+        self.cp.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// See [`ControlPanel::refresh`]. This must be called at least once every
+    /// 50ms, from any thread.
+    pub fn refresh(&self) -> RefreshReport {
+        self.lock().refresh()
+    }
+
+    /// See [`ControlPanel::send_command`].
+    pub fn send_command(&self, pd: i32, cmd: OsdpCommand) -> Result<()> {
+        self.lock().send_command(pd, cmd)
+    }
+
+    /// See [`ControlPanel::is_online`].
+    pub fn is_online(&self, pd: i32) -> Result<bool> {
+        self.lock().is_online(pd)
+    }
+
+    /// See [`ControlPanel::is_sc_active`].
+    pub fn is_sc_active(&self, pd: i32) -> Result<bool> {
+        self.lock().is_sc_active(pd)
+    }
+
+    /// See [`ControlPanel::online_mask`].
+    pub fn online_mask(&self) -> PdBitSet {
+        self.lock().online_mask()
+    }
+
+    /// See [`ControlPanel::sc_active_mask`].
+    pub fn sc_active_mask(&self) -> PdBitSet {
+        self.lock().sc_active_mask()
+    }
+
+    /// See [`ControlPanel::pending_commands`].
+    pub fn pending_commands(&self, pd: i32) -> usize {
+        self.lock().pending_commands(pd)
+    }
+
+    /// See [`ControlPanel::last_error`].
+    pub fn last_error(&self, pd: i32) -> Result<Option<PdError>> {
+        self.lock().last_error(pd)
+    }
+
+    /// See [`ControlPanel::get_pd_id`].
+    pub fn get_pd_id(&self, pd: i32) -> Result<PdId> {
+        self.lock().get_pd_id(pd)
+    }
+
+    /// See [`ControlPanel::get_capability`].
+    pub fn get_capability(&self, pd: i32, cap: PdCapability) -> Result<PdCapability> {
+        self.lock().get_capability(pd, cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncControlPanel;
+
+    #[test]
+    fn test_sync_control_panel_bounds() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SyncControlPanel>();
+    }
+}
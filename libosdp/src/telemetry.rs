@@ -0,0 +1,398 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A [`MeteredChannel`] wraps a [`Channel`] to keep count of the bytes that
+//! went through it, how long its reads and writes took and how many of them
+//! failed; this helps observe the health of a bus without a logic analyzer.
+//!
+//! This is also where the integration with the
+//! [`metrics`](https://docs.rs/metrics) crate lives. When the `metrics`
+//! feature is enabled, every channel handed to LibOSDP is wrapped in a
+//! [`MeteredChannel`] which also decodes the traffic flowing through it and
+//! emits the following per-PD (labelled by PD `address`) counters:
+//!
+//!   - `osdp_polls_total` - POLL commands sent by the CP
+//!   - `osdp_retries_total` - commands re-sent with the same sequence number
+//!   - `osdp_naks_total` - NAK replies sent by the PD
+//!   - `osdp_events_total` - event carrying replies sent by the PD
+//!   - `osdp_sc_handshakes_total` - secure channel (re)key attempts
+//!
+//! along with the following, labelled by `channel` ID (and `op` - read, write
+//! or flush - where it applies):
+//!
+//!   - `osdp_bytes_received_total` and `osdp_bytes_sent_total`
+//!   - `osdp_channel_errors_total` - calls that failed
+//!   - `osdp_channel_op_seconds` - histogram of the time taken by calls that
+//!     succeeded
+//!
+//! The CP also publishes a `osdp_pd_online` and `osdp_pd_sc_active` gauge per
+//! PD from its refresh path along with `osdp_pending_commands` and
+//! `osdp_pending_commands_high_water` gauges. These are labelled by PD
+//! `address` too, so that they can be joined with the counters above.
+//! Install any `metrics` compatible recorder (such as
+//! `metrics-exporter-prometheus`) in your application to collect them.
+//! Channels wrapped by the application are not decoded or reported a second
+//! time; only their statistics are kept.
+
+#[cfg(feature = "metrics")]
+use crate::wire::{Packet, PacketDecoder};
+use crate::{Channel, ChannelError, ReconfigurableChannel};
+#[cfg(feature = "metrics")]
+use alloc::{boxed::Box, string::ToString};
+use core::time::Duration;
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+#[cfg(feature = "metrics")]
+const CMD_POLL: u8 = 0x60;
+#[cfg(feature = "metrics")]
+const CMD_CHLNG: u8 = 0x76;
+#[cfg(feature = "metrics")]
+const REPLY_NAK: u8 = 0x41;
+#[cfg(feature = "metrics")]
+const EVENT_REPLIES: [u8; 8] = [0x48, 0x49, 0x4A, 0x4B, 0x50, 0x51, 0x53, 0x90];
+
+/// Statistics of one kind of channel operation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// Number of calls that succeeded
+    pub calls: u64,
+    /// Number of calls that failed (other than with
+    /// [`ChannelError::WouldBlock`])
+    pub errors: u64,
+    /// Bytes transferred
+    pub bytes: u64,
+    /// Total time taken by the calls that succeeded
+    pub total_time: Duration,
+    /// Longest time taken by a call that succeeded
+    pub max_time: Duration,
+}
+
+impl OpStats {
+    /// Mean time taken by the calls that succeeded
+    pub fn mean_time(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((self.total_time.as_nanos() / n as u128) as u64),
+        }
+    }
+
+    fn record(&mut self, elapsed: Duration, bytes: usize) {
+        self.calls += 1;
+        self.bytes += bytes as u64;
+        self.total_time += elapsed;
+        self.max_time = self.max_time.max(elapsed);
+    }
+}
+
+/// A snapshot of the statistics of an [`MeteredChannel`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Reads that returned data (or failed)
+    pub read: OpStats,
+    /// Reads that found nothing to read
+    pub empty_reads: u64,
+    /// Writes
+    pub write: OpStats,
+    /// Flushes
+    pub flush: OpStats,
+}
+
+/// A handle to the statistics of an [`MeteredChannel`] that outlives it
+/// being handed over to a CP or PD. See [`MeteredChannel::stats_handle`].
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStatsHandle(Arc<Mutex<ChannelStats>>);
+
+impl ChannelStatsHandle {
+    /// Take a snapshot of the statistics
+    pub fn snapshot(&self) -> ChannelStats {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start counting from zero again
+    pub fn reset(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = ChannelStats::default();
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ChannelStats)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Decodes the traffic of a channel to emit wire level metrics
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct WireMetrics {
+    rx: PacketDecoder,
+    tx: PacketDecoder,
+    last_sequence: HashMap<u8, u8>,
+}
+
+#[cfg(feature = "metrics")]
+impl WireMetrics {
+    fn received(&mut self, channel: i32, data: &[u8]) {
+        metrics::counter!("osdp_bytes_received_total", "channel" => channel.to_string())
+            .increment(data.len() as u64);
+        self.rx.push(data);
+        while let Some(packet) = self.rx.next_packet() {
+            self.record(packet);
+        }
+    }
+
+    fn sent(&mut self, channel: i32, data: &[u8]) {
+        metrics::counter!("osdp_bytes_sent_total", "channel" => channel.to_string())
+            .increment(data.len() as u64);
+        self.tx.push(data);
+        while let Some(packet) = self.tx.next_packet() {
+            self.record(packet);
+        }
+    }
+
+    fn record(&mut self, packet: Packet) {
+        let address = packet.address.to_string();
+        if packet.is_reply {
+            if packet.code == REPLY_NAK {
+                metrics::counter!("osdp_naks_total", "address" => address).increment(1);
+            } else if EVENT_REPLIES.contains(&packet.code) {
+                metrics::counter!("osdp_events_total", "address" => address).increment(1);
+            }
+            return;
+        }
+        let last = self.last_sequence.insert(packet.address, packet.sequence);
+        if packet.sequence != 0 && last == Some(packet.sequence) {
+            metrics::counter!("osdp_retries_total", "address" => address.clone()).increment(1);
+        }
+        match packet.code {
+            CMD_POLL => metrics::counter!("osdp_polls_total", "address" => address).increment(1),
+            CMD_CHLNG => {
+                metrics::counter!("osdp_sc_handshakes_total", "address" => address).increment(1)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A [`Channel`] that keeps statistics of the traffic going through another
+/// one. See module documentation.
+#[derive(Debug)]
+pub struct MeteredChannel<T: Channel> {
+    inner: T,
+    stats: ChannelStatsHandle,
+    /// Set on the channels wrapped for LibOSDP
+    #[cfg(feature = "metrics")]
+    wire: Option<WireMetrics>,
+}
+
+/// [`MeteredChannel`] by the name it was first added under; both keep the
+/// same traffic, latency and error statistics.
+pub type InstrumentedChannel<T> = MeteredChannel<T>;
+
+impl<T: Channel> MeteredChannel<T> {
+    /// Wrap `inner`
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            stats: ChannelStatsHandle::default(),
+            #[cfg(feature = "metrics")]
+            wire: None,
+        }
+    }
+
+    /// Take a snapshot of the statistics of this channel
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.snapshot()
+    }
+
+    /// Get a handle to read the statistics from after this channel has been
+    /// handed over to a CP or PD.
+    pub fn stats_handle(&self) -> ChannelStatsHandle {
+        self.stats.clone()
+    }
+
+    /// Unwrap this channel, returning the underlying channel
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn measure<R>(
+        &mut self,
+        op: &'static str,
+        f: impl FnOnce(&mut T) -> Result<R, ChannelError>,
+        bytes: impl FnOnce(&R) -> usize,
+        stats: impl FnOnce(&mut ChannelStats) -> &mut OpStats,
+    ) -> Result<R, ChannelError> {
+        let start = Instant::now();
+        let res = f(&mut self.inner);
+        let elapsed = start.elapsed();
+        #[cfg(feature = "metrics")]
+        let channel = self.wire.is_some().then(|| self.inner.get_id().to_string());
+        match &res {
+            Ok(r) => {
+                let bytes = bytes(r);
+                if op == "read" && bytes == 0 {
+                    self.stats.update(|s| s.empty_reads += 1);
+                    return res;
+                }
+                self.stats.update(|s| stats(s).record(elapsed, bytes));
+                #[cfg(feature = "metrics")]
+                if let Some(channel) = channel {
+                    metrics::histogram!("osdp_channel_op_seconds", "channel" => channel, "op" => op)
+                        .record(elapsed.as_secs_f64());
+                }
+            }
+            Err(ChannelError::WouldBlock) if op == "read" => {
+                self.stats.update(|s| s.empty_reads += 1);
+            }
+            Err(_) => {
+                self.stats.update(|s| stats(s).errors += 1);
+                #[cfg(feature = "metrics")]
+                if let Some(channel) = channel {
+                    metrics::counter!("osdp_channel_errors_total", "channel" => channel, "op" => op)
+                        .increment(1);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl MeteredChannel<Box<dyn Channel>> {
+    /// Wrap a channel that is being handed over to LibOSDP; this one also
+    /// emits metrics.
+    pub(crate) fn with_metrics(inner: Box<dyn Channel>) -> Self {
+        Self {
+            wire: Some(WireMetrics::default()),
+            ..Self::new(inner)
+        }
+    }
+}
+
+impl<T: Channel> Channel for MeteredChannel<T> {
+    fn get_id(&self) -> i32 {
+        self.inner.get_id()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        let n = self.measure("read", |c| c.read(buf), |n| *n, |s| &mut s.read)?;
+        #[cfg(feature = "metrics")]
+        if let (Some(wire), true) = (&mut self.wire, n > 0) {
+            wire.received(self.inner.get_id(), &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+        let n = self.measure("write", |c| c.write(buf), |n| *n, |s| &mut s.write)?;
+        #[cfg(feature = "metrics")]
+        if let (Some(wire), true) = (&mut self.wire, n > 0) {
+            wire.sent(self.inner.get_id(), &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), ChannelError> {
+        self.measure("flush", |c| c.flush(), |_| 0, |s| &mut s.flush)
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> Result<bool, ChannelError> {
+        self.inner.wait_readable(timeout)
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        self.inner.as_reconfigurable()
+    }
+}
+
+/// Publish the online and secure channel status of the PD at `address`.
+#[cfg(feature = "metrics")]
+pub(crate) fn record_pd_status(address: i32, online: bool, sc_active: bool) {
+    let address = address.to_string();
+    metrics::gauge!("osdp_pd_online", "address" => address.clone()).set(online as u8 as f64);
+    metrics::gauge!("osdp_pd_sc_active", "address" => address).set(sc_active as u8 as f64);
+}
+
+/// Publish the command queue depth (and its high-water mark) of the PD at
+/// `address`.
+#[cfg(feature = "metrics")]
+pub(crate) fn record_pending_commands(address: i32, pending: usize, high_water: usize) {
+    let address = address.to_string();
+    metrics::gauge!("osdp_pending_commands", "address" => address.clone()).set(pending as f64);
+    metrics::gauge!("osdp_pending_commands_high_water", "address" => address)
+        .set(high_water as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MeteredChannel;
+    use crate::{Channel, ChannelError};
+    use alloc::vec::Vec;
+
+    struct TestChannel {
+        rx: Vec<u8>,
+        fail_writes: bool,
+    }
+
+    impl Channel for TestChannel {
+        fn get_id(&self) -> i32 {
+            0
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+            if self.rx.is_empty() {
+                return Err(ChannelError::WouldBlock);
+            }
+            let n = buf.len().min(self.rx.len());
+            buf[..n].copy_from_slice(&self.rx.drain(..n).collect::<Vec<_>>());
+            Ok(n)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ChannelError> {
+            if self.fail_writes {
+                return Err(ChannelError::TransportError);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), ChannelError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_metered_channel() {
+        let mut channel = MeteredChannel::new(TestChannel {
+            rx: vec![1, 2, 3, 4, 5],
+            fail_writes: false,
+        });
+        let handle = channel.stats_handle();
+        let mut buf = [0u8; 4];
+        assert_eq!(channel.read(&mut buf), Ok(4));
+        assert_eq!(channel.read(&mut buf), Ok(1));
+        assert_eq!(channel.read(&mut buf), Err(ChannelError::WouldBlock));
+        assert_eq!(channel.write(&[1, 2, 3]), Ok(3));
+        channel.flush().unwrap();
+
+        let stats = handle.snapshot();
+        assert_eq!((stats.read.calls, stats.read.bytes), (2, 5));
+        assert_eq!(stats.empty_reads, 1);
+        assert_eq!((stats.write.calls, stats.write.bytes), (1, 3));
+        assert_eq!(stats.flush.calls, 1);
+        assert!(stats.read.max_time <= stats.read.total_time);
+
+        let mut channel = channel.into_inner();
+        channel.fail_writes = true;
+        let mut channel = MeteredChannel::new(channel);
+        assert!(channel.write(&[1]).is_err());
+        assert_eq!(channel.stats().write.errors, 1);
+        assert_eq!(channel.stats().write.calls, 0);
+        handle.reset();
+        assert_eq!(handle.snapshot(), Default::default());
+    }
+}
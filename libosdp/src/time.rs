@@ -0,0 +1,83 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP times its retransmissions, replies and polls off a monotonic
+//! millisecond clock, `osdp_millis_now()`. On hosted targets it reads the OS
+//! clock; bare-metal targets have none, so without `std` this crate provides
+//! the clock to LibOSDP and reads it from a [`TimeSource`] that the
+//! application registers with [`set_time_source`].
+
+use alloc::boxed::Box;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A monotonic clock with millisecond resolution, such as a SysTick counter
+/// or an RTOS tick count.
+///
+/// Closures of the form `Fn() -> u64` implement this trait.
+pub trait TimeSource: Send + Sync {
+    /// Milliseconds elapsed since some fixed point in time (usually boot).
+    /// This must never go backwards.
+    fn now_ms(&self) -> u64;
+}
+
+impl<F> TimeSource for F
+where
+    F: Fn() -> u64 + Send + Sync,
+{
+    fn now_ms(&self) -> u64 {
+        self()
+    }
+}
+
+static TIME_SOURCE: AtomicPtr<Box<dyn TimeSource>> = AtomicPtr::new(ptr::null_mut());
+
+/// Register the clock that LibOSDP reads its time from. This must be called
+/// before any [`crate::ControlPanel`] or [`crate::PeripheralDevice`] is
+/// created; they fail to set up with [`crate::OsdpError::PdInfoBuilder`]
+/// until it is.
+///
+/// The source is never dropped, as LibOSDP may be reading from it while it
+/// is being replaced.
+pub fn set_time_source(source: impl TimeSource + 'static) {
+    let source: Box<Box<dyn TimeSource>> = Box::new(Box::new(source));
+    TIME_SOURCE.store(Box::into_raw(source), Ordering::Release);
+}
+
+pub(crate) fn has_time_source() -> bool {
+    !TIME_SOURCE.load(Ordering::Acquire).is_null()
+}
+
+/// Milliseconds elapsed according to the registered [`TimeSource`], if there
+/// is one.
+pub(crate) fn millis_now() -> Option<u64> {
+    let source = TIME_SOURCE.load(Ordering::Acquire);
+    // SAFETY: only ever set from a leaked box in set_time_source.
+    unsafe { source.as_ref() }.map(|source| source.now_ms())
+}
+
+/// Overrides the (weak) definition in LibOSDP. No CP or PD is set up before
+/// a source is registered, so LibOSDP never reads the fallback of 0.
+#[cfg(not(feature = "std"))]
+#[no_mangle]
+extern "C" fn osdp_millis_now() -> i64 {
+    millis_now().unwrap_or(0) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_source() {
+        assert!(!has_time_source());
+        assert_eq!(millis_now(), None);
+        set_time_source(|| 42);
+        assert!(has_time_source());
+        assert_eq!(millis_now(), Some(42));
+    }
+}
@@ -0,0 +1,158 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP compiles in how long a CP waits for a PD to reply and how often it
+//! tries to reach a PD that is offline, and uses the same for all PDs. For
+//! PDs that have a [`crate::PdInfoBuilder::response_timeout`] or an
+//! [`crate::PdInfoBuilder::offline_backoff`], the CP holds back some of the
+//! commands that LibOSDP writes to the PD's channel (in the `CommandTap`
+//! around it):
+//!
+//! - A retransmission of a command that went out less than the response
+//!   timeout ago is not sent. If the PD replies to the command in time,
+//!   LibOSDP takes that for the reply to the retransmission. LibOSDP still
+//!   takes the PD offline once it has retransmitted a command a few times,
+//!   which bounds how long the response timeout can be.
+//! - While a PD is offline, LibOSDP's attempts to reach it are only sent once
+//!   the backoff has passed since the last one that was. The backoff doubles
+//!   with every attempt, up to its maximum.
+//!
+//! The poll interval can't be changed this way, as LibOSDP takes a POLL that
+//! is not replied to for a PD that is not responding.
+
+use crate::latency::Stamp;
+use core::time::Duration;
+
+/// Response timeout and offline backoff of a PD, and when these were last
+/// applied to it.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PdTiming {
+    response_timeout: Option<Duration>,
+    /// Initial and maximum backoff
+    offline_backoff: Option<(Duration, Duration)>,
+    /// When the last command to the PD went out
+    sent_at: Option<Stamp>,
+    /// While the PD is offline: when LibOSDP last got to try to reach it,
+    /// and how long it waits until the next try
+    offline: Option<(Stamp, Duration)>,
+}
+
+impl PdTiming {
+    pub fn new(
+        response_timeout: Option<Duration>,
+        offline_backoff: Option<(Duration, Duration)>,
+    ) -> Self {
+        Self {
+            response_timeout,
+            offline_backoff,
+            ..Default::default()
+        }
+    }
+
+    /// Whether there is anything to apply
+    pub fn is_set(&self) -> bool {
+        self.response_timeout.is_some() || self.offline_backoff.is_some()
+    }
+
+    /// Whether a command written to the PD at `now` must be held back; `retry`
+    /// if it is a retransmission of the last one.
+    pub fn hold(&mut self, retry: bool, now: Stamp) -> bool {
+        if retry {
+            if let (Some(timeout), Some(sent_at)) = (self.response_timeout, self.sent_at) {
+                if now.duration_since(sent_at) < timeout {
+                    return true;
+                }
+            }
+        }
+        if let (Some((last, wait)), Some((_, max))) = (&mut self.offline, self.offline_backoff) {
+            if now.duration_since(*last) < *wait {
+                return true;
+            }
+            *last = now;
+            *wait = wait.checked_mul(2).map_or(max, |wait| wait.min(max));
+        }
+        self.sent_at = Some(now);
+        false
+    }
+
+    /// The PD replied to the last command
+    pub fn replied(&mut self) {
+        self.sent_at = None;
+    }
+
+    /// The PD is `online` as of `now`. The backoff starts when it goes
+    /// offline.
+    pub fn set_online(&mut self, online: bool, now: Stamp) {
+        match self.offline_backoff {
+            Some((initial, _)) if !online => {
+                self.offline.get_or_insert((now, initial));
+            }
+            _ => self.offline = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PdTiming;
+    use crate::latency::Stamp;
+    use core::time::Duration;
+
+    #[test]
+    fn test_response_timeout() {
+        let ms = Duration::from_millis;
+        let start = Stamp::now();
+        let mut timing = PdTiming::new(Some(ms(500)), None);
+        assert!(timing.is_set());
+        assert!(!timing.hold(false, start));
+        // Retransmissions are held back until the timeout...
+        assert!(timing.hold(true, start.later(ms(200))));
+        assert!(timing.hold(true, start.later(ms(400))));
+        // ...which starts over with every one that goes out
+        assert!(!timing.hold(true, start.later(ms(600))));
+        assert!(timing.hold(true, start.later(ms(800))));
+        assert!(!timing.hold(true, start.later(ms(1100))));
+        // New commands always go out
+        assert!(!timing.hold(false, start.later(ms(1200))));
+        timing.replied();
+        assert!(!timing.hold(true, start.later(ms(1300))));
+
+        // Nothing is held back by default
+        let mut timing = PdTiming::default();
+        assert!(!timing.is_set());
+        assert!(!timing.hold(false, start));
+        assert!(!timing.hold(true, start));
+        timing.set_online(false, start);
+        assert!(!timing.hold(false, start));
+    }
+
+    #[test]
+    fn test_offline_backoff() {
+        let secs = Duration::from_secs;
+        let start = Stamp::now();
+        let mut timing = PdTiming::new(None, Some((secs(1), secs(3))));
+        assert!(!timing.hold(false, start));
+
+        // Tries are let through 1, 2, 3 and 3 seconds apart
+        timing.set_online(false, start);
+        assert!(timing.hold(false, start.later(secs(0))));
+        let mut at = start;
+        for wait in [1, 2, 3, 3] {
+            assert!(timing.hold(false, at.later(secs(wait) / 2)));
+            at = at.later(secs(wait));
+            assert!(!timing.hold(false, at));
+        }
+        // Staying offline does not restart it
+        timing.set_online(false, at);
+        assert!(timing.hold(false, at.later(secs(2))));
+
+        // Once the PD is back, nothing is held back and it starts over
+        timing.set_online(true, at);
+        assert!(!timing.hold(false, at));
+        timing.set_online(false, at);
+        assert!(timing.hold(false, at.later(secs(0))));
+        assert!(!timing.hold(false, at.later(secs(1))));
+    }
+}
@@ -0,0 +1,42 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LibOSDP's `packet_trace` and `data_trace` build options can only write
+//! pcap files. A CP sees every packet on its channels anyway (see
+//! [`crate::ControlPanel::start_packet_capture`]), so it can also hand them,
+//! decoded, to a closure registered with
+//! [`crate::ControlPanel::set_wire_frame_sink`] as they go by. This is what live
+//! protocol views (in a GUI, for instance) are built on.
+//!
+//! The packets are the ones on the wire: once a secure channel is active,
+//! their data is encrypted and only their header (address, command or reply
+//! code, sequence number) can be read. LibOSDP encrypts and decrypts inside
+//! its own buffers and doesn't share the session keys, so there is no point
+//! at which the plain text could be tapped.
+
+use crate::wire::Packet;
+
+/// Which way a [`WireFrame`] went
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FrameDirection {
+    /// Written by the CP (a command)
+    Sent,
+    /// Read by the CP (a reply)
+    Received,
+}
+
+/// A packet seen on a channel of a CP, as it was on the wire
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WireFrame<'a> {
+    /// Which way the packet went
+    pub direction: FrameDirection,
+    /// Offset number of the PD the packet was sent to (or came from), if its
+    /// address belongs to one of the PDs of the CP
+    pub pd: Option<i32>,
+    /// The packet. Its data is encrypted (see [`Packet::is_encrypted`]) when
+    /// a secure channel is active; LibOSDP doesn't share the session keys.
+    pub packet: &'a Packet,
+}
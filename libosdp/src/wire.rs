@@ -0,0 +1,1148 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! OSDP packets as they appear on the wire. LibOSDP does all of its framing
+//! internally; this module is a stand-alone re-implementation of the packet
+//! layer so that tools built on this crate (sniffers, analyzers, test
+//! harnesses) can make sense of the raw bytes flowing on a [`crate::Channel`].
+//!
+//! A packet on the wire looks like this:
+//!
+//! ```text
+//! +-----+------+---------+------+---------+------+------+-----+-------+
+//! | SOM | ADDR | LEN(LE) | CTRL | [SCB]   | CODE | DATA | MAC | CHECK |
+//! +-----+------+---------+------+---------+------+------+-----+-------+
+//! ```
+//!
+//! where `SCB` is the optional secure channel block, `MAC` is present only in
+//! secure channel packets, and `CHECK` is either an 8-bit checksum or a 16-bit
+//! CRC depending on the `CTRL` byte.
+//!
+//! [`explain`] labels each of these fields (and decodes the data, where it
+//! can) for diagnostics UIs and traces.
+
+use crate::{
+    OsdpCardFormats, OsdpComSet, OsdpCommand, OsdpCommandBuzzer, OsdpCommandFileTx,
+    OsdpCommandKeyset, OsdpCommandLed, OsdpCommandMfg, OsdpCommandOutput, OsdpCommandText,
+    OsdpError, OsdpEvent, OsdpEventCardRead, OsdpEventKeyPress, OsdpEventMfgReply, OsdpLedColor,
+    OsdpLedParams, OsdpStatusReport, OsdpStatusReportType,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+type Result<T> = core::result::Result<T, OsdpError>;
+
+/// Start of message marker; every OSDP packet begins with this byte.
+pub const SOM: u8 = 0x53;
+
+/// Optional mark byte that LibOSDP sends ahead of the SOM.
+pub const MARK: u8 = 0xFF;
+
+/// Largest packet that this module is willing to decode: 1024 bytes, or as
+/// much as LibOSDP was built to handle if that is more.
+pub const MAX_PACKET_LEN: usize = if crate::OSDP_MAX_PACKET_SIZE > 1024 {
+    crate::OSDP_MAX_PACKET_SIZE
+} else {
+    1024
+};
+
+const CTRL_SQN_MASK: u8 = 0x03;
+const CTRL_CRC: u8 = 0x04;
+const CTRL_SCB: u8 = 0x08;
+const ADDR_REPLY: u8 = 0x80;
+const MAC_LEN: usize = 4;
+
+/// Secure channel block types that carry a MAC.
+const SCS_15: u8 = 0x15;
+const SCS_17: u8 = 0x17;
+const SCS_18: u8 = 0x18;
+
+/// Secure channel block attached to a packet.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ScBlock {
+    /// Secure channel block type (SCS_11 .. SCS_18)
+    pub block_type: u8,
+    /// Block specific data (may be empty)
+    pub data: Vec<u8>,
+}
+
+/// A single, validated, OSDP packet.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Packet {
+    /// 7-bit PD address this packet is sent to (or from, for replies)
+    pub address: u8,
+    /// Set for packets sent by a PD to the CP
+    pub is_reply: bool,
+    /// Sequence number (0-3)
+    pub sequence: u8,
+    /// When set, packet uses a 16-bit CRC instead of the 8-bit checksum
+    pub use_crc: bool,
+    /// Secure channel block, if any
+    pub sc_block: Option<ScBlock>,
+    /// Command or reply code
+    pub code: u8,
+    /// Application data following the code (may be encrypted)
+    pub data: Vec<u8>,
+    /// Message authentication code, present in secure channel packets
+    pub mac: Option<[u8; 4]>,
+}
+
+/// CRC-16/AUG-CCITT as used by OSDP.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0x1D0F;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// 8-bit two's complement checksum as used by OSDP.
+pub fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    (!sum).wrapping_add(1)
+}
+
+fn command_name(code: u8) -> Option<&'static str> {
+    let name = match code {
+        0x60 => "POLL",
+        0x61 => "ID",
+        0x62 => "CAP",
+        0x64 => "LSTAT",
+        0x65 => "ISTAT",
+        0x66 => "OSTAT",
+        0x67 => "RSTAT",
+        0x68 => "OUT",
+        0x69 => "LED",
+        0x6A => "BUZ",
+        0x6B => "TEXT",
+        0x6E => "COMSET",
+        0x73 => "BIOREAD",
+        0x74 => "BIOMATCH",
+        0x75 => "KEYSET",
+        0x76 => "CHLNG",
+        0x77 => "SCRYPT",
+        0x7B => "ACURXSIZE",
+        0x7C => "FILETRANSFER",
+        0x80 => "MFG",
+        0xA1 => "XWR",
+        0xA2 => "ABORT",
+        0xA3 => "PIVDATA",
+        0xA4 => "GENAUTH",
+        0xA5 => "CRAUTH",
+        0xA7 => "KEEPACTIVE",
+        _ => return None,
+    };
+    Some(name)
+}
+
+pub(crate) fn reply_name(code: u8) -> Option<&'static str> {
+    let name = match code {
+        0x40 => "ACK",
+        0x41 => "NAK",
+        0x45 => "PDID",
+        0x46 => "PDCAP",
+        0x48 => "LSTATR",
+        0x49 => "ISTATR",
+        0x4A => "OSTATR",
+        0x4B => "RSTATR",
+        0x50 => "RAW",
+        0x51 => "FMT",
+        0x53 => "KEYPAD",
+        0x54 => "COM",
+        0x57 => "BIOREADR",
+        0x58 => "BIOMATCHR",
+        0x76 => "CCRYPT",
+        0x78 => "RMAC_I",
+        0x79 => "BUSY",
+        0x7A => "FTSTAT",
+        0x80 => "PIVDATAR",
+        0x81 => "GENAUTHR",
+        0x82 => "CRAUTHR",
+        0x83 => "MFGSTATR",
+        0x84 => "MFGERRR",
+        0x90 => "MFGREP",
+        0xB1 => "XRD",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Outcome of trying to parse a frame at the start of a buffer.
+pub(crate) enum Frame {
+    Complete(Packet, usize),
+    Incomplete,
+}
+
+pub(crate) fn parse_frame(buf: &[u8]) -> Result<Frame> {
+    if buf.len() < 6 {
+        return Ok(Frame::Incomplete);
+    }
+    if buf[0] != SOM {
+        return Err(OsdpError::Wire("missing SOM"));
+    }
+    let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+    if !(7..=MAX_PACKET_LEN).contains(&len) {
+        return Err(OsdpError::Wire("invalid packet length"));
+    }
+    if buf.len() < len {
+        return Ok(Frame::Incomplete);
+    }
+    let frame = &buf[..len];
+    let ctrl = frame[4];
+    let use_crc = ctrl & CTRL_CRC != 0;
+    let check_len = if use_crc { 2 } else { 1 };
+    if use_crc {
+        let expected = u16::from_le_bytes([frame[len - 2], frame[len - 1]]);
+        if crc16(&frame[..len - 2]) != expected {
+            return Err(OsdpError::Wire("CRC mismatch"));
+        }
+    } else if checksum(&frame[..len - 1]) != frame[len - 1] {
+        return Err(OsdpError::Wire("checksum mismatch"));
+    }
+
+    let mut idx = 5;
+    let mut end = len - check_len;
+    let mut sc_block = None;
+    let mut mac = None;
+    if ctrl & CTRL_SCB != 0 {
+        let sb_len = frame[idx] as usize;
+        if sb_len < 2 || idx + sb_len >= end {
+            return Err(OsdpError::Wire("invalid secure channel block"));
+        }
+        let block_type = frame[idx + 1];
+        sc_block = Some(ScBlock {
+            block_type,
+            data: frame[idx + 2..idx + sb_len].to_vec(),
+        });
+        idx += sb_len;
+        if (SCS_15..=SCS_18).contains(&block_type) {
+            if idx + MAC_LEN >= end {
+                return Err(OsdpError::Wire("missing MAC"));
+            }
+            let mut m = [0u8; MAC_LEN];
+            m.copy_from_slice(&frame[end - MAC_LEN..end]);
+            mac = Some(m);
+            end -= MAC_LEN;
+        }
+    }
+    if idx >= end {
+        return Err(OsdpError::Wire("missing command/reply code"));
+    }
+    let packet = Packet {
+        address: frame[1] & !ADDR_REPLY,
+        is_reply: frame[1] & ADDR_REPLY != 0,
+        sequence: ctrl & CTRL_SQN_MASK,
+        use_crc,
+        sc_block,
+        code: frame[idx],
+        data: frame[idx + 1..end].to_vec(),
+        mac,
+    };
+    Ok(Frame::Complete(packet, len))
+}
+
+fn led_color(value: u8) -> Result<OsdpLedColor> {
+    if value as libosdp_sys::osdp_led_color_e > libosdp_sys::osdp_led_color_e_OSDP_LED_COLOR_WHITE {
+        return Err(OsdpError::Wire("invalid LED color"));
+    }
+    Ok(value.into())
+}
+
+fn led_params(buf: &[u8], with_timer: bool) -> Result<OsdpLedParams> {
+    Ok(OsdpLedParams {
+        control_code: buf[0],
+        on_count: buf[1],
+        off_count: buf[2],
+        on_color: led_color(buf[3])?,
+        off_color: led_color(buf[4])?,
+        timer_count: if with_timer {
+            u16::from_le_bytes([buf[5], buf[6]])
+        } else {
+            0
+        },
+    })
+}
+
+fn status_report(type_: OsdpStatusReportType, data: &[u8]) -> Result<OsdpStatusReport> {
+    if data.len() > 32 {
+        return Err(OsdpError::Wire("too many status entries"));
+    }
+    let mask = data
+        .iter()
+        .enumerate()
+        .fold(0u32, |m, (i, v)| if *v != 0 { m | (1 << i) } else { m });
+    Ok(OsdpStatusReport {
+        type_,
+        nr_entries: data.len(),
+        mask,
+    })
+}
+
+fn check_len(data: &[u8], len: usize) -> Result<()> {
+    if data.len() < len {
+        Err(OsdpError::Wire("payload too short"))
+    } else {
+        Ok(())
+    }
+}
+
+fn decode_outputs(data: &[u8]) -> Result<Vec<OsdpCommandOutput>> {
+    let entries = data.chunks_exact(4);
+    if data.is_empty() || !entries.remainder().is_empty() {
+        return Err(OsdpError::Wire("invalid OUT length"));
+    }
+    Ok(entries
+        .map(|e| OsdpCommandOutput {
+            output_no: e[0],
+            control_code: e[1],
+            timer_count: u16::from_le_bytes([e[2], e[3]]),
+        })
+        .collect())
+}
+
+/// Encode an OUT command that sets all of `outputs` at once, as the
+/// application data of a CP to PD packet (see [`OsdpCommand::to_wire`]).
+/// The PD applies all entries of an OUT command together. Note that
+/// [`crate::ControlPanel`] can't send such a command: LibOSDP puts a single
+/// entry in each OUT command it sends.
+pub fn encode_outputs(outputs: &[OsdpCommandOutput]) -> Result<Vec<u8>> {
+    if outputs.is_empty() {
+        return Err(OsdpError::Wire("no outputs"));
+    }
+    let mut buf = vec![0x68];
+    for o in outputs {
+        buf.extend_from_slice(&[o.output_no, o.control_code]);
+        buf.extend_from_slice(&o.timer_count.to_le_bytes());
+    }
+    Ok(buf)
+}
+
+/// Decode the application data of a CP to PD packet into an [`OsdpCommand`].
+/// Returns `Ok(None)` for commands that have no [`OsdpCommand`] equivalent
+/// (POLL, ID, CAP, secure channel handshake, etc.,).
+pub(crate) fn decode_command(code: u8, data: &[u8]) -> Result<Option<OsdpCommand>> {
+    let cmd = match code {
+        0x64 => OsdpCommand::Status(status_report(OsdpStatusReportType::Local, &[])?),
+        0x65 => OsdpCommand::Status(status_report(OsdpStatusReportType::Input, &[])?),
+        0x66 => OsdpCommand::Status(status_report(OsdpStatusReportType::Output, &[])?),
+        0x67 => OsdpCommand::Status(status_report(OsdpStatusReportType::Remote, &[])?),
+        // An OsdpCommand holds one output; see Packet::outputs for more
+        0x68 => match decode_outputs(data)?[..] {
+            [output] => OsdpCommand::Output(output),
+            _ => return Err(OsdpError::Wire("OUT with more than one entry")),
+        },
+        0x69 => {
+            check_len(data, 14)?;
+            OsdpCommand::Led(OsdpCommandLed {
+                reader: data[0],
+                led_number: data[1],
+                temporary: led_params(&data[2..9], true)?,
+                permanent: led_params(&data[9..14], false)?,
+            })
+        }
+        0x6A => {
+            check_len(data, 5)?;
+            OsdpCommand::Buzzer(OsdpCommandBuzzer {
+                reader: data[0],
+                control_code: data[1],
+                on_count: data[2],
+                off_count: data[3],
+                rep_count: data[4],
+            })
+        }
+        0x6B => {
+            check_len(data, 6)?;
+            let n = data[5] as usize;
+            check_len(data, 6 + n)?;
+            OsdpCommand::Text(OsdpCommandText {
+                reader: data[0],
+                control_code: data[1],
+                temp_time: data[2],
+                offset_row: data[3],
+                offset_col: data[4],
+                data: data[6..6 + n].to_vec(),
+            })
+        }
+        0x6E => {
+            check_len(data, 5)?;
+            let baud_rate = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            OsdpCommand::ComSet(OsdpComSet::new(data[0], baud_rate))
+        }
+        0x75 => {
+            check_len(data, 2)?;
+            let n = data[1] as usize;
+            check_len(data, 2 + n)?;
+            OsdpCommand::KeySet(OsdpCommandKeyset {
+                key_type: data[0],
+                data: data[2..2 + n].to_vec(),
+            })
+        }
+        0x7C => {
+            check_len(data, 1)?;
+            OsdpCommand::FileTx(OsdpCommandFileTx::new(data[0] as i32, 0))
+        }
+        0x80 => {
+            check_len(data, 4)?;
+            OsdpCommand::Mfg(OsdpCommandMfg {
+                vendor_code: (data[0], data[1], data[2]),
+                command: data[3],
+                data: data[4..].to_vec(),
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(cmd))
+}
+
+/// Decode the application data of a PD to CP packet into an [`OsdpEvent`].
+/// Returns `Ok(None)` for replies that are not events (ACK, NAK, PDID, etc.,).
+pub(crate) fn decode_event(code: u8, data: &[u8]) -> Result<Option<OsdpEvent>> {
+    let event = match code {
+        0x48 => {
+            check_len(data, 2)?;
+            OsdpEvent::Status(status_report(OsdpStatusReportType::Local, &data[..2])?)
+        }
+        0x49 => OsdpEvent::Status(status_report(OsdpStatusReportType::Input, data)?),
+        0x4A => OsdpEvent::Status(status_report(OsdpStatusReportType::Output, data)?),
+        0x4B => OsdpEvent::Status(status_report(OsdpStatusReportType::Remote, data)?),
+        0x50 => {
+            check_len(data, 4)?;
+            let nr_bits = u16::from_le_bytes([data[2], data[3]]) as usize;
+            let nr_bytes = nr_bits.div_ceil(8);
+            check_len(data, 4 + nr_bytes)?;
+            let format = match data[1] {
+                1 => OsdpCardFormats::Wiegand,
+                _ => OsdpCardFormats::Unspecified,
+            };
+            OsdpEvent::CardRead(OsdpEventCardRead {
+                reader_no: data[0] as i32,
+                format,
+                direction: false,
+                nr_bits,
+                data: data[4..4 + nr_bytes].to_vec(),
+            })
+        }
+        0x51 => {
+            check_len(data, 3)?;
+            let n = data[2] as usize;
+            check_len(data, 3 + n)?;
+            OsdpEvent::CardRead(OsdpEventCardRead {
+                reader_no: data[0] as i32,
+                format: OsdpCardFormats::Ascii,
+                direction: data[1] == 1,
+                nr_bits: 0,
+                data: data[3..3 + n].to_vec(),
+            })
+        }
+        0x53 => {
+            check_len(data, 2)?;
+            let n = data[1] as usize;
+            check_len(data, 2 + n)?;
+            OsdpEvent::KeyPress(OsdpEventKeyPress {
+                reader_no: data[0] as i32,
+                data: data[2..2 + n].to_vec(),
+            })
+        }
+        0x90 => {
+            check_len(data, 4)?;
+            OsdpEvent::MfgReply(OsdpEventMfgReply {
+                vendor_code: (data[0], data[1], data[2]),
+                reply: data[3],
+                data: data[4..].to_vec(),
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
+
+fn push_led_params(buf: &mut Vec<u8>, params: &OsdpLedParams, with_timer: bool) {
+    buf.extend_from_slice(&[
+        params.control_code,
+        params.on_count,
+        params.off_count,
+        params.on_color.into(),
+        params.off_color.into(),
+    ]);
+    if with_timer {
+        buf.extend_from_slice(&params.timer_count.to_le_bytes());
+    }
+}
+
+fn push_status_report(buf: &mut Vec<u8>, report: &OsdpStatusReport) -> Result<()> {
+    if report.nr_entries > 32 {
+        return Err(OsdpError::Wire("too many status entries"));
+    }
+    buf.extend((0..report.nr_entries).map(|i| ((report.mask >> i) & 1) as u8));
+    Ok(())
+}
+
+fn len_u8(data: &[u8]) -> Result<u8> {
+    u8::try_from(data.len()).map_err(|_| OsdpError::Wire("payload too long"))
+}
+
+/// Encode an [`OsdpCommand`] as the application data (command code followed
+/// by the command specific data) of a CP to PD packet.
+pub(crate) fn encode_command(cmd: &OsdpCommand) -> Result<Vec<u8>> {
+    let buf = match cmd {
+        OsdpCommand::Status(s) => vec![match s.type_ {
+            OsdpStatusReportType::Local => 0x64,
+            OsdpStatusReportType::Input => 0x65,
+            OsdpStatusReportType::Output => 0x66,
+            OsdpStatusReportType::Remote => 0x67,
+        }],
+        OsdpCommand::Output(c) => encode_outputs(&[*c])?,
+        OsdpCommand::Led(c) => {
+            let mut buf = vec![0x69, c.reader, c.led_number];
+            push_led_params(&mut buf, &c.temporary, true);
+            push_led_params(&mut buf, &c.permanent, false);
+            buf
+        }
+        OsdpCommand::Buzzer(c) => vec![
+            0x6A,
+            c.reader,
+            c.control_code,
+            c.on_count,
+            c.off_count,
+            c.rep_count,
+        ],
+        OsdpCommand::Text(c) => {
+            let mut buf = vec![
+                0x6B,
+                c.reader,
+                c.control_code,
+                c.temp_time,
+                c.offset_row,
+                c.offset_col,
+                len_u8(&c.data)?,
+            ];
+            buf.extend_from_slice(&c.data);
+            buf
+        }
+        OsdpCommand::ComSet(c) => {
+            let mut buf = vec![0x6E, c.address];
+            buf.extend_from_slice(&c.baud_rate.to_le_bytes());
+            buf
+        }
+        OsdpCommand::KeySet(c) => {
+            let mut buf = vec![0x75, c.key_type, len_u8(&c.data)?];
+            buf.extend_from_slice(&c.data);
+            buf
+        }
+        OsdpCommand::Mfg(c) => {
+            let (a, b, c_) = c.vendor_code;
+            let mut buf = vec![0x80, a, b, c_, c.command];
+            buf.extend_from_slice(&c.data);
+            buf
+        }
+        OsdpCommand::FileTx(_) => {
+            return Err(OsdpError::Wire(
+                "file transfers span multiple packets built by LibOSDP",
+            ))
+        }
+    };
+    Ok(buf)
+}
+
+/// Encode an [`OsdpEvent`] as the application data (reply code followed by
+/// the reply specific data) of a PD to CP packet.
+pub(crate) fn encode_event(event: &OsdpEvent) -> Result<Vec<u8>> {
+    let buf = match event {
+        OsdpEvent::Status(s) => {
+            let mut buf = vec![match s.type_ {
+                OsdpStatusReportType::Local => 0x48,
+                OsdpStatusReportType::Input => 0x49,
+                OsdpStatusReportType::Output => 0x4A,
+                OsdpStatusReportType::Remote => 0x4B,
+            }];
+            push_status_report(&mut buf, s)?;
+            buf
+        }
+        OsdpEvent::CardRead(e) if e.format == OsdpCardFormats::Ascii => {
+            let mut buf = vec![0x51, e.reader_no as u8, e.direction as u8, len_u8(&e.data)?];
+            buf.extend_from_slice(&e.data);
+            buf
+        }
+        OsdpEvent::CardRead(e) => {
+            if e.data.len() != e.nr_bits.div_ceil(8) {
+                return Err(OsdpError::Wire("card data does not match nr_bits"));
+            }
+            let format = match e.format {
+                OsdpCardFormats::Wiegand => 1,
+                _ => 0,
+            };
+            let mut buf = vec![0x50, e.reader_no as u8, format];
+            buf.extend_from_slice(&(e.nr_bits as u16).to_le_bytes());
+            buf.extend_from_slice(&e.data);
+            buf
+        }
+        OsdpEvent::KeyPress(e) => {
+            let mut buf = vec![0x53, e.reader_no as u8, len_u8(&e.data)?];
+            buf.extend_from_slice(&e.data);
+            buf
+        }
+        OsdpEvent::MfgReply(e) => {
+            let (a, b, c) = e.vendor_code;
+            let mut buf = vec![0x90, a, b, c, e.reply];
+            buf.extend_from_slice(&e.data);
+            buf
+        }
+    };
+    Ok(buf)
+}
+
+impl OsdpCommand {
+    /// Encode this command as it appears in the application data of an OSDP
+    /// packet: the command code followed by the command specific data. This
+    /// is what LibOSDP puts on the wire (before secure channel encryption).
+    ///
+    /// [`OsdpCommand::FileTx`] can't be encoded as file transfers are carried
+    /// out by LibOSDP in multiple packets.
+    pub fn to_wire(&self) -> Result<Vec<u8>> {
+        encode_command(self)
+    }
+
+    /// Decode a command from the application data of an OSDP packet (see
+    /// [`OsdpCommand::to_wire`]). OUT commands with more than one entry are
+    /// rejected; see [`Packet::outputs`].
+    pub fn from_wire(buf: &[u8]) -> Result<Self> {
+        let (code, data) = buf.split_first().ok_or(OsdpError::Wire("empty buffer"))?;
+        decode_command(*code, data)?.ok_or(OsdpError::Wire("not an OsdpCommand"))
+    }
+}
+
+impl OsdpEvent {
+    /// Encode this event as it appears in the application data of an OSDP
+    /// packet: the reply code followed by the reply specific data. This is
+    /// what LibOSDP puts on the wire (before secure channel encryption).
+    pub fn to_wire(&self) -> Result<Vec<u8>> {
+        encode_event(self)
+    }
+
+    /// Decode an event from the application data of an OSDP packet (see
+    /// [`OsdpEvent::to_wire`]).
+    pub fn from_wire(buf: &[u8]) -> Result<Self> {
+        let (code, data) = buf.split_first().ok_or(OsdpError::Wire("empty buffer"))?;
+        decode_event(*code, data)?.ok_or(OsdpError::Wire("not an OsdpEvent"))
+    }
+}
+
+impl Packet {
+    /// Parse exactly one packet from `buf`. Leading mark bytes are skipped;
+    /// any trailing bytes after the packet are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let start = buf.iter().position(|b| *b != MARK).unwrap_or(buf.len());
+        match parse_frame(&buf[start..])? {
+            Frame::Complete(packet, _) => Ok(packet),
+            Frame::Incomplete => Err(OsdpError::Wire("incomplete packet")),
+        }
+    }
+
+    /// Serialize this packet, computing the length and check fields.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ctrl = self.sequence & CTRL_SQN_MASK;
+        if self.use_crc {
+            ctrl |= CTRL_CRC;
+        }
+        if self.sc_block.is_some() {
+            ctrl |= CTRL_SCB;
+        }
+        let address = if self.is_reply {
+            self.address | ADDR_REPLY
+        } else {
+            self.address
+        };
+        let mut buf = vec![SOM, address, 0, 0, ctrl];
+        if let Some(sb) = &self.sc_block {
+            buf.push((sb.data.len() + 2) as u8);
+            buf.push(sb.block_type);
+            buf.extend_from_slice(&sb.data);
+        }
+        buf.push(self.code);
+        buf.extend_from_slice(&self.data);
+        if let Some(mac) = &self.mac {
+            buf.extend_from_slice(mac);
+        }
+        let len = buf.len() + if self.use_crc { 2 } else { 1 };
+        buf[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        if self.use_crc {
+            let crc = crc16(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+        } else {
+            let cksum = checksum(&buf);
+            buf.push(cksum);
+        }
+        buf
+    }
+
+    /// Human readable name of the command or reply code (e.g. "POLL", "ACK").
+    pub fn name(&self) -> &'static str {
+        let name = if self.is_reply {
+            reply_name(self.code)
+        } else {
+            command_name(self.code)
+        };
+        name.unwrap_or("UNKNOWN")
+    }
+
+    /// Returns true if the application data of this packet is encrypted and
+    /// cannot be decoded without the session keys.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(&self.sc_block, Some(sb) if sb.block_type == SCS_17 || sb.block_type == SCS_18)
+            && !self.data.is_empty()
+    }
+
+    /// Decode this packet as an [`OsdpCommand`]. Returns `None` for replies,
+    /// encrypted packets, and commands that don't map to [`OsdpCommand`].
+    pub fn command(&self) -> Option<OsdpCommand> {
+        if self.is_reply || self.is_encrypted() {
+            return None;
+        }
+        decode_command(self.code, &self.data).ok().flatten()
+    }
+
+    /// Decode all entries of an OUT command. [`Packet::command`] only decodes
+    /// OUT commands with a single entry, as [`OsdpCommand::Output`]. Returns
+    /// `None` for other packets and encrypted ones.
+    pub fn outputs(&self) -> Option<Vec<OsdpCommandOutput>> {
+        if self.is_reply || self.is_encrypted() || self.code != 0x68 {
+            return None;
+        }
+        decode_outputs(&self.data).ok()
+    }
+
+    /// Decode this packet as an [`OsdpEvent`]. Returns `None` for commands,
+    /// encrypted packets, and replies that don't map to [`OsdpEvent`].
+    pub fn event(&self) -> Option<OsdpEvent> {
+        if !self.is_reply || self.is_encrypted() {
+            return None;
+        }
+        decode_event(self.code, &self.data).ok().flatten()
+    }
+}
+
+/// Incremental decoder that turns an arbitrarily chunked byte stream into
+/// [`Packet`]s. Bytes that cannot be part of a valid packet are discarded and
+/// the decoder re-synchronizes on the next SOM.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+    discarded: usize,
+}
+
+impl PacketDecoder {
+    /// Create a new, empty, decoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append bytes received from the bus.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes dropped so far while looking for valid packets (mark
+    /// bytes are not counted).
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// Return the next complete packet, if one is available.
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            let start = match self.buf.iter().position(|b| *b == SOM) {
+                Some(pos) => pos,
+                None => self.buf.len(),
+            };
+            self.discard(start);
+            if self.buf.is_empty() {
+                return None;
+            }
+            match parse_frame(&self.buf) {
+                Ok(Frame::Complete(packet, len)) => {
+                    self.buf.drain(..len);
+                    return Some(packet);
+                }
+                Ok(Frame::Incomplete) => return None,
+                Err(_) => self.discard(1),
+            }
+        }
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.discarded += self.buf[..n].iter().filter(|b| **b != MARK).count();
+        self.buf.drain(..n);
+    }
+}
+
+fn nak_reason(reason: u8) -> &'static str {
+    match reason {
+        0x00 => "no error",
+        0x01 => "message check character(s) error",
+        0x02 => "command length error",
+        0x03 => "unknown command code",
+        0x04 => "sequence number error",
+        0x05 => "secure channel not supported",
+        0x06 => "encryption required",
+        0x07 => "BIO type not supported",
+        0x08 => "BIO format not supported",
+        0x09 => "unable to process command record",
+        _ => "unknown reason",
+    }
+}
+
+fn sc_block_name(block_type: u8) -> &'static str {
+    match block_type {
+        0x11 => "SCS_11, CHLNG",
+        0x12 => "SCS_12, CCRYPT",
+        0x13 => "SCS_13, SCRYPT",
+        0x14 => "SCS_14, RMAC_I",
+        0x15 => "SCS_15, command with MAC",
+        0x16 => "SCS_16, reply with MAC",
+        SCS_17 => "SCS_17, encrypted command with MAC",
+        SCS_18 => "SCS_18, encrypted reply with MAC",
+        _ => "unknown",
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    use core::fmt::Write;
+
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// A field of a [`PacketExplanation`]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ExplainedField {
+    /// What the field is ("address", "sequence", "payload", ...)
+    pub label: &'static str,
+    /// Its value, decoded
+    pub value: String,
+}
+
+/// A [`Packet`] with its fields labeled and decoded, in the order they are
+/// on the wire; see [`explain`]. Displays as a header line (direction and
+/// name) followed by one indented line per field.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PacketExplanation {
+    /// The packet explained
+    pub packet: Packet,
+    /// Its fields
+    pub fields: Vec<ExplainedField>,
+}
+
+impl PacketExplanation {
+    /// Who sent the packet: "CP -> PD" or "PD -> CP"
+    pub fn direction(&self) -> &'static str {
+        if self.packet.is_reply {
+            "PD -> CP"
+        } else {
+            "CP -> PD"
+        }
+    }
+
+    /// Value of the field labeled `label`, if the packet has one
+    pub fn field(&self, label: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|f| f.label == label)
+            .map(|f| f.value.as_str())
+    }
+
+    fn payload(packet: &Packet) -> String {
+        if packet.data.is_empty() {
+            return "(none)".into();
+        }
+        if packet.is_encrypted() {
+            return format!("encrypted, {} bytes", packet.data.len());
+        }
+        if packet.is_reply && packet.code == 0x41 {
+            let reason = packet.data[0];
+            return format!("reason {reason:#04x} ({})", nak_reason(reason));
+        }
+        if let Some(outputs) = packet.outputs() {
+            return format!("{outputs:?}");
+        }
+        if let Some(command) = packet.command() {
+            return format!("{command:?}");
+        }
+        if let Some(event) = packet.event() {
+            return format!("{event:?}");
+        }
+        hex(&packet.data)
+    }
+}
+
+impl From<Packet> for PacketExplanation {
+    fn from(packet: Packet) -> Self {
+        let mut fields = vec![
+            (
+                "address",
+                format!("{} ({:#04x})", packet.address, packet.address),
+            ),
+            ("sequence", packet.sequence.to_string()),
+            (
+                "check",
+                if packet.use_crc { "CRC-16" } else { "checksum" }.into(),
+            ),
+        ];
+        if let Some(sb) = &packet.sc_block {
+            let mut block = format!("{:#04x} ({})", sb.block_type, sc_block_name(sb.block_type));
+            if !sb.data.is_empty() {
+                block.push_str(", data ");
+                block.push_str(&hex(&sb.data));
+            }
+            fields.push(("sc block", block));
+        }
+        fields.push(("code", format!("{:#04x} ({})", packet.code, packet.name())));
+        fields.push(("payload", Self::payload(&packet)));
+        if let Some(mac) = &packet.mac {
+            fields.push(("mac", hex(mac)));
+        }
+        let fields = fields
+            .into_iter()
+            .map(|(label, value)| ExplainedField { label, value })
+            .collect();
+        Self { packet, fields }
+    }
+}
+
+impl core::fmt::Display for PacketExplanation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}  {}", self.direction(), self.packet.name())?;
+        for field in &self.fields {
+            write!(f, "\n    {:<9} {}", field.label, field.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the packet in `buf` (see [`Packet::from_bytes`]) and explain it:
+/// label its fields and decode them, as far as that is possible without the
+/// secure channel session keys. Packets that come from a [`PacketDecoder`]
+/// can be explained with [`PacketExplanation::from`].
+pub fn explain(buf: &[u8]) -> Result<PacketExplanation> {
+    Packet::from_bytes(buf).map(PacketExplanation::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_outputs, explain, Packet, PacketDecoder, ScBlock};
+    use crate::{
+        OsdpCommand, OsdpCommandBuzzer, OsdpCommandLed, OsdpCommandOutput, OsdpCommandText,
+        OsdpEvent, OsdpEventCardRead, OsdpLedColor, OsdpLedParams, OsdpStatusReport,
+    };
+
+    #[test]
+    fn test_packet_parse() {
+        let poll = [0xFF, 0x53, 0x65, 0x08, 0x00, 0x05, 0x60, 0x51, 0xA3];
+        let pkt = Packet::from_bytes(&poll).unwrap();
+        assert_eq!(pkt.address, 0x65);
+        assert!(!pkt.is_reply);
+        assert_eq!(pkt.sequence, 1);
+        assert!(pkt.use_crc);
+        assert_eq!(pkt.name(), "POLL");
+        assert_eq!(pkt.to_bytes(), poll[1..]);
+
+        let ack = [0x53, 0xE5, 0x07, 0x00, 0x01, 0x40, 0x80];
+        let pkt = Packet::from_bytes(&ack).unwrap();
+        assert!(pkt.is_reply);
+        assert!(!pkt.use_crc);
+        assert_eq!(pkt.name(), "ACK");
+
+        let mut bad = poll;
+        bad[6] = 0x61;
+        assert!(Packet::from_bytes(&bad).is_err());
+    }
+
+    #[test]
+    fn test_packet_decoder() {
+        let buz = Packet {
+            address: 1,
+            is_reply: false,
+            sequence: 2,
+            use_crc: true,
+            sc_block: None,
+            code: 0x6A,
+            data: vec![0, 2, 5, 5, 3],
+            mac: None,
+        };
+        let bytes = buz.to_bytes();
+        let mut decoder = PacketDecoder::new();
+        decoder.push(&[0x00, 0x12, 0xFF]);
+        decoder.push(&bytes[..4]);
+        assert_eq!(decoder.next_packet(), None);
+        decoder.push(&bytes[4..]);
+        let pkt = decoder.next_packet().unwrap();
+        assert_eq!(pkt, buz);
+        assert_eq!(decoder.discarded(), 2);
+        assert_eq!(
+            pkt.command(),
+            Some(OsdpCommand::Buzzer(OsdpCommandBuzzer {
+                reader: 0,
+                control_code: 2,
+                on_count: 5,
+                off_count: 5,
+                rep_count: 3,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_wire_encoding() {
+        let buz = OsdpCommand::Buzzer(OsdpCommandBuzzer {
+            reader: 0,
+            control_code: 2,
+            on_count: 5,
+            off_count: 5,
+            rep_count: 3,
+        });
+        assert_eq!(buz.to_wire().unwrap(), [0x6A, 0, 2, 5, 5, 3]);
+
+        let commands = [
+            buz,
+            OsdpCommand::Led(OsdpCommandLed {
+                reader: 1,
+                led_number: 0,
+                temporary: OsdpLedParams {
+                    control_code: 2,
+                    on_count: 10,
+                    off_count: 10,
+                    on_color: OsdpLedColor::Red,
+                    off_color: OsdpLedColor::None,
+                    timer_count: 300,
+                },
+                permanent: OsdpLedParams {
+                    control_code: 1,
+                    on_count: 1,
+                    off_count: 0,
+                    on_color: OsdpLedColor::Green,
+                    off_color: OsdpLedColor::None,
+                    timer_count: 0,
+                },
+            }),
+            OsdpCommand::Text(OsdpCommandText {
+                reader: 0,
+                control_code: 1,
+                temp_time: 0,
+                offset_row: 1,
+                offset_col: 1,
+                data: b"Hello".to_vec(),
+            }),
+        ];
+        for cmd in commands {
+            let wire = cmd.to_wire().unwrap();
+            assert_eq!(OsdpCommand::from_wire(&wire).unwrap(), cmd);
+        }
+
+        let events = [
+            OsdpEvent::CardRead(OsdpEventCardRead::new_ascii(vec![0x55, 0xAA])),
+            OsdpEvent::CardRead(OsdpEventCardRead::new_wiegand(26, vec![1, 2, 3, 0x40]).unwrap()),
+            OsdpEvent::Status(OsdpStatusReport::new_input(4, 0b1010)),
+        ];
+        for event in events {
+            let wire = event.to_wire().unwrap();
+            assert_eq!(OsdpEvent::from_wire(&wire).unwrap(), event);
+        }
+        assert!(OsdpEvent::from_wire(&[0x40]).is_err());
+    }
+
+    #[test]
+    fn test_multi_output() {
+        let outputs = [
+            OsdpCommandOutput {
+                output_no: 0,
+                control_code: 5,
+                timer_count: 50,
+            },
+            OsdpCommandOutput {
+                output_no: 3,
+                control_code: 2,
+                timer_count: 0,
+            },
+        ];
+        let data = encode_outputs(&outputs).unwrap();
+        assert_eq!(data, [0x68, 0, 5, 50, 0, 3, 2, 0, 0]);
+        assert!(encode_outputs(&[]).is_err());
+
+        let pkt = Packet {
+            address: 1,
+            is_reply: false,
+            sequence: 1,
+            use_crc: true,
+            sc_block: None,
+            code: data[0],
+            data: data[1..].to_vec(),
+            mac: None,
+        };
+        let pkt = Packet::from_bytes(&pkt.to_bytes()).unwrap();
+        assert_eq!(pkt.outputs().unwrap(), outputs);
+        // Not one OsdpCommand; the second entry would be lost
+        assert_eq!(pkt.command(), None);
+        assert!(OsdpCommand::from_wire(&data).is_err());
+        assert_eq!(
+            OsdpCommand::from_wire(&data[..5]).unwrap(),
+            OsdpCommand::Output(outputs[0])
+        );
+        assert!(OsdpCommand::from_wire(&data[..7]).is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let poll = [0xFF, 0x53, 0x65, 0x08, 0x00, 0x05, 0x60, 0x51, 0xA3];
+        let poll = explain(&poll).unwrap();
+        assert_eq!(poll.direction(), "CP -> PD");
+        assert_eq!(poll.field("address"), Some("101 (0x65)"));
+        assert_eq!(poll.field("sequence"), Some("1"));
+        assert_eq!(poll.field("check"), Some("CRC-16"));
+        assert_eq!(poll.field("payload"), Some("(none)"));
+        assert_eq!(poll.field("sc block"), None);
+
+        let nak = Packet {
+            address: 1,
+            is_reply: true,
+            sequence: 2,
+            use_crc: true,
+            sc_block: Some(ScBlock {
+                block_type: 0x16,
+                data: vec![],
+            }),
+            code: 0x41,
+            data: vec![0x03],
+            mac: Some([0xde, 0xad, 0xbe, 0xef]),
+        };
+        let nak = explain(&nak.to_bytes()).unwrap();
+        assert_eq!(nak.field("sc block"), Some("0x16 (SCS_16, reply with MAC)"));
+        assert_eq!(nak.field("code"), Some("0x41 (NAK)"));
+        assert_eq!(
+            nak.field("payload"),
+            Some("reason 0x03 (unknown command code)")
+        );
+        assert_eq!(
+            nak.to_string(),
+            "PD -> CP  NAK\n    address   1 (0x01)\n    sequence  2\n    check     CRC-16\n    \
+             sc block  0x16 (SCS_16, reply with MAC)\n    code      0x41 (NAK)\n    \
+             payload   reason 0x03 (unknown command code)\n    mac       deadbeef"
+        );
+
+        assert!(explain(&[0x53, 0x01]).is_err());
+    }
+}
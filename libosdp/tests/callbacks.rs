@@ -0,0 +1,237 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{sync::mpsc, thread, time};
+
+use libosdp::{
+    MemoryChannel, OsdpCommand, OsdpCommandBuzzer, OsdpCommandKind, OsdpCommandMfg,
+    OsdpCommandOutput, OsdpError, OsdpEvent, OsdpEventCardRead, OsdpEventKeyPress, OsdpEventKind,
+    PdCapEntity, PdCapability, PeripheralDevice,
+};
+
+use crate::common::device::{self, CpDevice, PdDevice, KEY};
+
+#[test]
+fn test_sc_status_callback() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let _pd = PdDevice::new(Box::new(pd_bus))?;
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+
+    let (tx, rx) = mpsc::channel();
+    cp.set_sc_status_callback(move |pd, active| {
+        let _ = tx.send((pd, active));
+    })
+    .detach();
+    device::spawn_refresh("CP Thread", move || {
+        cp.refresh();
+    });
+
+    let status = rx.recv_timeout(time::Duration::from_secs(10)).unwrap();
+    assert_eq!(status, (0, true), "SC status callback check failed");
+    Ok(())
+}
+
+#[test]
+fn test_event_subscription() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    let cp = CpDevice::new(Box::new(cp_bus))?;
+
+    let (tx, card_reads) = mpsc::channel();
+    cp.get_device()
+        .subscribe(OsdpEventKind::CardRead, move |pd, event| {
+            let _ = tx.send((pd, event));
+            0
+        })
+        .detach();
+    while !pd.get_device().is_sc_active() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let card_read = OsdpEvent::CardRead(OsdpEventCardRead::new_ascii(vec![0x55, 0xAA]));
+    let key_press = OsdpEvent::KeyPress(OsdpEventKeyPress::new(vec![0x31, 0x32]));
+    pd.get_device().notify_event(card_read.clone())?;
+    pd.get_device().notify_event(key_press.clone())?;
+    assert_eq!(card_reads.recv().unwrap(), (0, card_read));
+    // Everything else still goes to the catch-all callback
+    assert_eq!(cp.receiver.recv().unwrap(), (0, key_press));
+    Ok(())
+}
+
+#[test]
+fn test_event_context() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    let cp = CpDevice::new(Box::new(cp_bus))?;
+
+    let (tx, events) = mpsc::channel();
+    cp.get_device()
+        .set_event_context_callback(move |context, event| {
+            let _ = tx.send((context.pd, context.name.to_string(), context.address, event));
+            0
+        })
+        .detach();
+    while !pd.get_device().is_sc_active() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let key_press = OsdpEvent::KeyPress(OsdpEventKeyPress::new(vec![0x31, 0x32]));
+    pd.get_device().notify_event(key_press.clone())?;
+    let received = events.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    assert_eq!(received, (0, "PD 101".to_string(), 101, key_press));
+    Ok(())
+}
+
+#[test]
+fn test_event_handler() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    let cp = CpDevice::new(Box::new(cp_bus))?;
+
+    let beep = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+    let command = beep.clone();
+    cp.get_device()
+        .set_event_handler(move |cp, pd, event| {
+            if let OsdpEvent::CardRead(_) = event {
+                cp.send_command(pd, command.clone()).unwrap();
+            }
+        })
+        .detach();
+    while !pd.get_device().is_sc_active() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let card_read = OsdpEvent::CardRead(OsdpEventCardRead::new_ascii(vec![0x55, 0xAA]));
+    pd.get_device().notify_event(card_read)?;
+    assert_eq!(pd.receiver.recv().unwrap(), beep);
+    // The handler takes precedence over the event callback
+    assert!(cp.receiver.try_recv().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_poll_events() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+    device::wait_for_sc(&mut cp, 0);
+
+    let card_read = OsdpEvent::CardRead(OsdpEventCardRead::new_ascii(vec![0x55, 0xAA]));
+    pd.get_device().notify_event(card_read.clone())?;
+    let mut events = Vec::new();
+    let mut events_received = 0;
+    while events.is_empty() {
+        events_received += cp.refresh().events_received;
+        events = cp.poll_events();
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert_eq!(events, [(0, card_read)]);
+    assert_eq!(events_received, 1);
+    assert!(cp.poll_events().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_pd_auto_ack() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd_info = device::pd_info()?
+        .capability(PdCapability::OutputControl(PdCapEntity::new(1, 1)))
+        .secure_channel_key(KEY)
+        .auto_ack(OsdpCommandKind::BENIGN);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    let (tx, outputs) = mpsc::channel();
+    pd.subscribe(OsdpCommandKind::Output, move |command| {
+        let _ = tx.send(command);
+        0
+    })
+    .detach();
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?.spawn()?;
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let timeout = time::Duration::from_secs(5);
+    let output = OsdpCommand::Output(OsdpCommandOutput::default());
+    cp.send_command_sync(0, output.clone(), timeout)?;
+    assert_eq!(outputs.try_recv().unwrap(), output);
+    // Not handled, but allowed by auto_ack
+    cp.send_command_sync(
+        0,
+        OsdpCommand::Buzzer(OsdpCommandBuzzer::default()),
+        timeout,
+    )?;
+    // Not handled and not allowed
+    let mfg = OsdpCommand::Mfg(OsdpCommandMfg::default());
+    assert!(matches!(
+        cp.send_command_sync(0, mfg, timeout),
+        Err(OsdpError::Nak(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_pd_acks_unhandled_commands() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd_info = device::pd_info()?.secure_channel_key(KEY);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?.spawn()?;
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    // Without auto_ack or nak_unhandled, commands that have no closure are ACK'd
+    let mfg = OsdpCommand::Mfg(OsdpCommandMfg::default());
+    cp.send_command_sync(0, mfg, time::Duration::from_secs(5))?;
+    Ok(())
+}
+
+#[test]
+fn test_panicking_callback() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd_info = device::pd_info()?
+        .capability(PdCapability::OutputControl(PdCapEntity::new(1, 1)))
+        .secure_channel_key(KEY);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    pd.set_command_callback(|_| panic!("command callback panicked"))
+        .detach();
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?.spawn()?;
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    // The panic doesn't unwind into LibOSDP; the command is NAK'd instead
+    let output = OsdpCommand::Output(OsdpCommandOutput::default());
+    let timeout = time::Duration::from_secs(5);
+    assert!(matches!(
+        cp.send_command_sync(0, output, timeout),
+        Err(OsdpError::Nak(_))
+    ));
+    assert!(cp.is_online(0)?);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{thread, time};
+
+use libosdp::{MemoryChannel, PdCapEntity, PdCapability};
+
+use crate::common::device::{CpDevice, PdDevice};
+
+#[test]
+fn test_get_capabilities() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    let cp = CpDevice::new(Box::new(cp_bus))?;
+    while !pd.get_device().is_sc_active() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let caps = cp.get_device().get_capabilities(0)?;
+    for cap in [
+        PdCapability::CommunicationSecurity(PdCapEntity::new(1, 1)),
+        PdCapability::AudibleOutput(PdCapEntity::new(1, 1)),
+        PdCapability::LedControl(PdCapEntity::new(1, 1)),
+    ] {
+        assert!(caps.contains(&cap), "{cap:?} missing in {caps:?}");
+    }
+    assert!(!caps.contains(&PdCapability::TextOutput(PdCapEntity::default())));
+    Ok(())
+}
+
+#[test]
+fn test_set_capabilities() -> Result<()> {
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    // One entry replaced and one added; LibOSDP must stop at the end of the
+    // list instead of reading past it.
+    pd.get_device().set_capabilities(&[
+        PdCapability::LedControl(PdCapEntity::new(2, 1)),
+        PdCapability::TextOutput(PdCapEntity::new(1, 1)),
+    ]);
+    let cp = CpDevice::new(Box::new(cp_bus))?;
+    while !pd.get_device().is_sc_active() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let caps = cp.get_device().get_capabilities(0)?;
+    for cap in [
+        PdCapability::CommunicationSecurity(PdCapEntity::new(1, 1)),
+        PdCapability::AudibleOutput(PdCapEntity::new(1, 1)),
+        PdCapability::LedControl(PdCapEntity::new(2, 1)),
+        PdCapability::TextOutput(PdCapEntity::new(1, 1)),
+    ] {
+        assert!(caps.contains(&cap), "{cap:?} missing in {caps:?}");
+    }
+    Ok(())
+}
@@ -9,13 +9,11 @@ type Result<T> = core::result::Result<T, libosdp::OsdpError>;
 use std::{sync::MutexGuard, thread, time};
 
 use libosdp::{
-    Channel, ControlPanel, OsdpCommand, OsdpCommandBuzzer, OsdpEvent, OsdpEventCardRead,
-    PeripheralDevice,
+    Channel, ControlPanel, MemoryChannel, OsdpCommand, OsdpCommandBuzzer, OsdpCommandOutput,
+    OsdpErrorKind, OsdpEvent, OsdpEventCardRead, OsdpStatusReport, PeripheralDevice, ThreadBus,
 };
 
-use crate::common::{
-    device::CpDevice, device::PdDevice, memory_channel::MemoryChannel, threadbus::ThreadBus,
-};
+use crate::common::{device::CpDevice, device::PdDevice};
 
 fn send_command(mut cp: MutexGuard<'_, ControlPanel>, command: OsdpCommand) -> Result<()> {
     cp.send_command(0, command)
@@ -59,6 +57,52 @@ fn test_commands() -> Result<()> {
     let cmd_rx = pd.receiver.recv().unwrap();
     assert_eq!(cmd_rx, command, "Buzzer command check failed");
 
+    let outputs = [
+        OsdpCommandOutput {
+            output_no: 0,
+            control_code: 5,
+            timer_count: 50,
+        },
+        OsdpCommandOutput {
+            output_no: 1,
+            control_code: 2,
+            timer_count: 0,
+        },
+    ];
+    for output in outputs {
+        send_command(cp.get_device(), OsdpCommand::Output(output))?;
+        let cmd_rx = pd.receiver.recv().unwrap();
+        assert_eq!(
+            cmd_rx,
+            OsdpCommand::Output(output),
+            "Output command check failed"
+        );
+    }
+    let err = cp
+        .get_device()
+        .send_command(1, OsdpCommand::Output(outputs[0]))
+        .unwrap_err();
+    assert_eq!(err.kind(), OsdpErrorKind::InvalidArgument);
+    assert!(!err.is_retryable());
+
+    let command = OsdpCommand::Buzzer(OsdpCommandBuzzer::access_granted());
+    let guard = cp
+        .get_device()
+        .schedule(0, command.clone(), time::Duration::from_millis(200))?;
+    for _ in 0..2 {
+        let cmd_rx = pd.receiver.recv().unwrap();
+        assert_eq!(cmd_rx, command, "Scheduled command check failed");
+    }
+    guard.cancel();
+
+    let state = pd.get_device().state();
+    assert!(state.buzzer(0).is_some(), "Buzzer state check failed");
+    assert_eq!(
+        state.output_status(2),
+        OsdpStatusReport::new_output(2, 0b11),
+        "Output state check failed"
+    );
+
     let event = OsdpEvent::CardRead(OsdpEventCardRead::new_ascii(vec![0x55, 0xAA]));
     notify_event(pd.get_device(), event.clone())?;
     assert_eq!(
@@ -67,5 +111,22 @@ fn test_commands() -> Result<()> {
         "Card read event check failed"
     );
 
+    pd.get_device().notify_tamper(true)?;
+    assert_eq!(
+        cp.receiver.recv().unwrap(),
+        (0_i32, OsdpEvent::Status(OsdpStatusReport::new_local(0b01))),
+        "Tamper event check failed"
+    );
+    pd.get_device().notify_power_failure()?;
+    pd.get_device().notify_tamper(false)?;
+    pd.get_device().notify_power_restored()?;
+    for mask in [0b11, 0b10, 0b00] {
+        assert_eq!(
+            cp.receiver.recv().unwrap(),
+            (0_i32, OsdpEvent::Status(OsdpStatusReport::new_local(mask))),
+            "Local status event check failed"
+        );
+    }
+
     Ok(())
 }
@@ -9,56 +9,91 @@ use std::{
 };
 
 use libosdp::{
-    ControlPanel, ControlPanelBuilder, OsdpCommand, OsdpEvent, PdCapEntity, PdCapability,
+    Channel, ControlPanel, ControlPanelBuilder, OsdpCommand, OsdpEvent, PdCapEntity, PdCapability,
     PdInfoBuilder, PeripheralDevice,
 };
 type Result<T> = core::result::Result<T, libosdp::OsdpError>;
 
+/// Secure channel base key of the PD in these tests
+#[rustfmt::skip]
+#[allow(unused)]
+pub const KEY: [u8; 16] = [
+    0x94, 0x4b, 0x8e, 0xdd, 0xcb, 0xaa, 0x2b, 0x5f,
+    0xe2, 0xb0, 0x14, 0x8d, 0x1b, 0x2f, 0x95, 0xc9
+];
+
+/// The PD as it describes itself: "PD 101" at 115200 baud, capable of the
+/// secure channel but without a key.
+pub fn pd_info() -> Result<PdInfoBuilder> {
+    Ok(PdInfoBuilder::new()
+        .name("PD 101")?
+        .address(101)?
+        .baud_rate(115200)?
+        .capability(PdCapability::CommunicationSecurity(PdCapEntity::new(1, 1))))
+}
+
+/// The PD as the CP knows it: "PD 101" at 115200 baud, without a key.
+#[allow(unused)]
+pub fn cp_info() -> Result<PdInfoBuilder> {
+    PdInfoBuilder::new()
+        .name("PD 101")?
+        .address(101)?
+        .baud_rate(115200)
+}
+
+/// A CP with `pd` on `bus`
+#[allow(unused)]
+pub fn control_panel(bus: Box<dyn Channel>, pd: PdInfoBuilder) -> Result<ControlPanel> {
+    ControlPanelBuilder::new()
+        .add_channel(bus, vec![pd])
+        .build()
+}
+
+/// Call `refresh` every 10ms from a thread of its own, for as long as the
+/// test runs.
+pub fn spawn_refresh(name: &str, mut refresh: impl FnMut() + Send + 'static) {
+    let _ = thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            refresh();
+            thread::sleep(time::Duration::from_millis(10));
+        });
+}
+
+/// Refresh `cp` until it has a secure channel session with `pd`; fails the
+/// test if that takes more than 10s.
+#[allow(unused)]
+pub fn wait_for_sc(cp: &mut ControlPanel, pd: i32) {
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while !cp.is_sc_active(pd).unwrap() {
+        assert!(time::Instant::now() < deadline, "No secure channel session");
+        cp.refresh();
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+#[allow(unused)]
 pub struct CpDevice {
     dev: Arc<Mutex<ControlPanel>>,
-    #[allow(unused)] // false positive, it is used!
     pub receiver: Receiver<(i32, OsdpEvent)>,
 }
 
+#[allow(unused)]
 impl CpDevice {
-    pub fn new(bus: Box<dyn libosdp::Channel>) -> Result<Self> {
-        #[rustfmt::skip]
-        let pd_0_key = [
-            0x94, 0x4b, 0x8e, 0xdd, 0xcb, 0xaa, 0x2b, 0x5f,
-            0xe2, 0xb0, 0x14, 0x8d, 0x1b, 0x2f, 0x95, 0xc9
-        ];
-
-        let pd_0 = PdInfoBuilder::new()
-            .name("PD 101")?
-            .address(101)?
-            .baud_rate(115200)?
-            .secure_channel_key(pd_0_key);
-        let mut cp = ControlPanelBuilder::new()
-            .add_channel(bus, vec![pd_0])
-            .build()?;
+    pub fn new(bus: Box<dyn Channel>) -> Result<Self> {
+        let mut cp = control_panel(bus, cp_info()?.secure_channel_key(KEY))?;
         let (event_tx, event_rx) = std::sync::mpsc::channel::<(i32, OsdpEvent)>();
-
-        cp.set_event_callback(|pd, event| {
-            event_tx.send((pd, event)).unwrap();
+        cp.set_event_callback(move |pd, event| {
+            event_tx.send((pd, event)).expect("CP event send");
             0
-        });
+        })
+        .detach();
 
         let dev = Arc::new(Mutex::new(cp));
-        let dev_clone = dev.clone();
-        let _ = thread::Builder::new()
-            .name("CP Thread".to_string())
-            .spawn(move || {
-                let dev = dev_clone;
-                let sender = event_tx;
-                dev.lock().unwrap().set_event_callback(|pd, event| {
-                    sender.send((pd, event)).expect("CP event send");
-                    0
-                });
-                loop {
-                    dev.lock().unwrap().refresh();
-                    thread::sleep(time::Duration::from_millis(10));
-                }
-            });
+        let refresher = dev.clone();
+        spawn_refresh("CP Thread", move || {
+            refresher.lock().unwrap().refresh();
+        });
         Ok(Self {
             dev,
             receiver: event_rx,
@@ -70,51 +105,30 @@ impl CpDevice {
     }
 }
 
+#[allow(unused)]
 pub struct PdDevice {
     dev: Arc<Mutex<PeripheralDevice>>,
     pub receiver: Receiver<OsdpCommand>,
 }
 
+#[allow(unused)]
 impl PdDevice {
-    pub fn new(bus: Box<dyn libosdp::Channel>) -> Result<Self> {
-        #[rustfmt::skip]
-        let key = [
-            0x94, 0x4b, 0x8e, 0xdd, 0xcb, 0xaa, 0x2b, 0x5f,
-            0xe2, 0xb0, 0x14, 0x8d, 0x1b, 0x2f, 0x95, 0xc9
-        ];
-
-        let pd_info = PdInfoBuilder::new()
-            .name("PD 101")?
-            .address(101)?
-            .baud_rate(115200)?
-            .capability(PdCapability::CommunicationSecurity(PdCapEntity::new(1, 1)))
+    pub fn new(bus: Box<dyn Channel>) -> Result<Self> {
+        let pd_info = pd_info()?
             .capability(PdCapability::AudibleOutput(PdCapEntity::new(1, 1)))
             .capability(PdCapability::LedControl(PdCapEntity::new(1, 1)))
-            .secure_channel_key(key);
+            .secure_channel_key(KEY);
         let mut pd = PeripheralDevice::new(pd_info, bus)?;
         let (cmd_tx, cmd_rx) = std::sync::mpsc::channel::<OsdpCommand>();
-        pd.set_command_callback(|command| {
-            cmd_tx.send(command).unwrap();
+        pd.set_command_callback(move |command| {
+            cmd_tx.send(command).expect("PD command send");
             0
-        });
+        })
+        .detach();
 
         let dev = Arc::new(Mutex::new(pd));
-        let dev_clone = dev.clone();
-        let _ = thread::Builder::new()
-            .name("PD Thread".to_string())
-            .spawn(move || {
-                let dev = dev_clone;
-                let sender = cmd_tx;
-                dev.lock().unwrap().set_command_callback(|command| {
-                    sender.send(command).expect("PD command send");
-                    0
-                });
-                loop {
-                    dev.lock().unwrap().refresh();
-                    thread::sleep(time::Duration::from_millis(10));
-                }
-            });
-
+        let refresher = dev.clone();
+        spawn_refresh("PD Thread", move || refresher.lock().unwrap().refresh());
         Ok(Self {
             dev,
             receiver: cmd_rx,
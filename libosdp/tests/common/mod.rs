@@ -4,13 +4,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod device;
-pub mod memory_channel;
-pub mod threadbus;
 
+/// Set up logging; tests in the same binary all call this, so only the first
+/// call does anything.
 pub fn setup() {
-    env_logger::builder()
+    let _ = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .format_target(false)
         .format_timestamp(None)
-        .init();
+        .is_test(true)
+        .try_init();
 }
@@ -0,0 +1,141 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{
+    sync::{Arc, Mutex},
+    thread, time,
+};
+
+use common::device::{self, KEY};
+use libosdp::{
+    Channel, ChannelError, MemoryChannel, OsdpComSet, OsdpCommand, OsdpCommandKind,
+    OsdpCommandOutput, OsdpError, PdCapEntity, PdCapability, PeripheralDevice,
+    ReconfigurableChannel,
+};
+
+/// A MemoryChannel that records the settings it was asked to switch to
+struct SerialChannel {
+    inner: MemoryChannel,
+    settings: Arc<Mutex<Vec<(u8, u32)>>>,
+    address: u8,
+}
+
+impl Channel for SerialChannel {
+    fn get_id(&self) -> i32 {
+        self.inner.get_id()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, ChannelError> {
+        self.inner.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, ChannelError> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> core::result::Result<(), ChannelError> {
+        self.inner.flush()
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        Some(self)
+    }
+}
+
+impl ReconfigurableChannel for SerialChannel {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> core::result::Result<(), ChannelError> {
+        self.settings
+            .lock()
+            .unwrap()
+            .push((self.address, baud_rate));
+        Ok(())
+    }
+
+    fn set_address_filter(&mut self, address: u8) -> core::result::Result<(), ChannelError> {
+        self.address = address;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pd_comset_reconfigures_channel() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let settings = Arc::new(Mutex::new(Vec::new()));
+    let pd_bus = SerialChannel {
+        inner: pd_bus,
+        settings: settings.clone(),
+        address: 101,
+    };
+    let pd_info = device::pd_info()?
+        .secure_channel_key(KEY)
+        .auto_ack(&[OsdpCommandKind::ComSet]);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?.spawn()?;
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let comset = OsdpCommand::ComSet(OsdpComSet::new(101, 38400));
+    cp.send_command_sync(0, comset, time::Duration::from_secs(5))?;
+    // The channel is switched over once the PD has sent its reply
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    while settings.lock().unwrap().is_empty() && time::Instant::now() < deadline {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert_eq!(*settings.lock().unwrap(), vec![(101, 38400)]);
+    Ok(())
+}
+
+#[test]
+fn test_cp_comset() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd_info = device::pd_info()?
+        .capability(PdCapability::OutputControl(PdCapEntity::new(1, 1)))
+        .secure_channel_key(KEY)
+        .auto_ack(&[OsdpCommandKind::ComSet, OsdpCommandKind::Output]);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let settings = Arc::new(Mutex::new(Vec::new()));
+    let cp_bus = SerialChannel {
+        inner: cp_bus,
+        settings: settings.clone(),
+        address: 0,
+    };
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+    device::wait_for_sc(&mut cp, 0);
+
+    assert!(cp.comset(0, 127, 38400).is_err());
+    assert_eq!(cp.index_of_address(101)?, 0);
+    cp.comset(0, 102, 38400)?;
+    assert_eq!(*settings.lock().unwrap(), vec![(0, 38400)]);
+    assert_eq!(cp.address_of(0)?, 102);
+    assert_eq!(cp.index_of_address(102)?, 0);
+    assert!(matches!(
+        cp.index_of_address(101),
+        Err(OsdpError::InvalidAddress(101))
+    ));
+    let output = OsdpCommand::Output(OsdpCommandOutput::default());
+    cp.send_command_to_address(102, output.clone())?;
+    assert!(matches!(
+        cp.send_command_to_address(101, output),
+        Err(OsdpError::InvalidAddress(101))
+    ));
+
+    // Commands can still be tracked at the new address
+    let cp = cp.spawn()?;
+    let output = OsdpCommand::Output(OsdpCommandOutput::default());
+    cp.send_command_sync(0, output, time::Duration::from_secs(5))?;
+    Ok(())
+}
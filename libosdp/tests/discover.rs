@@ -0,0 +1,44 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use common::device::PdDevice;
+use libosdp::{ControlPanelBuilder, Discovery, MemoryChannel};
+use std::time;
+
+#[test]
+fn test_discovery() -> Result<()> {
+    common::setup();
+    let (mut cp_bus, pd_bus) = MemoryChannel::new();
+    let _pd = PdDevice::new(Box::new(pd_bus))?;
+
+    let mut reported = Vec::new();
+    let found = Discovery::new()
+        .baud_rates(&[115200])
+        .addresses(99..=102)
+        .timeout(time::Duration::from_millis(100))
+        .run(&mut cp_bus, |pd| reported.push(*pd))?;
+    assert_eq!(found, reported);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].address, 101);
+    assert_eq!(found[0].baud_rate, 115200);
+    assert!(found[0].pd_id.is_some());
+
+    // The channel can then be used to talk to the PDs that were found
+    let pd_info = found[0].pd_info()?.name("PD 101")?;
+    let mut cp = ControlPanelBuilder::new()
+        .add_channel(Box::new(cp_bus), vec![pd_info])
+        .build()?;
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while !cp.is_online(0)? {
+        assert!(time::Instant::now() < deadline, "PD did not come online");
+        cp.refresh();
+        std::thread::sleep(time::Duration::from_millis(10));
+    }
+    assert_eq!(Some(cp.get_pd_id(0)?), found[0].pd_id);
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use common::device::{self, PdDevice};
+use libosdp::{MemoryChannel, OsdpCommand, OsdpCommandBuzzer, OsdpError, PdId};
+use std::{sync::mpsc, time};
+
+#[test]
+fn test_expected_id_mismatch() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let _pd = PdDevice::new(Box::new(pd_bus))?;
+    // The PD reports the default identity
+    let reported = PdId::default();
+    let expected = PdId::from_number(1);
+
+    let pd_info = device::cp_info()?.expected_id(&expected);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_info)?;
+    let (tx, rx) = mpsc::channel::<(i32, PdId, PdId)>();
+    let _guard = cp.set_id_mismatch_callback(move |pd, expected, reported| {
+        tx.send((pd, expected, reported)).unwrap();
+    });
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while !cp.is_online(0)? {
+        assert!(time::Instant::now() < deadline, "PD did not come online");
+        cp.refresh();
+        std::thread::sleep(time::Duration::from_millis(10));
+    }
+    assert_eq!(rx.try_recv(), Ok((0, expected, reported)));
+
+    let cmd = OsdpCommand::Buzzer(OsdpCommandBuzzer::default());
+    assert!(matches!(
+        cp.send_command(0, cmd),
+        Err(OsdpError::IdMismatch { pd: 0, .. })
+    ));
+    Ok(())
+}
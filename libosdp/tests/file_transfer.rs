@@ -8,7 +8,7 @@ mod common;
 type Result<T> = core::result::Result<T, libosdp::OsdpError>;
 
 use core::time::Duration;
-use libosdp::{OsdpCommand, OsdpCommandFileTx, OsdpError, OsdpFileOps};
+use libosdp::{MemoryChannel, OsdpCommand, OsdpCommandFileTx, OsdpError, OsdpFileOps};
 use rand::Rng;
 use std::{
     cmp,
@@ -20,7 +20,7 @@ use std::{
     thread,
 };
 
-use crate::common::{device::CpDevice, device::PdDevice, memory_channel::MemoryChannel};
+use crate::common::{device::CpDevice, device::PdDevice};
 
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::prelude::FileExt;
@@ -0,0 +1,220 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread, time,
+};
+
+use common::device::{self, KEY};
+use libosdp::{
+    ControlPanelBuilder, KeyRotation, MemoryChannel, OsdpCommand, OsdpFlag, PdInfoBuilder,
+    PeripheralDevice, SecureKeyStore,
+};
+
+/// A key store that outlives the PDs it is given to
+#[derive(Clone, Default)]
+struct SharedKeyStore(Arc<Mutex<Option<[u8; 16]>>>);
+
+impl SecureKeyStore for SharedKeyStore {
+    fn load(&mut self) -> Result<Option<[u8; 16]>> {
+        Ok(*self.0.lock().unwrap())
+    }
+
+    fn store(&mut self, key: &[u8; 16]) -> Result<()> {
+        *self.0.lock().unwrap() = Some(*key);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_provisioning() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let new_key = KEY;
+
+    let pd_info = device::pd_info()?.flag(OsdpFlag::InstallMode);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    let (key_tx, key_rx) = mpsc::channel();
+    pd.set_command_callback(move |cmd| {
+        if let OsdpCommand::KeySet(keyset) = cmd {
+            key_tx.send(keyset.data).unwrap();
+        }
+        0
+    })
+    .detach();
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let mut cp = device::control_panel(Box::new(cp_bus), device::cp_info()?)?;
+
+    let timeout = time::Duration::from_secs(10);
+    let key = cp
+        .provision(0)?
+        .establish_session(timeout)?
+        .set_key(new_key, timeout)?
+        .verify(timeout)?;
+    assert_eq!(key, new_key);
+    assert_eq!(key_rx.try_recv().unwrap(), new_key.to_vec());
+    assert!(cp.is_sc_active(0)?);
+    Ok(())
+}
+
+#[test]
+fn test_key_store() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let new_key = [0xa5; 16];
+    let store = SharedKeyStore::default();
+
+    let pd_info = |store: &SharedKeyStore| -> Result<PdInfoBuilder> {
+        Ok(device::pd_info()?
+            .flag(OsdpFlag::InstallMode)
+            .key_store(Box::new(store.clone())))
+    };
+    let mut pd = PeripheralDevice::new(pd_info(&store)?, Box::new(pd_bus))?;
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let pd_thread = thread::spawn(move || {
+        while stop_rx.try_recv().is_err() {
+            pd.refresh();
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        pd.teardown()
+    });
+
+    let mut cp = device::control_panel(Box::new(cp_bus), device::cp_info()?)?;
+    let timeout = time::Duration::from_secs(10);
+    cp.provision(0)?
+        .establish_session(timeout)?
+        .set_key(new_key, timeout)?
+        .verify(timeout)?;
+    assert_eq!(*store.0.lock().unwrap(), Some(new_key));
+
+    // The PD reboots, still configured for install mode, and comes back with
+    // the key it was given
+    stop_tx.send(()).unwrap();
+    let pd_bus = pd_thread.join().unwrap();
+    let mut pd = PeripheralDevice::new(pd_info(&store)?, pd_bus)?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let cp_bus = cp.teardown().pop().unwrap();
+    let pd_0 = device::cp_info()?
+        .flag(OsdpFlag::EnforceSecure)
+        .secure_channel_key(new_key);
+    let mut cp = device::control_panel(cp_bus, pd_0)?;
+    device::wait_for_sc(&mut cp, 0);
+    Ok(())
+}
+
+#[test]
+fn test_abandoned_provisioning() -> Result<()> {
+    common::setup();
+    let (cp_bus, _pd_bus) = MemoryChannel::new();
+    let mut cp = device::control_panel(Box::new(cp_bus), device::cp_info()?)?;
+
+    // There is no PD on the other end, so this can't go anywhere
+    let res = cp
+        .provision(0)?
+        .establish_session(time::Duration::from_millis(100));
+    assert!(matches!(res, Err(libosdp::OsdpError::Timeout)));
+    Ok(())
+}
+
+#[test]
+fn test_provisioning_waits_for_its_pd() -> Result<()> {
+    common::setup();
+    let (cp_bus_0, _pd_bus_0) = MemoryChannel::new();
+    let (cp_bus_1, pd_bus_1) = MemoryChannel::new();
+    let key = [0x5a; 16];
+
+    // Only the second PD is there, in a secure channel session
+    let pd_info = device::pd_info()?.address(102)?.secure_channel_key(key);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus_1))?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?;
+    let pd_1 = device::cp_info()?.address(102)?.secure_channel_key(key);
+    let mut cp = ControlPanelBuilder::new()
+        .add_channel(Box::new(cp_bus_0), vec![pd_0])
+        .add_channel(Box::new(cp_bus_1), vec![pd_1])
+        .build()?;
+    device::wait_for_sc(&mut cp, 1);
+
+    let res = cp
+        .provision(0)?
+        .establish_session(time::Duration::from_millis(200));
+    assert!(matches!(res, Err(libosdp::OsdpError::Timeout)));
+    Ok(())
+}
+
+#[test]
+fn test_rotate_key() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let old_key = [0x5a; 16];
+    let new_key = [0xa5; 16];
+
+    let pd_info = device::pd_info()?.secure_channel_key(old_key);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    let (key_tx, key_rx) = mpsc::channel();
+    pd.set_command_callback(move |cmd| {
+        if let OsdpCommand::KeySet(keyset) = cmd {
+            key_tx.send(keyset.data).unwrap();
+        }
+        0
+    })
+    .detach();
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = device::cp_info()?.secure_channel_key(old_key);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+
+    device::wait_for_sc(&mut cp, 0);
+    cp.rotate_key(0, new_key, time::Duration::from_secs(10))?;
+    assert_eq!(key_rx.try_recv().unwrap(), new_key.to_vec());
+    Ok(())
+}
+
+#[test]
+fn test_rotate_key_with_rollback() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let old_key = [0x5a; 16];
+    let new_key = [0xa5; 16];
+
+    let pd_info =
+        move || -> Result<PdInfoBuilder> { Ok(device::pd_info()?.secure_channel_key(old_key)) };
+    let mut pd = PeripheralDevice::new(pd_info()?, Box::new(pd_bus))?;
+    let (key_tx, key_rx) = mpsc::channel();
+    pd.set_command_callback(move |cmd| {
+        if let OsdpCommand::KeySet(keyset) = cmd {
+            key_tx.send(keyset.data).unwrap();
+        }
+        0
+    })
+    .detach();
+    // A PD that ACKs the new key, but comes back up with the old one
+    let _ = thread::Builder::new()
+        .name("PD Thread".to_string())
+        .spawn(move || loop {
+            pd.refresh();
+            if key_rx.try_recv().is_ok() {
+                let bus = pd.teardown();
+                pd = PeripheralDevice::new(pd_info().unwrap(), bus).unwrap();
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        });
+
+    let pd_0 = device::cp_info()?.secure_channel_key(old_key);
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+
+    device::wait_for_sc(&mut cp, 0);
+    let outcome = cp.rotate_key_with_rollback(0, new_key, time::Duration::from_secs(5))?;
+    assert!(matches!(outcome, KeyRotation::RolledBack(_)), "{outcome:?}");
+    assert!(cp.is_sc_active(0)?);
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{sync::Arc, thread, time};
+
+use libosdp::{MemoryChannel, OsdpCommand, OsdpCommandBuzzer, OsdpError, SyncControlPanel};
+
+use crate::common::device::{self, PdDevice, KEY};
+
+#[test]
+fn test_split_control_panel() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+
+    let (mut refresher, commander) = cp.split();
+    device::spawn_refresh("CP Thread", move || {
+        refresher.refresh();
+    });
+
+    while !commander.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let command = OsdpCommand::Buzzer(OsdpCommandBuzzer::default());
+    let sender = commander.clone();
+    let cmd = command.clone();
+    thread::spawn(move || sender.send_command(0, cmd))
+        .join()
+        .unwrap()?;
+    let cmd_rx = pd.receiver.recv().unwrap();
+    assert_eq!(cmd_rx, command, "Buzzer command check failed");
+    assert!(matches!(
+        commander.send_command(1, command.clone()),
+        Err(OsdpError::InvalidPd(1))
+    ));
+    assert!(matches!(
+        commander.send_command(-1, command),
+        Err(OsdpError::InvalidPd(-1))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_spawn_control_panel() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?.spawn()?;
+
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    let command = OsdpCommand::Buzzer(OsdpCommandBuzzer::default());
+    cp.send_command_sync(0, command.clone(), time::Duration::from_secs(5))?;
+    let cmd_rx = pd.receiver.try_recv().unwrap();
+    assert_eq!(cmd_rx, command, "Buzzer command check failed");
+    Ok(())
+}
+
+#[test]
+fn test_sync_control_panel() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd = PdDevice::new(Box::new(pd_bus))?;
+
+    let pd_0 = device::cp_info()?.secure_channel_key(KEY);
+    let cp = device::control_panel(Box::new(cp_bus), pd_0)?;
+
+    let cp = Arc::new(SyncControlPanel::new(cp));
+    let refresher = cp.clone();
+    device::spawn_refresh("CP Thread", move || {
+        refresher.refresh();
+    });
+
+    while !cp.is_sc_active(0)? {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    let command = OsdpCommand::Buzzer(OsdpCommandBuzzer::default());
+    let sender = cp.clone();
+    let cmd = command.clone();
+    thread::spawn(move || sender.send_command(0, cmd))
+        .join()
+        .unwrap()?;
+    let cmd_rx = pd.receiver.recv().unwrap();
+    assert_eq!(cmd_rx, command, "Buzzer command check failed");
+    assert!(cp.send_command(1, command).is_err());
+    Ok(())
+}
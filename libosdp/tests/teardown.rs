@@ -0,0 +1,49 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{thread, time};
+
+use common::device::{self, KEY};
+use libosdp::{Channel, ControlPanel, MemoryChannel, PeripheralDevice};
+
+fn setup(
+    cp_bus: Box<dyn Channel>,
+    pd_bus: Box<dyn Channel>,
+) -> Result<(ControlPanel, PeripheralDevice)> {
+    let pd = PeripheralDevice::new(device::pd_info()?.secure_channel_key(KEY), pd_bus)?;
+    let cp = device::control_panel(cp_bus, device::cp_info()?.secure_channel_key(KEY))?;
+    Ok((cp, pd))
+}
+
+fn connect(cp: &mut ControlPanel, pd: &mut PeripheralDevice) {
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while !cp.is_sc_active(0).unwrap() {
+        assert!(time::Instant::now() < deadline, "PD did not come online");
+        cp.refresh();
+        pd.refresh();
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_teardown_returns_channels() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let (mut cp, mut pd) = setup(Box::new(cp_bus), Box::new(pd_bus))?;
+    connect(&mut cp, &mut pd);
+
+    let mut cp_buses = cp.teardown();
+    let pd_bus = pd.teardown();
+    assert_eq!(cp_buses.len(), 1);
+    assert_eq!(pd_bus.get_id(), 1);
+
+    // The same channels can be used to set up new devices
+    let (mut cp, mut pd) = setup(cp_buses.remove(0), pd_bus)?;
+    connect(&mut cp, &mut pd);
+    Ok(())
+}
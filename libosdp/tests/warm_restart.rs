@@ -0,0 +1,68 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod common;
+type Result<T> = core::result::Result<T, libosdp::OsdpError>;
+
+use std::{sync::mpsc, thread, time};
+
+use common::device::{self, KEY};
+use libosdp::{
+    ControlPanelBuilder, CpRuntimeState, MemoryChannel, OsdpCommandKind, PeripheralDevice,
+};
+
+#[test]
+fn test_warm_restart() -> Result<()> {
+    common::setup();
+    let (cp_bus, pd_bus) = MemoryChannel::new();
+    let pd_info = device::pd_info()?
+        .secure_channel_key(KEY)
+        .auto_ack(&[OsdpCommandKind::ComSet]);
+    let mut pd = PeripheralDevice::new(pd_info, Box::new(pd_bus))?;
+    device::spawn_refresh("PD Thread", move || pd.refresh());
+
+    let pd_0 = || device::cp_info().map(|pd| pd.secure_channel_key(KEY));
+    let mut cp = device::control_panel(Box::new(cp_bus), pd_0()?)?;
+    device::wait_for_sc(&mut cp, 0);
+    cp.comset(0, 102, 38400)?;
+
+    let state = cp.export_state();
+    assert_eq!(state.pds.len(), 1);
+    assert_eq!(state.pds[0].address, 102);
+    assert_eq!(state.pds[0].baud_rate, 38400);
+    assert_eq!(state.pds[0].secure_channel_key, Some(KEY));
+    assert!(state.pds[0].online);
+
+    // A CP set up with the original configuration finds the PD again
+    let mut channels = cp.teardown();
+    let cp_bus = channels.pop().unwrap();
+    let wrong = ControlPanelBuilder::new()
+        .restore_state(CpRuntimeState::default())
+        .add_channel(Box::new(MemoryChannel::new().0), vec![pd_0()?])
+        .build();
+    assert!(wrong.is_err());
+    let mut cp = ControlPanelBuilder::new()
+        .add_channel(cp_bus, vec![pd_0()?])
+        .restore_state(state)
+        .build()?;
+    // The PD was online, with a secure channel session, all along as far as
+    // the application can tell
+    let (sc_tx, sc_rx) = mpsc::channel();
+    let _sc_status = cp.set_sc_status_callback(move |pd, active| {
+        let _ = sc_tx.send((pd, active));
+    });
+    assert!(cp.export_state().pds[0].online);
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while !cp.is_sc_active(0)? {
+        assert!(time::Instant::now() < deadline, "No secure channel session");
+        let report = cp.refresh();
+        assert!(report.went_offline.is_empty() && report.came_online.is_empty());
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    cp.refresh();
+    assert!(sc_rx.try_recv().is_err());
+    assert_eq!(cp.export_state().pds[0].address, 102);
+    Ok(())
+}
@@ -6,7 +6,9 @@
 use anyhow::bail;
 use anyhow::Context;
 use configparser::ini::Ini;
-use libosdp::{ControlPanelBuilder, OsdpFlag, PdCapability, PdId, PdInfoBuilder};
+use libosdp::{
+    Channel, ControlPanelBuilder, OsdpFlag, PdCapability, PdId, PdInfoBuilder, UnixChannel,
+};
 use rand::Rng;
 use std::{
     fmt::Write,
@@ -14,8 +16,6 @@ use std::{
     str::FromStr,
 };
 
-use crate::unix_channel::UnixChannel;
-
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
 fn vec_to_array<T, const N: usize>(v: Vec<T>) -> [T; N] {
@@ -23,26 +23,128 @@ fn vec_to_array<T, const N: usize>(v: Vec<T>) -> [T; N] {
         .unwrap_or_else(|v: Vec<T>| panic!("Expected a Vec of length {} but it was {}", N, v.len()))
 }
 
+/// How the keys of a [`KeyStore`] are protected at rest, given as `key_store`
+/// in the device config: `plain` (the default), `passphrase:<env var>` to
+/// encrypt its file with the passphrase in that environment variable,
+/// `keyfile:<path>` to encrypt its file to the age identity (as written by
+/// `age-keygen`) in that file or `keyring` to keep the key in the platform
+/// keyring (Secret Service, macOS Keychain or Windows Credential Manager)
+/// instead of a file. Encrypted files are age files, so they can be decrypted
+/// with the `age` tool too.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KeyProtection {
+    #[default]
+    Plain,
+    Passphrase(String),
+    KeyFile(PathBuf),
+    Keyring,
+}
+
+impl FromStr for KeyProtection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "plain" => Ok(KeyProtection::Plain),
+            None if s == "keyring" => Ok(KeyProtection::Keyring),
+            Some(("passphrase", var)) if !var.is_empty() => {
+                Ok(KeyProtection::Passphrase(var.to_owned()))
+            }
+            Some(("keyfile", path)) if !path.is_empty() => Ok(KeyProtection::KeyFile(path.into())),
+            _ => bail!(
+                "Invalid key_store '{s}'; expected plain, passphrase:<env var>, keyfile:<path> or keyring"
+            ),
+        }
+    }
+}
+
+impl KeyProtection {
+    fn passphrase(var: &str) -> Result<age::secrecy::SecretString> {
+        let passphrase = std::env::var(var)
+            .with_context(|| format!("Key store passphrase variable {var} is not set"))?;
+        Ok(passphrase.into())
+    }
+
+    fn identity(path: &Path) -> Result<age::x25519::Identity> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read key file {}", path.display()))?;
+        let key = file
+            .lines()
+            .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+            .with_context(|| format!("No age identity in {}", path.display()))?;
+        key.parse()
+            .map_err(|e| anyhow::anyhow!("{e}: {}", path.display()))
+    }
+
+    /// Protect `data` to be written to a key store file; keys in the keyring
+    /// are not kept in files.
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            KeyProtection::Plain => data.to_vec(),
+            KeyProtection::Keyring => unreachable!("keyring keys are not kept in files"),
+            KeyProtection::Passphrase(var) => {
+                let recipient = age::scrypt::Recipient::new(Self::passphrase(var)?);
+                age::encrypt(&recipient, data)?
+            }
+            KeyProtection::KeyFile(path) => age::encrypt(&Self::identity(path)?.to_public(), data)?,
+        })
+    }
+
+    /// Read `data` from a key store file back; see [`KeyProtection::seal`].
+    fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            KeyProtection::Plain => data.to_vec(),
+            KeyProtection::Keyring => unreachable!("keyring keys are not kept in files"),
+            KeyProtection::Passphrase(var) => {
+                let identity = age::scrypt::Identity::new(Self::passphrase(var)?);
+                age::decrypt(&identity, data).context("Unable to decrypt key store")?
+            }
+            KeyProtection::KeyFile(path) => {
+                age::decrypt(&Self::identity(path)?, data).context("Unable to decrypt key store")?
+            }
+        })
+    }
+}
+
+/// The secure channel key of a PD, kept in a file in the runtime directory
+/// (or in the keyring). The key in the store is the one in use: it is
+/// replaced when the key is rotated and survives restarts. The key given in
+/// the config (`scbk`) only seeds the store when it does not exist yet, after
+/// which the config need not have it; delete the store to go back to the key
+/// in the config.
+///
+/// Parsing a config does not touch the store (which can be slow to decrypt);
+/// devices [`KeyStore::open`] it before they use the key.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct KeyStore {
     store: PathBuf,
-    pub key: [u8; 16],
+    protection: KeyProtection,
+    /// Key given in the config, if any
+    seed: Option<[u8; 16]>,
+    /// Key in use, once the store is open
+    key: Option<[u8; 16]>,
 }
 
 impl KeyStore {
-    pub fn create(store: PathBuf, key: &str) -> Result<Self> {
-        let key = KeyStore::str_to_key(key)?;
-        std::fs::write(&store, key)
-            .expect("Unable to write to keystore");
-        Ok(Self { store, key })
+    pub fn new(store: PathBuf, seed: Option<&str>, protection: KeyProtection) -> Result<Self> {
+        let seed = seed.map(KeyStore::str_to_key).transpose()?;
+        Ok(Self {
+            store,
+            protection,
+            seed,
+            key: None,
+        })
     }
 
-    pub fn _new(store: PathBuf) -> Result<Self> {
-        let key = KeyStore::_random_key();
-        let key_str = KeyStore::key_to_str(&key);
-        std::fs::write(&store, key_str)
-            .expect("Unable to write to keystore");
-        Ok(Self { store, key })
+    pub fn _new(store: PathBuf, protection: KeyProtection) -> Result<Self> {
+        let mut key_store = Self {
+            store,
+            protection,
+            seed: None,
+            key: None,
+        };
+        key_store.store(KeyStore::_random_key())?;
+        Ok(key_store)
     }
 
     pub fn _random_key() -> [u8; 16] {
@@ -58,7 +160,11 @@ impl KeyStore {
             .collect()
     }
 
-    fn str_to_key(s: &str) -> Result<[u8; 16]> {
+    pub fn str_to_key(s: &str) -> Result<[u8; 16]> {
+        let s = s.trim();
+        if s.len() != 32 || !s.is_ascii() {
+            bail!("Invalid key; expected 32 hex digits");
+        }
         let key = KeyStore::decode_hex(s)?;
         Ok(vec_to_array::<u8, 16>(key))
     }
@@ -71,20 +177,123 @@ impl KeyStore {
         s
     }
 
-    pub fn load(&self) -> Result<[u8; 16]> {
-        let s = std::fs::read_to_string(&self.store)
-            .context(format!("keystore {} not found", self.store.display()))?;
+    /// The keyring entry of this store; the key is filed under the path its
+    /// file would have, which is unique to the device and PD.
+    fn keyring_entry(&self) -> Result<keyring::Entry> {
+        keyring::Entry::new("osdpctl", &self.store.to_string_lossy())
+            .context("Unable to access the keyring")
+    }
+
+    /// The key in the store, if there is one yet.
+    fn load(&self) -> Result<Option<[u8; 16]>> {
+        let s = match &self.protection {
+            KeyProtection::Keyring => match self.keyring_entry()?.get_password() {
+                Ok(s) => s,
+                Err(keyring::Error::NoEntry) => return Ok(None),
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "Unable to read keystore {} from the keyring",
+                        self.store.display()
+                    ))
+                }
+            },
+            protection => {
+                let data = match std::fs::read(&self.store) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => {
+                        return Err(e)
+                            .context(format!("Unable to read keystore {}", self.store.display()))
+                    }
+                };
+                String::from_utf8(protection.open(&data)?)?
+            }
+        };
         KeyStore::str_to_key(&s)
+            .map(Some)
+            .context(format!("Invalid keystore {}", self.store.display()))
+    }
+
+    /// Read the key from the store, creating the store with the key given in
+    /// the config if it does not exist yet.
+    pub fn open(&mut self) -> Result<[u8; 16]> {
+        let key = match self.load()? {
+            Some(key) => key,
+            None => {
+                let key = self.seed.with_context(|| {
+                    format!(
+                        "No keystore {} yet and no scbk in the config to create it with",
+                        self.store.display()
+                    )
+                })?;
+                self.store(key)?;
+                key
+            }
+        };
+        self.key = Some(key);
+        Ok(key)
+    }
+
+    /// The key in use; the store must have been opened.
+    pub fn key(&self) -> Result<[u8; 16]> {
+        self.key
+            .with_context(|| format!("Keystore {} is not open", self.store.display()))
     }
 
     pub fn store(&mut self, key: [u8; 16]) -> Result<()> {
-        std::fs::write(&self.store, KeyStore::key_to_str(&key))
-            .expect("Unable to write to keystore");
-        self.key = key;
+        let s = KeyStore::key_to_str(&key);
+        match &self.protection {
+            KeyProtection::Keyring => self
+                .keyring_entry()?
+                .set_password(&s)
+                .context("Unable to store key in the keyring")?,
+            protection => {
+                let data = protection.seal(s.as_bytes())?;
+                write_atomic(&self.store, &data)
+                    .context(format!("Unable to write keystore {}", self.store.display()))?;
+            }
+        }
+        self.key = Some(key);
         Ok(())
     }
 }
 
+/// Replace the file at `path` with `data` so that a crash leaves either the
+/// old contents or the new ones: write it next to `path`, readable only by
+/// its owner, sync it, rename it over `path` and sync the directory.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write as _, os::unix::fs::OpenOptionsExt};
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    // A stale one may have other permissions, which are kept on open
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// The `key_store` of a device config; see [`KeyProtection`].
+fn key_protection(config: &Ini) -> Result<KeyProtection> {
+    match config.get("default", "key_store") {
+        Some(s) => s.parse(),
+        None => Ok(KeyProtection::Plain),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct PdData {
     pub name: String,
@@ -94,12 +303,96 @@ pub struct PdData {
     flags: OsdpFlag,
 }
 
+/// System log that events are forwarded to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SystemLog {
+    Syslog,
+    Journald,
+}
+
+/// MQTT broker that events are published to, given as
+/// `mqtt://<host>[:<port>][/<topic>]`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Topic under which events are published; `osdp/<device name>` by
+    /// default
+    pub topic: String,
+}
+
+impl MqttConfig {
+    fn parse(url: &str, device: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("mqtt://") else {
+            bail!("MQTT broker '{url}' must be given as mqtt://<host>[:<port>][/<topic>]");
+        };
+        let (address, topic) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .with_context(|| format!("Invalid MQTT broker port '{port}'"))?,
+            ),
+            None => (address, 1883),
+        };
+        if host.is_empty() {
+            bail!("MQTT broker '{url}' has no host");
+        }
+        let topic = match topic.trim_end_matches('/') {
+            "" => format!("osdp/{device}"),
+            topic => topic.to_owned(),
+        };
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            topic,
+        })
+    }
+}
+
+/// Where a CP forwards the events of its PDs to; the `[events]` section of
+/// its config. See `forward.rs`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EventsConfig {
+    pub system_log: Option<SystemLog>,
+    pub mqtt: Option<MqttConfig>,
+    /// HTTP(S) endpoint that events are POSTed to
+    pub webhook: Option<String>,
+}
+
+impl EventsConfig {
+    fn new(config: &Ini, device: &str) -> Result<Self> {
+        let get = |key| config.get("events", key).filter(|v| !v.is_empty());
+        let system_log = match get("system_log").as_deref() {
+            None => None,
+            Some("syslog") => Some(SystemLog::Syslog),
+            Some("journald") => Some(SystemLog::Journald),
+            Some(other) => bail!("Unknown system_log '{other}'; expected syslog or journald"),
+        };
+        let mqtt = get("mqtt")
+            .map(|url| MqttConfig::parse(&url, device))
+            .transpose()?;
+        let webhook = get("webhook");
+        if let Some(url) = &webhook {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("Webhook '{url}' must be an http:// or https:// URL");
+            }
+        }
+        Ok(Self {
+            system_log,
+            mqtt,
+            webhook,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct CpConfig {
     pub runtime_dir: PathBuf,
     pub name: String,
     pd_data: Vec<PdData>,
     pub log_level: log::LevelFilter,
+    pub events: EventsConfig,
 }
 
 impl CpConfig {
@@ -107,15 +400,20 @@ impl CpConfig {
         let num_pd = config.getuint("default", "num_pd").unwrap().unwrap() as usize;
         let name = config.get("default", "name").unwrap();
         let runtime_dir = runtime_dir.to_owned();
+        let protection = key_protection(config)?;
         let mut pd_data = Vec::new();
         for pd in 0..num_pd {
             let section = format!("pd-{pd}");
-            let key = &config.get(&section, "scbk").unwrap();
+            let key_store = KeyStore::new(
+                runtime_dir.join(format!("pd-{}-key.store", pd)),
+                config.get(&section, "scbk").as_deref(),
+                protection.clone(),
+            )?;
             pd_data.push(PdData {
                 name: config.get(&section, "name").unwrap(),
                 channel: config.get(&section, "channel").unwrap(),
                 address: config.getuint(&section, "address").unwrap().unwrap() as i32,
-                key_store: KeyStore::create(runtime_dir.join(format!("pd-{}-key.store", pd)), key)?,
+                key_store,
                 flags: OsdpFlag::empty(),
             });
         }
@@ -127,18 +425,20 @@ impl CpConfig {
             "TRACE" => log::LevelFilter::Trace,
             _ => log::LevelFilter::Off,
         };
+        let events = EventsConfig::new(config, &name)?;
         Ok(Self {
             name,
             log_level,
             pd_data,
             runtime_dir,
+            events,
         })
     }
 
     pub fn pd_info(&self) -> Result<ControlPanelBuilder> {
         let mut runtime_dir = self.runtime_dir.clone();
         runtime_dir.pop();
-        let mut cp = ControlPanelBuilder::new();
+        let mut channels: Vec<Box<dyn Channel>> = Vec::new();
         for d in self.pd_data.iter() {
             let parts: Vec<&str> = d.channel.split("::").collect();
             if parts[0] != "unix" {
@@ -146,16 +446,59 @@ impl CpConfig {
             }
             let path = runtime_dir.join(format!("{}/{}.sock", d.name, parts[1]).as_str());
             let channel = UnixChannel::connect(&path).context("Unable to connect to PD channel")?;
+            channels.push(Box::new(channel));
+        }
+        self.pd_info_with_channels(channels)
+    }
+
+    /// Like [`CpConfig::pd_info`] but reuses already connected `channels`
+    /// (one per PD, in config order) instead of connecting to the PDs again.
+    pub fn pd_info_with_channels(
+        &self,
+        channels: Vec<Box<dyn Channel>>,
+    ) -> Result<ControlPanelBuilder> {
+        let mut cp = ControlPanelBuilder::new();
+        for (d, channel) in self.pd_data.iter().zip(channels) {
             let pd_info = PdInfoBuilder::new()
                 .name(&self.name)?
                 .address(d.address)?
                 .baud_rate(115200)?
                 .flag(d.flags)
-                .secure_channel_key(d.key_store.key);
-            cp = cp.add_channel(Box::new(channel), vec![pd_info]);
+                .secure_channel_key(d.key_store.key()?);
+            cp = cp.add_channel(channel, vec![pd_info]);
         }
         Ok(cp)
     }
+
+    /// Names of the PDs, in config order
+    pub fn pd_names(&self) -> impl Iterator<Item = &str> {
+        self.pd_data.iter().map(|d| d.name.as_str())
+    }
+
+    /// Store `key` as the key of PD `pd`, after the PD accepted it.
+    pub fn set_key(&mut self, pd: usize, key: [u8; 16]) -> Result<()> {
+        let d = self.pd_data.get_mut(pd).context("No such PD")?;
+        d.key_store.store(key)
+    }
+
+    /// Open the key stores of the PDs; see [`KeyStore::open`].
+    pub fn open_key_stores(&mut self) -> Result<()> {
+        for d in self.pd_data.iter_mut() {
+            d.key_store.open()?;
+        }
+        Ok(())
+    }
+
+    /// Whether `other` talks to the same PDs over the same channels as this
+    /// config, i.e. whether it can be applied without reconnecting.
+    pub fn same_channels(&self, other: &CpConfig) -> bool {
+        self.pd_data.len() == other.pd_data.len()
+            && self
+                .pd_data
+                .iter()
+                .zip(other.pd_data.iter())
+                .all(|(a, b)| a.name == b.name && a.channel == b.channel)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -173,27 +516,28 @@ pub struct PdConfig {
 
 impl PdConfig {
     pub fn new(config: &Ini, runtime_dir: &Path) -> Result<Self> {
-        let vendor_code = config.getuint("pd_id", "vendor_code").unwrap().unwrap() as u32;
-        let serial_number = config.getuint("pd_id", "serial_number").unwrap().unwrap() as u32;
-        let firmware_version = config
-            .getuint("pd_id", "firmware_version")
-            .unwrap()
-            .unwrap() as u32;
-        let pd_id = PdId {
+        let mut pd_id = PdId {
             version: config.getuint("pd_id", "version").unwrap().unwrap() as i32,
             model: config.getuint("pd_id", "model").unwrap().unwrap() as i32,
-            vendor_code: (
-                vendor_code as u8,
-                (vendor_code >> 8) as u8,
-                (vendor_code >> 16) as u8,
-            ),
-            serial_number: serial_number.to_le_bytes(),
-            firmware_version: (
-                firmware_version as u8,
-                (firmware_version >> 8) as u8,
-                (firmware_version >> 16) as u8,
-            ),
+            ..Default::default()
         };
+        pd_id.set_vendor_code_u32(config.getuint("pd_id", "vendor_code").unwrap().unwrap() as u32);
+        pd_id.set_serial_number_u32(
+            config.getuint("pd_id", "serial_number").unwrap().unwrap() as u32
+        );
+        // Either "major.minor.build" or a 24-bit number
+        let firmware_version = config
+            .get("pd_id", "firmware_version")
+            .context("pd_id.firmware_version is required")?;
+        if firmware_version.contains('.') {
+            pd_id.set_firmware_version_semver(&firmware_version)?;
+        } else {
+            pd_id.set_firmware_version_u32(
+                firmware_version
+                    .parse()
+                    .context("Invalid pd_id.firmware_version")?,
+            );
+        }
         let mut flags = OsdpFlag::empty();
         if let Some(val) = config.get("default", "flags") {
             let fl: Vec<&str> = val.split('|').collect();
@@ -217,10 +561,13 @@ impl PdConfig {
             "TRACE" => log::LevelFilter::Trace,
             _ => log::LevelFilter::Off,
         };
-        let key = &config.get("default", "scbk").unwrap();
         let name = config.get("default", "name").unwrap();
         let runtime_dir = runtime_dir.to_owned();
-        let key_store = KeyStore::create(runtime_dir.join("key.store"), key)?;
+        let key_store = KeyStore::new(
+            runtime_dir.join("key.store"),
+            config.get("default", "scbk").as_deref(),
+            key_protection(config)?,
+        )?;
         Ok(Self {
             name,
             channel: config.get("default", "channel").unwrap(),
@@ -240,7 +587,12 @@ impl PdConfig {
             bail!("Only unix channel is supported for now")
         }
         let path = self.runtime_dir.join(format!("{}.sock", parts[1]).as_str());
-        let channel = UnixChannel::new(&path)?;
+        let channel = UnixChannel::listen(&path)?;
+        Ok((Box::new(channel), self.pd_info_builder()?))
+    }
+
+    /// The PD info of this config, without setting up its channel.
+    pub fn pd_info_builder(&self) -> Result<PdInfoBuilder> {
         let pd_info = PdInfoBuilder::new()
             .name(&self.name)?
             .address(self.address)?
@@ -248,8 +600,27 @@ impl PdConfig {
             .flag(self.flags)
             .capabilities(&self.pd_cap)
             .id(&self.pd_id)
-            .secure_channel_key(self.key_store.key);
-        Ok((Box::new(channel), pd_info))
+            .secure_channel_key(self.key_store.key()?);
+        Ok(pd_info)
+    }
+
+    pub fn capabilities(&self) -> &[PdCapability] {
+        &self.pd_cap
+    }
+
+    /// Whether `other` talks over the same channel as this config, i.e.
+    /// whether it can be applied without re-creating the channel.
+    pub fn same_channel(&self, other: &PdConfig) -> bool {
+        self.channel == other.channel
+    }
+
+    /// Whether `other` differs from this config in more than capabilities
+    /// (which can be updated on a running PD) and log level.
+    pub fn needs_rebuild(&self, other: &PdConfig) -> bool {
+        let mut other = other.clone();
+        other.pd_cap = self.pd_cap.clone();
+        other.log_level = self.log_level;
+        *self != other
     }
 }
 
@@ -268,16 +639,11 @@ fn read_pid_from_file(file: PathBuf) -> Result<i32> {
 
 impl DeviceConfig {
     pub fn get_pid(&self) -> Result<i32> {
-        match self {
-            DeviceConfig::CpConfig(dev) => {
-                let pid_file = dev.runtime_dir.join(format!("dev-{}.pid", dev.name));
-                read_pid_from_file(pid_file)
-            }
-            DeviceConfig::PdConfig(dev) => {
-                let pid_file = dev.runtime_dir.join(format!("dev-{}.pid", dev.name));
-                read_pid_from_file(pid_file)
-            }
-        }
+        read_pid_from_file(self.pid_file())
+    }
+
+    pub fn pid_file(&self) -> PathBuf {
+        self.runtime_dir().join(format!("dev-{}.pid", self.name()))
     }
 }
 
@@ -307,4 +673,214 @@ impl DeviceConfig {
             DeviceConfig::PdConfig(c) => &c.name,
         }
     }
+
+    pub fn runtime_dir(&self) -> &Path {
+        match self {
+            DeviceConfig::CpConfig(c) => &c.runtime_dir,
+            DeviceConfig::PdConfig(c) => &c.runtime_dir,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_protection, write_atomic, DeviceConfig, KeyProtection, KeyStore};
+    use configparser::ini::Ini;
+    use std::path::{Path, PathBuf};
+
+    /// Write `config` to a scratch directory; returns its path and a runtime
+    /// directory next to it.
+    fn write_config(test: &str, config: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("osdpctl-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("device.cfg");
+        std::fs::write(&path, config).unwrap();
+        (path, dir.join("run"))
+    }
+
+    fn without_scbk(config: &str) -> String {
+        config
+            .lines()
+            .filter(|line| !line.starts_with("scbk"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn parse(path: &Path, runtime_dir: &Path) -> DeviceConfig {
+        std::fs::create_dir_all(runtime_dir).unwrap();
+        DeviceConfig::new(path, runtime_dir).unwrap()
+    }
+
+    #[test]
+    fn test_pd_key_store() {
+        let config = include_str!("../config/pd-0.cfg");
+        let (path, runtime_dir) = write_config("pd-key-store", config);
+        let DeviceConfig::PdConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a PD config");
+        };
+        assert!(dev.key_store.key().is_err());
+        let seed = dev.key_store.open().unwrap();
+        assert_eq!(seed[0], 0x73);
+
+        // A key set by the CP is kept over the one in the config
+        let rotated = [0x5a; 16];
+        dev.key_store.store(rotated).unwrap();
+        let DeviceConfig::PdConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a PD config");
+        };
+        assert_eq!(dev.key_store.open().unwrap(), rotated);
+
+        // and the config no longer needs one
+        std::fs::write(&path, without_scbk(config)).unwrap();
+        let DeviceConfig::PdConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a PD config");
+        };
+        assert_eq!(dev.key_store.open().unwrap(), rotated);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (path, _) = write_config("write-atomic", "");
+        let store = path.with_file_name("pd.key");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // Replaces what was there, including its permissions
+        std::fs::write(&store, "old key").unwrap();
+        std::fs::set_permissions(&store, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_atomic(&store, b"new key").unwrap();
+        assert_eq!(std::fs::read(&store).unwrap(), b"new key");
+        assert_eq!(mode(&store), 0o600);
+
+        // A temporary file left behind by a crash does not get in the way
+        let mut tmp = store.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, "partial").unwrap();
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_atomic(&store, b"newer key").unwrap();
+        assert_eq!(std::fs::read(&store).unwrap(), b"newer key");
+        assert_eq!(mode(&store), 0o600);
+        assert!(!Path::new(&tmp).exists());
+    }
+
+    #[test]
+    fn test_cp_key_store() {
+        let config = include_str!("../config/cp-single-pd.cfg");
+        let (path, runtime_dir) = write_config("cp-key-store", config);
+        let DeviceConfig::CpConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a CP config");
+        };
+        dev.open_key_stores().unwrap();
+        let rotated = [0xa5; 16];
+        dev.set_key(0, rotated).unwrap();
+
+        std::fs::write(&path, without_scbk(config)).unwrap();
+        let DeviceConfig::CpConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a CP config");
+        };
+        dev.open_key_stores().unwrap();
+        assert_eq!(dev.pd_data[0].key_store.key().unwrap(), rotated);
+
+        // Without a key store or a key in the config, there is no key to use
+        let (path, runtime_dir) = write_config("cp-no-key", &without_scbk(config));
+        let DeviceConfig::CpConfig(mut dev) = parse(&path, &runtime_dir) else {
+            panic!("not a CP config");
+        };
+        assert!(dev.open_key_stores().is_err());
+    }
+
+    #[test]
+    fn test_init_configs() {
+        let scbk = "0102030405060708090a0b0c0d0e0f10";
+        let (path, runtime_dir) = write_config(
+            "init-cp",
+            &crate::init::cp_config("cp", 2, 101, "unix::conn", Some(scbk)),
+        );
+        let DeviceConfig::CpConfig(mut cp) = parse(&path, &runtime_dir) else {
+            panic!("not a CP config");
+        };
+        cp.open_key_stores().unwrap();
+        let (path, runtime_dir) = write_config(
+            "init-pd",
+            &crate::init::pd_config("pd0", 101, "unix::conn", Some(scbk)),
+        );
+        let DeviceConfig::PdConfig(mut pd) = parse(&path, &runtime_dir) else {
+            panic!("not a PD config");
+        };
+        let key = pd.key_store.open().unwrap();
+        assert_eq!(key, KeyStore::str_to_key(scbk).unwrap());
+        assert!(cp
+            .pd_data
+            .iter()
+            .all(|pd| pd.key_store.key().unwrap() == key));
+        assert_eq!(cp.pd_data[1].address, 102);
+        assert_eq!(pd.address, cp.pd_data[0].address);
+        assert_eq!(pd.channel, cp.pd_data[0].channel);
+
+        // Without a key, each PD gets one of its own
+        let (path, runtime_dir) = write_config(
+            "init-cp-random",
+            &crate::init::cp_config("cp", 2, 101, "unix::conn", None),
+        );
+        let DeviceConfig::CpConfig(mut cp) = parse(&path, &runtime_dir) else {
+            panic!("not a CP config");
+        };
+        cp.open_key_stores().unwrap();
+        let keys: Vec<_> = cp
+            .pd_data
+            .iter()
+            .map(|pd| pd.key_store.key().unwrap())
+            .collect();
+        assert_ne!(keys[0], keys[1]);
+    }
+
+    #[test]
+    fn test_key_protection() {
+        let parse = |s: &str| s.parse::<KeyProtection>();
+        assert_eq!(parse("plain").unwrap(), KeyProtection::Plain);
+        assert_eq!(parse("keyring").unwrap(), KeyProtection::Keyring);
+        assert_eq!(
+            parse("passphrase:OSDP_PASS").unwrap(),
+            KeyProtection::Passphrase("OSDP_PASS".into())
+        );
+        assert_eq!(
+            parse("keyfile:/etc/osdp/age.key").unwrap(),
+            KeyProtection::KeyFile("/etc/osdp/age.key".into())
+        );
+        assert!(parse("passphrase:").is_err());
+        assert!(parse("vault").is_err());
+
+        let mut config = Ini::new_cs();
+        config.read("key_store = keyring".into()).unwrap();
+        assert_eq!(key_protection(&config).unwrap(), KeyProtection::Keyring);
+        let config = Ini::new_cs();
+        assert_eq!(key_protection(&config).unwrap(), KeyProtection::Plain);
+    }
+
+    #[test]
+    fn test_key_protection_round_trip() {
+        let (path, _) = write_config("key-protection", "");
+        std::env::set_var("OSDPCTL_TEST_PASSPHRASE", "correct horse battery staple");
+        let identity = age::x25519::Identity::generate();
+        let key_file = path.with_file_name("age.key");
+        std::fs::write(
+            &key_file,
+            age::secrecy::ExposeSecret::expose_secret(&identity.to_string()),
+        )
+        .unwrap();
+        let key = b"737dcd99395a0d92ea3d56cb67549df4";
+        for protection in [
+            KeyProtection::Plain,
+            KeyProtection::Passphrase("OSDPCTL_TEST_PASSPHRASE".into()),
+            KeyProtection::KeyFile(key_file),
+        ] {
+            let sealed = protection.seal(key).unwrap();
+            assert_eq!(protection.open(&sealed).unwrap(), key);
+            if protection != KeyProtection::Plain {
+                assert_ne!(sealed, key);
+            }
+        }
+    }
 }
@@ -3,53 +3,159 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{thread, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
-use crate::config::CpConfig;
-use anyhow::Context;
-use libosdp::OsdpEvent;
-use std::io::Write;
+use crate::config::{CpConfig, DeviceConfig};
+use crate::forward::Forwarder;
+use crate::{playbook, reload, rotate};
+use anyhow::{bail, Context};
+use libosdp::{EventContext, OsdpEvent};
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
-fn setup(dev: &CpConfig, daemonize: bool) -> Result<()> {
-    if dev.runtime_dir.exists() {
-        std::fs::remove_dir_all(&dev.runtime_dir)?;
-    }
+pub fn setup(dev: &CpConfig, daemonize: bool) -> Result<()> {
     std::fs::create_dir_all(&dev.runtime_dir)?;
+    // Keep the key stores; only what the last run left behind goes
+    crate::service::cleanup_dir(&dev.runtime_dir)?;
     if daemonize {
         crate::daemonize::daemonize(&dev.runtime_dir, &dev.name)?;
     } else {
-        let pid_file = dev.runtime_dir.join(format!("dev-{}.pid", dev.name));
-        let mut pid_file = std::fs::File::create(pid_file)?;
-        write!(pid_file, "{}", std::process::id())?;
+        crate::service::write_pid_file(&dev.runtime_dir.join(format!("dev-{}.pid", dev.name)))?;
     }
     Ok(())
 }
 
 pub fn main(dev: CpConfig, daemonize: bool) -> Result<()> {
     setup(&dev, daemonize)?;
-    let cp = dev.pd_info().context("Failed to create PD info list")?;
-    let mut cp = cp.build()?;
-    cp.set_event_callback(|pd, event| {
-        match event {
-            OsdpEvent::CardRead(e) => {
-                log::info!("Event: PD-{pd} {:?}", e);
-            }
-            OsdpEvent::KeyPress(e) => {
-                log::info!("Event: PD-{pd} {:?}", e);
-            }
-            OsdpEvent::MfgReply(e) => {
-                log::info!("Event: PD-{pd} {:?}", e);
-            }
-            OsdpEvent::Status(e) => {
-                log::info!("Event: PD-{pd} {:?}", e);
+    run(dev)
+}
+
+/// Connect to the PDs of `dev`; they may still be starting up when they run
+/// in the same process (see `osdpctl start`) so give them some time.
+fn connect(dev: &CpConfig) -> Result<libosdp::ControlPanelBuilder> {
+    let mut attempts = 50;
+    loop {
+        match dev.pd_info() {
+            Ok(cp) => return Ok(cp),
+            Err(_) if attempts > 0 => {
+                attempts -= 1;
+                thread::sleep(Duration::from_millis(100));
             }
+            Err(e) => return Err(e).context("Failed to create PD info list"),
+        }
+    }
+}
+
+fn on_event(pd: &EventContext<'_>, event: OsdpEvent) -> i32 {
+    let (name, address) = (pd.name, pd.address);
+    match event {
+        OsdpEvent::CardRead(e) => {
+            log::info!("Event: {name} (address {address}) {:?}", e);
+        }
+        OsdpEvent::KeyPress(e) => {
+            log::info!("Event: {name} (address {address}) {:?}", e);
+        }
+        OsdpEvent::MfgReply(e) => {
+            log::info!("Event: {name} (address {address}) {:?}", e);
+        }
+        OsdpEvent::Status(e) => {
+            log::info!("Event: {name} (address {address}) {:?}", e);
         }
-        0
-    });
+    }
+    0
+}
+
+/// Event callback that logs events and forwards them with `forwarder`
+fn event_callback(
+    forwarder: &Arc<Mutex<Forwarder>>,
+) -> impl FnMut(&EventContext<'_>, OsdpEvent) -> i32 + Send + 'static {
+    let forwarder = forwarder.clone();
+    move |pd, event| {
+        forwarder.lock().unwrap().event(pd.pd, &event);
+        on_event(pd, event)
+    }
+}
+
+/// Read the config of `dev` again; returns the new config if it changed and
+/// can be applied to the running CP.
+fn reloaded_config(dev: &CpConfig) -> Result<Option<CpConfig>> {
+    let mut new = match reload::read_config(&dev.name)? {
+        DeviceConfig::CpConfig(new) => new,
+        DeviceConfig::PdConfig(_) => bail!("Device is no longer a CP; restart it instead"),
+    };
+    // The keys in use (rotated ones, too) are read from the key stores
+    new.open_key_stores()?;
+    if new == *dev {
+        return Ok(None);
+    }
+    if !new.same_channels(dev) {
+        bail!("PD channels changed; restart the device to apply");
+    }
+    Ok(Some(new))
+}
+
+pub fn run(mut dev: CpConfig) -> Result<()> {
+    let mut watcher = reload::Watcher::new();
+    dev.open_key_stores()?;
+    let cp = connect(&dev)?;
+    let mut cp = cp.build()?;
+    let forwarder = Arc::new(Mutex::new(Forwarder::new(&dev)?));
+    let mut _event_callback = cp.set_event_context_callback(event_callback(&forwarder));
+    let rotate_requests = rotate::Listener::bind(&dev.runtime_dir)?;
+    let playbook_requests = playbook::Listener::bind(&dev.runtime_dir)?;
     loop {
+        if watcher.requested() {
+            match reloaded_config(&dev) {
+                Ok(Some(new)) => {
+                    // Set the CP up again over the PD connections it already
+                    // has so that the changes apply without reconnecting.
+                    let channels = cp.teardown();
+                    match new
+                        .pd_info_with_channels(channels)
+                        .and_then(|cp| Ok(cp.build()?))
+                    {
+                        Ok(new_cp) => {
+                            cp = new_cp;
+                            match Forwarder::new(&new) {
+                                Ok(new) => *forwarder.lock().unwrap() = new,
+                                Err(e) => log::error!("Failed to set up event forwarding: {e:#}"),
+                            }
+                            dev = new;
+                            log::info!("Config reloaded");
+                        }
+                        Err(e) => {
+                            // The channels went with the failed attempt;
+                            // connect to the PDs again, as before the reload.
+                            log::error!(
+                                "Failed to apply reloaded config, keeping the old one: {e:#}"
+                            );
+                            cp = connect(&dev)?
+                                .build()
+                                .context("Failed to set up CP again after reload")?;
+                        }
+                    }
+                    _event_callback = cp.set_event_context_callback(event_callback(&forwarder));
+                }
+                Ok(None) => log::info!("Config unchanged"),
+                Err(e) => log::error!("Failed to reload config: {e:#}"),
+            }
+        }
+        while let Some((pds, addr)) = rotate_requests.recv() {
+            let results = rotate::rotate(&mut cp, &mut dev, pds);
+            rotate_requests.reply(&addr, &results);
+        }
+        while let Some((steps, addr)) = playbook_requests.recv() {
+            let results = playbook::run(&mut cp, &steps, event_callback(&forwarder));
+            _event_callback = cp.set_event_context_callback(event_callback(&forwarder));
+            playbook_requests.reply(&addr, &results);
+        }
         cp.refresh();
-        thread::sleep(Duration::from_millis(50));
+        forwarder.lock().unwrap().online(cp.online_mask());
+        let wait = cp.time_until_next_work();
+        cp.wait_readable(wait);
     }
 }
@@ -0,0 +1,309 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl decode` turns raw OSDP traffic, a pcap capture (as written by
+//! LibOSDP's `packet_trace` or [`libosdp::ControlPanel::start_packet_capture`])
+//! or bytes given as a hex string, into a readable trace: each frame with its
+//! fields labeled and its payload decoded where that is possible.
+
+use anyhow::{bail, Context};
+use libosdp::wire::{PacketDecoder, PacketExplanation};
+use serde_json::{json, Value};
+use std::{fmt::Write, path::Path, time::Duration};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// Bytes that were captured together, and when (for pcap records)
+#[derive(Debug)]
+pub struct Record {
+    pub time: Option<Duration>,
+    pub bytes: Vec<u8>,
+}
+
+/// A frame found in the input, explained, with the time of the record it was
+/// found in
+pub struct Frame {
+    pub time: Option<Duration>,
+    pub explanation: PacketExplanation,
+}
+
+/// Read `input`, a pcap file if there is a file by that name and a hex string
+/// (whitespace, `:` and `-` separators and a `0x` prefix are allowed)
+/// otherwise.
+pub fn read_input(input: &str) -> Result<Vec<Record>> {
+    let path = Path::new(input);
+    if path.is_file() {
+        let data = std::fs::read(path).with_context(|| format!("Unable to read {input}"))?;
+        return read_pcap(&data).with_context(|| format!("{input} is not a pcap file"));
+    }
+    let hex: String = input
+        .trim()
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':' && *c != '-')
+        .collect();
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("'{input}' is neither a file nor a hex string");
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(vec![Record { time: None, bytes }])
+}
+
+fn read_pcap(data: &[u8]) -> Result<Vec<Record>> {
+    let Some(magic) = data.get(..4) else {
+        bail!("Too short");
+    };
+    let magic: [u8; 4] = magic.try_into()?;
+    // Either byte order, with microsecond or nanosecond timestamps
+    let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+        (0xa1b2c3d4, _) => (false, false),
+        (0xa1b23c4d, _) => (false, true),
+        (_, 0xa1b2c3d4) => (true, false),
+        (_, 0xa1b23c4d) => (true, true),
+        _ => bail!("Unknown magic number"),
+    };
+    let u32_at = |offset: usize| -> Result<u32> {
+        let bytes: [u8; 4] = data
+            .get(offset..offset + 4)
+            .context("Truncated")?
+            .try_into()?;
+        Ok(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let mut records = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let secs = u32_at(offset)? as u64;
+        let frac = u32_at(offset + 4)?;
+        let len = u32_at(offset + 8)? as usize;
+        let bytes = data
+            .get(offset + 16..offset + 16 + len)
+            .context("Truncated record")?;
+        let time = if nanos {
+            Duration::new(secs, frac)
+        } else {
+            Duration::new(secs, frac * 1000)
+        };
+        records.push(Record {
+            time: Some(time),
+            bytes: bytes.to_vec(),
+        });
+        offset += 16 + len;
+    }
+    Ok(records)
+}
+
+/// The frames in `records`, in order, and the number of bytes that were not
+/// part of any
+pub fn frames(records: &[Record]) -> (Vec<Frame>, usize) {
+    let mut decoder = PacketDecoder::new();
+    let mut frames = Vec::new();
+    for record in records {
+        decoder.push(&record.bytes);
+        while let Some(packet) = decoder.next_packet() {
+            frames.push(Frame {
+                time: record.time,
+                explanation: packet.into(),
+            });
+        }
+    }
+    (frames, decoder.discarded())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Print `frames` for people to read; times are relative to the first frame
+pub fn print(frames: &[Frame], discarded: usize) {
+    let start = frames.iter().find_map(|f| f.time);
+    for (i, frame) in frames.iter().enumerate() {
+        let time = match (frame.time, start) {
+            (Some(time), Some(start)) => {
+                format!("  +{:.6}s", time.saturating_sub(start).as_secs_f64())
+            }
+            _ => String::new(),
+        };
+        println!("#{}{time}  {}", i + 1, frame.explanation);
+    }
+    if frames.is_empty() {
+        println!("No OSDP frames found");
+    }
+    if discarded > 0 {
+        println!("{discarded} bytes were not part of any frame");
+    }
+}
+
+pub fn to_json(frames: &[Frame], discarded: usize) -> Value {
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|frame| {
+            let explanation = &frame.explanation;
+            let fields: serde_json::Map<_, _> = explanation
+                .fields
+                .iter()
+                .map(|f| (f.label.replace(' ', "_"), Value::from(f.value.clone())))
+                .collect();
+            json!({
+                "time": frame.time.map(|t| t.as_secs_f64()),
+                "direction": explanation.direction(),
+                "name": explanation.packet.name(),
+                "bytes": hex(&explanation.packet.to_bytes()),
+                "fields": fields,
+            })
+        })
+        .collect();
+    json!({ "frames": frames, "discarded": discarded })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frames, read_input, read_pcap, to_json};
+
+    const POLL: [u8; 9] = [0xFF, 0x53, 0x65, 0x08, 0x00, 0x05, 0x60, 0x51, 0xA3];
+    const ACK: [u8; 7] = [0x53, 0xE5, 0x07, 0x00, 0x01, 0x40, 0x80];
+
+    /// A little endian, microsecond pcap file with a record per entry of
+    /// `records` (seconds, microseconds, bytes)
+    fn pcap(records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xa1b2c3d4_u32.to_le_bytes());
+        data.extend_from_slice(&[2, 0, 4, 0]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&65535_u32.to_le_bytes());
+        data.extend_from_slice(&147_u32.to_le_bytes());
+        for (secs, usecs, bytes) in records {
+            data.extend_from_slice(&secs.to_le_bytes());
+            data.extend_from_slice(&usecs.to_le_bytes());
+            data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(bytes);
+        }
+        data
+    }
+
+    #[test]
+    fn test_pcap_records() {
+        let records = read_pcap(&pcap(&[(1, 500, &POLL), (2, 0, &ACK)])).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].time.unwrap().as_micros(), 1_000_500);
+        assert_eq!(records[0].bytes, POLL);
+        assert_eq!(records[1].time.unwrap().as_secs(), 2);
+        assert_eq!(records[1].bytes, ACK);
+
+        let (frames, discarded) = frames(&records);
+        assert_eq!(discarded, 0);
+        let names: Vec<_> = frames.iter().map(|f| f.explanation.packet.name()).collect();
+        assert_eq!(names, ["POLL", "ACK"]);
+    }
+
+    #[test]
+    fn test_pcap_big_endian_nanos() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xa1b23c4d_u32.to_be_bytes());
+        data.extend_from_slice(&[0; 20]);
+        data.extend_from_slice(&3_u32.to_be_bytes());
+        data.extend_from_slice(&250_u32.to_be_bytes());
+        data.extend_from_slice(&(ACK.len() as u32).to_be_bytes());
+        data.extend_from_slice(&(ACK.len() as u32).to_be_bytes());
+        data.extend_from_slice(&ACK);
+        let records = read_pcap(&data).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].time.unwrap().as_nanos(), 3_000_000_250);
+        assert_eq!(records[0].bytes, ACK);
+    }
+
+    #[test]
+    fn test_pcap_truncated() {
+        assert_eq!(
+            read_pcap(&[0xd4, 0xc3]).unwrap_err().to_string(),
+            "Too short"
+        );
+
+        // The last record claims more bytes than there are
+        let mut data = pcap(&[(1, 0, &POLL)]);
+        data.truncate(data.len() - 2);
+        assert_eq!(
+            read_pcap(&data).unwrap_err().to_string(),
+            "Truncated record"
+        );
+
+        // The last record's header is cut short
+        let mut data = pcap(&[(1, 0, &POLL)]);
+        data.extend_from_slice(&[0; 6]);
+        assert_eq!(read_pcap(&data).unwrap_err().to_string(), "Truncated");
+
+        // A header and no records is an empty capture
+        assert!(read_pcap(&pcap(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pcap_bad_magic() {
+        let mut data = pcap(&[(1, 0, &POLL)]);
+        data[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(
+            read_pcap(&data).unwrap_err().to_string(),
+            "Unknown magic number"
+        );
+    }
+
+    #[test]
+    fn test_pcap_file() {
+        let path = std::env::temp_dir().join(format!("osdpctl-decode-{}.pcap", std::process::id()));
+        std::fs::write(&path, pcap(&[(1, 0, &POLL)])).unwrap();
+        let records = read_input(path.to_str().unwrap()).unwrap();
+        assert_eq!(records[0].bytes, POLL);
+
+        std::fs::write(&path, b"not a capture").unwrap();
+        let err = read_input(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().ends_with("is not a pcap file"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hex_input() {
+        let records = read_input("0xff:53:65-08 00 05 60 51 a3").unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].time.is_none());
+        assert_eq!(records[0].bytes, POLL);
+
+        assert!(read_input("5365080").is_err(), "odd number of digits");
+        assert!(read_input("53 6g").is_err(), "not a hex digit");
+    }
+
+    #[test]
+    fn test_frame_split_across_records() {
+        let records = read_pcap(&pcap(&[(1, 0, &POLL[..4]), (1, 10, &POLL[4..])])).unwrap();
+        let (frames, discarded) = frames(&records);
+        assert_eq!(discarded, 0);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].explanation.packet.name(), "POLL");
+        // The frame is stamped with the record it was completed in
+        assert_eq!(frames[0].time.unwrap().as_micros(), 1_000_010);
+    }
+
+    #[test]
+    fn test_discarded_bytes() {
+        let records = read_input("01 02 03 ff 53 65 08 00 05 60 51 a3").unwrap();
+        let (frames, discarded) = frames(&records);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(discarded, 3);
+
+        let json = to_json(&frames, discarded);
+        assert_eq!(json["discarded"], 3);
+        assert_eq!(json["frames"][0]["name"], "POLL");
+        assert_eq!(json["frames"][0]["bytes"], "53650800056051a3");
+        assert!(json["frames"][0]["time"].is_null());
+    }
+}
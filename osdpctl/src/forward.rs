@@ -0,0 +1,624 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A CP device can forward the events it gets from its PDs (and PDs going
+//! offline or coming back online) to other systems, as configured in the
+//! `[events]` section of its config. Each event is turned into a [`Record`]
+//! of named fields that every [`Sink`] presents in its own way; structured
+//! data for syslog and journald, so that SIEMs can pick out the fields
+//! without parsing the message, and JSON objects for MQTT brokers and
+//! webhooks.
+//!
+//! Sinks that talk over the network do so from a thread of their own, so that
+//! a slow broker or endpoint does not hold up the CP; records are dropped
+//! (with a warning) when too many of them are waiting to be sent.
+
+use crate::config::{CpConfig, EventsConfig, MqttConfig, SystemLog};
+use anyhow::{bail, Context};
+use libosdp::{OsdpCardFormats, OsdpEvent, OsdpStatusReportType, PdBitSet};
+use serde_json::{Map, Value};
+use std::{
+    fmt::Write,
+    os::unix::net::UnixDatagram,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Structured data ID of the fields in syslog messages; 32473 is the private
+/// enterprise number reserved for documentation (RFC 5612).
+const SYSLOG_SD_ID: &str = "osdp@32473";
+
+/// Number of records a network sink holds on to while it can't keep up
+const QUEUE_LEN: usize = 256;
+
+/// How long to wait before connecting to a broker again
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Notice,
+    Info,
+}
+
+impl Severity {
+    /// syslog(3) severity level
+    fn level(self) -> u8 {
+        match self {
+            Severity::Warning => 4,
+            Severity::Notice => 5,
+            Severity::Info => 6,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Notice => "notice",
+            Severity::Info => "info",
+        }
+    }
+}
+
+/// An event of a CP device, decoded into named fields
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub severity: Severity,
+    /// Short, human readable description of the event
+    pub message: String,
+    /// Named fields; `device`, `pd`, `pd_name` and `event` come first
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+/// `value` as text, for sinks that only deal with strings
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .fold(String::with_capacity(data.len() * 2), |mut s, b| {
+            write!(s, "{b:02x}").unwrap();
+            s
+        })
+}
+
+impl Record {
+    fn new(device: &str, pd: i32, pd_name: &str, event: &str) -> Self {
+        Self {
+            severity: Severity::Info,
+            message: String::new(),
+            fields: vec![
+                ("device", device.into()),
+                ("pd", pd.into()),
+                ("pd_name", pd_name.into()),
+                ("event", event.into()),
+            ],
+        }
+    }
+
+    fn field(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    fn describe(mut self, severity: Severity, message: String) -> Self {
+        self.severity = severity;
+        self.message = message;
+        self
+    }
+
+    pub fn from_event(device: &str, pd: i32, pd_name: &str, event: &OsdpEvent) -> Self {
+        let who = format!("PD-{pd} ({pd_name})");
+        match event {
+            OsdpEvent::CardRead(e) => {
+                let format = match e.format {
+                    OsdpCardFormats::Unspecified => "raw",
+                    OsdpCardFormats::Wiegand => "wiegand",
+                    OsdpCardFormats::Ascii => "ascii",
+                };
+                Record::new(device, pd, pd_name, "card_read")
+                    .field("reader", e.reader_no)
+                    .field("card_format", format)
+                    .field("card_bits", e.nr_bits)
+                    .field("card_data", hex(&e.data))
+                    .describe(Severity::Info, format!("{who}: card read"))
+            }
+            OsdpEvent::KeyPress(e) => Record::new(device, pd, pd_name, "key_press")
+                .field("reader", e.reader_no)
+                .field("keys", String::from_utf8_lossy(&e.data).into_owned())
+                .describe(Severity::Info, format!("{who}: key press")),
+            OsdpEvent::MfgReply(e) => {
+                let (a, b, c) = e.vendor_code;
+                Record::new(device, pd, pd_name, "mfg_reply")
+                    .field("vendor_code", format!("{a:02x}{b:02x}{c:02x}"))
+                    .field("reply", e.reply)
+                    .field("data", hex(&e.data))
+                    .describe(Severity::Info, format!("{who}: manufacturer reply"))
+            }
+            OsdpEvent::Status(e) if e.report_type() == OsdpStatusReportType::Local => {
+                let tamper = e.mask() & 1 != 0;
+                let power = e.mask() & 2 != 0;
+                let (severity, message) = match (tamper, power) {
+                    (false, false) => (Severity::Notice, "tamper and power OK"),
+                    (true, false) => (Severity::Warning, "tamper"),
+                    (false, true) => (Severity::Warning, "power failure"),
+                    (true, true) => (Severity::Warning, "tamper and power failure"),
+                };
+                Record::new(device, pd, pd_name, "status")
+                    .field("status", "local")
+                    .field("tamper", tamper)
+                    .field("power_failure", power)
+                    .describe(severity, format!("{who}: {message}"))
+            }
+            OsdpEvent::Status(e) => {
+                let status = match e.report_type() {
+                    OsdpStatusReportType::Input => "input",
+                    OsdpStatusReportType::Output => "output",
+                    OsdpStatusReportType::Remote => "remote",
+                    OsdpStatusReportType::Local => "local",
+                };
+                Record::new(device, pd, pd_name, "status")
+                    .field("status", status)
+                    .field("entries", e.nr_entries())
+                    .field("mask", format!("{:#x}", e.mask()))
+                    .describe(Severity::Info, format!("{who}: {status} status changed"))
+            }
+        }
+    }
+
+    pub fn online(device: &str, pd: i32, pd_name: &str, online: bool) -> Self {
+        let who = format!("PD-{pd} ({pd_name})");
+        if online {
+            Record::new(device, pd, pd_name, "online")
+                .describe(Severity::Notice, format!("{who}: online"))
+        } else {
+            Record::new(device, pd, pd_name, "offline")
+                .describe(Severity::Warning, format!("{who}: offline"))
+        }
+    }
+
+    /// The fields of this record as a JSON object, along with its severity,
+    /// message and when it was forwarded (in milliseconds since the epoch).
+    pub fn to_json(&self) -> Value {
+        let mut object: Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        object.insert("severity".into(), self.severity.as_str().into());
+        object.insert("message".into(), self.message.clone().into());
+        object.insert("timestamp_ms".into(), (now.as_millis() as u64).into());
+        Value::Object(object)
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+/// Somewhere events are forwarded to
+pub trait Sink: Send {
+    fn send(&mut self, record: &Record) -> Result<()>;
+}
+
+/// Sends records to journald over its native protocol, as `OSDP_*` fields.
+struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    fn new() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNALD_SOCKET)
+            .with_context(|| format!("Unable to connect to journald at {JOURNALD_SOCKET}"))?;
+        Ok(Self { socket })
+    }
+}
+
+fn journald_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Values with newlines are sent as a little endian length and the
+        // raw value.
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// `record` as a journald native protocol datagram
+fn journald_message(record: &Record) -> Vec<u8> {
+    let mut buf = Vec::new();
+    journald_field(&mut buf, "MESSAGE", &record.message);
+    journald_field(&mut buf, "PRIORITY", &record.severity.level().to_string());
+    journald_field(&mut buf, "SYSLOG_IDENTIFIER", "osdpctl");
+    for (name, value) in &record.fields {
+        let name = format!("OSDP_{}", name.to_ascii_uppercase());
+        journald_field(&mut buf, &name, &text(value));
+    }
+    buf
+}
+
+impl Sink for Journald {
+    fn send(&mut self, record: &Record) -> Result<()> {
+        self.socket.send(&journald_message(record))?;
+        Ok(())
+    }
+}
+
+/// Sends records to the syslog daemon as RFC 5424 messages, with the fields
+/// as structured data.
+struct Syslog {
+    socket: UnixDatagram,
+}
+
+impl Syslog {
+    /// daemon(3) facility
+    const FACILITY: u8 = 3;
+
+    fn new() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SYSLOG_SOCKET)
+            .with_context(|| format!("Unable to connect to syslog at {SYSLOG_SOCKET}"))?;
+        Ok(Self { socket })
+    }
+}
+
+/// `record` as an RFC 5424 message from process `pid`
+fn syslog_message(record: &Record, pid: u32) -> String {
+    let pri = Syslog::FACILITY * 8 + record.severity.level();
+    // Time stamp and host name are left for the syslog daemon to fill in
+    let mut msg = format!("<{pri}>1 - - osdpctl {pid} - [{SYSLOG_SD_ID}");
+    for (name, value) in &record.fields {
+        let value = text(value)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]");
+        write!(msg, " {name}=\"{value}\"").unwrap();
+    }
+    write!(msg, "] {}", record.message).unwrap();
+    msg
+}
+
+impl Sink for Syslog {
+    fn send(&mut self, record: &Record) -> Result<()> {
+        let msg = syslog_message(record, std::process::id());
+        self.socket.send(msg.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Publishes records as JSON to an MQTT broker, on
+/// `<topic>/<pd_name>/<event>`. The connection to the broker is looked after
+/// by a thread of its own; it is closed when the sink is dropped.
+struct Mqtt {
+    client: rumqttc::Client,
+    topic: String,
+}
+
+impl Mqtt {
+    fn new(config: &MqttConfig, client_id: &str) -> Result<Self> {
+        let mut options = rumqttc::MqttOptions::new(client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut connection) = rumqttc::Client::new(options, QUEUE_LEN);
+        let broker = format!("{}:{}", config.host, config.port);
+        thread::Builder::new()
+            .name(format!("mqtt-{client_id}"))
+            .spawn(move || {
+                // Iterating drives the connection (and reconnects after
+                // errors); it ends once the client is dropped.
+                for notification in connection.iter() {
+                    if let Err(e) = notification {
+                        log::warn!("MQTT broker {broker}: {e}");
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            })
+            .context("Failed to start MQTT connection thread")?;
+        Ok(Self {
+            client,
+            topic: config.topic.clone(),
+        })
+    }
+}
+
+impl Sink for Mqtt {
+    fn send(&mut self, record: &Record) -> Result<()> {
+        let pd_name = record.get("pd_name").map(text).unwrap_or_default();
+        let event = record.get("event").map(text).unwrap_or_default();
+        let topic = format!("{}/{pd_name}/{event}", self.topic);
+        self.client
+            .try_publish(
+                topic,
+                rumqttc::QoS::AtLeastOnce,
+                false,
+                record.to_json().to_string(),
+            )
+            .context("Unable to publish to MQTT broker")
+    }
+}
+
+/// POSTs records as JSON to an HTTP endpoint, from a thread of its own.
+struct Webhook {
+    queue: SyncSender<Value>,
+}
+
+impl Webhook {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn new(url: &str, device: &str) -> Result<Self> {
+        let (queue, records) = mpsc::sync_channel::<Value>(QUEUE_LEN);
+        let url = url.to_owned();
+        let agent = ureq::AgentBuilder::new().timeout(Self::TIMEOUT).build();
+        thread::Builder::new()
+            .name(format!("webhook-{device}"))
+            .spawn(move || {
+                for record in records {
+                    let res = agent
+                        .post(&url)
+                        .set("Content-Type", "application/json")
+                        .send_string(&record.to_string());
+                    if let Err(e) = res {
+                        log::warn!("Webhook {url}: {e}");
+                    }
+                }
+            })
+            .context("Failed to start webhook thread")?;
+        Ok(Self { queue })
+    }
+}
+
+impl Sink for Webhook {
+    fn send(&mut self, record: &Record) -> Result<()> {
+        match self.queue.try_send(record.to_json()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!("Webhook is not keeping up; dropped an event"),
+            Err(TrySendError::Disconnected(_)) => bail!("Webhook thread exited"),
+        }
+    }
+}
+
+/// Forwards the events of a CP device to the sinks set up in its config.
+pub struct Forwarder {
+    device: String,
+    pd_names: Vec<String>,
+    sinks: Vec<Box<dyn Sink>>,
+    online: PdBitSet,
+}
+
+impl Forwarder {
+    pub fn new(dev: &CpConfig) -> Result<Self> {
+        let EventsConfig {
+            system_log,
+            mqtt,
+            webhook,
+        } = &dev.events;
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        match system_log {
+            Some(SystemLog::Journald) => sinks.push(Box::new(Journald::new()?)),
+            Some(SystemLog::Syslog) => sinks.push(Box::new(Syslog::new()?)),
+            None => {}
+        }
+        if let Some(mqtt) = mqtt {
+            let client_id = format!("osdpctl-{}", dev.name);
+            sinks.push(Box::new(Mqtt::new(mqtt, &client_id)?));
+        }
+        if let Some(url) = webhook {
+            sinks.push(Box::new(Webhook::new(url, &dev.name)?));
+        }
+        Ok(Self {
+            device: dev.name.clone(),
+            pd_names: dev.pd_names().map(|n| n.to_owned()).collect(),
+            sinks,
+            online: PdBitSet::default(),
+        })
+    }
+
+    fn pd_name(&self, pd: i32) -> &str {
+        self.pd_names.get(pd as usize).map_or("", |n| n.as_str())
+    }
+
+    fn forward(&mut self, record: Record) {
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.send(&record) {
+                log::warn!("Unable to forward event: {e:#}");
+            }
+        }
+    }
+
+    pub fn event(&mut self, pd: i32, event: &OsdpEvent) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let record = Record::from_event(&self.device, pd, self.pd_name(pd), event);
+        self.forward(record);
+    }
+
+    /// Forward the PDs that went offline or came online since the last call,
+    /// given the PDs that are `online` now.
+    pub fn online(&mut self, online: PdBitSet) {
+        if online == self.online || self.sinks.is_empty() {
+            self.online = online;
+            return;
+        }
+        let changes = online
+            .difference(&self.online)
+            .iter()
+            .map(|pd| (pd, true))
+            .chain(self.online.difference(&online).iter().map(|pd| (pd, false)))
+            .collect::<Vec<_>>();
+        for (pd, online) in changes {
+            let record = Record::online(&self.device, pd, self.pd_name(pd), online);
+            self.forward(record);
+        }
+        self.online = online;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{journald_message, syslog_message, Record, Severity};
+    use libosdp::{
+        OsdpEvent, OsdpEventCardRead, OsdpEventKeyPress, OsdpEventMfgReply, OsdpStatusReport,
+    };
+    use serde_json::Value;
+
+    fn field<'a>(record: &'a Record, name: &str) -> &'a Value {
+        record
+            .get(name)
+            .unwrap_or_else(|| panic!("no field {name}"))
+    }
+
+    #[test]
+    fn test_card_read() {
+        let event = OsdpEvent::CardRead(
+            OsdpEventCardRead::new_wiegand(26, vec![0x12, 0x34, 0x56, 0xc0]).unwrap(),
+        );
+        let record = Record::from_event("cp0", 1, "door", &event);
+        assert_eq!(record.severity, Severity::Info);
+        assert_eq!(record.message, "PD-1 (door): card read");
+        let names: Vec<_> = record.fields.iter().map(|(n, _)| *n).collect();
+        assert_eq!(
+            names,
+            [
+                "device",
+                "pd",
+                "pd_name",
+                "event",
+                "reader",
+                "card_format",
+                "card_bits",
+                "card_data"
+            ]
+        );
+        assert_eq!(field(&record, "device"), "cp0");
+        assert_eq!(field(&record, "pd"), 1);
+        assert_eq!(field(&record, "event"), "card_read");
+        assert_eq!(field(&record, "card_format"), "wiegand");
+        assert_eq!(field(&record, "card_bits"), 26);
+        assert_eq!(field(&record, "card_data"), "123456c0");
+    }
+
+    #[test]
+    fn test_key_press_and_mfg_reply() {
+        let event = OsdpEvent::KeyPress(OsdpEventKeyPress::new(b"1234#".to_vec()));
+        let record = Record::from_event("cp0", 0, "lobby", &event);
+        assert_eq!(field(&record, "event"), "key_press");
+        assert_eq!(field(&record, "keys"), "1234#");
+
+        let event = OsdpEvent::MfgReply(OsdpEventMfgReply {
+            vendor_code: (0x0a, 0x0b, 0x0c),
+            reply: 0x42,
+            data: vec![0xde, 0xad],
+        });
+        let record = Record::from_event("cp0", 0, "lobby", &event);
+        assert_eq!(field(&record, "event"), "mfg_reply");
+        assert_eq!(field(&record, "vendor_code"), "0a0b0c");
+        assert_eq!(field(&record, "reply"), 0x42);
+        assert_eq!(field(&record, "data"), "dead");
+    }
+
+    #[test]
+    fn test_status() {
+        let local = |mask| {
+            let event = OsdpEvent::Status(OsdpStatusReport::new_local(mask));
+            Record::from_event("cp0", 2, "gate", &event)
+        };
+        let record = local(0);
+        assert_eq!(record.severity, Severity::Notice);
+        assert_eq!(record.message, "PD-2 (gate): tamper and power OK");
+        let record = local(3);
+        assert_eq!(record.severity, Severity::Warning);
+        assert_eq!(record.message, "PD-2 (gate): tamper and power failure");
+        assert_eq!(field(&record, "tamper"), true);
+        assert_eq!(field(&record, "power_failure"), true);
+
+        let event = OsdpEvent::Status(OsdpStatusReport::new_input(4, 0x5));
+        let record = Record::from_event("cp0", 2, "gate", &event);
+        assert_eq!(record.severity, Severity::Info);
+        assert_eq!(record.message, "PD-2 (gate): input status changed");
+        assert_eq!(field(&record, "status"), "input");
+        assert_eq!(field(&record, "entries"), 4);
+        assert_eq!(field(&record, "mask"), "0x5");
+    }
+
+    #[test]
+    fn test_online() {
+        let record = Record::online("cp0", 3, "dock", false);
+        assert_eq!(record.severity, Severity::Warning);
+        assert_eq!(record.message, "PD-3 (dock): offline");
+        assert_eq!(field(&record, "event"), "offline");
+        let record = Record::online("cp0", 3, "dock", true);
+        assert_eq!(record.severity, Severity::Notice);
+        assert_eq!(field(&record, "event"), "online");
+    }
+
+    #[test]
+    fn test_json() {
+        let json = Record::online("cp0", 3, "dock", true).to_json();
+        assert_eq!(json["device"], "cp0");
+        assert_eq!(json["pd"], 3);
+        assert_eq!(json["severity"], "notice");
+        assert_eq!(json["message"], "PD-3 (dock): online");
+        assert!(json["timestamp_ms"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        let event = OsdpEvent::KeyPress(OsdpEventKeyPress::new(b"1\"]\\".to_vec()));
+        let record = Record::from_event("cp0", 0, "lobby", &event);
+        assert_eq!(
+            syslog_message(&record, 42),
+            "<30>1 - - osdpctl 42 - [osdp@32473 device=\"cp0\" pd=\"0\" pd_name=\"lobby\" \
+             event=\"key_press\" reader=\"0\" keys=\"1\\\"\\]\\\\\"] PD-0 (lobby): key press"
+        );
+
+        // daemon.warning
+        let record = Record::online("cp0", 0, "lobby", false);
+        assert!(syslog_message(&record, 42).starts_with("<28>1 "));
+    }
+
+    #[test]
+    fn test_journald_message() {
+        let record = Record::online("cp0", 0, "lobby", false);
+        assert_eq!(
+            String::from_utf8(journald_message(&record)).unwrap(),
+            "MESSAGE=PD-0 (lobby): offline\n\
+             PRIORITY=4\n\
+             SYSLOG_IDENTIFIER=osdpctl\n\
+             OSDP_DEVICE=cp0\n\
+             OSDP_PD=0\n\
+             OSDP_PD_NAME=lobby\n\
+             OSDP_EVENT=offline\n"
+        );
+
+        // Values with newlines are length prefixed
+        let event = OsdpEvent::KeyPress(OsdpEventKeyPress::new(b"1\n2".to_vec()));
+        let record = Record::from_event("cp0", 0, "lobby", &event);
+        let buf = journald_message(&record);
+        let mut expected = b"OSDP_KEYS\n".to_vec();
+        expected.extend_from_slice(&3_u64.to_le_bytes());
+        expected.extend_from_slice(b"1\n2\n");
+        assert!(buf.ends_with(&expected));
+    }
+}
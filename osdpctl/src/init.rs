@@ -0,0 +1,121 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl init` writes out a new (commented) device config that can then be
+//! edited and passed to `osdpctl create`.
+
+use std::fmt::Write;
+
+fn random_key() -> String {
+    let key: [u8; 16] = rand::random();
+    key.iter().fold(String::with_capacity(32), |mut s, b| {
+        write!(s, "{b:02x}").unwrap();
+        s
+    })
+}
+
+fn pd_section(address: u8, channel: &str, scbk: &str) -> String {
+    format!(
+        "\
+# Address of the PD on the OSDP bus (0-126)
+address = {address}
+# Channel to talk over, as <kind>::<id>. Only the `unix` kind is supported for
+# now; for it, the CP and PD need to use the same id.
+channel = {channel}
+# Secure channel base key (16 bytes, hex); it must be the same on both the CP
+# and the PD.
+scbk = {scbk}
+"
+    )
+}
+
+/// Config of a CP named `name` that controls `num_pd` PDs over `channel`, at
+/// consecutive addresses starting from `address`. All PDs get `scbk` as their
+/// secure channel key if it is given, and a random key of their own if not.
+pub fn cp_config(name: &str, num_pd: u8, address: u8, channel: &str, scbk: Option<&str>) -> String {
+    let mut config = format!(
+        "\
+# OSDP CP config; generated by `osdpctl init`
+
+# Name of this device; used to refer to it in osdpctl
+name = {name}
+# Number of PDs; each of them has a [pd-N] section below
+num_pd = {num_pd}
+# Log level of this device; one of INFO, DEBUG, WARN or TRACE
+log_level = INFO
+"
+    );
+    for pd in 0..num_pd {
+        let scbk = scbk.map_or_else(random_key, str::to_owned);
+        let section = pd_section(address.saturating_add(pd), channel, &scbk);
+        write!(
+            config,
+            "
+[pd-{pd}]
+# Name of the PD device (see `osdpctl init pd`)
+name = pd{pd}
+{section}"
+        )
+        .unwrap();
+    }
+    write!(
+        config,
+        "
+[events]
+# Forward events (card reads, key presses, status changes and PDs going offline
+# or coming online) to the system log, with structured fields; one of syslog
+# or journald
+#system_log = journald
+# Publish events as JSON to an MQTT broker, on <topic>/<pd name>/<event>; the
+# topic defaults to osdp/<name of this device>
+#mqtt = mqtt://localhost:1883/osdp/{name}
+# POST events as JSON to an HTTP(S) endpoint
+#webhook = http://localhost:8080/osdp/events
+"
+    )
+    .unwrap();
+    config
+}
+
+/// Config of a PD named `name` at `address` that talks to its CP over
+/// `channel`, with `scbk` (or a random key) as its secure channel key.
+pub fn pd_config(name: &str, address: u8, channel: &str, scbk: Option<&str>) -> String {
+    let scbk = scbk.map_or_else(random_key, str::to_owned);
+    let section = pd_section(address, channel, &scbk);
+    format!(
+        "\
+# OSDP PD config; generated by `osdpctl init`
+
+# Name of this device; used to refer to it in osdpctl
+name = {name}
+{section}\
+# Flags, separated by `|`; any of EnforceSecure, InstallMode (accept a new
+# key from the CP) and IgnoreUnsolicited
+flags = InstallMode
+# Log level of this device; one of INFO, DEBUG, WARN or TRACE
+log_level = INFO
+
+# What this PD can do; reported to the CP. Each entry is of the form
+# <Capability> = Compliance:<level>,NumItems:<count>
+[capability]
+CommunicationSecurity = Compliance:1,NumItems:1
+ContactStatusMonitoring = Compliance:1,NumItems:1
+OutputControl = Compliance:1,NumItems:1
+LedControl = Compliance:1,NumItems:1
+AudibleOutput = Compliance:1,NumItems:1
+TextOutput = Compliance:1,NumItems:1
+CardDataFormat = Compliance:1,NumItems:1
+
+# Identity of this PD; reported to the CP
+[pd_id]
+vendor_code = 153
+model = 1
+version = 1
+serial_number = 1234
+# major.minor.build, or a 24-bit number with major in the low byte
+firmware_version = 4321
+"
+    )
+}
@@ -0,0 +1,53 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl inject` makes a running PD emit an event, as if a card was read or
+//! a key was pressed on it. Events are sent (serialized as JSON) to a datagram
+//! socket that each PD device listens on, in its runtime directory.
+
+use anyhow::Context;
+use libosdp::OsdpEvent;
+use std::{os::unix::net::UnixDatagram, path::Path};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+const SOCKET_NAME: &str = "inject.sock";
+
+pub struct Listener {
+    socket: UnixDatagram,
+}
+
+impl Listener {
+    pub fn bind(runtime_dir: &Path) -> Result<Self> {
+        let path = runtime_dir.join(SOCKET_NAME);
+        _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)
+            .with_context(|| format!("Unable to bind to {}", path.display()))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// The next event that was injected, if any.
+    pub fn recv(&self) -> Option<OsdpEvent> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = self.socket.recv(&mut buf).ok()?;
+            match serde_json::from_slice(&buf[..len]) {
+                Ok(event) => return Some(event),
+                Err(e) => log::warn!("Dropped malformed injected event: {e}"),
+            }
+        }
+    }
+}
+
+/// Send `event` to the PD whose runtime directory is `runtime_dir`.
+pub fn send(runtime_dir: &Path, event: &OsdpEvent) -> Result<()> {
+    let path = runtime_dir.join(SOCKET_NAME);
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to(&serde_json::to_vec(event)?, &path)
+        .context("Unable to reach the device; is it running?")?;
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Devices started with `--log-file` log to a file that is rolled over when it
+//! grows too large or gets too old (see [`Rotation`]), keeping a few of the
+//! older files around as `<file>.1`, `<file>.2`, and so on.
+
+use anyhow::{bail, Context};
+use log4rs::{
+    append::rolling_file::{
+        policy::compound::{
+            roll::fixed_window::FixedWindowRoller,
+            trigger::{
+                size::SizeTrigger,
+                time::{TimeTrigger, TimeTriggerConfig, TimeTriggerInterval},
+                Trigger,
+            },
+            CompoundPolicy,
+        },
+        RollingFileAppender,
+    },
+    encode::Encode,
+};
+use std::{path::PathBuf, str::FromStr};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// When to roll over to a new log file
+#[derive(Clone, Copy, Debug)]
+pub enum Rotation {
+    /// Once the file grows past this many bytes
+    Size(u64),
+    /// Every hour, day or week
+    Every(TimeTriggerInterval),
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    /// A size in bytes, optionally suffixed with K, M or G (e.g. 10M), or one
+    /// of hourly, daily or weekly.
+    fn from_str(s: &str) -> Result<Self> {
+        let interval = match s {
+            "hourly" => Some(TimeTriggerInterval::Hour(1)),
+            "daily" => Some(TimeTriggerInterval::Day(1)),
+            "weekly" => Some(TimeTriggerInterval::Week(1)),
+            _ => None,
+        };
+        if let Some(interval) = interval {
+            return Ok(Rotation::Every(interval));
+        }
+        let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => s.split_at(i),
+            None => (s, ""),
+        };
+        let unit = match unit {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => bail!("Invalid log rotation '{s}'; expected a size or hourly/daily/weekly"),
+        };
+        let num = num
+            .parse::<u64>()
+            .with_context(|| format!("Invalid log rotation size '{s}'"))?;
+        if num == 0 {
+            bail!("Log rotation size must be greater than zero");
+        }
+        Ok(Rotation::Size(num * unit))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub rotation: Rotation,
+    /// Number of rolled over files to keep
+    pub keep: u32,
+}
+
+impl LogFile {
+    pub fn new(path: &str, rotation: Rotation, keep: u32) -> Result<Self> {
+        // Daemons change their working directory; so make it absolute now.
+        let path = std::env::current_dir()?.join(path);
+        Ok(Self {
+            path,
+            rotation,
+            keep,
+        })
+    }
+
+    pub fn appender(&self, encoder: Box<dyn Encode>) -> Result<RollingFileAppender> {
+        let trigger: Box<dyn Trigger> = match self.rotation {
+            Rotation::Size(limit) => Box::new(SizeTrigger::new(limit)),
+            Rotation::Every(interval) => Box::new(TimeTrigger::new(TimeTriggerConfig {
+                interval,
+                modulate: false,
+                max_random_delay: 0,
+            })),
+        };
+        let pattern = format!("{}.{{}}", self.path.display());
+        let roller = FixedWindowRoller::builder()
+            .base(1)
+            .build(&pattern, self.keep)?;
+        let appender = RollingFileAppender::builder()
+            .encoder(encoder)
+            .build(
+                &self.path,
+                Box::new(CompoundPolicy::new(trigger, Box::new(roller))),
+            )
+            .with_context(|| format!("Unable to open log file {}", self.path.display()))?;
+        Ok(appender)
+    }
+}
@@ -6,23 +6,41 @@
 mod config;
 mod cp;
 mod daemonize;
+mod decode;
+mod forward;
+mod init;
+mod inject;
+mod log_file;
 mod pd;
-mod unix_channel;
+mod playbook;
+mod reload;
+mod rotate;
+mod scan;
+mod serial;
+mod service;
 
 use anyhow::{bail, Context};
-use clap::{arg, Command};
+use clap::{arg, value_parser, ArgMatches, Command};
 use config::DeviceConfig;
+use libosdp::{OsdpEvent, OsdpEventCardRead, OsdpEventKeyPress, OsdpStatusReport};
 use log::LevelFilter;
 use log4rs::{
-    append::console::ConsoleAppender,
+    append::{console::ConsoleAppender, Append},
     config::{Appender, Root},
+    encode::pattern::PatternEncoder,
     Config,
 };
+use log_file::{LogFile, Rotation};
 use nix::{
     sys::signal::{self, Signal},
     unistd::Pid,
 };
-use std::{path::PathBuf, str::FromStr};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
 const HELP_TEMPLATE: &str = "{before-help}
@@ -43,7 +61,33 @@ fn cli() -> Command {
         .help_template(HELP_TEMPLATE)
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(arg!(--json "Print machine-readable JSON output").global(true))
         .subcommand(Command::new("list").about("List configured OSDP devices"))
+        .subcommand(
+            Command::new("init")
+                .about("Generate a new device config")
+                .long_about(
+                    "Generate a new device config, with comments on what each entry \
+                     does. Edit it as needed and add the device with `osdpctl create`. \
+                     Secure channel keys are random unless --scbk is given; to get a CP \
+                     and a PD that can talk to each other, pass the same key to both.",
+                )
+                .arg(arg!(<KIND> "kind of device").value_parser(["cp", "pd"]))
+                .arg(arg!(--name <NAME> "device name (default: cp or pd0)"))
+                .arg(
+                    arg!(--pds <N> "number of PDs of the CP")
+                        .value_parser(value_parser!(u8).range(1..=127))
+                        .default_value("1"),
+                )
+                .arg(
+                    arg!(--address <ADDR> "address of the PD (the first one for a CP)")
+                        .value_parser(value_parser!(u8).range(0..=126))
+                        .default_value("1"),
+                )
+                .arg(arg!(--channel <CHANNEL> "channel to talk over").default_value("unix::conn"))
+                .arg(arg!(--scbk <KEY> "secure channel key (32 hex digits; of all PDs for a CP)"))
+                .arg(arg!(-o --output <FILE> "write the config to FILE instead of stdout")),
+        )
         .subcommand(
             Command::new("create")
                 .about("Create a device specified by config")
@@ -64,15 +108,186 @@ fn cli() -> Command {
         )
         .subcommand(
             Command::new("start")
-                .about("Start a OSDP device")
-                .arg(arg!(<DEV> "device to start"))
+                .about("Start one or more OSDP devices")
+                .long_about(
+                    "Start one or more OSDP devices. When more than one device is \
+                     given, they all run in this process (each on a thread of its \
+                     own) and share its log; stopping any of them stops them all.",
+                )
+                .arg(arg!(<DEV>... "devices to start"))
                 .arg(arg!(-d --daemonize "Fork and run in the background"))
+                .args(log_args())
                 .arg_required_else_help(true),
         )
         .subcommand(
             Command::new("stop")
                 .about("Stop a running OSDP device")
+                .long_about(
+                    "Stop a running OSDP device (with SIGTERM; SIGHUP makes it \
+                     reload its config instead, see `osdpctl reload`) and remove \
+                     the pid file and sockets it leaves behind (also when it is no \
+                     longer running).",
+                )
                 .arg(arg!(<DEV> "device to stop"))
+                .arg(arg!(-f --force "Kill the device if it does not stop in time"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("restart")
+                .about("Stop an OSDP device (if it is running) and start it in the background")
+                .arg(arg!(<DEV> "device to restart"))
+                .arg(arg!(-f --force "Kill the device if it does not stop in time"))
+                .args(log_args())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show whether an OSDP device is running")
+                .arg(arg!(<DEV> "device to show the status of"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("reload")
+                .about("Make a running OSDP device re-read its config")
+                .long_about(
+                    "Make a running OSDP device re-read its config (this is also \
+                     done on SIGHUP). Key, flag and capability changes are applied \
+                     without dropping the bus connection; changes to channels need \
+                     a restart.",
+                )
+                .arg(arg!(<DEV> "device to reload"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("inject")
+                .about("Make a running PD device emit an event")
+                .arg(arg!(<DEV> "PD device to emit the event"))
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("cardread")
+                        .about("Read a 26-bit Wiegand card")
+                        .arg(
+                            arg!(--facility <NUM> "facility code (0-255)")
+                                .value_parser(value_parser!(u8))
+                                .required(true),
+                        )
+                        .arg(
+                            arg!(--card <NUM> "card number (0-65535)")
+                                .value_parser(value_parser!(u16))
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("keypress")
+                        .about("Press keys on the keypad")
+                        .arg(arg!(<KEYS> "keys to press (digits, * and #)")),
+                )
+                .subcommand(
+                    Command::new("tamper")
+                        .about("Report that the PD was tampered with")
+                        .arg(arg!(--clear "Report that the tamper condition cleared")),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("rotate-keys")
+                .about("Give the PDs of a running CP new secure channel keys")
+                .long_about(
+                    "Give the PDs of a running CP new (random) secure channel keys, \
+                     one PD at a time. A PD's key store is only updated once it has \
+                     accepted its new key; PDs that fail keep their old key.",
+                )
+                .arg(arg!(<DEV> "CP device to rotate the keys of"))
+                .arg(arg!(--all "Rotate the keys of all PDs").conflicts_with("pd"))
+                .arg(
+                    arg!(--pd <N> "Rotate the key of PD N (offset in the config)")
+                        .value_parser(value_parser!(i32).range(0..127))
+                        .action(clap::ArgAction::Append),
+                )
+                .group(
+                    clap::ArgGroup::new("pds")
+                        .args(["all", "pd"])
+                        .required(true),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Run a playbook of commands and checks against a running CP")
+                .long_about(
+                    "Run a playbook (YAML) of timed commands and checks against a \
+                     running CP device: send commands and expect ACK or NAK, wait, \
+                     and expect events such as card reads. Steps run in order and \
+                     the playbook stops at the first step that fails.",
+                )
+                .arg(arg!(<PLAYBOOK> "playbook file"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Look for PDs on a bus")
+                .long_about(
+                    "Look for PDs on a bus by probing each address at each baud rate \
+                     and report the ones that answer, with their identity (PdId). \
+                     This takes a while: up to 127 timeouts per baud rate.",
+                )
+                .arg(arg!(--channel <CHANNEL> "channel to scan, as serial::<path>").required(true))
+                .arg(
+                    arg!(--baud <RATE> "baud rate to scan at (default: 9600 to 230400)")
+                        .value_parser(value_parser!(u32))
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    arg!(--address <ADDR> "address to probe (default: all of 0-126)")
+                        .value_parser(value_parser!(u8).range(0..=126))
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    arg!(--timeout <MS> "how long to wait for each address to answer")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("200"),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("conformance")
+                .about("Check a PD against the OSDP specification")
+                .long_about(
+                    "Run a script of exchanges whose outcome the OSDP specification \
+                     mandates (start up, sequence numbers, NAKs, secure channel set up) \
+                     against a PD and report which ones it got right. This is meant \
+                     for pre-certification testing on the bench.",
+                )
+                .arg(
+                    arg!(--channel <CHANNEL> "channel to the PD, as serial::<path>").required(true),
+                )
+                .arg(
+                    arg!(--address <ADDR> "address of the PD")
+                        .value_parser(value_parser!(u8).range(0..=126))
+                        .required(true),
+                )
+                .arg(
+                    arg!(--baud <RATE> "baud rate of the PD")
+                        .value_parser(value_parser!(u32))
+                        .default_value("9600"),
+                )
+                .arg(
+                    arg!(--timeout <MS> "how long to wait for each reply")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("200"),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("decode")
+                .about("Decode captured OSDP traffic")
+                .long_about(
+                    "Print each OSDP frame in a pcap capture (as written with \
+                     packet_trace) or in a hex string, with its fields labeled: \
+                     address, sequence number, command or reply, secure channel \
+                     block and the decoded payload.",
+                )
+                .arg(arg!(<INPUT> "pcap file, or the bytes as a hex string"))
                 .arg_required_else_help(true),
         )
         .subcommand(
@@ -83,6 +298,28 @@ fn cli() -> Command {
         )
 }
 
+/// Logging options of the commands that start devices
+fn log_args() -> [clap::Arg; 3] {
+    [
+        arg!(--"log-file" <FILE> "Log to FILE instead of the console"),
+        arg!(--"log-rotate" <WHEN> "Roll the log file over at a size (like 10M) or hourly, daily or weekly")
+            .value_parser(Rotation::from_str)
+            .default_value("10M"),
+        arg!(--"log-keep" <N> "Number of rolled over log files to keep")
+            .value_parser(value_parser!(u32).range(1..))
+            .default_value("5"),
+    ]
+}
+
+fn log_file(matches: &ArgMatches) -> Result<Option<LogFile>> {
+    let Some(path) = matches.get_one::<String>("log-file") else {
+        return Ok(None);
+    };
+    let rotation = *matches.get_one::<Rotation>("log-rotate").unwrap();
+    let keep = *matches.get_one::<u32>("log-keep").unwrap();
+    Ok(Some(LogFile::new(path, rotation, keep)?))
+}
+
 fn osdpctl_config_dir() -> Result<PathBuf> {
     let mut cfg_dir = dirs::config_dir().expect("Failed to read system config directory");
     cfg_dir.push("osdp");
@@ -97,19 +334,149 @@ fn device_runtime_dir() -> Result<PathBuf> {
     Ok(runtime_dir)
 }
 
-fn get_logger_config(log_level: LevelFilter) -> Result<Config> {
-    let stdout = ConsoleAppender::builder().build();
+/// Logs go to the console unless a `log_file` is given.
+fn get_appender(encoder: PatternEncoder, log_file: Option<&LogFile>) -> Result<Box<dyn Append>> {
+    Ok(match log_file {
+        Some(log_file) => Box::new(log_file.appender(Box::new(encoder))?),
+        None => Box::new(
+            ConsoleAppender::builder()
+                .encoder(Box::new(encoder))
+                .build(),
+        ),
+    })
+}
+
+fn get_logger_config(log_level: LevelFilter, log_file: Option<&LogFile>) -> Result<Config> {
+    let output = get_appender(PatternEncoder::default(), log_file)?;
+    let config = Config::builder()
+        .appender(Appender::builder().build("output", output))
+        .build(Root::builder().appender("output").build(log_level))?;
+    Ok(config)
+}
+
+/// Like [`get_logger_config`] but tags each line with the name of the device
+/// (thread) that logged it.
+fn get_combined_logger_config(
+    log_level: LevelFilter,
+    log_file: Option<&LogFile>,
+) -> Result<Config> {
+    let encoder = PatternEncoder::new("{d} {l} [{T}] {t} - {m}{n}");
+    let output = get_appender(encoder, log_file)?;
     let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .build(Root::builder().appender("stdout").build(log_level))?;
+        .appender(Appender::builder().build("output", output))
+        .build(Root::builder().appender("output").build(log_level))?;
     Ok(config)
 }
 
+/// Run several devices in this process, each on a thread named after it. PDs
+/// are started first so that CPs in the same process can connect to them.
+fn start_many(
+    mut devs: Vec<DeviceConfig>,
+    rt_dir: &Path,
+    daemonize: bool,
+    log_file: Option<&LogFile>,
+    lh: &log4rs::Handle,
+) -> Result<()> {
+    let log_level = devs
+        .iter()
+        .map(|dev| match dev {
+            DeviceConfig::CpConfig(dev) => dev.log_level,
+            DeviceConfig::PdConfig(dev) => dev.log_level,
+        })
+        .max()
+        .unwrap_or(LevelFilter::Info);
+    lh.set_config(get_combined_logger_config(log_level, log_file)?);
+    if daemonize {
+        daemonize::daemonize(rt_dir, std::env!("CARGO_PKG_NAME"))?;
+    }
+    devs.sort_by_key(|dev| matches!(dev, DeviceConfig::CpConfig(_)));
+    for dev in &devs {
+        match dev {
+            DeviceConfig::CpConfig(dev) => cp::setup(dev, false)?,
+            DeviceConfig::PdConfig(dev) => pd::setup(dev, false)?,
+        }
+    }
+    let mut threads = Vec::with_capacity(devs.len());
+    for dev in devs {
+        let name = dev.name().to_owned();
+        let thread = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || match dev {
+                DeviceConfig::CpConfig(dev) => cp::run(dev),
+                DeviceConfig::PdConfig(dev) => pd::run(dev),
+            })
+            .with_context(|| format!("Failed to start device '{name}'"))?;
+        threads.push((name, thread));
+    }
+    for (name, thread) in threads {
+        match thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Device '{name}' exited: {e:#}"),
+            Err(_) => log::error!("Device '{name}' panicked"),
+        }
+    }
+    Ok(())
+}
+
+/// Start the devices `devs`, refusing to start any that is already running.
+fn start(
+    mut devs: Vec<DeviceConfig>,
+    rt_dir: &Path,
+    daemonize: bool,
+    log_file: Option<LogFile>,
+    lh: &log4rs::Handle,
+) -> Result<()> {
+    for dev in &devs {
+        match service::status(dev) {
+            service::Status::Running(pid) => {
+                bail!("Device '{}' is already running (pid {pid})", dev.name())
+            }
+            service::Status::Stale(_) => service::cleanup(dev)?,
+            service::Status::Stopped => {}
+        }
+    }
+    reload::install()?;
+    if devs.len() > 1 {
+        return start_many(devs, rt_dir, daemonize, log_file.as_ref(), lh);
+    }
+    match devs.remove(0) {
+        DeviceConfig::CpConfig(dev) => {
+            lh.set_config(get_logger_config(dev.log_level, log_file.as_ref())?);
+            cp::main(dev, daemonize)
+        }
+        DeviceConfig::PdConfig(dev) => {
+            lh.set_config(get_logger_config(dev.log_level, log_file.as_ref())?);
+            pd::main(dev, daemonize)
+        }
+    }
+}
+
+/// Print the outcome of a command; as `value` with `--json` and as `text`
+/// otherwise.
+fn report(json: bool, text: &str, value: serde_json::Value) {
+    if json {
+        println!("{value}");
+    } else {
+        println!("{text}");
+    }
+}
+
 fn main() -> Result<()> {
-    let lh = log4rs::init_config(get_logger_config(LevelFilter::Info)?)?;
+    let matches = cli().get_matches();
+    let json = matches.get_flag("json");
+    match run(&matches, json) {
+        Err(e) if json => {
+            println!("{}", json!({ "error": format!("{e:#}") }));
+            std::process::exit(1);
+        }
+        res => res,
+    }
+}
+
+fn run(matches: &ArgMatches, json: bool) -> Result<()> {
+    let lh = log4rs::init_config(get_logger_config(LevelFilter::Info, None)?)?;
     let cfg_dir = osdpctl_config_dir()?;
     let rt_dir = device_runtime_dir()?;
-    let matches = cli().get_matches();
     match matches.subcommand() {
         Some(("edit", sub_matches)) => {
             let name = sub_matches
@@ -123,6 +490,41 @@ fn main() -> Result<()> {
                 .status()
                 .context("External editor returned error code")?;
         }
+        Some(("init", sub_matches)) => {
+            let kind = sub_matches
+                .get_one::<String>("KIND")
+                .context("Device kind is required")?;
+            let num_pd = *sub_matches.get_one::<u8>("pds").unwrap();
+            let address = *sub_matches.get_one::<u8>("address").unwrap();
+            let channel = sub_matches.get_one::<String>("channel").unwrap();
+            let scbk = sub_matches.get_one::<String>("scbk").map(String::as_str);
+            if let Some(scbk) = scbk {
+                config::KeyStore::str_to_key(scbk).context("Invalid --scbk")?;
+            }
+            let config = if kind == "cp" {
+                if address as usize + num_pd as usize > 127 {
+                    bail!("PD addresses must be in the range 0-126");
+                }
+                let name = sub_matches.get_one::<String>("name").map_or("cp", |n| n);
+                init::cp_config(name, num_pd, address, channel, scbk)
+            } else {
+                let name = sub_matches.get_one::<String>("name").map_or("pd0", |n| n);
+                init::pd_config(name, address, channel, scbk)
+            };
+            match sub_matches.get_one::<String>("output") {
+                Some(path) => {
+                    std::fs::write(path, &config)
+                        .with_context(|| format!("Unable to write to {path}"))?;
+                    report(
+                        json,
+                        &format!("Wrote new {kind} config to {path}"),
+                        json!({ "kind": kind, "output": path }),
+                    );
+                }
+                None if json => println!("{}", json!({ "kind": kind, "config": config })),
+                None => print!("{config}"),
+            }
+        }
         Some(("create", sub_matches)) => {
             let config = sub_matches
                 .get_one::<String>("CONFIG")
@@ -137,7 +539,11 @@ fn main() -> Result<()> {
                 );
             }
             std::fs::copy(&config, &dest_path).unwrap();
-            println!("Created new device '{}'.", dev.name())
+            report(
+                json,
+                &format!("Created new device '{}'.", dev.name()),
+                json!({ "device": dev.name(), "created": true }),
+            );
         }
         Some(("destroy", sub_matches)) => {
             let name = sub_matches
@@ -147,55 +553,298 @@ fn main() -> Result<()> {
             if !config_path.exists() {
                 bail!(format!("Device '{}' not found. See `osdpctl list`.", name));
             }
-            let sock = &rt_dir.join(format!("{name}/{name}.sock"));
-            if sock.exists() {
+            let dev = DeviceConfig::new(&config_path, &rt_dir)?;
+            if let service::Status::Running(_) = service::status(&dev) {
                 bail!("Device '{name}' is still running; stop it first.");
             }
+            service::cleanup(&dev)?;
             std::fs::remove_file(config_path).unwrap();
-            println!("Destroyed device '{name}'.")
+            report(
+                json,
+                &format!("Destroyed device '{name}'."),
+                json!({ "device": name, "destroyed": true }),
+            );
         }
         Some(("list", _)) => {
             let paths = std::fs::read_dir(&cfg_dir).unwrap();
-            println!("  Nr  Device Name     Status   ");
-            println!("-------------------------------");
+            let mut devices = Vec::new();
+            if !json {
+                println!("  Nr  Device Name     Status   ");
+                println!("-------------------------------");
+            }
             for (i, path) in paths.enumerate() {
                 let path = path.unwrap().path();
                 if let Some(ext) = path.extension() {
                     if ext == "cfg" {
                         let dev = DeviceConfig::new(&path, &rt_dir)?;
-                        println!("  {:02}  {:<13}   {:^8}  ", i, dev.name(), "Offline");
+                        let status = service::status(&dev).as_str();
+                        if json {
+                            devices.push(json!({
+                                "nr": i,
+                                "name": dev.name(),
+                                "status": status,
+                            }));
+                        } else {
+                            println!("  {:02}  {:<13}   {:^8}  ", i, dev.name(), status);
+                        }
                     }
                 }
             }
+            if json {
+                println!("{}", serde_json::Value::Array(devices));
+            }
         }
         Some(("start", sub_matches)) => {
+            let names = sub_matches
+                .get_many::<String>("DEV")
+                .context("Device name is required")?;
+            let daemonize = sub_matches.get_flag("daemonize");
+            let log_file = log_file(sub_matches)?;
+            let devs = names
+                .map(|name| DeviceConfig::new(&cfg_dir.join(format!("{name}.cfg")), &rt_dir))
+                .collect::<Result<Vec<_>>>()?;
+            start(devs, &rt_dir, daemonize, log_file, &lh)?;
+        }
+        Some(("stop", sub_matches)) => {
             let name = sub_matches
                 .get_one::<String>("DEV")
                 .context("Device name is required")?;
-            let daemonize = sub_matches.get_flag("daemonize");
             let config_path = cfg_dir.join(format!("{name}.cfg"));
             let dev = DeviceConfig::new(&config_path, &rt_dir)?;
-            match dev {
-                DeviceConfig::CpConfig(dev) => {
-                    lh.set_config(get_logger_config(dev.log_level)?);
-                    cp::main(dev, daemonize)?;
-                }
-                DeviceConfig::PdConfig(dev) => {
-                    lh.set_config(get_logger_config(dev.log_level)?);
-                    pd::main(dev, daemonize)?;
+            let text = match service::stop(&dev, sub_matches.get_flag("force"))? {
+                service::Status::Running(_) => format!("Device `{name}` stopped"),
+                service::Status::Stale(pid) => {
+                    format!("Device `{name}` was not running; cleaned up after pid {pid}")
                 }
+                service::Status::Stopped => format!("Device `{name}` was not running"),
             };
+            report(json, &text, json!({ "device": name, "stopped": true }));
         }
-        Some(("stop", sub_matches)) => {
+        Some(("restart", sub_matches)) => {
             let name = sub_matches
                 .get_one::<String>("DEV")
                 .context("Device name is required")?;
             let config_path = cfg_dir.join(format!("{name}.cfg"));
             let dev = DeviceConfig::new(&config_path, &rt_dir)?;
-            let pid = dev.get_pid()?;
+            service::stop(&dev, sub_matches.get_flag("force"))?;
+            let log_file = log_file(sub_matches)?;
+            report(
+                json,
+                &format!("Device `{name}` restarting"),
+                json!({ "device": name, "restarted": true }),
+            );
+            start(vec![dev], &rt_dir, true, log_file, &lh)?;
+        }
+        Some(("status", sub_matches)) => {
+            let name = sub_matches
+                .get_one::<String>("DEV")
+                .context("Device name is required")?;
+            let config_path = cfg_dir.join(format!("{name}.cfg"));
+            let dev = DeviceConfig::new(&config_path, &rt_dir)?;
+            let status = service::status(&dev);
+            let text = match status {
+                service::Status::Running(pid) => format!("Device `{name}` is running (pid {pid})"),
+                service::Status::Stale(pid) => format!(
+                    "Device `{name}` is not running; pid {pid} exited without cleaning up \
+                     (see `osdpctl stop`)"
+                ),
+                service::Status::Stopped => format!("Device `{name}` is not running"),
+            };
+            report(
+                json,
+                &text,
+                json!({ "device": name, "status": status.as_str(), "pid": status.pid() }),
+            );
+        }
+        Some(("reload", sub_matches)) => {
+            let name = sub_matches
+                .get_one::<String>("DEV")
+                .context("Device name is required")?;
+            let config_path = cfg_dir.join(format!("{name}.cfg"));
+            let dev = DeviceConfig::new(&config_path, &rt_dir)?;
+            let service::Status::Running(pid) = service::status(&dev) else {
+                bail!("Device '{name}' is not running");
+            };
             signal::kill(Pid::from_raw(pid), Signal::SIGHUP)
-                .context("Failed to stop to requested device")?;
-            println!("Device `{}` stopped", dev.name());
+                .context("Failed to reload requested device")?;
+            report(
+                json,
+                &format!("Device `{}` reloading", dev.name()),
+                json!({ "device": dev.name(), "reloaded": true }),
+            );
+        }
+        Some(("inject", sub_matches)) => {
+            let name = sub_matches
+                .get_one::<String>("DEV")
+                .context("Device name is required")?;
+            let config_path = cfg_dir.join(format!("{name}.cfg"));
+            let DeviceConfig::PdConfig(dev) = DeviceConfig::new(&config_path, &rt_dir)? else {
+                bail!("Device '{name}' is not a PD");
+            };
+            let event = match sub_matches.subcommand() {
+                Some(("cardread", m)) => {
+                    let facility = *m
+                        .get_one::<u8>("facility")
+                        .context("Facility is required")?;
+                    let card = *m
+                        .get_one::<u16>("card")
+                        .context("Card number is required")?;
+                    OsdpEvent::CardRead(OsdpEventCardRead::from_wiegand26(facility, card))
+                }
+                Some(("keypress", m)) => {
+                    let keys = m.get_one::<String>("KEYS").context("Keys are required")?;
+                    OsdpEvent::KeyPress(OsdpEventKeyPress::new(keys.as_bytes().to_vec()))
+                }
+                Some(("tamper", m)) => {
+                    let tampered = !m.get_flag("clear");
+                    OsdpEvent::Status(OsdpStatusReport::new_local(tampered as u32))
+                }
+                _ => bail!("Unknown event"),
+            };
+            inject::send(&dev.runtime_dir, &event)?;
+            report(
+                json,
+                &format!("Injected event into device `{name}`"),
+                json!({ "device": name, "injected": event }),
+            );
+        }
+        Some(("rotate-keys", sub_matches)) => {
+            let name = sub_matches
+                .get_one::<String>("DEV")
+                .context("Device name is required")?;
+            let config_path = cfg_dir.join(format!("{name}.cfg"));
+            let DeviceConfig::CpConfig(dev) = DeviceConfig::new(&config_path, &rt_dir)? else {
+                bail!("Device '{name}' is not a CP");
+            };
+            let pds: Vec<i32> = match sub_matches.get_many::<i32>("pd") {
+                Some(pds) => pds.copied().collect(),
+                None => (0..dev.pd_names().count() as i32).collect(),
+            };
+            let results = rotate::request(&dev.runtime_dir, &pds)?;
+            let results = results.as_array().unwrap();
+            let failed = results.iter().filter(|r| r["rotated"] != true).count();
+            if json {
+                println!("{}", json!({ "device": name, "results": results }));
+            } else {
+                for r in results {
+                    match r["error"].as_str() {
+                        None => println!("PD-{} ({}): rotated", r["pd"], r["name"]),
+                        Some(e) => println!("PD-{} ({}): failed: {e}", r["pd"], r["name"]),
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("Failed to rotate the keys of {failed} PD(s)");
+            }
+        }
+        Some(("run", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("PLAYBOOK")
+                .context("Playbook is required")?;
+            let playbook = playbook::Playbook::load(Path::new(path))?;
+            let name = &playbook.device;
+            let config_path = cfg_dir.join(format!("{name}.cfg"));
+            let DeviceConfig::CpConfig(dev) = DeviceConfig::new(&config_path, &rt_dir)? else {
+                bail!("Device '{name}' is not a CP");
+            };
+            let results = playbook::request(&dev.runtime_dir, &playbook)?;
+            let steps = results["steps"].as_array().unwrap();
+            if json {
+                println!("{}", json!({ "device": name, "results": results }));
+            } else {
+                for r in steps {
+                    match r["error"].as_str() {
+                        None => println!("Step {} ({}): ok", r["step"], r["what"]),
+                        Some(e) => println!("Step {} ({}): failed: {e}", r["step"], r["what"]),
+                    }
+                }
+            }
+            if results["passed"] != true {
+                bail!(
+                    "Playbook failed; {} of {} steps passed",
+                    steps.iter().filter(|r| r["ok"] == true).count(),
+                    playbook.steps.len()
+                );
+            }
+        }
+        Some(("scan", sub_matches)) => {
+            let spec = sub_matches.get_one::<String>("channel").unwrap();
+            let baud_rates: Vec<u32> = match sub_matches.get_many::<u32>("baud") {
+                Some(rates) => rates.copied().collect(),
+                None => libosdp::OSDP_BAUD_RATES.to_vec(),
+            };
+            let mut discovery = libosdp::Discovery::new().baud_rates(&baud_rates).timeout(
+                std::time::Duration::from_millis(*sub_matches.get_one::<u64>("timeout").unwrap()),
+            );
+            if let Some(addresses) = sub_matches.get_many::<u8>("address") {
+                discovery = discovery.addresses(addresses.map(|a| *a as i32));
+            }
+            let mut channel = serial::SerialChannel::from_spec(spec, baud_rates[0])?;
+            if !json {
+                println!("  Addr  Baud     Identity");
+                println!("----------------------------------------------------------------");
+            }
+            let found = discovery.run(&mut channel, |pd| {
+                if !json {
+                    let identity = scan::identity(pd);
+                    println!("  {:>4}  {:<7}  {identity}", pd.address, pd.baud_rate);
+                }
+            })?;
+            if json {
+                let found: Vec<_> = found.iter().map(scan::to_json).collect();
+                println!("{}", json!({ "channel": spec, "found": found }));
+            } else if found.is_empty() {
+                println!("No PDs answered");
+            }
+        }
+        Some(("conformance", sub_matches)) => {
+            let spec = sub_matches.get_one::<String>("channel").unwrap();
+            let address = *sub_matches.get_one::<u8>("address").unwrap();
+            let baud_rate = *sub_matches.get_one::<u32>("baud").unwrap();
+            let timeout =
+                std::time::Duration::from_millis(*sub_matches.get_one::<u64>("timeout").unwrap());
+            let mut channel = serial::SerialChannel::from_spec(spec, baud_rate)?;
+            let report = libosdp::Conformance::new(address as i32)?
+                .timeout(timeout)
+                .run(&mut channel)?;
+            if json {
+                let checks: Vec<_> = report
+                    .checks
+                    .iter()
+                    .map(|c| {
+                        let (verdict, detail) = match &c.verdict {
+                            libosdp::Verdict::Pass => ("pass", None),
+                            libosdp::Verdict::Fail(why) => ("fail", Some(why.clone())),
+                            libosdp::Verdict::Skip(why) => ("skip", Some(why.to_string())),
+                        };
+                        json!({
+                            "name": c.name,
+                            "expectation": c.expectation,
+                            "verdict": verdict,
+                            "detail": detail,
+                        })
+                    })
+                    .collect();
+                println!(
+                    "{}",
+                    json!({ "address": address, "passed": report.passed(), "checks": checks })
+                );
+            } else {
+                println!("{report}");
+            }
+            if !report.passed() {
+                bail!("PD failed {} checks", report.failures().count());
+            }
+        }
+        Some(("decode", sub_matches)) => {
+            let input = sub_matches.get_one::<String>("INPUT").unwrap();
+            let records = decode::read_input(input)?;
+            let (frames, discarded) = decode::frames(&records);
+            if json {
+                println!("{}", decode::to_json(&frames, discarded));
+            } else {
+                decode::print(&frames, discarded);
+            }
         }
         Some(("attach", sub_matches)) => {
             let name = sub_matches
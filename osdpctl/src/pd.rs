@@ -3,35 +3,37 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{thread, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::config::PdConfig;
-use anyhow::Context;
+use crate::config::{DeviceConfig, KeyStore, PdConfig};
+use crate::{inject, reload};
+use anyhow::{bail, Context};
 use libosdp::{OsdpCommand, PeripheralDevice};
-use std::io::Write;
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
-fn setup(dev: &PdConfig, daemonize: bool) -> Result<()> {
-    if dev.runtime_dir.exists() {
-        std::fs::remove_dir_all(&dev.runtime_dir)?;
-    }
+pub fn setup(dev: &PdConfig, daemonize: bool) -> Result<()> {
     std::fs::create_dir_all(&dev.runtime_dir)?;
+    // Keep the key stores; only what the last run left behind goes
+    crate::service::cleanup_dir(&dev.runtime_dir)?;
     if daemonize {
         crate::daemonize::daemonize(&dev.runtime_dir, &dev.name)?;
     } else {
-        let pid_file = dev.runtime_dir.join(format!("dev-{}.pid", dev.name));
-        let mut pid_file = std::fs::File::create(pid_file)?;
-        write!(pid_file, "{}", std::process::id())?;
+        crate::service::write_pid_file(&dev.runtime_dir.join(format!("dev-{}.pid", dev.name)))?;
     }
     Ok(())
 }
 
-pub fn main(mut dev: PdConfig, daemonize: bool) -> Result<()> {
+pub fn main(dev: PdConfig, daemonize: bool) -> Result<()> {
     setup(&dev, daemonize)?;
-    let (channel, pd_info) = dev.pd_info().context("Failed to create PD info")?;
-    let mut pd = PeripheralDevice::new(pd_info, channel)?;
-    pd.set_command_callback(|command| {
+    run(dev)
+}
+
+fn on_command(key_store: Arc<Mutex<KeyStore>>) -> impl FnMut(OsdpCommand) -> i32 + Send {
+    move |command| {
         match command {
             OsdpCommand::Led(c) => {
                 log::info!("Command: {:?}", c);
@@ -52,7 +54,10 @@ pub fn main(mut dev: PdConfig, daemonize: bool) -> Result<()> {
                 log::info!("Command: {:?}", c);
                 let mut key = [0; 16];
                 key.copy_from_slice(&c.data[0..16]);
-                dev.key_store.store(key).unwrap();
+                if let Err(e) = key_store.lock().unwrap().store(key) {
+                    log::error!("Failed to store the new key: {e:#}");
+                    return -1;
+                }
             }
             OsdpCommand::Mfg(c) => {
                 log::info!("Command: {:?}", c);
@@ -65,9 +70,95 @@ pub fn main(mut dev: PdConfig, daemonize: bool) -> Result<()> {
             }
         }
         0
-    });
+    }
+}
+
+/// Read the config of `dev` again; returns the new config if it changed and
+/// can be applied to the running PD.
+fn reloaded_config(dev: &PdConfig, key_store: &Mutex<KeyStore>) -> Result<Option<PdConfig>> {
+    let mut new = match reload::read_config(&dev.name)? {
+        DeviceConfig::PdConfig(new) => new,
+        DeviceConfig::CpConfig(_) => bail!("Device is no longer a PD; restart it instead"),
+    };
+    // The key in use (one that the CP set with a KEYSET, too) is read from
+    // the key store.
+    new.key_store.open()?;
+    *key_store.lock().unwrap() = new.key_store.clone();
+    if new == *dev {
+        return Ok(None);
+    }
+    if !new.same_channel(dev) {
+        bail!("PD channel changed; restart the device to apply");
+    }
+    Ok(Some(new))
+}
+
+pub fn run(mut dev: PdConfig) -> Result<()> {
+    let mut watcher = reload::Watcher::new();
+    dev.key_store.open()?;
+    let key_store = Arc::new(Mutex::new(dev.key_store.clone()));
+    let (channel, pd_info) = dev.pd_info().context("Failed to create PD info")?;
+    let mut pd = PeripheralDevice::new(pd_info, channel)?;
+    let mut _command_callback = pd.set_command_callback(on_command(key_store.clone()));
+    let injected = inject::Listener::bind(&dev.runtime_dir)?;
     loop {
+        if watcher.requested() {
+            // Keys set by the CP (with a KEYSET) go to the shared key store
+            dev.key_store = key_store.lock().unwrap().clone();
+            match reloaded_config(&dev, &key_store) {
+                Ok(Some(new)) if !dev.needs_rebuild(&new) => {
+                    pd.set_capabilities(new.capabilities());
+                    dev = new;
+                    log::info!("Config reloaded");
+                }
+                Ok(Some(new)) => {
+                    let pd_info = new.pd_info_builder().and_then(|info| {
+                        info.validate()?;
+                        Ok(info)
+                    });
+                    match pd_info {
+                        Ok(pd_info) => {
+                            // Set the PD up again over the channel it already
+                            // has so that the CP does not have to reconnect.
+                            let channel = pd.teardown();
+                            pd = match PeripheralDevice::new(pd_info, channel) {
+                                Ok(pd) => {
+                                    dev = new;
+                                    log::info!("Config reloaded");
+                                    pd
+                                }
+                                Err(e) => {
+                                    // The channel went with the failed
+                                    // attempt; listen again, as before.
+                                    log::error!(
+                                        "Failed to apply reloaded config, keeping the old one: {e}"
+                                    );
+                                    let (channel, pd_info) = dev.pd_info()?;
+                                    PeripheralDevice::new(pd_info, channel)
+                                        .context("Failed to set up PD again after reload")?
+                                }
+                            };
+                            _command_callback =
+                                pd.set_command_callback(on_command(key_store.clone()));
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "Failed to apply reloaded config, keeping the old one: {e:#}"
+                            )
+                        }
+                    }
+                }
+                Ok(None) => log::info!("Config unchanged"),
+                Err(e) => log::error!("Failed to reload config: {e:#}"),
+            }
+        }
+        while let Some(event) = injected.recv() {
+            log::info!("Injected: {:?}", event);
+            if let Err(e) = pd.notify_event(event) {
+                log::error!("Failed to notify injected event: {e}");
+            }
+        }
         pd.refresh();
-        thread::sleep(Duration::from_millis(50));
+        pd.wait_readable(Duration::from_millis(50));
     }
 }
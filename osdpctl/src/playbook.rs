@@ -0,0 +1,467 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl run <playbook.yaml>` runs a sequence of commands and assertions
+//! against a running CP, for repeatable acceptance tests of a reader
+//! installation. A playbook looks like this:
+//!
+//! ```yaml
+//! device: cp
+//! steps:
+//!   - send:
+//!       pd: 0
+//!       command:
+//!         Buzzer: { reader: 0, control_code: 2, on_count: 2, off_count: 2, rep_count: 1 }
+//!       expect: ack          # or nak; ack is the default
+//!   - wait: { ms: 500 }
+//!   - expect_event:
+//!       pd: 0
+//!       kind: card_read      # or key_press, mfg_reply, status
+//!       timeout_ms: 30000
+//! ```
+//!
+//! Commands are written as the [`OsdpCommand`] they stand for. The playbook
+//! is sent (serialized as JSON) to a datagram socket that each CP device
+//! listens on, in its runtime directory; the CP runs the steps in order,
+//! stops at the first one that fails and replies with how each step went.
+//!
+//! An `expect_event` step is satisfied by an event that arrived after the
+//! previous `expect_event` step (or the start of the playbook), so an event
+//! that comes in while an earlier step is still running is not missed.
+
+use anyhow::{bail, Context};
+use libosdp::{ControlPanel, EventContext, OsdpCommand, OsdpError, OsdpEvent, OsdpEventKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+const SOCKET_NAME: &str = "playbook.sock";
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Playbook {
+    /// Name of the CP device to run the steps on
+    pub device: String,
+    pub steps: Vec<Step>,
+}
+
+impl Playbook {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid playbook {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        // Steps (and commands) are written as `variant: { .. }` maps rather
+        // than the `!variant` tags serde_yaml expects by default.
+        let de = serde_yaml::Deserializer::from_str(contents);
+        Ok(serde_yaml::with::singleton_map_recursive::deserialize(de)?)
+    }
+
+    /// Longest the playbook can take to run
+    fn duration(&self) -> Duration {
+        self.steps.iter().map(|step| step.duration()).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    #[default]
+    Ack,
+    Nak,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    CardRead,
+    KeyPress,
+    MfgReply,
+    Status,
+}
+
+impl From<EventKind> for OsdpEventKind {
+    fn from(kind: EventKind) -> Self {
+        match kind {
+            EventKind::CardRead => OsdpEventKind::CardRead,
+            EventKind::KeyPress => OsdpEventKind::KeyPress,
+            EventKind::MfgReply => OsdpEventKind::MfgReply,
+            EventKind::Status => OsdpEventKind::Status,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Send `command` to PD `pd` and check that it replies with `expect`
+    Send {
+        pd: i32,
+        command: OsdpCommand,
+        #[serde(default)]
+        expect: Reply,
+        #[serde(default = "default_timeout_ms")]
+        timeout_ms: u64,
+    },
+    /// Keep the CP running for `ms` milliseconds
+    Wait { ms: u64 },
+    /// Wait for PD `pd` to send an event of `kind`
+    ExpectEvent {
+        pd: i32,
+        kind: EventKind,
+        #[serde(default = "default_timeout_ms")]
+        timeout_ms: u64,
+    },
+}
+
+impl Step {
+    fn duration(&self) -> Duration {
+        match self {
+            Step::Send { timeout_ms, .. } | Step::ExpectEvent { timeout_ms, .. } => {
+                Duration::from_millis(*timeout_ms)
+            }
+            Step::Wait { ms } => Duration::from_millis(*ms),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Step::Send {
+                pd,
+                command,
+                expect,
+                ..
+            } => format!("send {:?} to PD-{pd}, expect {expect:?}", command.kind()),
+            Step::Wait { ms } => format!("wait {ms} ms"),
+            Step::ExpectEvent { pd, kind, .. } => format!("expect {kind:?} event from PD-{pd}"),
+        }
+    }
+}
+
+pub struct Listener {
+    socket: UnixDatagram,
+}
+
+impl Listener {
+    pub fn bind(runtime_dir: &Path) -> Result<Self> {
+        let path = runtime_dir.join(SOCKET_NAME);
+        _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)
+            .with_context(|| format!("Unable to bind to {}", path.display()))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// The next playbook to run, if any, and where to send the results.
+    pub fn recv(&self) -> Option<(Vec<Step>, SocketAddr)> {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).ok()?;
+            match serde_json::from_slice(&buf[..len]) {
+                Ok(steps) => return Some((steps, addr)),
+                Err(e) => log::warn!("Dropped malformed playbook: {e}"),
+            }
+        }
+    }
+
+    /// Send the `results` of a playbook to `addr`.
+    pub fn reply(&self, addr: &SocketAddr, results: &Value) {
+        let Some(path) = addr.as_pathname() else {
+            return;
+        };
+        if let Err(e) = self.socket.send_to(results.to_string().as_bytes(), path) {
+            log::warn!("Unable to send playbook results: {e}");
+        }
+    }
+}
+
+type Events = Arc<Mutex<VecDeque<(i32, OsdpEvent)>>>;
+
+/// Keep `cp` running until `deadline` or until `done` returns true.
+fn refresh_until(cp: &mut ControlPanel, deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    loop {
+        cp.refresh();
+        if done() {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        cp.wait_readable(cp.time_until_next_work().min(deadline - now));
+    }
+}
+
+/// Drop queued events up to and including the first one of `kind` from PD
+/// `pd`; returns whether there was one.
+fn take_event(events: &Events, pd: i32, kind: OsdpEventKind) -> bool {
+    let mut events = events.lock().unwrap();
+    while let Some((from, event)) = events.pop_front() {
+        if from == pd && event.kind() == kind {
+            return true;
+        }
+    }
+    false
+}
+
+fn run_step(cp: &mut ControlPanel, events: &Events, step: &Step) -> Result<()> {
+    match step {
+        Step::Send {
+            pd,
+            command,
+            expect,
+            timeout_ms,
+        } => {
+            let timeout = Duration::from_millis(*timeout_ms);
+            match (cp.send_command_sync(*pd, command.clone(), timeout), expect) {
+                (Ok(()), Reply::Ack) | (Err(OsdpError::Nak(_)), Reply::Nak) => Ok(()),
+                (Ok(()), Reply::Nak) => bail!("PD-{pd} ACK'd the command"),
+                (Err(OsdpError::Nak(reason)), Reply::Ack) => {
+                    bail!("PD-{pd} NAK'd the command (reason {reason})")
+                }
+                (Err(e), _) => Err(e).context("Command failed"),
+            }
+        }
+        Step::Wait { ms } => {
+            refresh_until(cp, Instant::now() + Duration::from_millis(*ms), || false);
+            Ok(())
+        }
+        Step::ExpectEvent {
+            pd,
+            kind,
+            timeout_ms,
+        } => {
+            let deadline = Instant::now() + Duration::from_millis(*timeout_ms);
+            let kind = OsdpEventKind::from(*kind);
+            let found = refresh_until(cp, deadline, || take_event(events, *pd, kind));
+            if !found {
+                bail!("No {kind:?} event from PD-{pd} in {timeout_ms} ms");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Run `steps` on `cp`, stopping at the first one that fails. Events that
+/// come in meanwhile are passed to `on_event` too; the caller sets its own
+/// event callback again afterwards. Returns how each step went, as JSON.
+pub fn run(
+    cp: &mut ControlPanel,
+    steps: &[Step],
+    mut on_event: impl FnMut(&EventContext<'_>, OsdpEvent) -> i32 + Send + 'static,
+) -> Value {
+    let events: Events = Arc::new(Mutex::new(VecDeque::new()));
+    let queue = events.clone();
+    let _event_callback = cp.set_event_context_callback(move |pd, event| {
+        queue.lock().unwrap().push_back((pd.pd, event.clone()));
+        on_event(pd, event)
+    });
+    log::info!("Running a playbook of {} steps", steps.len());
+    run_steps(steps, |step| run_step(cp, &events, step))
+}
+
+/// Run `steps` with `run_step`, stopping at the first one that fails, and
+/// report how each step went.
+fn run_steps(steps: &[Step], mut run_step: impl FnMut(&Step) -> Result<()>) -> Value {
+    let mut results = Vec::with_capacity(steps.len());
+    for (i, step) in steps.iter().enumerate() {
+        let what = step.describe();
+        match run_step(step) {
+            Ok(()) => {
+                log::info!("Playbook step {i} ({what}): ok");
+                results.push(json!({ "step": i, "what": what, "ok": true }));
+            }
+            Err(e) => {
+                log::error!("Playbook step {i} ({what}) failed: {e:#}");
+                results.push(json!({
+                    "step": i,
+                    "what": what,
+                    "ok": false,
+                    "error": format!("{e:#}"),
+                }));
+                break;
+            }
+        }
+    }
+    let passed = results.len() == steps.len() && results.iter().all(|r| r["ok"] == true);
+    json!({ "passed": passed, "steps": results })
+}
+
+/// Run `playbook` on the CP whose runtime directory is `runtime_dir` and wait
+/// for the results.
+pub fn request(runtime_dir: &Path, playbook: &Playbook) -> Result<Value> {
+    let reply_path = runtime_dir.join(format!("playbook-{}.sock", std::process::id()));
+    _ = std::fs::remove_file(&reply_path);
+    let socket = UnixDatagram::bind(&reply_path)
+        .with_context(|| format!("Unable to bind to {}", reply_path.display()))?;
+    let result = (|| {
+        socket.set_read_timeout(Some(playbook.duration() + Duration::from_secs(1)))?;
+        socket
+            .send_to(
+                &serde_json::to_vec(&playbook.steps)?,
+                runtime_dir.join(SOCKET_NAME),
+            )
+            .context("Unable to reach the device; is it running?")?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let len = socket
+            .recv(&mut buf)
+            .context("Device did not report back in time")?;
+        let results: Value = serde_json::from_slice(&buf[..len])?;
+        if !results["steps"].is_array() {
+            bail!("Unexpected reply from the device: {results}");
+        }
+        Ok(results)
+    })();
+    _ = std::fs::remove_file(&reply_path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_steps, take_event, EventKind, Events, Playbook, Reply, Step};
+    use anyhow::bail;
+    use libosdp::{OsdpCommand, OsdpEvent, OsdpEventKeyPress, OsdpEventKind, OsdpStatusReport};
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    const PLAYBOOK: &str = r#"
+device: cp
+steps:
+  - send:
+      pd: 0
+      command:
+        Buzzer: { reader: 0, control_code: 2, on_count: 2, off_count: 2, rep_count: 1 }
+      expect: nak
+  - wait: { ms: 500 }
+  - expect_event:
+      pd: 1
+      kind: card_read
+      timeout_ms: 30000
+"#;
+
+    #[test]
+    fn test_parse() {
+        let playbook = Playbook::parse(PLAYBOOK).unwrap();
+        assert_eq!(playbook.device, "cp");
+        assert_eq!(playbook.steps.len(), 3);
+        let Step::Send {
+            pd,
+            command: OsdpCommand::Buzzer(buzzer),
+            expect,
+            timeout_ms,
+        } = &playbook.steps[0]
+        else {
+            panic!("not a buzzer send step: {:?}", playbook.steps[0]);
+        };
+        assert_eq!(*pd, 0);
+        assert_eq!((buzzer.control_code, buzzer.on_count), (2, 2));
+        assert_eq!(*expect, Reply::Nak);
+        assert_eq!(*timeout_ms, 5000, "default timeout");
+        assert!(matches!(playbook.steps[1], Step::Wait { ms: 500 }));
+        assert!(matches!(
+            playbook.steps[2],
+            Step::ExpectEvent {
+                pd: 1,
+                kind: EventKind::CardRead,
+                timeout_ms: 30000
+            }
+        ));
+        assert_eq!(playbook.duration(), Duration::from_millis(35500));
+    }
+
+    #[test]
+    fn test_parse_defaults_and_errors() {
+        let playbook = Playbook::parse(
+            "device: cp\nsteps:\n  - send: { pd: 2, command: { Buzzer: { reader: 0, control_code: 1, on_count: 1, off_count: 0, rep_count: 1 } } }\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            playbook.steps[0],
+            Step::Send {
+                expect: Reply::Ack,
+                ..
+            }
+        ));
+
+        assert!(Playbook::parse("device: cp\nsteps:\n  - sleep: { ms: 5 }\n").is_err());
+        assert!(Playbook::parse("device: cp\nsteps:\n  - wait: { ms: -5 }\n").is_err());
+        assert!(Playbook::parse("steps: []\n").is_err(), "no device");
+        let err = "device: cp\nsteps:\n  - expect_event: { pd: 0, kind: door_open }\n";
+        assert!(Playbook::parse(err).is_err());
+    }
+
+    #[test]
+    fn test_steps_round_trip() {
+        // Steps travel to the CP as JSON
+        let playbook = Playbook::parse(PLAYBOOK).unwrap();
+        let json = serde_json::to_vec(&playbook.steps).unwrap();
+        let steps: Vec<Step> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(format!("{steps:?}"), format!("{:?}", playbook.steps));
+        assert_eq!(steps[0].describe(), "send Buzzer to PD-0, expect Nak");
+        assert_eq!(steps[1].describe(), "wait 500 ms");
+        assert_eq!(steps[2].describe(), "expect CardRead event from PD-1");
+    }
+
+    #[test]
+    fn test_take_event() {
+        let events: Events = Arc::new(Mutex::new(VecDeque::from([
+            (0, OsdpEvent::KeyPress(OsdpEventKeyPress::new(vec![0x31]))),
+            (1, OsdpEvent::Status(OsdpStatusReport::new_local(0))),
+            (1, OsdpEvent::KeyPress(OsdpEventKeyPress::new(vec![0x32]))),
+            (1, OsdpEvent::KeyPress(OsdpEventKeyPress::new(vec![0x33]))),
+        ])));
+        assert!(take_event(&events, 1, OsdpEventKind::KeyPress));
+        // Events before the match are consumed, later ones are kept
+        assert_eq!(events.lock().unwrap().len(), 1);
+        assert!(take_event(&events, 1, OsdpEventKind::KeyPress));
+        assert!(!take_event(&events, 1, OsdpEventKind::KeyPress));
+        assert!(!take_event(&events, 0, OsdpEventKind::KeyPress));
+    }
+
+    #[test]
+    fn test_run_steps() {
+        let steps = Playbook::parse(PLAYBOOK).unwrap().steps;
+        let results = run_steps(&steps, |_| Ok(()));
+        assert_eq!(results["passed"], true);
+        assert_eq!(results["steps"].as_array().unwrap().len(), 3);
+        assert_eq!(results["steps"][1]["what"], "wait 500 ms");
+
+        // Stops at the first failure
+        let mut ran = 0;
+        let results = run_steps(&steps, |step| {
+            ran += 1;
+            match step {
+                Step::Wait { .. } => bail!("too slow"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(ran, 2);
+        assert_eq!(results["passed"], false);
+        let results = results["steps"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["ok"], true);
+        assert_eq!(results[1]["ok"], false);
+        assert_eq!(results[1]["error"], "too slow");
+
+        // An empty playbook passes
+        assert_eq!(run_steps(&[], |_| bail!("no steps"))["passed"], true);
+    }
+}
@@ -0,0 +1,62 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running devices re-read their config when they get a SIGHUP (which is what
+//! `osdpctl reload` sends). The signal handler only bumps a counter; each
+//! device loop polls it through a [`Watcher`] of its own so that all devices
+//! running in a process (see `osdpctl start`) get to see the request.
+
+use anyhow::Context;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::DeviceConfig;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_sighup(_: nix::libc::c_int) {
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn install() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_sighup),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(Signal::SIGHUP, &action) }
+        .context("Failed to install SIGHUP handler")?;
+    Ok(())
+}
+
+pub struct Watcher {
+    seen: usize,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self {
+            seen: RELOADS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether a reload was requested since the last call.
+    pub fn requested(&mut self) -> bool {
+        let reloads = RELOADS.load(Ordering::Relaxed);
+        if reloads == self.seen {
+            return false;
+        }
+        self.seen = reloads;
+        true
+    }
+}
+
+/// Read the (possibly edited) config of the device `name` again.
+pub fn read_config(name: &str) -> Result<DeviceConfig> {
+    let config_path = crate::osdpctl_config_dir()?.join(format!("{name}.cfg"));
+    DeviceConfig::new(&config_path, &crate::device_runtime_dir()?)
+}
@@ -0,0 +1,138 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl rotate-keys` makes a running CP give its PDs new secure channel
+//! keys. The request (serialized as JSON) is sent to a datagram socket that
+//! each CP device listens on, in its runtime directory; the CP rotates the
+//! keys one PD at a time and replies with how that went for each of them.
+//!
+//! A new key is only written to the key store of a PD once the PD has
+//! accepted it (ACK'd the KEYSET command); a PD that failed keeps its key.
+
+use crate::config::CpConfig;
+use anyhow::{bail, Context};
+use libosdp::ControlPanel;
+use serde_json::{json, Value};
+use std::{
+    os::unix::net::{SocketAddr, UnixDatagram},
+    path::Path,
+    time::Duration,
+};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+const SOCKET_NAME: &str = "rotate.sock";
+
+/// How long a PD gets to accept its new key
+const KEYSET_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Listener {
+    socket: UnixDatagram,
+}
+
+impl Listener {
+    pub fn bind(runtime_dir: &Path) -> Result<Self> {
+        let path = runtime_dir.join(SOCKET_NAME);
+        _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)
+            .with_context(|| format!("Unable to bind to {}", path.display()))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// The next request, if any: the PDs to rotate the keys of (all of them
+    /// if `None`) and where to send the results.
+    pub fn recv(&self) -> Option<(Option<Vec<i32>>, SocketAddr)> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).ok()?;
+            match serde_json::from_slice::<Value>(&buf[..len]) {
+                Ok(request) => {
+                    let pds = request["pds"].as_array().map(|pds| {
+                        pds.iter()
+                            .filter_map(|pd| pd.as_i64())
+                            .map(|pd| pd as i32)
+                            .collect()
+                    });
+                    return Some((pds, addr));
+                }
+                Err(e) => log::warn!("Dropped malformed rotate-keys request: {e}"),
+            }
+        }
+    }
+
+    /// Send the `results` of a request to `addr`.
+    pub fn reply(&self, addr: &SocketAddr, results: &Value) {
+        let Some(path) = addr.as_pathname() else {
+            return;
+        };
+        if let Err(e) = self.socket.send_to(results.to_string().as_bytes(), path) {
+            log::warn!("Unable to send rotate-keys results: {e}");
+        }
+    }
+}
+
+/// Give each of `pds` (all PDs if `None`) of `cp` a new random key and store
+/// the ones that were accepted in the key stores of `dev`. Returns what
+/// became of each PD, as a JSON array.
+pub fn rotate(cp: &mut ControlPanel, dev: &mut CpConfig, pds: Option<Vec<i32>>) -> Value {
+    let names: Vec<String> = dev.pd_names().map(|n| n.to_owned()).collect();
+    let pds = pds.unwrap_or_else(|| (0..names.len() as i32).collect());
+    let mut results = Vec::with_capacity(pds.len());
+    for pd in pds {
+        let key: [u8; 16] = rand::random();
+        let result = cp
+            .rotate_key(pd, key, KEYSET_TIMEOUT)
+            .context("PD did not accept the new key")
+            .and_then(|_| dev.set_key(pd as usize, key));
+        let name = names.get(pd as usize).map_or("", |n| n.as_str());
+        match result {
+            Ok(()) => {
+                log::info!("Rotated the key of PD-{pd}");
+                results.push(json!({ "pd": pd, "name": name, "rotated": true }));
+            }
+            Err(e) => {
+                log::error!("Failed to rotate the key of PD-{pd}: {e:#}");
+                results.push(json!({
+                    "pd": pd,
+                    "name": name,
+                    "rotated": false,
+                    "error": format!("{e:#}"),
+                }));
+            }
+        }
+    }
+    Value::Array(results)
+}
+
+/// Ask the CP whose runtime directory is `runtime_dir` to rotate the keys of
+/// `pds` and wait for the results.
+pub fn request(runtime_dir: &Path, pds: &[i32]) -> Result<Value> {
+    let reply_path = runtime_dir.join(format!("rotate-{}.sock", std::process::id()));
+    _ = std::fs::remove_file(&reply_path);
+    let socket = UnixDatagram::bind(&reply_path)
+        .with_context(|| format!("Unable to bind to {}", reply_path.display()))?;
+    let result = (|| {
+        let timeout = KEYSET_TIMEOUT * pds.len() as u32 + Duration::from_secs(1);
+        socket.set_read_timeout(Some(timeout))?;
+        socket
+            .send_to(
+                json!({ "pds": pds }).to_string().as_bytes(),
+                runtime_dir.join(SOCKET_NAME),
+            )
+            .context("Unable to reach the device; is it running?")?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let len = socket
+            .recv(&mut buf)
+            .context("Device did not report back in time")?;
+        let results: Value = serde_json::from_slice(&buf[..len])?;
+        if !results.is_array() {
+            bail!("Unexpected reply from the device: {results}");
+        }
+        Ok(results)
+    })();
+    _ = std::fs::remove_file(&reply_path);
+    result
+}
@@ -0,0 +1,28 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! `osdpctl scan` looks for PDs on a bus whose addressing is not known, with
+//! [`libosdp::Discovery`], and reports the ones that answer with the identity
+//! they sent back (some PDs may NAK instead; they are reported too).
+
+use libosdp::DiscoveredPd;
+use serde_json::{json, Value};
+
+/// What to print about `pd`
+pub fn identity(pd: &DiscoveredPd) -> String {
+    match &pd.pd_id {
+        Some(id) => id.to_string(),
+        None => format!("(replied {})", pd.reply_name()),
+    }
+}
+
+pub fn to_json(pd: &DiscoveredPd) -> Value {
+    json!({
+        "address": pd.address,
+        "baud_rate": pd.baud_rate,
+        "pd_id": pd.pd_id.map(|id| id.to_string()),
+        "reply": pd.reply_name(),
+    })
+}
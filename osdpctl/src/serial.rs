@@ -0,0 +1,102 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! OSDP channel over a serial port (an RS-485 adapter, usually), given as
+//! `serial::<path>` (for instance, `serial::/dev/ttyUSB0`).
+
+use anyhow::{bail, Context};
+use libosdp::{Channel, ChannelError, ReconfigurableChannel};
+use nix::poll::{poll, PollFd, PollFlags};
+use serialport::{SerialPort, TTYPort};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
+    time::Duration,
+};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+pub struct SerialChannel {
+    id: i32,
+    port: TTYPort,
+}
+
+impl SerialChannel {
+    /// Open the serial port at `path`, as 8N1 at `baud_rate`.
+    pub fn open(path: &str, baud_rate: u32) -> Result<Self> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(Duration::ZERO)
+            .open_native()
+            .with_context(|| format!("Unable to open serial port {path}"))?;
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let id = hasher.finish();
+        Ok(Self {
+            id: ((id >> 32) ^ (id & 0xffffffff)) as i32,
+            port,
+        })
+    }
+
+    /// Open the channel described by `spec` (`serial::<path>`).
+    pub fn from_spec(spec: &str, baud_rate: u32) -> Result<Self> {
+        match spec.split_once("::") {
+            Some(("serial", path)) if !path.is_empty() => Self::open(path, baud_rate),
+            _ => bail!("Channel '{spec}' is not of the form serial::<path>"),
+        }
+    }
+}
+
+fn channel_error(e: io::Error) -> ChannelError {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {
+            ChannelError::WouldBlock
+        }
+        _ => ChannelError::TransportError,
+    }
+}
+
+impl Channel for SerialChannel {
+    fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, ChannelError> {
+        self.port.read(buf).map_err(channel_error)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, ChannelError> {
+        self.port.write(buf).map_err(channel_error)
+    }
+
+    fn flush(&mut self) -> std::result::Result<(), ChannelError> {
+        self.port.flush().map_err(channel_error)
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> std::result::Result<bool, ChannelError> {
+        // SAFETY: the descriptor stays open for as long as `self.port` does
+        let fd = unsafe { BorrowedFd::borrow_raw(self.port.as_raw_fd()) };
+        let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+        let timeout = timeout.as_millis().min(u16::MAX as u128) as u16;
+        match poll(&mut fds, timeout) {
+            Ok(n) => Ok(n > 0),
+            Err(nix::errno::Errno::EINTR) => Ok(true),
+            Err(_) => Err(ChannelError::TransportError),
+        }
+    }
+
+    fn as_reconfigurable(&mut self) -> Option<&mut dyn ReconfigurableChannel> {
+        Some(self)
+    }
+}
+
+impl ReconfigurableChannel for SerialChannel {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> std::result::Result<(), ChannelError> {
+        self.port
+            .set_baud_rate(baud_rate)
+            .map_err(|_| ChannelError::TransportError)
+    }
+}
@@ -0,0 +1,178 @@
+//
+// Copyright (c) 2024 Siddharth Chandrasekaran <sidcha.dev@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A device that is running has a pid file in its runtime directory (see
+//! `osdpctl start`), which its process holds a lock on. A device that was
+//! killed, or whose process crashed, leaves it (and the sockets it was
+//! listening on) behind; this module tells such devices apart from running
+//! ones and cleans up after them, so that `osdpctl start|stop|restart|status`
+//! can manage devices like services.
+//!
+//! The lock, rather than the pid, is what tells whether the device runs: the
+//! pid of a device that is gone may have been reused by an unrelated process
+//! that must not be signalled.
+
+use crate::config::DeviceConfig;
+use anyhow::{bail, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use std::{fs::File, io::Write, path::Path, thread, time::Duration};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// How long a device gets to exit after being asked to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Running as this process
+    Running(i32),
+    /// Not running
+    Stopped,
+    /// Not running, but the process with this pid left its pid file behind
+    Stale(i32),
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Running(_) => "Running",
+            Status::Stopped => "Stopped",
+            Status::Stale(_) => "Stale",
+        }
+    }
+
+    pub fn pid(&self) -> Option<i32> {
+        match self {
+            Status::Running(pid) | Status::Stale(pid) => Some(*pid),
+            Status::Stopped => None,
+        }
+    }
+}
+
+/// Write the pid file of a device that runs in this process and lock it for
+/// as long as the process lives. The daemonize crate does the same for the
+/// pid files it writes.
+pub fn write_pid_file(pid_file: &Path) -> Result<()> {
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(pid_file)
+        .with_context(|| format!("Unable to create {}", pid_file.display()))?;
+    let mut file = Flock::lock(file, FlockArg::LockExclusiveNonblock)
+        .map_err(|(_, e)| e)
+        .with_context(|| format!("Unable to lock {}", pid_file.display()))?;
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    // Closing the file would release the lock
+    std::mem::forget(file);
+    Ok(())
+}
+
+/// Whether a process holds the lock on `pid_file`
+fn is_locked(pid_file: &Path) -> bool {
+    let Ok(file) = File::open(pid_file) else {
+        return false;
+    };
+    matches!(
+        Flock::lock(file, FlockArg::LockSharedNonblock),
+        Err((_, Errno::EWOULDBLOCK))
+    )
+}
+
+pub fn status(dev: &DeviceConfig) -> Status {
+    match dev.get_pid() {
+        Ok(pid) if is_locked(&dev.pid_file()) => Status::Running(pid),
+        Ok(pid) => Status::Stale(pid),
+        Err(_) => Status::Stopped,
+    }
+}
+
+/// Remove the pid file and sockets that `dev` left in its runtime directory.
+pub fn cleanup(dev: &DeviceConfig) -> Result<()> {
+    cleanup_dir(dev.runtime_dir())
+}
+
+/// Remove the pid files and sockets in `runtime_dir`; other files in it
+/// (such as key stores) are kept.
+pub fn cleanup_dir(runtime_dir: &Path) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(runtime_dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if matches!(path.extension(), Some(ext) if ext == "sock" || ext == "pid") {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Unable to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Stop `dev` if it is running (killing it if it does not exit in time and
+/// `force` is set) and clean up after it. Returns the status it had.
+pub fn stop(dev: &DeviceConfig, force: bool) -> Result<Status> {
+    let status = status(dev);
+    if let Status::Running(pid) = status {
+        let pid_t = Pid::from_raw(pid);
+        signal::kill(pid_t, Signal::SIGTERM).context("Failed to stop the device")?;
+        if !wait_for_exit(dev, STOP_TIMEOUT) {
+            if !force {
+                bail!(
+                    "Device '{}' did not stop; try again with --force",
+                    dev.name()
+                );
+            }
+            log::warn!("Device '{}' did not stop; killing it", dev.name());
+            signal::kill(pid_t, Signal::SIGKILL).context("Failed to kill the device")?;
+            if !wait_for_exit(dev, STOP_TIMEOUT) {
+                bail!("Device '{}' (pid {pid}) could not be killed", dev.name());
+            }
+        }
+    }
+    cleanup(dev)?;
+    Ok(status)
+}
+
+fn wait_for_exit(dev: &DeviceConfig, timeout: Duration) -> bool {
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::ZERO;
+    while is_locked(&dev.pid_file()) {
+        if waited >= timeout {
+            return false;
+        }
+        thread::sleep(step);
+        waited += step;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_locked, write_pid_file};
+
+    #[test]
+    fn test_pid_file_lock() {
+        let dir = std::env::temp_dir().join(format!("osdpctl-pid-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid_file = dir.join("dev-test.pid");
+
+        // Left behind by a process that is gone
+        std::fs::write(&pid_file, "1").unwrap();
+        assert!(!is_locked(&pid_file));
+
+        write_pid_file(&pid_file).unwrap();
+        assert!(is_locked(&pid_file));
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert_eq!(pid, std::process::id().to_string());
+        assert!(write_pid_file(&pid_file).is_err());
+        assert!(!is_locked(&dir.join("dev-none.pid")));
+    }
+}